@@ -0,0 +1,14 @@
+#![no_main]
+
+use isotp_core::frame::{decode_first_frame, decode_single_frame};
+use libfuzzer_sys::fuzz_target;
+
+// Frames off the bus are attacker controlled, decoding must never read past them
+fuzz_target!(|data: &[u8]| {
+    if let Ok(payload) = decode_single_frame(data) {
+        assert!(payload.len() < data.len());
+    }
+    if let Ok((length, first_chunk)) = decode_first_frame(data) {
+        assert!(length > first_chunk.len());
+    }
+});
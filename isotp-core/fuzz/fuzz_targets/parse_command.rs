@@ -0,0 +1,12 @@
+#![no_main]
+
+use isotp_core::protocol::{BleMessageParser, ParsedBleMessage};
+use libfuzzer_sys::fuzz_target;
+
+// Whatever a client writes to the command characteristic has to parse or be rejected
+fuzz_target!(|data: &[u8]| {
+    if let Ok(ParsedBleMessage::StartPeriodicIsotpMessage(command)) = BleMessageParser::parse(data)
+    {
+        for _ in command.iter_messages() {}
+    }
+});
@@ -0,0 +1,432 @@
+//! ISO 15765-2 frame layout
+//! Protocol control information of single, first, consecutive and flow control frames,
+//! without any timing or I/O. The transfer state machines and the handler on the bridge
+//! drive these.
+
+use heapless::Vec;
+
+// ISO-15765 constants
+pub const SF_DL_MAX: usize = 7; // Single Frame max data length
+pub const CLASSIC_FRAME_LEN: usize = 8;
+pub const FF_DL_MAX: usize = 4095; // Longest length the 12 bit First Frame field can hold
+
+/// CAN FD data lengths above 8 bytes, frames are padded up to the next one
+pub const FD_FRAME_LENS: [usize; 7] = [12, 16, 20, 24, 32, 48, 64];
+/// Longest CAN FD frame
+pub const MAX_FRAME_LEN: usize = 64;
+
+/// One encoded frame, at most as long as the format's frame length
+pub type Frame = Vec<u8, MAX_FRAME_LEN>;
+
+// Frame types
+pub const SINGLE_FRAME: u8 = 0x00;
+pub const FIRST_FRAME: u8 = 0x10;
+pub const CONSECUTIVE_FRAME: u8 = 0x20;
+pub const FLOW_CONTROL: u8 = 0x30;
+
+// Flow Status
+pub const CONTINUE_TO_SEND: u8 = 0x00;
+pub const WAIT: u8 = 0x01;
+pub const OVERFLOW: u8 = 0x02;
+
+/// Malformed frames from the peer, reported to the BLE client as events
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ProtocolError {
+    // SF_DL of 0 or longer than the frame
+    InvalidSingleFrameLength = 0x01,
+    // FF too short for its header or FF_DL that should have been a Single Frame
+    InvalidFirstFrameLength = 0x02,
+    // CF without any data
+    InvalidConsecutiveFrameLength = 0x03,
+    // FC without BS and STmin
+    InvalidFlowControlLength = 0x04,
+    // FC flow status other than CTS, WAIT or OVERFLOW
+    ReservedFlowStatus = 0x05,
+    // PCI frame type above 3
+    UnknownFrameType = 0x06,
+}
+
+/// Flow status, block size and STmin a receiver asked for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FlowControl {
+    pub flow_status: u8,
+    pub block_size: u8,
+    pub st_min: u8,
+}
+
+/// How the frames we send are laid out
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameFormat {
+    // Longest frame the backend takes, 8 bytes on classic CAN
+    pub frame_len: usize,
+    // Extended addressing (N_AE) byte put in front of every frame
+    pub address_extension: Option<u8>,
+    pub pad_byte: u8,
+    // Data bytes per consecutive frame, None fills the frame. Shorter ones go out unpadded.
+    pub consecutive_frame_payload: Option<u8>,
+}
+
+impl FrameFormat {
+    fn address_extension_len(&self) -> usize {
+        self.address_extension.map_or(0, |_| 1)
+    }
+
+    // Empty frame, or the address extension byte when extended addressing is on
+    fn new_frame(&self) -> Frame {
+        let mut frame = Vec::new();
+        if let Some(extension) = self.address_extension {
+            frame.push(extension).unwrap();
+        }
+        frame
+    }
+
+    fn pad(&self, frame: &mut Frame) {
+        let padded_len = padded_len(frame.len(), self.frame_len);
+        while frame.len() < padded_len {
+            frame.push(self.pad_byte).unwrap();
+        }
+    }
+
+    /// Longest message that goes out as a Single Frame. Classic CAN fits the length in the
+    /// PCI nibble, FD frames escape it into a second byte.
+    pub fn single_frame_max(&self) -> usize {
+        if self.frame_len > CLASSIC_FRAME_LEN {
+            self.frame_len - 2 - self.address_extension_len()
+        } else {
+            SF_DL_MAX - self.address_extension_len()
+        }
+    }
+
+    /// Data bytes carried by each consecutive frame
+    pub fn consecutive_frame_payload(&self) -> usize {
+        let full = self.frame_len - 1 - self.address_extension_len();
+        self.consecutive_frame_payload
+            .map_or(full, |payload| (payload as usize).min(full))
+    }
+
+    /// Single Frame carrying all of `data`, at most single_frame_max() bytes
+    pub fn single_frame(&self, data: &[u8]) -> Frame {
+        let mut frame = self.new_frame();
+        if frame.len() + 1 + data.len() <= CLASSIC_FRAME_LEN {
+            frame.push(SINGLE_FRAME | data.len() as u8).unwrap();
+        } else {
+            // CAN FD escape: SF_DL of 0 followed by the real length
+            frame
+                .extend_from_slice(&[SINGLE_FRAME, data.len() as u8])
+                .unwrap();
+        }
+        frame.extend_from_slice(data).unwrap();
+        self.pad(&mut frame);
+        frame
+    }
+
+    /// First Frame of a message longer than single_frame_max(), with how many of its bytes
+    /// it carries. The frame is full, so it needs no padding.
+    pub fn first_frame(&self, data: &[u8]) -> (Frame, usize) {
+        let mut frame = self.new_frame();
+        let length = data.len();
+        if length <= FF_DL_MAX {
+            frame
+                .extend_from_slice(&[FIRST_FRAME | (length >> 8) as u8, length as u8])
+                .unwrap();
+        } else {
+            // Escape sequence: FF_DL of 0 followed by the real length on 32 bits
+            frame.extend_from_slice(&[FIRST_FRAME, 0x00]).unwrap();
+            frame
+                .extend_from_slice(&(length as u32).to_be_bytes())
+                .unwrap();
+        }
+        let first_chunk = self.frame_len - frame.len();
+        frame.extend_from_slice(&data[..first_chunk]).unwrap();
+        (frame, first_chunk)
+    }
+
+    /// Consecutive Frame with at most consecutive_frame_payload() bytes. Receivers take
+    /// padding as data, so shortened ones go out unpadded.
+    pub fn consecutive_frame(&self, sequence_number: u8, chunk: &[u8]) -> Frame {
+        let mut frame = self.new_frame();
+        frame.push(CONSECUTIVE_FRAME | sequence_number).unwrap();
+        frame.extend_from_slice(chunk).unwrap();
+        if self.consecutive_frame_payload.is_none() {
+            self.pad(&mut frame);
+        }
+        frame
+    }
+
+    pub fn flow_control(&self, flow_status: u8, block_size: u8, st_min: u8) -> Frame {
+        let mut frame = self.new_frame();
+        frame
+            .extend_from_slice(&[FLOW_CONTROL | flow_status, block_size, st_min])
+            .unwrap();
+        self.pad(&mut frame);
+        frame
+    }
+}
+
+pub fn is_valid_st_min(st_min: u8) -> bool {
+    matches!(st_min, 0x00..=0x7F | 0xF1..=0xF9)
+}
+
+/// Decode an STmin byte: 0x00-0x7F are milliseconds, 0xF1-0xF9 are 100-900 microseconds.
+/// Reserved values must be treated as the longest valid gap (127ms).
+pub fn st_min_to_micros(st_min: u8) -> u32 {
+    match st_min {
+        0x00..=0x7F => st_min as u32 * 1000,
+        0xF1..=0xF9 => (st_min - 0xF0) as u32 * 100,
+        _ => 0x7F * 1000,
+    }
+}
+
+/// Sequence numbers wrap from 0xF back to 0x0
+pub fn next_sequence_number(sequence_number: u8) -> u8 {
+    (sequence_number + 1) & 0x0F
+}
+
+/// Length a frame of `len` bytes is padded to, classic frames always take 8 bytes and
+/// CAN FD frames the next valid data length, at most `max_len`
+pub fn padded_len(len: usize, max_len: usize) -> usize {
+    if len <= CLASSIC_FRAME_LEN {
+        return CLASSIC_FRAME_LEN;
+    }
+    FD_FRAME_LENS
+        .into_iter()
+        .find(|&fd_len| fd_len >= len)
+        .unwrap_or(max_len)
+}
+
+/// Payload of a single frame, `data` starting at the PCI byte
+pub fn decode_single_frame(data: &[u8]) -> Result<&[u8], ProtocolError> {
+    let Some((&pci, mut payload)) = data.split_first() else {
+        return Err(ProtocolError::InvalidSingleFrameLength);
+    };
+    let mut length = (pci & 0x0F) as usize;
+
+    // CAN FD escape, frames longer than 8 bytes carry SF_DL in the second byte
+    if length == 0 && data.len() > CLASSIC_FRAME_LEN {
+        length = data[1] as usize;
+        payload = &data[2..];
+    }
+
+    // Unpadded senders use a shorter DLC, so check against what actually arrived
+    if length == 0 || length > payload.len() {
+        return Err(ProtocolError::InvalidSingleFrameLength);
+    }
+    Ok(&payload[..length])
+}
+
+/// Announced message length and the data carried by a first frame, `data` starting at
+/// the PCI byte
+pub fn decode_first_frame(data: &[u8]) -> Result<(usize, &[u8]), ProtocolError> {
+    let [pci, low, ..] = *data else {
+        return Err(ProtocolError::InvalidFirstFrameLength);
+    };
+    let mut length = (((pci & 0x0F) as u32) << 8) | (low as u32);
+    let mut first_chunk = &data[2..];
+
+    // Escape sequence, the length follows as 32 bits
+    if length == 0 {
+        let [_, _, a, b, c, d, ..] = *data else {
+            return Err(ProtocolError::InvalidFirstFrameLength);
+        };
+        length = u32::from_be_bytes([a, b, c, d]);
+        first_chunk = &data[6..];
+    }
+
+    // A message that fits in this frame should have been a Single Frame
+    if length as usize <= first_chunk.len() {
+        return Err(ProtocolError::InvalidFirstFrameLength);
+    }
+    Ok((length as usize, first_chunk))
+}
+
+/// Flow status, BS and STmin of a flow control frame, `data` starting at the PCI byte
+pub fn decode_flow_control(data: &[u8]) -> Result<FlowControl, ProtocolError> {
+    let [pci, block_size, st_min, ..] = *data else {
+        return Err(ProtocolError::InvalidFlowControlLength);
+    };
+    let flow_status = pci & 0x0F;
+    if !matches!(flow_status, CONTINUE_TO_SEND | WAIT | OVERFLOW) {
+        return Err(ProtocolError::ReservedFlowStatus);
+    }
+    Ok(FlowControl {
+        flow_status,
+        block_size,
+        st_min,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn single_frame_payload() {
+        let frame = [0x03, 0x22, 0xF1, 0x90, 0x55, 0x55, 0x55, 0x55];
+        assert_eq!(decode_single_frame(&frame), Ok(&[0x22, 0xF1, 0x90][..]));
+    }
+
+    #[test]
+    fn unpadded_single_frame() {
+        assert_eq!(
+            decode_single_frame(&[0x02, 0x3E, 0x00]),
+            Ok(&[0x3E, 0x00][..])
+        );
+    }
+
+    #[test]
+    fn single_frame_longer_than_frame() {
+        assert_eq!(
+            decode_single_frame(&[0x05, 0x3E, 0x00]),
+            Err(ProtocolError::InvalidSingleFrameLength)
+        );
+    }
+
+    #[test]
+    fn empty_single_frame() {
+        assert_eq!(
+            decode_single_frame(&[]),
+            Err(ProtocolError::InvalidSingleFrameLength)
+        );
+        assert_eq!(
+            decode_single_frame(&[0x00; 8]),
+            Err(ProtocolError::InvalidSingleFrameLength)
+        );
+    }
+
+    #[test]
+    fn escaped_single_frame() {
+        let mut frame = [0xAA; 12];
+        frame[0] = 0x00;
+        frame[1] = 10;
+        assert_eq!(decode_single_frame(&frame), Ok(&[0xAA; 10][..]));
+    }
+
+    #[test]
+    fn first_frame_length() {
+        let frame = [0x10, 0x14, 0x62, 0xF1, 0x90, 0x57, 0x30, 0x4C];
+        assert_eq!(
+            decode_first_frame(&frame),
+            Ok((0x14, &[0x62, 0xF1, 0x90, 0x57, 0x30, 0x4C][..]))
+        );
+    }
+
+    #[test]
+    fn escaped_first_frame_length() {
+        let frame = [0x10, 0x00, 0x00, 0x00, 0x10, 0x00, 0x01, 0x02];
+        assert_eq!(decode_first_frame(&frame), Ok((0x1000, &[0x01, 0x02][..])));
+    }
+
+    #[test]
+    fn truncated_first_frames() {
+        assert_eq!(
+            decode_first_frame(&[0x10]),
+            Err(ProtocolError::InvalidFirstFrameLength)
+        );
+        assert_eq!(
+            decode_first_frame(&[0x10, 0x00, 0x00]),
+            Err(ProtocolError::InvalidFirstFrameLength)
+        );
+    }
+
+    #[test]
+    fn first_frame_that_fits_a_single_frame() {
+        let frame = [0x10, 0x06, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06];
+        assert_eq!(
+            decode_first_frame(&frame),
+            Err(ProtocolError::InvalidFirstFrameLength)
+        );
+    }
+
+    #[test]
+    fn flow_control_fields() {
+        assert_eq!(
+            decode_flow_control(&[0x30, 0x08, 0x14, 0x55, 0x55, 0x55, 0x55, 0x55]),
+            Ok(FlowControl {
+                flow_status: CONTINUE_TO_SEND,
+                block_size: 8,
+                st_min: 0x14,
+            })
+        );
+        assert_eq!(
+            decode_flow_control(&[0x31, 0x00]),
+            Err(ProtocolError::InvalidFlowControlLength)
+        );
+        assert_eq!(
+            decode_flow_control(&[0x33, 0x00, 0x00]),
+            Err(ProtocolError::ReservedFlowStatus)
+        );
+    }
+
+    const CLASSIC: FrameFormat = FrameFormat {
+        frame_len: CLASSIC_FRAME_LEN,
+        address_extension: None,
+        pad_byte: 0xAA,
+        consecutive_frame_payload: None,
+    };
+
+    #[test]
+    fn encoded_frames() {
+        assert_eq!(
+            CLASSIC.single_frame(&[0x3E, 0x00]),
+            [0x02, 0x3E, 0x00, 0xAA, 0xAA, 0xAA, 0xAA, 0xAA]
+        );
+        let (first_frame, first_chunk) = CLASSIC.first_frame(&[0x11; 20]);
+        assert_eq!(first_frame, [0x10, 20, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11]);
+        assert_eq!(first_chunk, 6);
+        assert_eq!(
+            CLASSIC.consecutive_frame(0x0F, &[0x01, 0x02]),
+            [0x2F, 0x01, 0x02, 0xAA, 0xAA, 0xAA, 0xAA, 0xAA]
+        );
+        assert_eq!(
+            CLASSIC.flow_control(WAIT, 0, 0),
+            [0x31, 0x00, 0x00, 0xAA, 0xAA, 0xAA, 0xAA, 0xAA]
+        );
+    }
+
+    #[test]
+    fn extended_addressing_and_short_consecutive_frames() {
+        let format = FrameFormat {
+            address_extension: Some(0xF1),
+            consecutive_frame_payload: Some(3),
+            ..CLASSIC
+        };
+        assert_eq!(format.single_frame_max(), 6);
+        assert_eq!(format.consecutive_frame_payload(), 3);
+        let (first_frame, first_chunk) = format.first_frame(&[0x11; 20]);
+        assert_eq!(first_frame[..3], [0xF1, 0x10, 20]);
+        assert_eq!(first_chunk, 5);
+        // Unpadded, the receiver would take the padding as data
+        assert_eq!(
+            format.consecutive_frame(1, &[0x01, 0x02, 0x03]),
+            [0xF1, 0x21, 0x01, 0x02, 0x03]
+        );
+    }
+
+    #[test]
+    fn st_min_values() {
+        assert_eq!(st_min_to_micros(0x0A), 10_000);
+        assert_eq!(st_min_to_micros(0xF1), 100);
+        assert_eq!(st_min_to_micros(0xF9), 900);
+        // Reserved values are the longest gap
+        assert_eq!(st_min_to_micros(0x80), 127_000);
+        assert_eq!(st_min_to_micros(0xFA), 127_000);
+        assert!(is_valid_st_min(0x7F));
+        assert!(!is_valid_st_min(0xF0));
+    }
+
+    #[test]
+    fn sequence_numbers_wrap() {
+        assert_eq!(next_sequence_number(0x01), 0x02);
+        assert_eq!(next_sequence_number(0x0F), 0x00);
+    }
+
+    #[test]
+    fn padding() {
+        assert_eq!(padded_len(3, 8), 8);
+        assert_eq!(padded_len(8, 8), 8);
+        assert_eq!(padded_len(9, 64), 12);
+        assert_eq!(padded_len(33, 64), 48);
+    }
+}
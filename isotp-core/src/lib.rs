@@ -0,0 +1,18 @@
+//! Hardware-free core of the bridge
+//! BLE command parsing, ISO-TP frame layout and the transfer state machines, kept apart
+//! from the firmware so they build and test on a host: `cargo test --target <host triple>`
+//! from this directory.
+
+#![cfg_attr(not(test), no_std)]
+
+// defmt logging when the firmware enables it, nothing on a host
+macro_rules! debug {
+    ($($arg:tt)*) => {
+        #[cfg(feature = "defmt")]
+        defmt::debug!($($arg)*);
+    };
+}
+
+pub mod frame;
+pub mod protocol;
+pub mod transfer;
@@ -0,0 +1,1354 @@
+//! BLE command framing
+//! Commands written by the client and how they're parsed. Parsing only looks at the bytes
+//! it's given, so it runs the same on the bridge and on a host.
+
+use core::convert::TryFrom;
+
+/// Error type for message parsing, the number is reported to the client
+#[repr(u8)]
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ParseError {
+    InvalidCommand = 0x01,
+    BufferTooSmall = 0x02,
+    BufferTooLarge = 0x03,
+}
+
+/// Command IDs extracted from the JavaScript code
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CommandId {
+    UploadIsotpChunk = 0x02,
+    SendIsotpBuffer = 0x03,
+    StartPeriodicIsotpMessage = 0x04,
+    StopPeriodicIsotpMessage = 0x05,
+    ConfigureIsotpFilter = 0x06,
+    SetDeviceConfig = 0x07,
+    SendCanFrame = 0x08,
+    ConfigureSniffer = 0x09,
+    ConfigureRateLimit = 0x0A,
+    SetCanMode = 0x0B,
+    SetTermination = 0x0C,
+    GetStatistics = 0x0D,
+    ConfigureIsotpTiming = 0x0E,
+    ConfigureNormalFixedFilter = 0x0F,
+    ConfigureFunctionalFilter = 0x10,
+    CancelIsotpTransmission = 0x11,
+    SendIsotpFlowControl = 0x12,
+    RunSelfTest = 0x13,
+    SecurityAccess = 0x14,
+    ConfigureObdPoll = 0x15,
+    SaveSession = 0x16,
+    UploadMacro = 0x17,
+    RunMacro = 0x18,
+    ConfigureIsotpRelay = 0x19,
+    ConfigureIsotpThrottle = 0x1A,
+    GetEventLog = 0x1B,
+}
+
+impl TryFrom<u8> for CommandId {
+    type Error = ParseError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0x02 => Ok(CommandId::UploadIsotpChunk),
+            0x03 => Ok(CommandId::SendIsotpBuffer),
+            0x04 => Ok(CommandId::StartPeriodicIsotpMessage),
+            0x05 => Ok(CommandId::StopPeriodicIsotpMessage),
+            0x06 => Ok(CommandId::ConfigureIsotpFilter),
+            0x07 => Ok(CommandId::SetDeviceConfig),
+            0x08 => Ok(CommandId::SendCanFrame),
+            0x09 => Ok(CommandId::ConfigureSniffer),
+            0x0A => Ok(CommandId::ConfigureRateLimit),
+            0x0B => Ok(CommandId::SetCanMode),
+            0x0C => Ok(CommandId::SetTermination),
+            0x0D => Ok(CommandId::GetStatistics),
+            0x0E => Ok(CommandId::ConfigureIsotpTiming),
+            0x0F => Ok(CommandId::ConfigureNormalFixedFilter),
+            0x10 => Ok(CommandId::ConfigureFunctionalFilter),
+            0x11 => Ok(CommandId::CancelIsotpTransmission),
+            0x12 => Ok(CommandId::SendIsotpFlowControl),
+            0x13 => Ok(CommandId::RunSelfTest),
+            0x14 => Ok(CommandId::SecurityAccess),
+            0x15 => Ok(CommandId::ConfigureObdPoll),
+            0x16 => Ok(CommandId::SaveSession),
+            0x17 => Ok(CommandId::UploadMacro),
+            0x18 => Ok(CommandId::RunMacro),
+            0x19 => Ok(CommandId::ConfigureIsotpRelay),
+            0x1A => Ok(CommandId::ConfigureIsotpThrottle),
+            0x1B => Ok(CommandId::GetEventLog),
+            _ => Err(ParseError::InvalidCommand),
+        }
+    }
+}
+/// Upload Chunk Command (0x02)
+/// Used to upload chunks of a large message
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct UploadIsotpChunkCommand {
+    pub offset: u16,
+    pub chunk_length: u16,
+    pub chunk: heapless::Vec<u8, 512>,
+    // Upload the chunk belongs to, optional after the chunk
+    pub staging_id: u32,
+}
+
+impl UploadIsotpChunkCommand {
+    /// Parse an upload chunk command from a byte buffer
+    pub fn parse(buffer: &[u8]) -> Result<Self, ParseError> {
+        // Need at least 5 bytes: command(1) + offset(2) + length(2)
+        if buffer.len() < 5 {
+            return Err(ParseError::BufferTooSmall);
+        }
+
+        let offset = u16::from_be_bytes([buffer[1], buffer[2]]);
+
+        let chunk_length = u16::from_be_bytes([buffer[3], buffer[4]]);
+
+        // Validate that buffer contains enough data
+        if buffer.len() < 5 + chunk_length as usize {
+            return Err(ParseError::BufferTooSmall);
+        }
+
+        let chunk = &buffer[5..5 + chunk_length as usize];
+        let staging_id = match buffer.get(5 + chunk_length as usize..9 + chunk_length as usize) {
+            Some(bytes) => u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+            None => 0,
+        };
+
+        Ok(Self {
+            offset,
+            chunk_length,
+            chunk: heapless::Vec::from_slice(chunk).map_err(|_| ParseError::BufferTooLarge)?,
+            staging_id,
+        })
+    }
+}
+
+/// Trigger BLE Send Command (0x03)
+/// Used to trigger sending of accumulated chunks
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct SendIsotpBufferCommand {
+    // Total length of message to send
+    pub total_length: u16,
+    // Upload to send, optional after the length
+    pub staging_id: u32,
+    // Echoed with the response to this request, optional after the staging id
+    pub correlation_tag: Option<u16>,
+    // Overrides the filter's P2 for this request in milliseconds, 0 turns supervision off.
+    // Optional after the correlation tag.
+    pub response_timeout_ms: Option<u16>,
+}
+
+impl SendIsotpBufferCommand {
+    /// Parse a trigger BLE send command from a byte buffer
+    pub fn parse(buffer: &[u8]) -> Result<Self, ParseError> {
+        debug!("[ble] SendIsotpBufferCommand: {:02x}", buffer);
+
+        // Need 3 bytes: command(1) + length(2)
+        if buffer.len() < 3 {
+            return Err(ParseError::BufferTooSmall);
+        }
+
+        let total_length = u16::from_be_bytes([buffer[1], buffer[2]]);
+        let staging_id = match buffer.get(3..7) {
+            Some(bytes) => u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+            None => 0,
+        };
+        let correlation_tag = buffer
+            .get(7..9)
+            .map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]));
+        let response_timeout_ms = buffer
+            .get(9..11)
+            .map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]));
+
+        Ok(Self {
+            total_length,
+            staging_id,
+            correlation_tag,
+            response_timeout_ms,
+        })
+    }
+}
+
+/// Start Periodic Message Command (0x04)
+/// Used to start sending a message periodically
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct StartPeriodicIsotpMessageCommand {
+    pub periodic_message_index: u8,
+    pub interval_ms: u16,
+    pub request_arbitration_id: u32,
+    pub reply_arbitration_id: u32,
+    pub message_count: u16,
+    pub message_data: heapless::Vec<u8, 512>,
+}
+
+#[allow(dead_code)]
+impl StartPeriodicIsotpMessageCommand {
+    /// Parse a start periodic message command from a byte buffer
+    pub fn parse(buffer: &[u8]) -> Result<Self, ParseError> {
+        // Need at least 14 bytes for header
+        // command(1) + index(1) + interval(2) + req_id(4) + reply_id(4) + msg_count(2)
+        if buffer.len() < 14 {
+            return Err(ParseError::BufferTooSmall);
+        }
+
+        let periodic_message_index = buffer[1];
+        let interval_ms = u16::from_be_bytes([buffer[2], buffer[3]]);
+        let request_arbitration_id =
+            u32::from_be_bytes([buffer[4], buffer[5], buffer[6], buffer[7]]);
+        let reply_arbitration_id =
+            u32::from_be_bytes([buffer[8], buffer[9], buffer[10], buffer[11]]);
+        let message_count = u16::from_be_bytes([buffer[12], buffer[13]]);
+
+        // Message data starts at offset 14
+        let message_data = &buffer[14..];
+
+        Ok(Self {
+            periodic_message_index,
+            interval_ms,
+            request_arbitration_id,
+            reply_arbitration_id,
+            message_count,
+            message_data: heapless::Vec::from_slice(message_data)
+                .map_err(|_| ParseError::BufferTooLarge)?,
+        })
+    }
+
+    /// Helper to iterate over the individual messages in the payload
+    pub fn iter_messages(&self) -> PeriodicMessageIterator<'_> {
+        PeriodicMessageIterator::new(self.message_data.as_slice())
+    }
+}
+
+/// Iterator for periodic messages in a StartPeriodicMessageCommand
+pub struct PeriodicMessageIterator<'a> {
+    data: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> PeriodicMessageIterator<'a> {
+    /// Iterate over messages packed as length(2) + data each
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data, offset: 0 }
+    }
+}
+
+impl<'a> Iterator for PeriodicMessageIterator<'a> {
+    type Item = &'a [u8];
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.offset + 2 > self.data.len() {
+            return None;
+        }
+
+        // Get message length (16-bit BE)
+        let length =
+            u16::from_be_bytes([self.data[self.offset], self.data[self.offset + 1]]) as usize;
+
+        // Check if we have enough data
+        if self.offset + 2 + length > self.data.len() {
+            return None;
+        }
+
+        // Get message slice
+        let message = &self.data[self.offset + 2..self.offset + 2 + length];
+
+        // Update offset for next iteration
+        self.offset += 2 + length;
+
+        Some(message)
+    }
+}
+
+/// Stop Periodic Message Command (0x05)
+/// Used to stop a periodic message
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct StopPeriodicIsotpMessageCommand {
+    // Periodic message index to stop
+    pub periodic_message_index: u8,
+    // Request arbitration ID
+    pub request_arbitration_id: u32,
+    // Reply arbitration ID
+    pub reply_arbitration_id: u32,
+}
+
+impl StopPeriodicIsotpMessageCommand {
+    /// Parse a stop periodic message command from a byte buffer
+    pub fn parse(buffer: &[u8]) -> Result<Self, ParseError> {
+        // Need 10 bytes: command(1) + index(1) + req_id(4) + reply_id(4)
+        if buffer.len() < 10 {
+            return Err(ParseError::BufferTooSmall);
+        }
+
+        let periodic_message_index = buffer[1];
+        let request_arbitration_id =
+            u32::from_be_bytes([buffer[2], buffer[3], buffer[4], buffer[5]]);
+        let reply_arbitration_id = u32::from_be_bytes([buffer[6], buffer[7], buffer[8], buffer[9]]);
+
+        Ok(Self {
+            periodic_message_index,
+            request_arbitration_id,
+            reply_arbitration_id,
+        })
+    }
+}
+
+/// Configure Filter Command (0x06)
+/// Used to configure a message filter
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ConfigureIsotpFilterCommand {
+    // Filter ID
+    pub filter_id: u32,
+    // Request arbitration ID
+    pub request_arbitration_id: u32,
+    // Reply arbitration ID
+    pub reply_arbitration_id: u32,
+    // Filter name (null-terminated string)
+    pub name: heapless::Vec<u8, 32>,
+    // Receive buffer size class (see pdu_buffer::BufferClass), optional after the name
+    pub buffer_class: u8,
+    // Replies are accepted up to this id, optional after the buffer class
+    pub last_reply_arbitration_id: u32,
+    // Applied to the handler as it's created, optional after the last reply id
+    pub parameters: heapless::Vec<IsotpParameterValue, MAX_FILTER_PARAMETERS>,
+}
+
+// One for each isotp_handler::IsotpParameter
+pub const MAX_FILTER_PARAMETERS: usize = 16;
+
+/// Initial value of an ISO-TP parameter (see isotp_handler::IsotpParameter)
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct IsotpParameterValue {
+    pub parameter: u8,
+    pub value: u32,
+}
+
+impl ConfigureIsotpFilterCommand {
+    /// Parse a configure filter command from a byte buffer
+    pub fn parse(buffer: &[u8]) -> Result<Self, ParseError> {
+        debug!("[ble] ConfigureIsotpFilterCommand: {:02x}", buffer);
+
+        // Need at least 13 bytes: command(1) + filter_id(4) + req_id(4) + reply_id(4) + name_len(4)
+        if buffer.len() < 17 {
+            return Err(ParseError::BufferTooSmall);
+        }
+
+        let filter_id = u32::from_be_bytes([buffer[1], buffer[2], buffer[3], buffer[4]]);
+        let request_arbitration_id =
+            u32::from_be_bytes([buffer[5], buffer[6], buffer[7], buffer[8]]);
+        let reply_arbitration_id =
+            u32::from_be_bytes([buffer[9], buffer[10], buffer[11], buffer[12]]);
+        let name_len =
+            u32::from_be_bytes([buffer[13], buffer[14], buffer[15], buffer[16]]) as usize;
+
+        // Validate that buffer contains the full name, without overflowing on huge lengths
+        if name_len > buffer.len() - 17 {
+            return Err(ParseError::BufferTooSmall);
+        }
+
+        let name = &buffer[17..17 + name_len];
+        let buffer_class = buffer.get(17 + name_len).copied().unwrap_or(0);
+        let last_reply_arbitration_id = match buffer.get(18 + name_len..22 + name_len) {
+            Some(bytes) => u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+            None => reply_arbitration_id,
+        };
+
+        // Any remaining bytes are parameter(1) + value(4) pairs
+        let mut parameters = heapless::Vec::new();
+        let pairs = buffer.get(22 + name_len..).unwrap_or(&[]);
+        if pairs.len() % 5 != 0 {
+            return Err(ParseError::BufferTooSmall);
+        }
+        for pair in pairs.chunks_exact(5) {
+            parameters
+                .push(IsotpParameterValue {
+                    parameter: pair[0],
+                    value: u32::from_be_bytes([pair[1], pair[2], pair[3], pair[4]]),
+                })
+                .map_err(|_| ParseError::BufferTooLarge)?;
+        }
+
+        Ok(Self {
+            filter_id,
+            request_arbitration_id,
+            reply_arbitration_id,
+            name: heapless::Vec::from_slice(name).map_err(|_| ParseError::BufferTooLarge)?,
+            buffer_class,
+            last_reply_arbitration_id,
+            parameters,
+        })
+    }
+}
+
+/// Set Device Config Command (0x07)
+/// Used to update a persistent device setting (see config::ConfigKey)
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct SetDeviceConfigCommand {
+    // Config key
+    pub key: u8,
+    // New value
+    pub value: u32,
+}
+
+impl SetDeviceConfigCommand {
+    /// Parse a set device config command from a byte buffer
+    pub fn parse(buffer: &[u8]) -> Result<Self, ParseError> {
+        debug!("[ble] SetDeviceConfigCommand: {:02x}", buffer);
+
+        // Need 6 bytes: command(1) + key(1) + value(4)
+        if buffer.len() < 6 {
+            return Err(ParseError::BufferTooSmall);
+        }
+
+        let key = buffer[1];
+        let value = u32::from_be_bytes([buffer[2], buffer[3], buffer[4], buffer[5]]);
+
+        Ok(Self { key, value })
+    }
+}
+
+/// Send CAN Frame Command (0x08)
+/// Used to put a single raw frame on the bus
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct SendCanFrameCommand {
+    // Don't retransmit on error or lost arbitration
+    pub one_shot: bool,
+    // Arbitration ID
+    pub arbitration_id: u32,
+    // Frame data, dlc is taken from its length
+    pub data: heapless::Vec<u8, 8>,
+}
+
+impl SendCanFrameCommand {
+    const FLAG_ONE_SHOT: u8 = 0x01;
+
+    /// Parse a send CAN frame command from a byte buffer
+    pub fn parse(buffer: &[u8]) -> Result<Self, ParseError> {
+        debug!("[ble] SendCanFrameCommand: {:02x}", buffer);
+
+        // Need at least 6 bytes: command(1) + flags(1) + arbitration_id(4) + data(0..8)
+        if buffer.len() < 6 {
+            return Err(ParseError::BufferTooSmall);
+        }
+
+        let flags = buffer[1];
+        let arbitration_id = u32::from_be_bytes([buffer[2], buffer[3], buffer[4], buffer[5]]);
+        let data =
+            heapless::Vec::from_slice(&buffer[6..]).map_err(|_| ParseError::BufferTooLarge)?;
+
+        Ok(Self {
+            one_shot: flags & Self::FLAG_ONE_SHOT != 0,
+            arbitration_id,
+            data,
+        })
+    }
+}
+
+/// Configure Sniffer Command (0x09)
+/// Used to stream raw bus traffic to the client
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ConfigureSnifferCommand {
+    // Forward every received frame, regardless of filters
+    pub enabled: bool,
+    // Include frames we transmitted, marked as self-sent
+    pub tx_echo: bool,
+}
+
+impl ConfigureSnifferCommand {
+    const FLAG_ENABLED: u8 = 0x01;
+    const FLAG_TX_ECHO: u8 = 0x02;
+
+    /// Parse a configure sniffer command from a byte buffer
+    pub fn parse(buffer: &[u8]) -> Result<Self, ParseError> {
+        // Need 2 bytes: command(1) + flags(1)
+        if buffer.len() < 2 {
+            return Err(ParseError::BufferTooSmall);
+        }
+
+        let flags = buffer[1];
+
+        Ok(Self {
+            enabled: flags & Self::FLAG_ENABLED != 0,
+            tx_echo: flags & Self::FLAG_TX_ECHO != 0,
+        })
+    }
+}
+
+/// Configure Rate Limit Command (0x0A)
+/// Used to decimate a chatty arbitration ID before it reaches BLE
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ConfigureRateLimitCommand {
+    // Arbitration ID
+    pub arbitration_id: u32,
+    // Forward one in `divisor` frames, 0 or 1 removes the limit
+    pub divisor: u16,
+}
+
+impl ConfigureRateLimitCommand {
+    /// Parse a configure rate limit command from a byte buffer
+    pub fn parse(buffer: &[u8]) -> Result<Self, ParseError> {
+        // Need 7 bytes: command(1) + arbitration_id(4) + divisor(2)
+        if buffer.len() < 7 {
+            return Err(ParseError::BufferTooSmall);
+        }
+
+        let arbitration_id = u32::from_be_bytes([buffer[1], buffer[2], buffer[3], buffer[4]]);
+        let divisor = u16::from_be_bytes([buffer[5], buffer[6]]);
+
+        Ok(Self {
+            arbitration_id,
+            divisor,
+        })
+    }
+}
+
+/// Set CAN Mode Command (0x0B)
+/// Used to switch between normal, listen-only and standby (see can_manager::CanMode)
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct SetCanModeCommand {
+    pub mode: u8,
+}
+
+impl SetCanModeCommand {
+    /// Parse a set CAN mode command from a byte buffer
+    pub fn parse(buffer: &[u8]) -> Result<Self, ParseError> {
+        // Need 2 bytes: command(1) + mode(1)
+        if buffer.len() < 2 {
+            return Err(ParseError::BufferTooSmall);
+        }
+
+        Ok(Self { mode: buffer[1] })
+    }
+}
+
+/// Set Termination Command (0x0C)
+/// Used to switch the 120 ohm bus termination on boards that have one
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct SetTerminationCommand {
+    pub enabled: bool,
+}
+
+impl SetTerminationCommand {
+    /// Parse a set termination command from a byte buffer
+    pub fn parse(buffer: &[u8]) -> Result<Self, ParseError> {
+        // Need 2 bytes: command(1) + enabled(1)
+        if buffer.len() < 2 {
+            return Err(ParseError::BufferTooSmall);
+        }
+
+        Ok(Self {
+            enabled: buffer[1] != 0,
+        })
+    }
+}
+
+/// Get Statistics Command (0x0D)
+/// Used to request the statistics events, takes no arguments
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct GetStatisticsCommand;
+
+/// Configure ISO-TP Timing Command (0x0E)
+/// Used to set a transport parameter on a filter's handler (see isotp_handler::IsotpParameter)
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ConfigureIsotpTimingCommand {
+    // Filter ID
+    pub filter_id: u32,
+    // Parameter key
+    pub parameter: u8,
+    // New value
+    pub value: u32,
+}
+
+impl ConfigureIsotpTimingCommand {
+    /// Parse a configure ISO-TP timing command from a byte buffer
+    pub fn parse(buffer: &[u8]) -> Result<Self, ParseError> {
+        debug!("[ble] ConfigureIsotpTimingCommand: {:02x}", buffer);
+
+        // Need 10 bytes: command(1) + filter_id(4) + parameter(1) + value(4)
+        if buffer.len() < 10 {
+            return Err(ParseError::BufferTooSmall);
+        }
+
+        let filter_id = u32::from_be_bytes([buffer[1], buffer[2], buffer[3], buffer[4]]);
+        let parameter = buffer[5];
+        let value = u32::from_be_bytes([buffer[6], buffer[7], buffer[8], buffer[9]]);
+
+        Ok(Self {
+            filter_id,
+            parameter,
+            value,
+        })
+    }
+}
+
+/// Configure Normal Fixed Filter Command (0x0F)
+/// Used to configure a filter whose 29-bit ids are derived from ISO 15765-2 normal fixed
+/// addresses (e.g. J1939 style 0x18DA<TA><SA>)
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ConfigureNormalFixedFilterCommand {
+    // Filter ID
+    pub filter_id: u32,
+    // Our (tester) address
+    pub source_address: u8,
+    // ECU address
+    pub target_address: u8,
+    // Receive buffer size class (see pdu_buffer::BufferClass), optional
+    pub buffer_class: u8,
+}
+
+impl ConfigureNormalFixedFilterCommand {
+    /// Parse a configure normal fixed filter command from a byte buffer
+    pub fn parse(buffer: &[u8]) -> Result<Self, ParseError> {
+        debug!("[ble] ConfigureNormalFixedFilterCommand: {:02x}", buffer);
+
+        // Need 7 bytes: command(1) + filter_id(4) + source_address(1) + target_address(1)
+        if buffer.len() < 7 {
+            return Err(ParseError::BufferTooSmall);
+        }
+
+        let filter_id = u32::from_be_bytes([buffer[1], buffer[2], buffer[3], buffer[4]]);
+
+        Ok(Self {
+            filter_id,
+            source_address: buffer[5],
+            target_address: buffer[6],
+            buffer_class: buffer.get(7).copied().unwrap_or(0),
+        })
+    }
+}
+
+/// Configure Functional Filter Command (0x10)
+/// Used to configure a filter that sends single frame requests on a functional id and
+/// collects the single frame answers of every ECU in a reply id range
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ConfigureFunctionalFilterCommand {
+    // Filter ID
+    pub filter_id: u32,
+    // Functional request arbitration ID (e.g. 0x7DF)
+    pub request_arbitration_id: u32,
+    // Reply arbitration ID range, inclusive
+    pub first_reply_arbitration_id: u32,
+    pub last_reply_arbitration_id: u32,
+    // How long answers are collected after each request
+    pub window_ms: u16,
+    // Receive buffer size class (see pdu_buffer::BufferClass), optional
+    pub buffer_class: u8,
+    // Temporary handlers for multi-frame answers from ECUs without a filter, optional,
+    // 0 ignores those answers
+    pub max_ephemeral_handlers: u8,
+}
+
+impl ConfigureFunctionalFilterCommand {
+    /// Parse a configure functional filter command from a byte buffer
+    pub fn parse(buffer: &[u8]) -> Result<Self, ParseError> {
+        debug!("[ble] ConfigureFunctionalFilterCommand: {:02x}", buffer);
+
+        // Need 19 bytes: command(1) + filter_id(4) + req_id(4) + first_reply_id(4)
+        // + last_reply_id(4) + window_ms(2)
+        if buffer.len() < 19 {
+            return Err(ParseError::BufferTooSmall);
+        }
+
+        let filter_id = u32::from_be_bytes([buffer[1], buffer[2], buffer[3], buffer[4]]);
+        let request_arbitration_id =
+            u32::from_be_bytes([buffer[5], buffer[6], buffer[7], buffer[8]]);
+        let first_reply_arbitration_id =
+            u32::from_be_bytes([buffer[9], buffer[10], buffer[11], buffer[12]]);
+        let last_reply_arbitration_id =
+            u32::from_be_bytes([buffer[13], buffer[14], buffer[15], buffer[16]]);
+        let window_ms = u16::from_be_bytes([buffer[17], buffer[18]]);
+
+        Ok(Self {
+            filter_id,
+            request_arbitration_id,
+            first_reply_arbitration_id,
+            last_reply_arbitration_id,
+            window_ms,
+            buffer_class: buffer.get(19).copied().unwrap_or(0),
+            max_ephemeral_handlers: buffer.get(20).copied().unwrap_or(0),
+        })
+    }
+}
+
+/// Cancel ISO-TP Transmission Command (0x11)
+/// Used to stop a multi-frame send that is still in progress
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct CancelIsotpTransmissionCommand {
+    // Request arbitration ID
+    pub request_arbitration_id: u32,
+    // Reply arbitration ID
+    pub reply_arbitration_id: u32,
+}
+
+impl CancelIsotpTransmissionCommand {
+    /// Parse a cancel transmission command from a byte buffer
+    pub fn parse(buffer: &[u8]) -> Result<Self, ParseError> {
+        // Need 9 bytes: command(1) + req_id(4) + reply_id(4)
+        if buffer.len() < 9 {
+            return Err(ParseError::BufferTooSmall);
+        }
+
+        let request_arbitration_id =
+            u32::from_be_bytes([buffer[1], buffer[2], buffer[3], buffer[4]]);
+        let reply_arbitration_id = u32::from_be_bytes([buffer[5], buffer[6], buffer[7], buffer[8]]);
+
+        Ok(Self {
+            request_arbitration_id,
+            reply_arbitration_id,
+        })
+    }
+}
+
+/// Send ISO-TP Flow Control Command (0x12)
+/// Used in flow control pass-through mode to answer a sender with an FC of our choosing
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct SendIsotpFlowControlCommand {
+    // Request arbitration ID, the FC is sent on it
+    pub request_arbitration_id: u32,
+    // Reply arbitration ID
+    pub reply_arbitration_id: u32,
+    pub flow_status: u8,
+    pub block_size: u8,
+    pub st_min: u8,
+}
+
+impl SendIsotpFlowControlCommand {
+    /// Parse a send flow control command from a byte buffer
+    pub fn parse(buffer: &[u8]) -> Result<Self, ParseError> {
+        debug!("[ble] SendIsotpFlowControlCommand: {:02x}", buffer);
+
+        // Need 12 bytes: command(1) + req_id(4) + reply_id(4) + flow_status(1)
+        // + block_size(1) + st_min(1)
+        if buffer.len() < 12 {
+            return Err(ParseError::BufferTooSmall);
+        }
+
+        let request_arbitration_id =
+            u32::from_be_bytes([buffer[1], buffer[2], buffer[3], buffer[4]]);
+        let reply_arbitration_id = u32::from_be_bytes([buffer[5], buffer[6], buffer[7], buffer[8]]);
+
+        Ok(Self {
+            request_arbitration_id,
+            reply_arbitration_id,
+            flow_status: buffer[9],
+            block_size: buffer[10],
+            st_min: buffer[11],
+        })
+    }
+}
+
+/// Run Self-Test Command (0x13)
+/// Used to run the ISO-TP conformance self-test in CAN loopback mode, takes no arguments
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct RunSelfTestCommand;
+
+/// Longest seed or key passed through a SecurityAccess exchange
+pub const MAX_SECURITY_ACCESS_DATA: usize = 64;
+
+/// Security Access Command (0x14)
+/// Used to run a UDS SecurityAccess step on the handler between these ids: an odd
+/// sub-function requests a seed, the even one after it sends the key
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct SecurityAccessCommand {
+    // Request arbitration ID
+    pub request_arbitration_id: u32,
+    // Reply arbitration ID
+    pub reply_arbitration_id: u32,
+    pub sub_function: u8,
+    // Computed key, empty for a seed request
+    pub key: heapless::Vec<u8, MAX_SECURITY_ACCESS_DATA>,
+}
+
+impl SecurityAccessCommand {
+    /// Parse a security access command from a byte buffer
+    pub fn parse(buffer: &[u8]) -> Result<Self, ParseError> {
+        debug!("[ble] SecurityAccessCommand: {:02x}", buffer);
+
+        // Need 10 bytes: command(1) + req_id(4) + reply_id(4) + sub_function(1), then the key
+        if buffer.len() < 10 {
+            return Err(ParseError::BufferTooSmall);
+        }
+
+        let request_arbitration_id =
+            u32::from_be_bytes([buffer[1], buffer[2], buffer[3], buffer[4]]);
+        let reply_arbitration_id = u32::from_be_bytes([buffer[5], buffer[6], buffer[7], buffer[8]]);
+        let key =
+            heapless::Vec::from_slice(&buffer[10..]).map_err(|_| ParseError::BufferTooLarge)?;
+
+        Ok(Self {
+            request_arbitration_id,
+            reply_arbitration_id,
+            sub_function: buffer[9],
+            key,
+        })
+    }
+}
+
+/// Longest PID value carried by a sample, larger answers go to the client as plain PDUs
+pub const MAX_OBD_SAMPLE_DATA: usize = 64;
+
+/// Most PIDs a handler polls at once
+pub const MAX_POLLED_PIDS: usize = 16;
+
+/// A PID and how often to poll it
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ObdPollEntry {
+    pub mode: u8,
+    pub pid: u8,
+    pub period_ms: u16,
+}
+
+/// Configure OBD Poll Command (0x15)
+/// Used to poll PIDs on the handler between these ids, an empty list stops polling
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ConfigureObdPollCommand {
+    // Request arbitration ID
+    pub request_arbitration_id: u32,
+    // Reply arbitration ID
+    pub reply_arbitration_id: u32,
+    pub entries: heapless::Vec<ObdPollEntry, MAX_POLLED_PIDS>,
+}
+
+impl ConfigureObdPollCommand {
+    /// Parse a configure OBD poll command from a byte buffer
+    pub fn parse(buffer: &[u8]) -> Result<Self, ParseError> {
+        debug!("[ble] ConfigureObdPollCommand: {:02x}", buffer);
+
+        // Need 9 bytes: command(1) + req_id(4) + reply_id(4)
+        if buffer.len() < 9 {
+            return Err(ParseError::BufferTooSmall);
+        }
+
+        let request_arbitration_id =
+            u32::from_be_bytes([buffer[1], buffer[2], buffer[3], buffer[4]]);
+        let reply_arbitration_id = u32::from_be_bytes([buffer[5], buffer[6], buffer[7], buffer[8]]);
+
+        // Then mode(1) + pid(1) + period_ms(2) per entry
+        let mut entries = heapless::Vec::new();
+        let records = buffer[9..].chunks_exact(4);
+        if !records.remainder().is_empty() {
+            return Err(ParseError::BufferTooSmall);
+        }
+        for record in records {
+            entries
+                .push(ObdPollEntry {
+                    mode: record[0],
+                    pid: record[1],
+                    period_ms: u16::from_be_bytes([record[2], record[3]]),
+                })
+                .map_err(|_| ParseError::BufferTooLarge)?;
+        }
+
+        Ok(Self {
+            request_arbitration_id,
+            reply_arbitration_id,
+            entries,
+        })
+    }
+}
+
+/// Save Session Command (0x16)
+/// Used to keep the current filter setup across power cycles, or to forget the saved one
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct SaveSessionCommand {
+    // false erases the saved session
+    pub save: bool,
+}
+
+impl SaveSessionCommand {
+    /// Parse a save session command from a byte buffer
+    pub fn parse(buffer: &[u8]) -> Result<Self, ParseError> {
+        // Need 2 bytes: command(1) + save(1)
+        if buffer.len() < 2 {
+            return Err(ParseError::BufferTooSmall);
+        }
+
+        Ok(Self {
+            save: buffer[1] != 0,
+        })
+    }
+}
+
+/// Largest macro script, see macro_engine for the instructions
+pub const MAX_MACRO_SIZE: usize = 256;
+
+/// Upload Macro Command (0x17)
+/// Used to store a script under a macro id, an empty script deletes the macro
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct UploadMacroCommand {
+    pub macro_id: u8,
+    pub script: heapless::Vec<u8, MAX_MACRO_SIZE>,
+}
+
+impl UploadMacroCommand {
+    /// Parse an upload macro command from a byte buffer
+    pub fn parse(buffer: &[u8]) -> Result<Self, ParseError> {
+        debug!("[ble] UploadMacroCommand: {:02x}", buffer);
+
+        // Need 2 bytes: command(1) + macro_id(1), then the script
+        if buffer.len() < 2 {
+            return Err(ParseError::BufferTooSmall);
+        }
+
+        let script =
+            heapless::Vec::from_slice(&buffer[2..]).map_err(|_| ParseError::BufferTooLarge)?;
+
+        Ok(Self {
+            macro_id: buffer[1],
+            script,
+        })
+    }
+}
+
+/// Run Macro Command (0x18)
+/// Used to run a stored macro on the handler between these ids
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct RunMacroCommand {
+    pub macro_id: u8,
+    // Request arbitration ID
+    pub request_arbitration_id: u32,
+    // Reply arbitration ID
+    pub reply_arbitration_id: u32,
+}
+
+impl RunMacroCommand {
+    /// Parse a run macro command from a byte buffer
+    pub fn parse(buffer: &[u8]) -> Result<Self, ParseError> {
+        // Need 10 bytes: command(1) + macro_id(1) + req_id(4) + reply_id(4)
+        if buffer.len() < 10 {
+            return Err(ParseError::BufferTooSmall);
+        }
+
+        let request_arbitration_id =
+            u32::from_be_bytes([buffer[2], buffer[3], buffer[4], buffer[5]]);
+        let reply_arbitration_id = u32::from_be_bytes([buffer[6], buffer[7], buffer[8], buffer[9]]);
+
+        Ok(Self {
+            macro_id: buffer[1],
+            request_arbitration_id,
+            reply_arbitration_id,
+        })
+    }
+}
+
+/// Configure ISO-TP Relay Command (0x19)
+/// Used to retransmit the PDUs one handler receives on another handler's ids, see
+/// isotp_handler::RelayMode. To edit PDUs in flight, leave the relay off and send the
+/// edited PDU on the target with SendIsotpBuffer.
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ConfigureIsotpRelayCommand {
+    // Handler the PDUs are received on
+    pub request_arbitration_id: u32,
+    pub reply_arbitration_id: u32,
+    // Handler they are sent on
+    pub target_request_arbitration_id: u32,
+    pub target_reply_arbitration_id: u32,
+    pub mode: u8,
+}
+
+impl ConfigureIsotpRelayCommand {
+    /// Parse a configure relay command from a byte buffer
+    pub fn parse(buffer: &[u8]) -> Result<Self, ParseError> {
+        debug!("[ble] ConfigureIsotpRelayCommand: {:02x}", buffer);
+
+        // Need 18 bytes: command(1) + req_id(4) + reply_id(4) + target_req_id(4)
+        // + target_reply_id(4) + mode(1)
+        if buffer.len() < 18 {
+            return Err(ParseError::BufferTooSmall);
+        }
+
+        let id_at = |at: usize| {
+            u32::from_be_bytes([buffer[at], buffer[at + 1], buffer[at + 2], buffer[at + 3]])
+        };
+
+        Ok(Self {
+            request_arbitration_id: id_at(1),
+            reply_arbitration_id: id_at(5),
+            target_request_arbitration_id: id_at(9),
+            target_reply_arbitration_id: id_at(13),
+            mode: buffer[17],
+        })
+    }
+}
+
+/// Configure ISO-TP Throttle Command (0x1A)
+/// Used to keep unsolicited PDUs from one handler from flooding the client, both zero turns
+/// the throttle off
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ConfigureIsotpThrottleCommand {
+    // Request arbitration ID
+    pub request_arbitration_id: u32,
+    // Reply arbitration ID
+    pub reply_arbitration_id: u32,
+    // Least time between PDUs handed to the client
+    pub min_interval_ms: u16,
+    // Repeats of the last PDU within this window are dropped
+    pub dedup_window_ms: u16,
+}
+
+impl ConfigureIsotpThrottleCommand {
+    /// Parse a configure throttle command from a byte buffer
+    pub fn parse(buffer: &[u8]) -> Result<Self, ParseError> {
+        // Need 13 bytes: command(1) + req_id(4) + reply_id(4) + min_interval_ms(2)
+        // + dedup_window_ms(2)
+        if buffer.len() < 13 {
+            return Err(ParseError::BufferTooSmall);
+        }
+
+        let request_arbitration_id =
+            u32::from_be_bytes([buffer[1], buffer[2], buffer[3], buffer[4]]);
+        let reply_arbitration_id = u32::from_be_bytes([buffer[5], buffer[6], buffer[7], buffer[8]]);
+
+        Ok(Self {
+            request_arbitration_id,
+            reply_arbitration_id,
+            min_interval_ms: u16::from_be_bytes([buffer[9], buffer[10]]),
+            dedup_window_ms: u16::from_be_bytes([buffer[11], buffer[12]]),
+        })
+    }
+}
+
+/// Get Event Log Command (0x1B)
+/// Used to request the bridge's recent event log, optionally clearing it
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct GetEventLogCommand {
+    // Empty the log once it has been read, optional
+    pub clear: bool,
+}
+
+impl GetEventLogCommand {
+    /// Parse a get event log command from a byte buffer
+    pub fn parse(buffer: &[u8]) -> Result<Self, ParseError> {
+        Ok(Self {
+            clear: buffer.get(1).is_some_and(|&clear| clear != 0),
+        })
+    }
+}
+
+/// Main message parser
+pub struct BleMessageParser;
+
+impl BleMessageParser {
+    /// Parse a message from a byte buffer
+    pub fn parse(buffer: &[u8]) -> Result<ParsedBleMessage, ParseError> {
+        if buffer.is_empty() {
+            return Err(ParseError::BufferTooSmall);
+        }
+
+        let command_id = CommandId::try_from(buffer[0])?;
+
+        match command_id {
+            CommandId::UploadIsotpChunk => {
+                let command = UploadIsotpChunkCommand::parse(buffer)?;
+                Ok(ParsedBleMessage::UploadIsotpChunk(command))
+            }
+            CommandId::SendIsotpBuffer => {
+                let command = SendIsotpBufferCommand::parse(buffer)?;
+                Ok(ParsedBleMessage::SendIsotpBuffer(command))
+            }
+            CommandId::StartPeriodicIsotpMessage => {
+                let command = StartPeriodicIsotpMessageCommand::parse(buffer)?;
+                Ok(ParsedBleMessage::StartPeriodicIsotpMessage(command))
+            }
+            CommandId::StopPeriodicIsotpMessage => {
+                let command = StopPeriodicIsotpMessageCommand::parse(buffer)?;
+                Ok(ParsedBleMessage::StopPeriodicIsotpMessage(command))
+            }
+            CommandId::ConfigureIsotpFilter => {
+                let command = ConfigureIsotpFilterCommand::parse(buffer)?;
+                Ok(ParsedBleMessage::ConfigureIsotpFilter(command))
+            }
+            CommandId::SetDeviceConfig => {
+                let command = SetDeviceConfigCommand::parse(buffer)?;
+                Ok(ParsedBleMessage::SetDeviceConfig(command))
+            }
+            CommandId::SendCanFrame => {
+                let command = SendCanFrameCommand::parse(buffer)?;
+                Ok(ParsedBleMessage::SendCanFrame(command))
+            }
+            CommandId::ConfigureSniffer => {
+                let command = ConfigureSnifferCommand::parse(buffer)?;
+                Ok(ParsedBleMessage::ConfigureSniffer(command))
+            }
+            CommandId::ConfigureRateLimit => {
+                let command = ConfigureRateLimitCommand::parse(buffer)?;
+                Ok(ParsedBleMessage::ConfigureRateLimit(command))
+            }
+            CommandId::SetCanMode => {
+                let command = SetCanModeCommand::parse(buffer)?;
+                Ok(ParsedBleMessage::SetCanMode(command))
+            }
+            CommandId::SetTermination => {
+                let command = SetTerminationCommand::parse(buffer)?;
+                Ok(ParsedBleMessage::SetTermination(command))
+            }
+            CommandId::GetStatistics => Ok(ParsedBleMessage::GetStatistics(GetStatisticsCommand)),
+            CommandId::ConfigureIsotpTiming => {
+                let command = ConfigureIsotpTimingCommand::parse(buffer)?;
+                Ok(ParsedBleMessage::ConfigureIsotpTiming(command))
+            }
+            CommandId::ConfigureNormalFixedFilter => {
+                let command = ConfigureNormalFixedFilterCommand::parse(buffer)?;
+                Ok(ParsedBleMessage::ConfigureNormalFixedFilter(command))
+            }
+            CommandId::ConfigureFunctionalFilter => {
+                let command = ConfigureFunctionalFilterCommand::parse(buffer)?;
+                Ok(ParsedBleMessage::ConfigureFunctionalFilter(command))
+            }
+            CommandId::CancelIsotpTransmission => {
+                let command = CancelIsotpTransmissionCommand::parse(buffer)?;
+                Ok(ParsedBleMessage::CancelIsotpTransmission(command))
+            }
+            CommandId::SendIsotpFlowControl => {
+                let command = SendIsotpFlowControlCommand::parse(buffer)?;
+                Ok(ParsedBleMessage::SendIsotpFlowControl(command))
+            }
+            CommandId::RunSelfTest => Ok(ParsedBleMessage::RunSelfTest(RunSelfTestCommand)),
+            CommandId::SecurityAccess => {
+                let command = SecurityAccessCommand::parse(buffer)?;
+                Ok(ParsedBleMessage::SecurityAccess(command))
+            }
+            CommandId::ConfigureObdPoll => {
+                let command = ConfigureObdPollCommand::parse(buffer)?;
+                Ok(ParsedBleMessage::ConfigureObdPoll(command))
+            }
+            CommandId::SaveSession => {
+                let command = SaveSessionCommand::parse(buffer)?;
+                Ok(ParsedBleMessage::SaveSession(command))
+            }
+            CommandId::UploadMacro => {
+                let command = UploadMacroCommand::parse(buffer)?;
+                Ok(ParsedBleMessage::UploadMacro(command))
+            }
+            CommandId::RunMacro => {
+                let command = RunMacroCommand::parse(buffer)?;
+                Ok(ParsedBleMessage::RunMacro(command))
+            }
+            CommandId::ConfigureIsotpRelay => {
+                let command = ConfigureIsotpRelayCommand::parse(buffer)?;
+                Ok(ParsedBleMessage::ConfigureIsotpRelay(command))
+            }
+            CommandId::ConfigureIsotpThrottle => {
+                let command = ConfigureIsotpThrottleCommand::parse(buffer)?;
+                Ok(ParsedBleMessage::ConfigureIsotpThrottle(command))
+            }
+            CommandId::GetEventLog => {
+                let command = GetEventLogCommand::parse(buffer)?;
+                Ok(ParsedBleMessage::GetEventLog(command))
+            }
+        }
+    }
+}
+
+/// Enum containing all possible parsed messages
+#[derive(Debug)]
+pub enum ParsedBleMessage {
+    UploadIsotpChunk(UploadIsotpChunkCommand),
+    SendIsotpBuffer(SendIsotpBufferCommand),
+    StartPeriodicIsotpMessage(StartPeriodicIsotpMessageCommand),
+    StopPeriodicIsotpMessage(StopPeriodicIsotpMessageCommand),
+    ConfigureIsotpFilter(ConfigureIsotpFilterCommand),
+    SetDeviceConfig(SetDeviceConfigCommand),
+    SendCanFrame(SendCanFrameCommand),
+    ConfigureSniffer(ConfigureSnifferCommand),
+    ConfigureRateLimit(ConfigureRateLimitCommand),
+    SetCanMode(SetCanModeCommand),
+    SetTermination(SetTerminationCommand),
+    GetStatistics(GetStatisticsCommand),
+    ConfigureIsotpTiming(ConfigureIsotpTimingCommand),
+    ConfigureNormalFixedFilter(ConfigureNormalFixedFilterCommand),
+    ConfigureFunctionalFilter(ConfigureFunctionalFilterCommand),
+    CancelIsotpTransmission(CancelIsotpTransmissionCommand),
+    SendIsotpFlowControl(SendIsotpFlowControlCommand),
+    RunSelfTest(RunSelfTestCommand),
+    SecurityAccess(SecurityAccessCommand),
+    ConfigureObdPoll(ConfigureObdPollCommand),
+    SaveSession(SaveSessionCommand),
+    UploadMacro(UploadMacroCommand),
+    RunMacro(RunMacroCommand),
+    ConfigureIsotpRelay(ConfigureIsotpRelayCommand),
+    ConfigureIsotpThrottle(ConfigureIsotpThrottleCommand),
+    GetEventLog(GetEventLogCommand),
+}
+
+impl ParsedBleMessage {
+    /// Filter setup that a saved session restores at boot
+    pub fn is_session_setup(&self) -> bool {
+        matches!(
+            self,
+            ParsedBleMessage::ConfigureIsotpFilter(_)
+                | ParsedBleMessage::ConfigureIsotpTiming(_)
+                | ParsedBleMessage::ConfigureNormalFixedFilter(_)
+                | ParsedBleMessage::ConfigureFunctionalFilter(_)
+                | ParsedBleMessage::ConfigureObdPoll(_)
+                | ParsedBleMessage::ConfigureIsotpRelay(_)
+                | ParsedBleMessage::ConfigureIsotpThrottle(_)
+                | ParsedBleMessage::StartPeriodicIsotpMessage(_)
+                | ParsedBleMessage::StopPeriodicIsotpMessage(_)
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty_buffer() {
+        assert!(matches!(
+            BleMessageParser::parse(&[]),
+            Err(ParseError::BufferTooSmall)
+        ));
+    }
+
+    #[test]
+    fn oversized_chunk() {
+        let mut buffer = [0u8; 5 + 600];
+        buffer[0] = CommandId::UploadIsotpChunk as u8;
+        buffer[3..5].copy_from_slice(&600u16.to_be_bytes());
+        assert!(matches!(
+            BleMessageParser::parse(&buffer),
+            Err(ParseError::BufferTooLarge)
+        ));
+    }
+
+    #[test]
+    fn oversized_periodic_message() {
+        let mut buffer = [0u8; 14 + 600];
+        buffer[0] = CommandId::StartPeriodicIsotpMessage as u8;
+        assert!(matches!(
+            BleMessageParser::parse(&buffer),
+            Err(ParseError::BufferTooLarge)
+        ));
+    }
+
+    #[test]
+    fn huge_filter_name_length() {
+        let mut buffer = [0u8; 17];
+        buffer[0] = CommandId::ConfigureIsotpFilter as u8;
+        buffer[13..17].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert!(matches!(
+            BleMessageParser::parse(&buffer),
+            Err(ParseError::BufferTooSmall)
+        ));
+    }
+
+    #[test]
+    fn unknown_command() {
+        assert!(matches!(
+            BleMessageParser::parse(&[0x00]),
+            Err(ParseError::InvalidCommand)
+        ));
+        assert!(matches!(
+            BleMessageParser::parse(&[0xFF, 0x01]),
+            Err(ParseError::InvalidCommand)
+        ));
+    }
+
+    #[test]
+    fn send_isotp_buffer_optional_fields() {
+        let Ok(ParsedBleMessage::SendIsotpBuffer(command)) =
+            BleMessageParser::parse(&[0x03, 0x00, 0x10])
+        else {
+            panic!("not parsed");
+        };
+        assert_eq!(command.total_length, 0x10);
+        assert_eq!(command.staging_id, 0);
+        assert_eq!(command.correlation_tag, None);
+
+        let Ok(ParsedBleMessage::SendIsotpBuffer(command)) =
+            BleMessageParser::parse(&[0x03, 0x00, 0x10, 0x00, 0x00, 0x00, 0x07, 0x12, 0x34])
+        else {
+            panic!("not parsed");
+        };
+        assert_eq!(command.staging_id, 7);
+        assert_eq!(command.correlation_tag, Some(0x1234));
+        assert_eq!(command.response_timeout_ms, None);
+    }
+
+    #[test]
+    fn upload_chunk_shorter_than_announced() {
+        assert!(matches!(
+            BleMessageParser::parse(&[0x02, 0x00, 0x00, 0x00, 0x04, 0xAA, 0xBB]),
+            Err(ParseError::BufferTooSmall)
+        ));
+    }
+
+    #[test]
+    fn send_can_frame() {
+        let Ok(ParsedBleMessage::SendCanFrame(command)) =
+            BleMessageParser::parse(&[0x08, 0x01, 0x00, 0x00, 0x07, 0xDF, 0x02, 0x01, 0x0D])
+        else {
+            panic!("not parsed");
+        };
+        assert!(command.one_shot);
+        assert_eq!(command.arbitration_id, 0x7DF);
+        assert_eq!(command.data.as_slice(), &[0x02, 0x01, 0x0D]);
+
+        let too_long = [
+            0x08, 0x00, 0x00, 0x00, 0x07, 0xDF, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+        ];
+        assert!(matches!(
+            BleMessageParser::parse(&too_long),
+            Err(ParseError::BufferTooLarge)
+        ));
+    }
+
+    #[test]
+    fn obd_poll_entries() {
+        let Ok(ParsedBleMessage::ConfigureObdPoll(command)) = BleMessageParser::parse(&[
+            0x15, 0x00, 0x00, 0x07, 0xE0, 0x00, 0x00, 0x07, 0xE8, 0x01, 0x0C, 0x00, 0x64,
+        ]) else {
+            panic!("not parsed");
+        };
+        assert_eq!(command.request_arbitration_id, 0x7E0);
+        assert_eq!(command.reply_arbitration_id, 0x7E8);
+        assert_eq!(command.entries.len(), 1);
+        assert_eq!(command.entries[0].pid, 0x0C);
+        assert_eq!(command.entries[0].period_ms, 100);
+
+        // Partial entry
+        assert!(matches!(
+            BleMessageParser::parse(&[0x15, 0, 0, 0x07, 0xE0, 0, 0, 0x07, 0xE8, 0x01]),
+            Err(ParseError::BufferTooSmall)
+        ));
+    }
+
+    #[test]
+    fn periodic_messages() {
+        let data = [0x00, 0x02, 0x3E, 0x00, 0x00, 0x01, 0x10, 0x00, 0x05];
+        let mut messages = PeriodicMessageIterator::new(&data);
+        assert_eq!(messages.next(), Some(&[0x3E, 0x00][..]));
+        assert_eq!(messages.next(), Some(&[0x10][..]));
+        // Truncated last message
+        assert_eq!(messages.next(), None);
+    }
+}
@@ -0,0 +1,814 @@
+//! ISO 15765-2 transfer state machines
+//! One multi-frame transmission and one reception: flow control, block size, WAIT,
+//! sequence numbers and the N_Bs/N_Cr deadlines. Frames go out through a FrameSink and
+//! time comes from a Clock, so the bridge drives these with its CAN controller and the
+//! tests with a scripted peer.
+
+use crate::frame::{
+    next_sequence_number, FlowControl, FrameFormat, ProtocolError, CONTINUE_TO_SEND, OVERFLOW, WAIT,
+};
+
+/// N_As: how long one of our frames may take to get onto the bus, enforced by the sink
+pub const N_AS_TIMEOUT_US: u64 = 1_000_000;
+/// N_Bs: how long we wait for the receiver's flow control
+pub const N_BS_TIMEOUT_US: u64 = 1_000_000;
+/// N_Cr: how long we wait for the sender's next consecutive frame
+pub const N_CR_TIMEOUT_US: u64 = 1_000_000;
+/// N_Br: how long we hold a WAIT before sending the next flow control, well inside N_Bs
+pub const N_BR_WAIT_US: u64 = 100_000;
+
+/// WAIT frames we send while we can't take the data before refusing the reception
+pub const RX_WFT_MAX: u8 = 10;
+
+/// Time source for the transfer deadlines
+pub trait Clock {
+    /// Microseconds since any fixed point, never going backwards
+    fn now_micros(&self) -> u64;
+}
+
+/// Where the frames of a transmission go
+pub trait FrameSink {
+    /// Hand a frame to the bus, an error ends the transmission
+    fn send(&mut self, frame: &[u8]) -> Result<(), IsotpError>;
+}
+
+/// Transport layer errors, reported to the BLE client as events
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum IsotpError {
+    // Frame couldn't be queued for transmission
+    FailedToSend = 0x01,
+    // N_As: our frame didn't make it onto the bus in time
+    TimeoutAs = 0x02,
+    // N_Bs: receiver's flow control didn't arrive in time
+    TimeoutBs = 0x03,
+    // N_Cr: sender's consecutive frame didn't arrive in time
+    TimeoutCr = 0x04,
+    // Receiver answered with flow status OVERFLOW
+    ReceiverOverflow = 0x05,
+    // Receiver sent more than WFTmax WAIT frames in a row
+    WftOverrun = 0x06,
+    // Sender announced a message larger than our receive buffer
+    RxOverflow = 0x07,
+    // A reception is in progress on this handler, ISO-TP links are half-duplex
+    Busy = 0x08,
+    // New First Frame before the previous reception completed, the old one was dropped
+    ReceptionRestarted = 0x09,
+    // No response within P2 (or P2* after a response pending)
+    ResponseTimeout = 0x0A,
+    // Functional requests have to fit in a single frame
+    FunctionalTooLong = 0x0B,
+    // Transmission cancelled by the client
+    Aborted = 0x0C,
+    // BLE side stayed saturated for RX_WFT_MAX WAIT frames, the reception was refused
+    ReceiverSaturated = 0x0D,
+    // Client flow control outside of pass-through mode
+    PassThroughDisabled = 0x0E,
+    // No pool buffer to hand a completed PDU over in, it was dropped
+    NoBufferAvailable = 0x0F,
+    // N_INVALID_FS: receiver answered with a reserved flow status, the transmission was aborted
+    InvalidFlowStatus = 0x10,
+    // Relay target was busy or is gone, the received PDU wasn't retransmitted
+    RelayFailed = 0x11,
+    // PDU too long for one notification with streaming off, it was dropped
+    PduTooLongToNotify = 0x12,
+}
+
+/// Transmit side of a multi-frame transfer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TxState {
+    Idle,
+    // First Frame or a full block sent, the receiver has to send flow control by the
+    // deadline. Each WAIT restarts N_Bs.
+    WaitingForFlowControl {
+        wait_frames: u8,
+        deadline: u64,
+    },
+    // Sending consecutive frames with the pacing from the last CTS. A block size of 0
+    // means the rest of the message goes in one block.
+    SendingConsecutive {
+        block_size: u8,
+        remaining_in_block: u8,
+        st_min: u8,
+    },
+}
+
+/// One multi-frame transmission. The message is passed to every call, only how much of
+/// it went out is kept here.
+pub struct Transmitter {
+    format: FrameFormat,
+    // Consecutive WAIT frames accepted before giving up
+    wft_max: u8,
+    state: TxState,
+    length: usize,
+    sent: usize,
+    sequence_number: u8,
+}
+
+impl Transmitter {
+    pub fn new(format: FrameFormat, wft_max: u8) -> Self {
+        Self {
+            format,
+            wft_max,
+            state: TxState::Idle,
+            length: 0,
+            sent: 0,
+            sequence_number: 0,
+        }
+    }
+
+    pub fn state(&self) -> TxState {
+        self.state
+    }
+
+    pub fn is_idle(&self) -> bool {
+        self.state == TxState::Idle
+    }
+
+    /// Bytes of the message handed to the sink so far
+    pub fn sent(&self) -> usize {
+        self.sent
+    }
+
+    /// When N_Bs runs out, None unless flow control is awaited
+    pub fn flow_control_deadline(&self) -> Option<u64> {
+        match self.state {
+            TxState::WaitingForFlowControl { deadline, .. } => Some(deadline),
+            _ => None,
+        }
+    }
+
+    /// Send the First Frame of `data`, longer than the format's single_frame_max()
+    pub fn start(
+        &mut self,
+        data: &[u8],
+        sink: &mut impl FrameSink,
+        clock: &impl Clock,
+    ) -> Result<(), IsotpError> {
+        let (first_frame, first_chunk) = self.format.first_frame(data);
+        sink.send(&first_frame)?;
+
+        self.length = data.len();
+        self.sent = first_chunk;
+        self.sequence_number = 1;
+        self.wait_for_flow_control(0, clock);
+        Ok(())
+    }
+
+    fn wait_for_flow_control(&mut self, wait_frames: u8, clock: &impl Clock) {
+        self.state = TxState::WaitingForFlowControl {
+            wait_frames,
+            deadline: clock.now_micros() + N_BS_TIMEOUT_US,
+        };
+    }
+
+    /// Act on the receiver's flow control. Flow control nobody waits for is ignored.
+    pub fn handle_flow_control(
+        &mut self,
+        flow_control: FlowControl,
+        clock: &impl Clock,
+    ) -> Result<(), IsotpError> {
+        let TxState::WaitingForFlowControl { wait_frames, .. } = self.state else {
+            debug!("Ignoring FC, not waiting for one");
+            return Ok(());
+        };
+
+        match flow_control.flow_status {
+            CONTINUE_TO_SEND => {
+                self.state = TxState::SendingConsecutive {
+                    block_size: flow_control.block_size,
+                    remaining_in_block: flow_control.block_size,
+                    st_min: flow_control.st_min,
+                };
+                Ok(())
+            }
+            WAIT => {
+                let wait_frames = wait_frames.saturating_add(1);
+                debug!("Received WAIT flow status ({})", wait_frames);
+                if wait_frames > self.wft_max {
+                    self.state = TxState::Idle;
+                    return Err(IsotpError::WftOverrun);
+                }
+                self.wait_for_flow_control(wait_frames, clock);
+                Ok(())
+            }
+            OVERFLOW => {
+                self.state = TxState::Idle;
+                Err(IsotpError::ReceiverOverflow)
+            }
+            // The frame's BS and STmin can't be trusted either, so stop here
+            _ => {
+                self.state = TxState::Idle;
+                Err(IsotpError::InvalidFlowStatus)
+            }
+        }
+    }
+
+    /// Send the next consecutive frame of `data`, if the receiver allows one. The caller
+    /// keeps STmin between them.
+    pub fn send_consecutive_frame(
+        &mut self,
+        data: &[u8],
+        sink: &mut impl FrameSink,
+        clock: &impl Clock,
+    ) -> Result<(), IsotpError> {
+        let TxState::SendingConsecutive {
+            block_size,
+            remaining_in_block,
+            st_min,
+        } = self.state
+        else {
+            return Ok(());
+        };
+
+        let chunk_size = (self.length - self.sent).min(self.format.consecutive_frame_payload());
+        let chunk = &data[self.sent..self.sent + chunk_size];
+        sink.send(&self.format.consecutive_frame(self.sequence_number, chunk))?;
+
+        self.sent += chunk_size;
+        self.sequence_number = next_sequence_number(self.sequence_number);
+        if self.sent == self.length {
+            self.state = TxState::Idle;
+        } else if block_size > 0 && remaining_in_block == 1 {
+            // Block complete, pause until the receiver sends the next flow control
+            self.wait_for_flow_control(0, clock);
+        } else {
+            self.state = TxState::SendingConsecutive {
+                block_size,
+                remaining_in_block: remaining_in_block.saturating_sub(1),
+                st_min,
+            };
+        }
+        Ok(())
+    }
+
+    /// TimeoutBs once flow control is overdue, the transmission is over then
+    pub fn check_timeout(&mut self, clock: &impl Clock) -> Result<(), IsotpError> {
+        match self.flow_control_deadline() {
+            Some(deadline) if clock.now_micros() > deadline => {
+                self.state = TxState::Idle;
+                Err(IsotpError::TimeoutBs)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Stop the transmission, returns how many bytes of the message had been sent
+    pub fn abort(&mut self) -> usize {
+        self.state = TxState::Idle;
+        self.sent
+    }
+}
+
+/// Receive side of a multi-frame transfer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RxState {
+    Idle,
+    // First Frame received, consecutive frames are expected until the message is complete
+    Receiving {
+        // Sender of the reception, CFs from anyone else are ignored
+        source_id: u32,
+        expected_length: usize,
+        received: usize,
+        sequence_number: u8,
+        // Consecutive frames received since our last flow control
+        block_count: u8,
+        // N_Cr for the next consecutive frame
+        deadline: u64,
+    },
+    // First Frame taken but the rest can't be yet, the sender is held off with WAIT frames
+    Throttled {
+        source_id: u32,
+        expected_length: usize,
+        received: usize,
+        wait_frames: u8,
+        next_flow_control: u64,
+    },
+}
+
+/// Why a consecutive frame didn't add to the reception
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RxError {
+    // No reception in progress, or one from another sender
+    Unexpected,
+    // The frame is malformed, the reception carries on
+    Protocol(ProtocolError),
+    // Out of order, the rest of the transfer can't be reassembled and was dropped
+    Sequence { expected: u8, received: u8 },
+    // The reception is over
+    Transfer(IsotpError),
+}
+
+/// Data a consecutive frame adds to the reception, padding after the end of the
+/// message left out
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConsecutiveFrame<'a> {
+    pub data: &'a [u8],
+    pub complete: bool,
+    // A block is complete, the sender waits for our CTS
+    pub clear_to_send: bool,
+}
+
+/// One multi-frame reception
+pub struct Receiver {
+    state: RxState,
+}
+
+impl Default for Receiver {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Receiver {
+    pub const fn new() -> Self {
+        Self {
+            state: RxState::Idle,
+        }
+    }
+
+    pub fn state(&self) -> RxState {
+        self.state
+    }
+
+    /// Whether a sender is in the middle of a transfer to us
+    pub fn is_receiving(&self, clock: &impl Clock) -> bool {
+        match self.state {
+            RxState::Receiving { deadline, .. } => clock.now_micros() <= deadline,
+            RxState::Throttled { .. } => true,
+            RxState::Idle => false,
+        }
+    }
+
+    /// Expect the consecutive frames after a First Frame that carried `received` bytes,
+    /// the caller answers with CTS
+    pub fn start(
+        &mut self,
+        source_id: u32,
+        expected_length: usize,
+        received: usize,
+        clock: &impl Clock,
+    ) {
+        self.state = RxState::Receiving {
+            source_id,
+            expected_length,
+            received,
+            sequence_number: 1,
+            block_count: 0,
+            deadline: clock.now_micros() + N_CR_TIMEOUT_US,
+        };
+    }
+
+    /// Like start, but the data can't be taken yet. The caller answers with WAIT.
+    pub fn hold_off(
+        &mut self,
+        source_id: u32,
+        expected_length: usize,
+        received: usize,
+        clock: &impl Clock,
+    ) {
+        self.state = RxState::Throttled {
+            source_id,
+            expected_length,
+            received,
+            wait_frames: 1,
+            next_flow_control: clock.now_micros() + N_BR_WAIT_US,
+        };
+    }
+
+    /// Flow status for a held off sender once N_Br has passed: CTS if there is `room`
+    /// now, otherwise another WAIT. After RX_WFT_MAX WAITs the reception is refused with
+    /// ReceiverSaturated and the caller sends OVERFLOW. None while nothing is due.
+    pub fn resume(&mut self, room: bool, clock: &impl Clock) -> Option<Result<u8, IsotpError>> {
+        let RxState::Throttled {
+            source_id,
+            expected_length,
+            received,
+            wait_frames,
+            next_flow_control,
+        } = self.state
+        else {
+            return None;
+        };
+        if clock.now_micros() < next_flow_control {
+            return None;
+        }
+
+        if room {
+            self.start(source_id, expected_length, received, clock);
+            Some(Ok(CONTINUE_TO_SEND))
+        } else if wait_frames >= RX_WFT_MAX {
+            self.reset();
+            Some(Err(IsotpError::ReceiverSaturated))
+        } else {
+            self.state = RxState::Throttled {
+                source_id,
+                expected_length,
+                received,
+                wait_frames: wait_frames + 1,
+                next_flow_control: clock.now_micros() + N_BR_WAIT_US,
+            };
+            Some(Ok(WAIT))
+        }
+    }
+
+    /// Check a consecutive frame from `source_id`, `data` starting at the PCI byte.
+    /// `block_size` is the BS our flow control asked for.
+    pub fn handle_consecutive_frame<'a>(
+        &mut self,
+        source_id: u32,
+        data: &'a [u8],
+        block_size: u8,
+        clock: &impl Clock,
+    ) -> Result<ConsecutiveFrame<'a>, RxError> {
+        if data.len() < 2 {
+            return Err(RxError::Protocol(
+                ProtocolError::InvalidConsecutiveFrameLength,
+            ));
+        }
+
+        let RxState::Receiving {
+            source_id: sender,
+            expected_length,
+            received,
+            sequence_number: expected,
+            block_count,
+            deadline,
+        } = self.state
+        else {
+            return Err(RxError::Unexpected);
+        };
+        if source_id != sender {
+            return Err(RxError::Unexpected);
+        }
+
+        if clock.now_micros() > deadline {
+            self.reset();
+            return Err(RxError::Transfer(IsotpError::TimeoutCr));
+        }
+
+        let sequence_number = data[0] & 0x0F;
+        if sequence_number != expected {
+            self.reset();
+            return Err(RxError::Sequence {
+                expected,
+                received: sequence_number,
+            });
+        }
+
+        // The last CF may be unpadded and padding after the last data byte isn't part of
+        // the message, so only take what is both present and still expected
+        let chunk = &data[1..];
+        let chunk = &chunk[..chunk.len().min(expected_length - received)];
+        let received = received + chunk.len();
+        if received >= expected_length {
+            self.reset();
+            return Ok(ConsecutiveFrame {
+                data: chunk,
+                complete: true,
+                clear_to_send: false,
+            });
+        }
+
+        let block_count = match block_size {
+            0 => 0,
+            block_size => (block_count + 1) % block_size,
+        };
+        self.state = RxState::Receiving {
+            source_id,
+            expected_length,
+            received,
+            sequence_number: next_sequence_number(expected),
+            block_count,
+            deadline: clock.now_micros() + N_CR_TIMEOUT_US,
+        };
+        Ok(ConsecutiveFrame {
+            data: chunk,
+            complete: false,
+            clear_to_send: block_size > 0 && block_count == 0,
+        })
+    }
+
+    /// TimeoutCr once the sender stalled mid-transfer, the reception is dropped then
+    pub fn check_timeout(&mut self, clock: &impl Clock) -> Result<(), IsotpError> {
+        match self.state {
+            RxState::Receiving { deadline, .. } if clock.now_micros() > deadline => {
+                self.reset();
+                Err(IsotpError::TimeoutCr)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.state = RxState::Idle;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frame::CLASSIC_FRAME_LEN;
+    use std::cell::Cell;
+    use std::vec::Vec;
+
+    #[derive(Default)]
+    struct TestClock(Cell<u64>);
+
+    impl TestClock {
+        fn advance(&self, micros: u64) {
+            self.0.set(self.0.get() + micros);
+        }
+    }
+
+    impl Clock for TestClock {
+        fn now_micros(&self) -> u64 {
+            self.0.get()
+        }
+    }
+
+    #[derive(Default)]
+    struct Bus(Vec<Vec<u8>>);
+
+    impl FrameSink for Bus {
+        fn send(&mut self, frame: &[u8]) -> Result<(), IsotpError> {
+            self.0.push(frame.to_vec());
+            Ok(())
+        }
+    }
+
+    const FORMAT: FrameFormat = FrameFormat {
+        frame_len: CLASSIC_FRAME_LEN,
+        address_extension: None,
+        pad_byte: 0xAA,
+        consecutive_frame_payload: None,
+    };
+
+    fn pattern(len: usize) -> Vec<u8> {
+        (0..len).map(|i| i as u8).collect()
+    }
+
+    fn flow_control(flow_status: u8, block_size: u8) -> FlowControl {
+        FlowControl {
+            flow_status,
+            block_size,
+            st_min: 0,
+        }
+    }
+
+    // Send consecutive frames until the transmitter waits or is done
+    fn send_block(transmitter: &mut Transmitter, data: &[u8], bus: &mut Bus, clock: &TestClock) {
+        while matches!(transmitter.state(), TxState::SendingConsecutive { .. }) {
+            transmitter
+                .send_consecutive_frame(data, bus, clock)
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn nothing_after_first_frame_until_clear_to_send() {
+        let (clock, mut bus) = (TestClock::default(), Bus::default());
+        let data = pattern(20);
+        let mut transmitter = Transmitter::new(FORMAT, 0);
+
+        transmitter.start(&data, &mut bus, &clock).unwrap();
+        transmitter
+            .send_consecutive_frame(&data, &mut bus, &clock)
+            .unwrap();
+        assert_eq!(bus.0, [[0x10, 20, 0, 1, 2, 3, 4, 5]]);
+
+        transmitter
+            .handle_flow_control(flow_control(CONTINUE_TO_SEND, 0), &clock)
+            .unwrap();
+        send_block(&mut transmitter, &data, &mut bus, &clock);
+        assert_eq!(bus.0[1], [0x21, 6, 7, 8, 9, 10, 11, 12]);
+        assert_eq!(bus.0[2], [0x22, 13, 14, 15, 16, 17, 18, 19]);
+        assert!(transmitter.is_idle());
+        assert_eq!(transmitter.sent(), data.len());
+    }
+
+    #[test]
+    fn block_size_pauses_for_flow_control() {
+        let (clock, mut bus) = (TestClock::default(), Bus::default());
+        // FF and five CFs
+        let data = pattern(40);
+        let mut transmitter = Transmitter::new(FORMAT, 0);
+        transmitter.start(&data, &mut bus, &clock).unwrap();
+
+        for block in [2, 2, 1] {
+            let before = bus.0.len();
+            transmitter
+                .handle_flow_control(flow_control(CONTINUE_TO_SEND, 2), &clock)
+                .unwrap();
+            send_block(&mut transmitter, &data, &mut bus, &clock);
+            assert_eq!(bus.0.len() - before, block);
+        }
+        assert!(transmitter.is_idle());
+        assert_eq!(bus.0[5][0], 0x25);
+    }
+
+    #[test]
+    fn wait_restarts_flow_control_timeout() {
+        let (clock, mut bus) = (TestClock::default(), Bus::default());
+        let data = pattern(20);
+        let mut transmitter = Transmitter::new(FORMAT, 2);
+        transmitter.start(&data, &mut bus, &clock).unwrap();
+
+        clock.advance(N_BS_TIMEOUT_US - 1);
+        transmitter
+            .handle_flow_control(flow_control(WAIT, 0), &clock)
+            .unwrap();
+        clock.advance(N_BS_TIMEOUT_US - 1);
+        assert_eq!(transmitter.check_timeout(&clock), Ok(()));
+        assert!(matches!(
+            transmitter.state(),
+            TxState::WaitingForFlowControl { wait_frames: 1, .. }
+        ));
+
+        clock.advance(2);
+        assert_eq!(
+            transmitter.check_timeout(&clock),
+            Err(IsotpError::TimeoutBs)
+        );
+        assert!(transmitter.is_idle());
+    }
+
+    #[test]
+    fn too_many_waits() {
+        let (clock, mut bus) = (TestClock::default(), Bus::default());
+        let data = pattern(20);
+        let mut transmitter = Transmitter::new(FORMAT, 2);
+        transmitter.start(&data, &mut bus, &clock).unwrap();
+
+        for _ in 0..2 {
+            transmitter
+                .handle_flow_control(flow_control(WAIT, 0), &clock)
+                .unwrap();
+        }
+        assert_eq!(
+            transmitter.handle_flow_control(flow_control(WAIT, 0), &clock),
+            Err(IsotpError::WftOverrun)
+        );
+        assert_eq!(bus.0.len(), 1);
+    }
+
+    #[test]
+    fn receiver_overflow_ends_transmission() {
+        let (clock, mut bus) = (TestClock::default(), Bus::default());
+        let data = pattern(20);
+        let mut transmitter = Transmitter::new(FORMAT, 0);
+        transmitter.start(&data, &mut bus, &clock).unwrap();
+
+        assert_eq!(
+            transmitter.handle_flow_control(flow_control(OVERFLOW, 0), &clock),
+            Err(IsotpError::ReceiverOverflow)
+        );
+        assert!(transmitter.is_idle());
+        assert_eq!(transmitter.abort(), 6);
+    }
+
+    #[test]
+    fn transmit_sequence_numbers_wrap() {
+        let (clock, mut bus) = (TestClock::default(), Bus::default());
+        // 120 bytes take 17 CFs, so the sequence number goes 1..=F, 0, 1
+        let data = pattern(120);
+        let mut transmitter = Transmitter::new(FORMAT, 0);
+        transmitter.start(&data, &mut bus, &clock).unwrap();
+        transmitter
+            .handle_flow_control(flow_control(CONTINUE_TO_SEND, 0), &clock)
+            .unwrap();
+        send_block(&mut transmitter, &data, &mut bus, &clock);
+
+        let sequence_numbers: Vec<u8> = bus.0[1..].iter().map(|frame| frame[0]).collect();
+        assert_eq!(sequence_numbers.len(), 17);
+        assert_eq!(sequence_numbers[14..], [0x2F, 0x20, 0x21]);
+    }
+
+    fn consecutive_frame(sequence_number: u8, data: &[u8]) -> Vec<u8> {
+        FORMAT.consecutive_frame(sequence_number, data).to_vec()
+    }
+
+    #[test]
+    fn receiver_sends_clear_to_send_after_each_block() {
+        let clock = TestClock::default();
+        let data = pattern(40);
+        let mut receiver = Receiver::new();
+        receiver.start(0x7E8, data.len(), 6, &clock);
+
+        let mut message = data[..6].to_vec();
+        let mut clear_to_send = Vec::new();
+        for (index, chunk) in data[6..].chunks(7).enumerate() {
+            let frame = consecutive_frame(index as u8 + 1, chunk);
+            let consecutive = receiver
+                .handle_consecutive_frame(0x7E8, &frame, 2, &clock)
+                .unwrap();
+            message.extend_from_slice(consecutive.data);
+            clear_to_send.push(consecutive.clear_to_send);
+            assert_eq!(consecutive.complete, index == 4);
+        }
+        assert_eq!(message, data);
+        assert_eq!(clear_to_send, [false, true, false, true, false]);
+        assert_eq!(receiver.state(), RxState::Idle);
+    }
+
+    #[test]
+    fn receiver_drops_out_of_order_frames() {
+        let clock = TestClock::default();
+        let mut receiver = Receiver::new();
+        receiver.start(0x7E8, 40, 6, &clock);
+
+        assert_eq!(
+            receiver.handle_consecutive_frame(0x7E8, &consecutive_frame(2, &[0; 7]), 0, &clock),
+            Err(RxError::Sequence {
+                expected: 1,
+                received: 2
+            })
+        );
+        assert_eq!(receiver.state(), RxState::Idle);
+    }
+
+    #[test]
+    fn receiver_ignores_other_senders() {
+        let clock = TestClock::default();
+        let mut receiver = Receiver::new();
+        let frame = consecutive_frame(1, &[0; 7]);
+        assert_eq!(
+            receiver.handle_consecutive_frame(0x7E8, &frame, 0, &clock),
+            Err(RxError::Unexpected)
+        );
+
+        receiver.start(0x7E8, 40, 6, &clock);
+        assert_eq!(
+            receiver.handle_consecutive_frame(0x7E9, &frame, 0, &clock),
+            Err(RxError::Unexpected)
+        );
+        assert!(receiver.is_receiving(&clock));
+    }
+
+    #[test]
+    fn receiver_leaves_out_padding() {
+        let clock = TestClock::default();
+        let mut receiver = Receiver::new();
+        receiver.start(0x7E8, 8, 6, &clock);
+
+        let frame = consecutive_frame(1, &[6, 7]);
+        let consecutive = receiver
+            .handle_consecutive_frame(0x7E8, &frame, 0, &clock)
+            .unwrap();
+        assert_eq!(consecutive.data, [6, 7]);
+        assert!(consecutive.complete);
+    }
+
+    #[test]
+    fn receiver_consecutive_frame_timeout() {
+        let clock = TestClock::default();
+        let mut receiver = Receiver::new();
+        receiver.start(0x7E8, 40, 6, &clock);
+
+        clock.advance(N_CR_TIMEOUT_US + 1);
+        assert!(!receiver.is_receiving(&clock));
+        assert_eq!(
+            receiver.handle_consecutive_frame(0x7E8, &consecutive_frame(1, &[0; 7]), 0, &clock),
+            Err(RxError::Transfer(IsotpError::TimeoutCr))
+        );
+        assert_eq!(receiver.check_timeout(&clock), Ok(()));
+    }
+
+    #[test]
+    fn held_off_sender_gets_wait_until_saturated() {
+        let clock = TestClock::default();
+        let mut receiver = Receiver::new();
+        receiver.hold_off(0x7E8, 40, 6, &clock);
+        assert_eq!(receiver.resume(false, &clock), None);
+
+        for _ in 1..RX_WFT_MAX {
+            clock.advance(N_BR_WAIT_US);
+            assert_eq!(receiver.resume(false, &clock), Some(Ok(WAIT)));
+        }
+        clock.advance(N_BR_WAIT_US);
+        assert_eq!(
+            receiver.resume(false, &clock),
+            Some(Err(IsotpError::ReceiverSaturated))
+        );
+        assert_eq!(receiver.state(), RxState::Idle);
+    }
+
+    #[test]
+    fn held_off_sender_continues_once_there_is_room() {
+        let clock = TestClock::default();
+        let mut receiver = Receiver::new();
+        receiver.hold_off(0x7E8, 40, 6, &clock);
+
+        clock.advance(N_BR_WAIT_US);
+        assert_eq!(receiver.resume(true, &clock), Some(Ok(CONTINUE_TO_SEND)));
+        assert!(matches!(
+            receiver.state(),
+            RxState::Receiving {
+                received: 6,
+                sequence_number: 1,
+                ..
+            }
+        ));
+    }
+}
@@ -3,8 +3,11 @@ MEMORY {
      * The RP2350 has either external or internal flash.
      *
      * 2 MiB is a safe default here, although a Pico 2 has 4 MiB.
+     * The last 4K sector is reserved for the device config (see src/config.rs),
+     * the one below it for the saved ISO-TP session (see src/session_store.rs),
+     * and the one below that for the watchdog reset record (see src/supervisor.rs).
      */
-    FLASH : ORIGIN = 0x10000000, LENGTH = 2048K
+    FLASH : ORIGIN = 0x10000000, LENGTH = 2036K
     /*
      * RAM consists of 8 banks, SRAM0-SRAM7, with a striped mapping.
      * This is usually good for performance, as it distributes load on
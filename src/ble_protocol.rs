@@ -1,319 +1,774 @@
-use core::convert::TryFrom;
+use defmt::Format;
+use embassy_time::Instant;
 
-use defmt::{debug, Format};
+pub use isotp_core::protocol::*;
 
-/// Error type for message parsing
-#[derive(Debug, Format)]
-pub enum ParseError {
-    InvalidCommand,
-    BufferTooSmall,
-}
+use crate::can_manager::{MAX_FILTERS, MAX_FRAME_LEN};
+use crate::channels::CHANNEL_COUNT;
+use crate::event_log::{LogEntry, EVENT_LOG_SIZE};
+use crate::isotp_handler::{IsotpError, ProtocolError, MAX_ISOTP_ERROR_CODES};
+use crate::isotp_selftest::MAX_SELF_TEST_CASES;
+use crate::pdu_buffer::PduBuffer;
 
-/// Command IDs extracted from the JavaScript code
+/// Which side of a handler's conversation a delivered PDU came from
 #[repr(u8)]
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub enum CommandId {
-    UploadIsotpChunk = 0x02,
-    SendIsotpBuffer = 0x03,
-    StartPeriodicIsotpMessage = 0x04,
-    StopPeriodicIsotpMessage = 0x05,
-    ConfigureIsotpFilter = 0x06,
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum Direction {
+    // Received on a reply id, an ECU's answer
+    Response = 0x00,
+    // Observed on the request id, another tester's request
+    Request = 0x01,
 }
 
-impl TryFrom<u8> for CommandId {
-    type Error = ParseError;
-
-    fn try_from(value: u8) -> Result<Self, Self::Error> {
-        match value {
-            0x02 => Ok(CommandId::UploadIsotpChunk),
-            0x03 => Ok(CommandId::SendIsotpBuffer),
-            0x04 => Ok(CommandId::StartPeriodicIsotpMessage),
-            0x05 => Ok(CommandId::StopPeriodicIsotpMessage),
-            0x06 => Ok(CommandId::ConfigureIsotpFilter),
-            _ => Err(ParseError::InvalidCommand),
-        }
-    }
-}
-/// Upload Chunk Command (0x02)
-/// Used to upload chunks of a large message
+/// Message payload with arbitration IDs
+/// This represents the format of data messages
 #[derive(Debug, Format)]
-pub struct UploadIsotpChunkCommand {
-    pub offset: u16,
-    pub chunk_length: u16,
-    pub chunk: heapless::Vec<u8, 512>,
+pub struct IsoTpMessage {
+    pub request_arbitration_id: u32,
+    pub reply_arbitration_id: u32,
+    pub direction: Direction,
+    // Tag of the request this answers, when the client gave one
+    pub correlation_tag: Option<u16>,
+    // When its last frame was received, for the notify latency
+    pub received_at: Option<Instant>,
+    // Pool buffer the PDU was received into, released once the message is dropped
+    pub pdu: PduBuffer,
 }
 
-impl UploadIsotpChunkCommand {
-    /// Parse an upload chunk command from a byte buffer
-    pub fn parse(buffer: &[u8]) -> Result<Self, ParseError> {
-        // Need at least 5 bytes: command(1) + offset(2) + length(2)
-        if buffer.len() < 5 {
-            return Err(ParseError::BufferTooSmall);
-        }
+impl IsoTpMessage {
+    /// Set in the leading arbitration id of requests, ids are at most 29 bits wide
+    pub const DIRECTION_REQUEST_FLAG: u32 = 0x8000_0000;
+    /// Set in the leading arbitration id when a correlation tag follows the request id
+    pub const CORRELATION_TAG_FLAG: u32 = 0x4000_0000;
+    /// Longest PDU that fits a notification after both ids and a correlation tag
+    pub const MAX_NOTIFIED_PDU: usize = NOTIFICATION_SIZE - 10;
+}
 
-        let offset = u16::from_be_bytes([buffer[1], buffer[2]]);
+/// Largest message sent as a single notification
+pub const NOTIFICATION_SIZE: usize = 512;
 
-        let chunk_length = u16::from_be_bytes([buffer[3], buffer[4]]);
+/// An event that didn't fit a notification, it is dropped rather than sent cut short
+#[derive(Debug, Format)]
+pub struct EventTooLarge;
 
-        // Validate that buffer contains enough data
-        if buffer.len() < 5 + chunk_length as usize {
-            return Err(ParseError::BufferTooSmall);
-        }
+fn put(
+    buffer: &mut heapless::Vec<u8, NOTIFICATION_SIZE>,
+    bytes: &[u8],
+) -> Result<(), EventTooLarge> {
+    buffer.extend_from_slice(bytes).map_err(|_| EventTooLarge)
+}
 
-        let chunk = &buffer[5..5 + chunk_length as usize];
+/// Leading byte of event notifications. Arbitration ids are at most 29 bits wide, so a
+/// notification starting with 0xFF can never be mistaken for an ISO-TP response.
+pub const EVENT_MARKER: u8 = 0xFF;
 
-        Ok(Self {
-            offset,
-            chunk_length,
-            chunk: heapless::Vec::from_slice(chunk).unwrap(),
-        })
-    }
+/// Event IDs sent after the event marker
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum EventId {
+    CanError = 0x01,
+    CanFrames = 0x02,
+    FrameExpired = 0x03,
+    CanStatistics = 0x04,
+    FilterStatistics = 0x05,
+    QueueStatistics = 0x06,
+    IsotpError = 0x07,
+    IsotpSequenceError = 0x08,
+    FunctionalWindowClosed = 0x09,
+    IsotpTransmissionAborted = 0x0A,
+    IsotpFlowControl = 0x0B,
+    IsotpProtocolError = 0x0C,
+    IsotpTransmitProgress = 0x0D,
+    SelfTestReport = 0x0E,
+    IsotpSegment = 0x0F,
+    IsotpSendResult = 0x10,
+    BridgeStatistics = 0x11,
+    SecuritySeed = 0x12,
+    SecurityAccessResult = 0x13,
+    ObdSample = 0x14,
+    MacroResult = 0x15,
+    CommandRejected = 0x16,
+    FilterExpired = 0x17,
+    DryRunFrame = 0x18,
+    EventLog = 0x19,
+    ChannelStatistics = 0x1A,
+    StackStatistics = 0x1B,
+    // Not a BleEvent, several notifications as length(1) + bytes each
+    ResponseBatch = 0x1C,
+    NotifyLatency = 0x1D,
 }
 
-/// Trigger BLE Send Command (0x03)
-/// Used to trigger sending of accumulated chunks
-#[derive(Debug, Format)]
-pub struct SendIsotpBufferCommand {
-    // Total length of message to send
-    pub total_length: u16,
+/// Best-effort classification of a can2040 error notification
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum CanErrorKind {
+    // Interrupt handling fell behind and the PIO rx fifo overflowed
+    RxOverflow = 0x00,
+    // Stuff, form or CRC errors seen while parsing the bitstream
+    Bitstream = 0x01,
+    // Transmissions were not acknowledged or lost and had to be retried
+    Transmit = 0x02,
 }
 
-impl SendIsotpBufferCommand {
-    /// Parse a trigger BLE send command from a byte buffer
-    pub fn parse(buffer: &[u8]) -> Result<Self, ParseError> {
-        debug!("[ble] SendIsotpBufferCommand: {:02x}", buffer);
+/// Bytes of frame records in one sniffer notification, sized so the whole event fits
+/// the default 128 byte L2CAP MTU
+pub const SNIFFER_BATCH_SIZE: usize = 112;
 
-        // Need 3 bytes: command(1) + length(2)
-        if buffer.len() < 3 {
-            return Err(ParseError::BufferTooSmall);
-        }
+// Data bytes per streamed segment, no larger than a sniffer batch so events don't grow
+pub const STREAM_SEGMENT_SIZE: usize = 112;
 
-        let total_length = u16::from_be_bytes([buffer[1], buffer[2]]);
+/// Largest coalesced notification, marker and event id included, so batching small
+/// responses never needs more than the default MTU
+pub const RESPONSE_BATCH_SIZE: usize = 120;
 
-        Ok(Self { total_length })
-    }
+/// Timestamped frames packed into a single sniffer notification
+#[derive(Debug, Default, Format)]
+pub struct SnifferBatch {
+    count: u8,
+    // flags(1) + timestamp(4) + id(4) + dlc(1) + data, per frame
+    records: heapless::Vec<u8, SNIFFER_BATCH_SIZE>,
 }
 
-/// Start Periodic Message Command (0x04)
-/// Used to start sending a message periodically
-#[allow(dead_code)]
-#[derive(Debug)]
-pub struct StartPeriodicIsotpMessageCommand {
-    pub periodic_message_index: u8,
-    pub interval_ms: u16,
-    pub request_arbitration_id: u32,
-    pub reply_arbitration_id: u32,
-    pub message_count: u16,
-    pub message_data: heapless::Vec<u8, 512>,
-}
+impl SnifferBatch {
+    // Frame was transmitted by us and looped back
+    pub const FLAG_SELF_SENT: u8 = 0x01;
+    // 29-bit identifier
+    pub const FLAG_EXTENDED: u8 = 0x02;
+    // Remote transmission request
+    pub const FLAG_REMOTE: u8 = 0x04;
 
-#[allow(dead_code)]
-impl StartPeriodicIsotpMessageCommand {
-    /// Parse a start periodic message command from a byte buffer
-    pub fn parse(buffer: &[u8]) -> Result<Self, ParseError> {
-        // Need at least 14 bytes for header
-        // command(1) + index(1) + interval(2) + req_id(4) + reply_id(4) + msg_count(2)
-        if buffer.len() < 14 {
-            return Err(ParseError::BufferTooSmall);
-        }
+    const RECORD_HEADER_SIZE: usize = 10;
 
-        let periodic_message_index = buffer[1];
-        let interval_ms = u16::from_be_bytes([buffer[2], buffer[3]]);
-        let request_arbitration_id =
-            u32::from_be_bytes([buffer[4], buffer[5], buffer[6], buffer[7]]);
-        let reply_arbitration_id =
-            u32::from_be_bytes([buffer[8], buffer[9], buffer[10], buffer[11]]);
-        let message_count = u16::from_be_bytes([buffer[12], buffer[13]]);
-
-        // Message data starts at offset 14
-        let message_data = &buffer[14..];
-
-        Ok(Self {
-            periodic_message_index,
-            interval_ms,
-            request_arbitration_id,
-            reply_arbitration_id,
-            message_count,
-            message_data: heapless::Vec::from_slice(message_data).unwrap(),
-        })
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
     }
 
-    /// Helper to iterate over the individual messages in the payload
-    pub fn iter_messages(&self) -> PeriodicMessageIterator {
-        PeriodicMessageIterator {
-            data: self.message_data.as_slice(),
-            offset: 0,
+    /// Append a frame, returns false if the batch has no room left for it
+    pub fn push(&mut self, timestamp_us: u32, id: u32, flags: u8, data: &[u8]) -> bool {
+        if self.records.len() + Self::RECORD_HEADER_SIZE + data.len() > SNIFFER_BATCH_SIZE {
+            return false;
         }
+
+        self.records.push(flags).unwrap();
+        self.records
+            .extend_from_slice(&timestamp_us.to_be_bytes())
+            .unwrap();
+        self.records.extend_from_slice(&id.to_be_bytes()).unwrap();
+        self.records.push(data.len() as u8).unwrap();
+        self.records.extend_from_slice(data).unwrap();
+        self.count += 1;
+        true
     }
 }
 
-/// Iterator for periodic messages in a StartPeriodicMessageCommand
-pub struct PeriodicMessageIterator<'a> {
-    data: &'a [u8],
-    offset: usize,
+/// CAN counters since boot (controller counters since the last restart)
+#[derive(Debug, Format)]
+pub struct CanStatistics {
+    pub rx_total: u32,
+    pub tx_total: u32,
+    pub tx_attempt: u32,
+    pub parse_error: u32,
+    pub malformed: u32,
+    pub errors: u32,
+    pub resets: u32,
+    pub expired: u32,
 }
 
-impl<'a> Iterator for PeriodicMessageIterator<'a> {
-    type Item = &'a [u8];
-
-    fn next(&mut self) -> Option<Self::Item> {
-        if self.offset + 2 > self.data.len() {
-            return None;
-        }
-
-        // Get message length (16-bit BE)
-        let length =
-            u16::from_be_bytes([self.data[self.offset], self.data[self.offset + 1]]) as usize;
-
-        // Check if we have enough data
-        if self.offset + 2 + length > self.data.len() {
-            return None;
-        }
-
-        // Get message slice
-        let message = &self.data[self.offset + 2..self.offset + 2 + length];
-
-        // Update offset for next iteration
-        self.offset += 2 + length;
-
-        Some(message)
-    }
+/// Rx queue sizing diagnostics
+#[derive(Debug, Format)]
+pub struct QueueStatistics {
+    pub rx_queue_capacity: u32,
+    pub rx_queue_high_water: u32,
+    pub rx_queue_dropped: u32,
+    pub rx_latency_max_us: u32,
+    // Frames an rx consumer missed because it fell behind
+    pub rx_subscriber_lagged: u32,
 }
 
-/// Stop Periodic Message Command (0x05)
-/// Used to stop a periodic message
-#[derive(Debug)]
-#[allow(dead_code)]
-pub struct StopPeriodicIsotpMessageCommand {
-    // Periodic message index to stop
-    pub periodic_message_index: u8,
-    // Request arbitration ID
-    pub request_arbitration_id: u32,
-    // Reply arbitration ID
-    pub reply_arbitration_id: u32,
+/// Depth of an inter-task channel
+#[derive(Debug, Format)]
+pub struct ChannelStatistic {
+    pub capacity: u32,
+    // Most messages queued at once since boot
+    pub high_water: u32,
+    // Messages turned away because the channel was full
+    pub dropped: u32,
 }
 
-impl StopPeriodicIsotpMessageCommand {
-    /// Parse a stop periodic message command from a byte buffer
-    pub fn parse(buffer: &[u8]) -> Result<Self, ParseError> {
-        // Need 10 bytes: command(1) + index(1) + req_id(4) + reply_id(4)
-        if buffer.len() < 10 {
-            return Err(ParseError::BufferTooSmall);
-        }
-
-        let periodic_message_index = buffer[1];
-        let request_arbitration_id =
-            u32::from_be_bytes([buffer[2], buffer[3], buffer[4], buffer[5]]);
-        let reply_arbitration_id = u32::from_be_bytes([buffer[6], buffer[7], buffer[8], buffer[9]]);
-
-        Ok(Self {
-            periodic_message_index,
-            request_arbitration_id,
-            reply_arbitration_id,
-        })
-    }
+/// Deepest use of a core's stack since boot
+#[derive(Debug, Format)]
+pub struct StackStatistic {
+    pub size: u32,
+    pub used: u32,
 }
 
-/// Configure Filter Command (0x06)
-/// Used to configure a message filter
+/// Time from the last frame of a PDU reaching the CAN interrupt to its notification
+/// having been sent, in microseconds
 #[derive(Debug, Format)]
-pub struct ConfigureIsotpFilterCommand {
-    // Filter ID
-    pub filter_id: u32,
-    // Request arbitration ID
-    pub request_arbitration_id: u32,
-    // Reply arbitration ID
-    pub reply_arbitration_id: u32,
-    // Filter name (null-terminated string)
-    pub name: heapless::Vec<u8, 32>,
+pub struct NotifyLatency {
+    pub samples: u32,
+    pub last_us: u32,
+    pub max_us: u32,
 }
 
-impl ConfigureIsotpFilterCommand {
-    /// Parse a configure filter command from a byte buffer
-    pub fn parse(buffer: &[u8]) -> Result<Self, ParseError> {
-        debug!("[ble] ConfigureIsotpFilterCommand: {:02x}", buffer);
+/// Frames matched by a single registered filter
+#[derive(Debug, Format)]
+pub struct FilterStatistic {
+    pub id: u32,
+    pub matched: u32,
+}
 
-        // Need at least 13 bytes: command(1) + filter_id(4) + req_id(4) + reply_id(4) + name_len(4)
-        if buffer.len() < 17 {
-            return Err(ParseError::BufferTooSmall);
-        }
+/// Times an ISO-TP error has been reported
+#[derive(Debug, Format)]
+pub struct IsotpErrorCount {
+    pub error: u8,
+    pub count: u32,
+}
 
-        let filter_id = u32::from_be_bytes([buffer[1], buffer[2], buffer[3], buffer[4]]);
-        let request_arbitration_id =
-            u32::from_be_bytes([buffer[5], buffer[6], buffer[7], buffer[8]]);
-        let reply_arbitration_id =
-            u32::from_be_bytes([buffer[9], buffer[10], buffer[11], buffer[12]]);
-        let name_len =
-            u32::from_be_bytes([buffer[13], buffer[14], buffer[15], buffer[16]]) as usize;
-
-        // Validate that buffer contains the full name
-        if buffer.len() < 17 + name_len {
-            return Err(ParseError::BufferTooSmall);
-        }
+/// ISO-TP traffic through the bridge since boot, and what it's using right now
+#[derive(Debug, Format)]
+pub struct BridgeStatistics {
+    // Client requests that made it onto the bus
+    pub pdus_sent: u32,
+    // PDUs handed to the client, observed requests included
+    pub pdus_received: u32,
+    // BLE commands that failed
+    pub commands_rejected: u32,
+    // Sniffer captures dropped because the client fell behind
+    pub sniffer_events_dropped: u32,
+    // Unsolicited PDUs held back by filter throttles
+    pub pdus_suppressed: u32,
+    // PDUs and data events lost to a full response queue, by backpressure policy
+    pub responses_dropped: u32,
+    pub responses_evicted: u32,
+    pub active_handlers: u8,
+    pub ephemeral_handlers: u8,
+    pub small_buffers_in_use: u8,
+    pub small_buffer_count: u8,
+    pub large_buffers_in_use: u8,
+    pub large_buffer_count: u8,
+    // Only errors that have happened
+    pub errors: heapless::Vec<IsotpErrorCount, MAX_ISOTP_ERROR_CODES>,
+}
 
-        let name = &buffer[17..17 + name_len];
+/// How a SendIsotpBuffer ended
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum SendOutcome {
+    // Every frame made it onto the bus
+    Sent = 0x00,
+    // Never queued, the code is a bridge error
+    Rejected = 0x01,
+    // Stopped by the transport, the code is an ISO-TP error
+    Failed = 0x02,
+}
 
-        Ok(Self {
-            filter_id,
-            request_arbitration_id,
-            reply_arbitration_id,
-            name: heapless::Vec::from_slice(name).unwrap(),
-        })
-    }
+/// How a macro run ended
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum MacroOutcome {
+    // Reached END or ran off the end of the script
+    Completed = 0x00,
+    // Reached FAIL, the code is its operand
+    Failed = 0x01,
+    // WAIT_RESPONSE ran out
+    ResponseTimeout = 0x02,
+    // A SEND failed, the code is an ISO-TP error
+    IsotpError = 0x03,
+    // Too many instructions without reaching the end
+    StepLimit = 0x04,
+    // Deleted before it could run
+    NotFound = 0x05,
 }
 
-/// Message payload with arbitration IDs
-/// This represents the format of data messages
+/// Outcome of one self-test check (see isotp_selftest::SelfTestCase)
 #[derive(Debug, Format)]
-pub struct IsoTpMessage {
-    pub request_arbitration_id: u32,
-    pub reply_arbitration_id: u32,
-    pub pdu: heapless::Vec<u8, 4096>,
+pub struct SelfTestResult {
+    pub case: u8,
+    pub passed: bool,
 }
 
-/// Main message parser
-pub struct BleMessageParser;
+/// Asynchronous events pushed to the BLE client
+#[derive(Debug, Format)]
+pub enum BleEvent {
+    CanError {
+        kind: CanErrorKind,
+        // Total error notifications since boot
+        error_count: u32,
+    },
+    CanFrames(SnifferBatch),
+    FrameExpired {
+        id: u32,
+        // Total expired frames since boot
+        expired_count: u32,
+    },
+    CanStatistics(CanStatistics),
+    FilterStatistics(heapless::Vec<FilterStatistic, MAX_FILTERS>),
+    QueueStatistics(QueueStatistics),
+    BridgeStatistics(BridgeStatistics),
+    // In the order of channels::statistics()
+    ChannelStatistics([ChannelStatistic; CHANNEL_COUNT]),
+    // core0 and core1, in that order
+    StackStatistics([StackStatistic; 2]),
+    NotifyLatency(NotifyLatency),
+    // Tagged with the request waiting for its response, if any
+    IsotpError {
+        request_arbitration_id: u32,
+        reply_arbitration_id: u32,
+        error: IsotpError,
+        correlation_tag: Option<u16>,
+    },
+    // N_WRONG_SN, the reception was abandoned
+    IsotpSequenceError {
+        request_arbitration_id: u32,
+        reply_arbitration_id: u32,
+        expected: u8,
+        received: u8,
+    },
+    // Collection window of a functional request ended
+    FunctionalWindowClosed {
+        request_arbitration_id: u32,
+        responses: u8,
+    },
+    // Multi-frame transmission cancelled by the client
+    IsotpTransmissionAborted {
+        request_arbitration_id: u32,
+        reply_arbitration_id: u32,
+        bytes_sent: u32,
+    },
+    // Flow control received by a handler in pass-through mode
+    IsotpFlowControl {
+        request_arbitration_id: u32,
+        reply_arbitration_id: u32,
+        flow_status: u8,
+        block_size: u8,
+        st_min: u8,
+    },
+    // Malformed frame from the peer, it was ignored
+    IsotpProtocolError {
+        request_arbitration_id: u32,
+        reply_arbitration_id: u32,
+        error: ProtocolError,
+        pci: u8,
+    },
+    // Periodic progress of a large multi-frame transmission
+    IsotpTransmitProgress {
+        request_arbitration_id: u32,
+        reply_arbitration_id: u32,
+        bytes_sent: u32,
+        total_length: u32,
+    },
+    SelfTestReport(heapless::Vec<SelfTestResult, MAX_SELF_TEST_CASES>),
+    // Part of a large PDU still being received, forwarded as it arrives when the handler
+    // streams its receptions
+    IsotpSegment {
+        request_arbitration_id: u32,
+        reply_arbitration_id: u32,
+        direction: Direction,
+        offset: u32,
+        total_length: u32,
+        data: heapless::Vec<u8, STREAM_SEGMENT_SIZE>,
+    },
+    // Result of a SendIsotpBuffer, identified by the upload's staging id
+    IsotpSendResult {
+        staging_id: u32,
+        outcome: SendOutcome,
+        code: u8,
+    },
+    // Seed for a SecurityAccess request, answered with the key
+    SecuritySeed {
+        request_arbitration_id: u32,
+        reply_arbitration_id: u32,
+        sub_function: u8,
+        seed: heapless::Vec<u8, MAX_SECURITY_ACCESS_DATA>,
+    },
+    // 0x00 when access was granted, otherwise the ECU's negative response code
+    SecurityAccessResult {
+        request_arbitration_id: u32,
+        reply_arbitration_id: u32,
+        sub_function: u8,
+        response_code: u8,
+    },
+    // Answer to a polled PID, timestamped on arrival
+    ObdSample {
+        request_arbitration_id: u32,
+        reply_arbitration_id: u32,
+        mode: u8,
+        pid: u8,
+        timestamp_ms: u32,
+        data: heapless::Vec<u8, MAX_OBD_SAMPLE_DATA>,
+    },
+    // Recent bridge events, oldest first
+    EventLog(heapless::Vec<LogEntry, EVENT_LOG_SIZE>),
+    // Frame that would have been transmitted, in dry run mode
+    DryRunFrame {
+        id: u32,
+        extended: bool,
+        data: heapless::Vec<u8, MAX_FRAME_LEN>,
+    },
+    // Filter removed after going unused for the configured idle timeout
+    FilterExpired {
+        filter_id: u32,
+        request_arbitration_id: u32,
+        reply_arbitration_id: u32,
+    },
+    // Command the bridge couldn't make sense of, the reason is a ParseError
+    CommandRejected {
+        command: u8,
+        reason: ParseError,
+    },
+    // End of a macro run, position is the offset of the instruction it stopped at
+    MacroResult {
+        macro_id: u8,
+        request_arbitration_id: u32,
+        reply_arbitration_id: u32,
+        outcome: MacroOutcome,
+        code: u8,
+        position: u16,
+    },
+}
 
-impl BleMessageParser {
-    /// Parse a message from a byte buffer
-    pub fn parse(buffer: &[u8]) -> Result<ParsedBleMessage, ParseError> {
-        if buffer.is_empty() {
-            return Err(ParseError::BufferTooSmall);
+impl BleEvent {
+    pub fn event_id(&self) -> EventId {
+        match self {
+            BleEvent::CanError { .. } => EventId::CanError,
+            BleEvent::CanFrames(_) => EventId::CanFrames,
+            BleEvent::FrameExpired { .. } => EventId::FrameExpired,
+            BleEvent::CanStatistics(_) => EventId::CanStatistics,
+            BleEvent::FilterStatistics(_) => EventId::FilterStatistics,
+            BleEvent::QueueStatistics(_) => EventId::QueueStatistics,
+            BleEvent::BridgeStatistics(_) => EventId::BridgeStatistics,
+            BleEvent::ChannelStatistics(_) => EventId::ChannelStatistics,
+            BleEvent::StackStatistics(_) => EventId::StackStatistics,
+            BleEvent::NotifyLatency(_) => EventId::NotifyLatency,
+            BleEvent::IsotpError { .. } => EventId::IsotpError,
+            BleEvent::IsotpSequenceError { .. } => EventId::IsotpSequenceError,
+            BleEvent::FunctionalWindowClosed { .. } => EventId::FunctionalWindowClosed,
+            BleEvent::IsotpTransmissionAborted { .. } => EventId::IsotpTransmissionAborted,
+            BleEvent::IsotpFlowControl { .. } => EventId::IsotpFlowControl,
+            BleEvent::IsotpProtocolError { .. } => EventId::IsotpProtocolError,
+            BleEvent::IsotpTransmitProgress { .. } => EventId::IsotpTransmitProgress,
+            BleEvent::SelfTestReport(_) => EventId::SelfTestReport,
+            BleEvent::IsotpSegment { .. } => EventId::IsotpSegment,
+            BleEvent::IsotpSendResult { .. } => EventId::IsotpSendResult,
+            BleEvent::SecuritySeed { .. } => EventId::SecuritySeed,
+            BleEvent::SecurityAccessResult { .. } => EventId::SecurityAccessResult,
+            BleEvent::ObdSample { .. } => EventId::ObdSample,
+            BleEvent::MacroResult { .. } => EventId::MacroResult,
+            BleEvent::CommandRejected { .. } => EventId::CommandRejected,
+            BleEvent::FilterExpired { .. } => EventId::FilterExpired,
+            BleEvent::DryRunFrame { .. } => EventId::DryRunFrame,
+            BleEvent::EventLog(_) => EventId::EventLog,
         }
+    }
 
-        let command_id = CommandId::try_from(buffer[0])?;
-
-        match command_id {
-            CommandId::UploadIsotpChunk => {
-                let command = UploadIsotpChunkCommand::parse(buffer)?;
-                Ok(ParsedBleMessage::UploadIsotpChunk(command))
+    /// Serialize as marker(1) + event_id(1) + payload
+    pub fn serialize(
+        &self,
+        buffer: &mut heapless::Vec<u8, NOTIFICATION_SIZE>,
+    ) -> Result<(), EventTooLarge> {
+        buffer.clear();
+        put(buffer, &[EVENT_MARKER])?;
+        put(buffer, &[self.event_id() as u8])?;
+
+        match self {
+            BleEvent::CanError { kind, error_count } => {
+                put(buffer, &[*kind as u8])?;
+                put(buffer, &error_count.to_be_bytes())?;
+            }
+            BleEvent::CanFrames(batch) => {
+                put(buffer, &[batch.count])?;
+                put(buffer, &batch.records)?;
+            }
+            BleEvent::FrameExpired { id, expired_count } => {
+                put(buffer, &id.to_be_bytes())?;
+                put(buffer, &expired_count.to_be_bytes())?;
+            }
+            BleEvent::CanStatistics(stats) => {
+                for counter in [
+                    stats.rx_total,
+                    stats.tx_total,
+                    stats.tx_attempt,
+                    stats.parse_error,
+                    stats.malformed,
+                    stats.errors,
+                    stats.resets,
+                    stats.expired,
+                ] {
+                    put(buffer, &counter.to_be_bytes())?;
+                }
+            }
+            BleEvent::FilterStatistics(filters) => {
+                // count(1) + (id(4) + matched(4)) per filter
+                put(buffer, &[filters.len() as u8])?;
+                for filter in filters {
+                    put(buffer, &filter.id.to_be_bytes())?;
+                    put(buffer, &filter.matched.to_be_bytes())?;
+                }
             }
-            CommandId::SendIsotpBuffer => {
-                let command = SendIsotpBufferCommand::parse(buffer)?;
-                Ok(ParsedBleMessage::SendIsotpBuffer(command))
+            BleEvent::QueueStatistics(stats) => {
+                for counter in [
+                    stats.rx_queue_capacity,
+                    stats.rx_queue_high_water,
+                    stats.rx_queue_dropped,
+                    stats.rx_latency_max_us,
+                    stats.rx_subscriber_lagged,
+                ] {
+                    put(buffer, &counter.to_be_bytes())?;
+                }
             }
-            CommandId::StartPeriodicIsotpMessage => {
-                let command = StartPeriodicIsotpMessageCommand::parse(buffer)?;
-                Ok(ParsedBleMessage::StartPeriodicIsotpMessage(command))
+            BleEvent::ChannelStatistics(channels) => {
+                put(buffer, &[channels.len() as u8])?;
+                for channel in channels {
+                    for counter in [channel.capacity, channel.high_water, channel.dropped] {
+                        put(buffer, &counter.to_be_bytes())?;
+                    }
+                }
             }
-            CommandId::StopPeriodicIsotpMessage => {
-                let command = StopPeriodicIsotpMessageCommand::parse(buffer)?;
-                Ok(ParsedBleMessage::StopPeriodicIsotpMessage(command))
+            BleEvent::StackStatistics(stacks) => {
+                put(buffer, &[stacks.len() as u8])?;
+                for stack in stacks {
+                    put(buffer, &stack.size.to_be_bytes())?;
+                    put(buffer, &stack.used.to_be_bytes())?;
+                }
             }
-            CommandId::ConfigureIsotpFilter => {
-                let command = ConfigureIsotpFilterCommand::parse(buffer)?;
-                Ok(ParsedBleMessage::ConfigureIsotpFilter(command))
+            BleEvent::NotifyLatency(latency) => {
+                for value in [latency.samples, latency.last_us, latency.max_us] {
+                    put(buffer, &value.to_be_bytes())?;
+                }
+            }
+            BleEvent::BridgeStatistics(stats) => {
+                for counter in [
+                    stats.pdus_sent,
+                    stats.pdus_received,
+                    stats.commands_rejected,
+                    stats.sniffer_events_dropped,
+                    stats.pdus_suppressed,
+                    stats.responses_dropped,
+                    stats.responses_evicted,
+                ] {
+                    put(buffer, &counter.to_be_bytes())?;
+                }
+                put(
+                    buffer,
+                    &[
+                        stats.active_handlers,
+                        stats.ephemeral_handlers,
+                        stats.small_buffers_in_use,
+                        stats.small_buffer_count,
+                        stats.large_buffers_in_use,
+                        stats.large_buffer_count,
+                    ],
+                )?;
+                // count(1) + (error(1) + count(4)) per error
+                put(buffer, &[stats.errors.len() as u8])?;
+                for error in &stats.errors {
+                    put(buffer, &[error.error])?;
+                    put(buffer, &error.count.to_be_bytes())?;
+                }
+            }
+            BleEvent::IsotpError {
+                request_arbitration_id,
+                reply_arbitration_id,
+                error,
+                correlation_tag,
+            } => {
+                put(buffer, &reply_arbitration_id.to_be_bytes())?;
+                put(buffer, &request_arbitration_id.to_be_bytes())?;
+                put(buffer, &[*error as u8])?;
+                if let Some(tag) = correlation_tag {
+                    put(buffer, &tag.to_be_bytes())?;
+                }
+            }
+            BleEvent::IsotpSequenceError {
+                request_arbitration_id,
+                reply_arbitration_id,
+                expected,
+                received,
+            } => {
+                put(buffer, &reply_arbitration_id.to_be_bytes())?;
+                put(buffer, &request_arbitration_id.to_be_bytes())?;
+                put(buffer, &[*expected])?;
+                put(buffer, &[*received])?;
+            }
+            BleEvent::FunctionalWindowClosed {
+                request_arbitration_id,
+                responses,
+            } => {
+                put(buffer, &request_arbitration_id.to_be_bytes())?;
+                put(buffer, &[*responses])?;
+            }
+            BleEvent::IsotpTransmissionAborted {
+                request_arbitration_id,
+                reply_arbitration_id,
+                bytes_sent,
+            } => {
+                put(buffer, &reply_arbitration_id.to_be_bytes())?;
+                put(buffer, &request_arbitration_id.to_be_bytes())?;
+                put(buffer, &bytes_sent.to_be_bytes())?;
+            }
+            BleEvent::IsotpFlowControl {
+                request_arbitration_id,
+                reply_arbitration_id,
+                flow_status,
+                block_size,
+                st_min,
+            } => {
+                put(buffer, &reply_arbitration_id.to_be_bytes())?;
+                put(buffer, &request_arbitration_id.to_be_bytes())?;
+                put(buffer, &[*flow_status, *block_size, *st_min])?;
+            }
+            BleEvent::IsotpProtocolError {
+                request_arbitration_id,
+                reply_arbitration_id,
+                error,
+                pci,
+            } => {
+                put(buffer, &reply_arbitration_id.to_be_bytes())?;
+                put(buffer, &request_arbitration_id.to_be_bytes())?;
+                put(buffer, &[*error as u8])?;
+                put(buffer, &[*pci])?;
+            }
+            BleEvent::IsotpTransmitProgress {
+                request_arbitration_id,
+                reply_arbitration_id,
+                bytes_sent,
+                total_length,
+            } => {
+                put(buffer, &reply_arbitration_id.to_be_bytes())?;
+                put(buffer, &request_arbitration_id.to_be_bytes())?;
+                put(buffer, &bytes_sent.to_be_bytes())?;
+                put(buffer, &total_length.to_be_bytes())?;
+            }
+            BleEvent::SelfTestReport(results) => {
+                // count(1) + (case(1) + passed(1)) per check
+                put(buffer, &[results.len() as u8])?;
+                for result in results {
+                    put(buffer, &[result.case, result.passed as u8])?;
+                }
+            }
+            BleEvent::IsotpSegment {
+                request_arbitration_id,
+                reply_arbitration_id,
+                direction,
+                offset,
+                total_length,
+                data,
+            } => {
+                // Same id header as a complete PDU
+                let leading_id = match direction {
+                    Direction::Response => *reply_arbitration_id,
+                    Direction::Request => {
+                        reply_arbitration_id | IsoTpMessage::DIRECTION_REQUEST_FLAG
+                    }
+                };
+                put(buffer, &leading_id.to_be_bytes())?;
+                put(buffer, &request_arbitration_id.to_be_bytes())?;
+                put(buffer, &offset.to_be_bytes())?;
+                put(buffer, &total_length.to_be_bytes())?;
+                put(buffer, data)?;
+            }
+            BleEvent::IsotpSendResult {
+                staging_id,
+                outcome,
+                code,
+            } => {
+                put(buffer, &staging_id.to_be_bytes())?;
+                put(buffer, &[*outcome as u8, *code])?;
+            }
+            BleEvent::SecuritySeed {
+                request_arbitration_id,
+                reply_arbitration_id,
+                sub_function,
+                seed,
+            } => {
+                put(buffer, &reply_arbitration_id.to_be_bytes())?;
+                put(buffer, &request_arbitration_id.to_be_bytes())?;
+                put(buffer, &[*sub_function])?;
+                put(buffer, seed)?;
+            }
+            BleEvent::SecurityAccessResult {
+                request_arbitration_id,
+                reply_arbitration_id,
+                sub_function,
+                response_code,
+            } => {
+                put(buffer, &reply_arbitration_id.to_be_bytes())?;
+                put(buffer, &request_arbitration_id.to_be_bytes())?;
+                put(buffer, &[*sub_function, *response_code])?;
+            }
+            BleEvent::ObdSample {
+                request_arbitration_id,
+                reply_arbitration_id,
+                mode,
+                pid,
+                timestamp_ms,
+                data,
+            } => {
+                put(buffer, &reply_arbitration_id.to_be_bytes())?;
+                put(buffer, &request_arbitration_id.to_be_bytes())?;
+                put(buffer, &[*mode, *pid])?;
+                put(buffer, &timestamp_ms.to_be_bytes())?;
+                put(buffer, data)?;
+            }
+            BleEvent::MacroResult {
+                macro_id,
+                request_arbitration_id,
+                reply_arbitration_id,
+                outcome,
+                code,
+                position,
+            } => {
+                put(buffer, &[*macro_id])?;
+                put(buffer, &reply_arbitration_id.to_be_bytes())?;
+                put(buffer, &request_arbitration_id.to_be_bytes())?;
+                put(buffer, &[*outcome as u8, *code])?;
+                put(buffer, &position.to_be_bytes())?;
+            }
+            BleEvent::EventLog(entries) => {
+                // count(1) + (timestamp_ms(4) + kind(1) + code(1) + argument(4)) per entry
+                put(buffer, &[entries.len() as u8])?;
+                for entry in entries {
+                    put(buffer, &entry.timestamp_ms.to_be_bytes())?;
+                    put(buffer, &[entry.kind as u8, entry.code])?;
+                    put(buffer, &entry.argument.to_be_bytes())?;
+                }
+            }
+            BleEvent::DryRunFrame { id, extended, data } => {
+                put(buffer, &id.to_be_bytes())?;
+                put(buffer, &[*extended as u8])?;
+                put(buffer, data)?;
+            }
+            BleEvent::FilterExpired {
+                filter_id,
+                request_arbitration_id,
+                reply_arbitration_id,
+            } => {
+                put(buffer, &reply_arbitration_id.to_be_bytes())?;
+                put(buffer, &request_arbitration_id.to_be_bytes())?;
+                put(buffer, &filter_id.to_be_bytes())?;
+            }
+            BleEvent::CommandRejected { command, reason } => {
+                put(buffer, &[*command, *reason as u8])?;
             }
         }
+        Ok(())
     }
 }
 
-/// Enum containing all possible parsed messages
-#[derive(Debug)]
-pub enum ParsedBleMessage {
-    UploadIsotpChunk(UploadIsotpChunkCommand),
-    SendIsotpBuffer(SendIsotpBufferCommand),
-    StartPeriodicIsotpMessage(StartPeriodicIsotpMessageCommand),
-    StopPeriodicIsotpMessage(StopPeriodicIsotpMessageCommand),
-    ConfigureIsotpFilter(ConfigureIsotpFilterCommand),
+/// Everything that can be notified to the BLE client
+#[derive(Debug, Format)]
+pub enum BleResponse {
+    IsoTp(IsoTpMessage),
+    Event(BleEvent),
+}
+
+impl BleResponse {
+    /// When the CAN frame that completed it was received, only known for PDUs
+    pub fn received_at(&self) -> Option<Instant> {
+        match self {
+            BleResponse::IsoTp(message) => message.received_at,
+            BleResponse::Event(_) => None,
+        }
+    }
 }
@@ -1,11 +1,23 @@
 use defmt::{debug, info, warn};
-use embassy_futures::{join::join, select::select};
+use embassy_futures::{
+    join::join3,
+    select::{select, select3, Either3},
+};
+use embassy_sync::channel::TrySendError;
+use embassy_time::{Instant, Timer};
+use portable_atomic::{AtomicU32, Ordering};
 use trouble_host::prelude::*;
 
 use crate::{
-    ble_protocol::{self, IsoTpMessage},
-    channels::BLE_RESPONSE_CHANNEL,
-    isotp_ble_bridge,
+    ble_protocol::{
+        self, BleEvent, BleResponse, Direction, EventId, IsoTpMessage, NotifyLatency, EVENT_MARKER,
+        RESPONSE_BATCH_SIZE,
+    },
+    channels::{BLE_PRIORITY_CHANNEL, BLE_RESPONSE_CHANNEL, SNIFFER_CHANNEL},
+    config::{self, BackpressurePolicy, DisconnectPolicy},
+    event_bus::{self, BusEvent},
+    isotp_ble_bridge, isotp_handler, session_store,
+    supervisor::{self, MonitoredTask},
 };
 
 /// Device name
@@ -68,10 +80,11 @@ where
     }))
     .unwrap();
 
-    let _ = join(ble_task(runner), async {
+    let _ = join3(ble_task(runner), heartbeat_task(), async {
         loop {
             match advertise(DEVICE_NAME, &mut peripheral).await {
                 Ok(conn) => {
+                    event_bus::publish(BusEvent::Connected);
                     let a = incoming_gatt_events_task(&server, &conn);
                     let b = outgoing_gatt_events_task(&server, &conn);
                     select(a, b).await;
@@ -87,6 +100,14 @@ where
     .await;
 }
 
+// Polled alongside the stack, so it only checks in while nothing in here hangs
+async fn heartbeat_task() {
+    loop {
+        supervisor::heartbeat(MonitoredTask::Ble);
+        Timer::after(supervisor::HEARTBEAT_INTERVAL).await;
+    }
+}
+
 /// This is a background task that is required to run forever alongside any other BLE tasks.
 async fn ble_task<C: Controller>(mut runner: Runner<'_, C>) {
     loop {
@@ -120,27 +141,39 @@ async fn outgoing_gatt_events_task(
     server: &Server<'_>,
     conn: &Connection<'_>,
 ) -> Result<(), Error> {
-    loop {
-        // Receive structured message from the channel
-        let message = BLE_RESPONSE_CHANNEL.receive().await;
-
-        debug!("[ble] outgoing_gatt_events_task message: {:?}", message);
-
-        // Serialize the message into a single buffer
-        let mut response_data = heapless::Vec::<u8, 512>::new();
+    // A message that didn't fit the last batch, sent next
+    let mut pending: Option<(heapless::Vec<u8, 512>, Option<Instant>)> = None;
 
-        // Write reply_arbitration_id (4 bytes)
-        response_data
-            .extend_from_slice(&message.reply_arbitration_id.to_be_bytes())
-            .unwrap();
+    loop {
+        let (mut response_data, mut received_at) = match pending.take() {
+            Some(pending) => pending,
+            None => {
+                // Receive structured message from the channels, the earlier ones go first:
+                // events can't wait behind PDUs, and a capture can't hold up a diagnostic
+                // session
+                let response = match select3(
+                    BLE_PRIORITY_CHANNEL.receive(),
+                    BLE_RESPONSE_CHANNEL.receive(),
+                    SNIFFER_CHANNEL.receive(),
+                )
+                .await
+                {
+                    Either3::First(event) => BleResponse::Event(event),
+                    Either3::Second(response) => response,
+                    Either3::Third(capture) => BleResponse::Event(capture),
+                };
 
-        // Write request_arbitration_id (4 bytes)
-        response_data
-            .extend_from_slice(&message.request_arbitration_id.to_be_bytes())
-            .unwrap();
+                debug!("[ble] outgoing_gatt_events_task message: {:?}", response);
+                match serialize_response(&response) {
+                    Some(response_data) => (response_data, response.received_at()),
+                    None => continue,
+                }
+            }
+        };
 
-        // Write the actual data
-        response_data.extend_from_slice(&message.pdu).unwrap();
+        if config::get().coalesce_responses {
+            response_data = coalesce(response_data, &mut received_at, &mut pending);
+        }
 
         debug!(
             "[ble] outgoing_gatt_events_task response_data: {:02x}",
@@ -148,7 +181,113 @@ async fn outgoing_gatt_events_task(
         );
 
         update_response_characteristic(server, conn, &response_data).await;
+        if let Some(received_at) = received_at {
+            record_notify_latency(received_at);
+        }
+    }
+}
+
+// Serialize the message into a single buffer, None if it doesn't fit one notification
+fn serialize_response(response: &BleResponse) -> Option<heapless::Vec<u8, 512>> {
+    let mut response_data = heapless::Vec::<u8, 512>::new();
+
+    match response {
+        BleResponse::IsoTp(message) => {
+            // Write reply_arbitration_id (4 bytes), the top bits tag observed requests
+            // and responses carrying a correlation tag
+            let mut leading_id = match message.direction {
+                Direction::Response => message.reply_arbitration_id,
+                Direction::Request => {
+                    message.reply_arbitration_id | IsoTpMessage::DIRECTION_REQUEST_FLAG
+                }
+            };
+            if message.correlation_tag.is_some() {
+                leading_id |= IsoTpMessage::CORRELATION_TAG_FLAG;
+            }
+            response_data
+                .extend_from_slice(&leading_id.to_be_bytes())
+                .unwrap();
+
+            // Write request_arbitration_id (4 bytes)
+            response_data
+                .extend_from_slice(&message.request_arbitration_id.to_be_bytes())
+                .unwrap();
+
+            // Write the correlation tag (2 bytes) if there is one
+            if let Some(tag) = message.correlation_tag {
+                response_data.extend_from_slice(&tag.to_be_bytes()).unwrap();
+            }
+
+            // Write the actual data, handlers only hand over PDUs that fit
+            if response_data
+                .extend_from_slice(message.pdu.as_slice())
+                .is_err()
+            {
+                warn!(
+                    "[ble] dropping {} byte PDU, too long to notify",
+                    message.pdu.len()
+                );
+                return None;
+            }
+        }
+        BleResponse::Event(event) => {
+            if event.serialize(&mut response_data).is_err() {
+                warn!("[ble] dropping {:?}, too long to notify", event.event_id());
+                return None;
+            }
+        }
+    }
+
+    Some(response_data)
+}
+
+// Messages already queued behind this one mean the link is busy, pack the small ones
+// into a batch so they share a notification. The first one that doesn't fit is left in
+// `pending`, `received_at` ends up with the earliest PDU in the batch.
+fn coalesce(
+    response_data: heapless::Vec<u8, 512>,
+    received_at: &mut Option<Instant>,
+    pending: &mut Option<(heapless::Vec<u8, 512>, Option<Instant>)>,
+) -> heapless::Vec<u8, 512> {
+    if response_data.len() + 3 > RESPONSE_BATCH_SIZE {
+        return response_data;
+    }
+
+    let mut batch = heapless::Vec::<u8, 512>::new();
+    batch
+        .extend_from_slice(&[EVENT_MARKER, EventId::ResponseBatch as u8])
+        .unwrap();
+    let mut batched = 0;
+    let mut next = Some((response_data, *received_at));
+    while let Some((response_data, next_received_at)) = next.take() {
+        if batch.len() + 1 + response_data.len() > RESPONSE_BATCH_SIZE {
+            *pending = Some((response_data, next_received_at));
+            break;
+        }
+        batch.push(response_data.len() as u8).unwrap();
+        batch.extend_from_slice(&response_data).unwrap();
+        batched += 1;
+        *received_at = match (*received_at, next_received_at) {
+            (Some(earliest), Some(at)) => Some(earliest.min(at)),
+            (earliest, at) => earliest.or(at),
+        };
+
+        next = BLE_PRIORITY_CHANNEL
+            .try_receive()
+            .map(BleResponse::Event)
+            .or_else(|| BLE_RESPONSE_CHANNEL.try_receive())
+            .and_then(|response| {
+                serialize_response(&response).map(|data| (data, response.received_at()))
+            });
     }
+
+    // Nothing small was waiting, send it as it is
+    if batched == 1 {
+        let len = batch.len();
+        batch.copy_within(3.., 0);
+        batch.truncate(len - 3);
+    }
+    batch
 }
 
 /// Stream Events until the connection closes.
@@ -167,9 +306,23 @@ async fn incoming_gatt_events_task(
         match conn.next().await {
             ConnectionEvent::Disconnected { reason } => {
                 info!("[gatt] disconnected: {:?}", reason);
+                event_bus::publish(BusEvent::Disconnected);
+
+                match config::get().disconnect_policy {
+                    // restart on disconnect
+                    DisconnectPolicy::Reset => cortex_m::peripheral::SCB::sys_reset(),
+                    DisconnectPolicy::EndSession => {
+                        isotp_ble_bridge::end_session().await;
+                        // Nobody left to deliver these to
+                        BLE_PRIORITY_CHANNEL.clear();
+                        BLE_RESPONSE_CHANNEL.clear();
+                        SNIFFER_CHANNEL.clear();
+                    }
+                    DisconnectPolicy::KeepSession => (),
+                }
 
-                // restart on disconnect
-                cortex_m::peripheral::SCB::sys_reset();
+                // back to advertising for the next client
+                return Ok(());
             }
             ConnectionEvent::Gatt { data: gatt_data } => {
                 // We can choose to handle event directly without an attribute table
@@ -196,18 +349,24 @@ async fn incoming_gatt_events_task(
                                 let event_handle = write_event.handle();
                                 let event_data = write_event.data();
                                 if event_handle == request_handle {
-                                    info!(
-                                        "[gatt] Write Event to Request Characteristic: {:02x}",
-                                        event_data
-                                    );
+                                    if !isotp_handler::flashing_mode() {
+                                        info!(
+                                            "[gatt] Write Event to Request Characteristic: {:02x}",
+                                            event_data
+                                        );
+                                    }
 
                                     match ble_protocol::BleMessageParser::parse(event_data) {
                                         Ok(parsed) => {
+                                            if parsed.is_session_setup() {
+                                                session_store::record(event_data);
+                                            }
                                             isotp_ble_bridge::handle_ble_message(parsed).await;
                                         }
                                         Err(e) => {
                                             warn!("[gatt] Parse error: {:?}", e);
-                                            // TODO: Send error response
+                                            // Reported like the commands the bridge rejects
+                                            isotp_ble_bridge::reject_unparsed(event_data, e);
                                         }
                                     }
                                 } else if event_handle == response_cccd_handle {
@@ -273,8 +432,94 @@ async fn advertise<'a, C: Controller>(
     Ok(conn)
 }
 
+static RESPONSES_DROPPED: AtomicU32 = AtomicU32::new(0);
+static RESPONSES_EVICTED: AtomicU32 = AtomicU32::new(0);
+
+// Queue a PDU or data event, a full queue is handled by the configured policy
+async fn queue_response(response: BleResponse) {
+    match config::get().response_backpressure {
+        BackpressurePolicy::Block => BLE_RESPONSE_CHANNEL.send(response).await,
+        BackpressurePolicy::DropNewest => {
+            if BLE_RESPONSE_CHANNEL.try_send(response).is_err() {
+                RESPONSES_DROPPED.fetch_add(1, Ordering::Relaxed);
+                warn!("[ble] response channel full, dropping response");
+            }
+        }
+        BackpressurePolicy::DropOldest => {
+            let mut response = response;
+            while let Err(TrySendError::Full(rejected)) = BLE_RESPONSE_CHANNEL.try_send(response) {
+                if BLE_RESPONSE_CHANNEL.try_receive().is_some() {
+                    RESPONSES_EVICTED.fetch_add(1, Ordering::Relaxed);
+                    warn!("[ble] response channel full, dropping oldest response");
+                }
+                response = rejected;
+            }
+        }
+    }
+}
+
+static NOTIFY_LATENCY_SAMPLES: AtomicU32 = AtomicU32::new(0);
+static NOTIFY_LATENCY_LAST_US: AtomicU32 = AtomicU32::new(0);
+static NOTIFY_LATENCY_MAX_US: AtomicU32 = AtomicU32::new(0);
+
+fn record_notify_latency(received_at: Instant) {
+    let latency_us = received_at.elapsed().as_micros().min(u32::MAX as u64) as u32;
+    NOTIFY_LATENCY_SAMPLES.fetch_add(1, Ordering::Relaxed);
+    NOTIFY_LATENCY_LAST_US.store(latency_us, Ordering::Relaxed);
+    NOTIFY_LATENCY_MAX_US.fetch_max(latency_us, Ordering::Relaxed);
+}
+
+/// CAN interrupt to notification latency of received PDUs since boot
+pub fn notify_latency() -> NotifyLatency {
+    NotifyLatency {
+        samples: NOTIFY_LATENCY_SAMPLES.load(Ordering::Relaxed),
+        last_us: NOTIFY_LATENCY_LAST_US.load(Ordering::Relaxed),
+        max_us: NOTIFY_LATENCY_MAX_US.load(Ordering::Relaxed),
+    }
+}
+
+/// Responses lost to a full queue since boot, (dropped newest, dropped oldest)
+pub fn responses_dropped() -> (u32, u32) {
+    (
+        RESPONSES_DROPPED.load(Ordering::Relaxed),
+        RESPONSES_EVICTED.load(Ordering::Relaxed),
+    )
+}
+
 // Helper function to send responses to BLE client
 pub async fn send_isotp_response(message: IsoTpMessage) {
-    // Ignore send errors - the receiver might be gone
-    let _ = BLE_RESPONSE_CHANNEL.send(message).await;
+    queue_response(BleResponse::IsoTp(message)).await;
+}
+
+/// Whether another ISO-TP response would have to wait for room in the queue
+pub fn response_queue_full() -> bool {
+    BLE_RESPONSE_CHANNEL.is_full()
+}
+
+// Events carrying PDU data are queued like complete PDUs instead of being dropped outright
+pub async fn send_data_event(event: BleEvent) {
+    queue_response(BleResponse::Event(event)).await;
+}
+
+static SNIFFER_EVENTS_DROPPED: AtomicU32 = AtomicU32::new(0);
+
+/// Queue captured traffic, dropped rather than waited for when the client can't keep up
+pub fn send_sniffer_event(event: BleEvent) {
+    if SNIFFER_CHANNEL.try_send(event).is_err() {
+        SNIFFER_EVENTS_DROPPED.fetch_add(1, Ordering::Relaxed);
+        warn!("[ble] sniffer channel full, dropping capture");
+    }
+}
+
+/// Captures lost to a full sniffer channel since boot
+pub fn sniffer_events_dropped() -> u32 {
+    SNIFFER_EVENTS_DROPPED.load(Ordering::Relaxed)
+}
+
+// Helper function to push events to BLE client without blocking the caller, they skip
+// ahead of queued PDUs
+pub fn send_event(event: BleEvent) {
+    if BLE_PRIORITY_CHANNEL.try_send(event).is_err() {
+        warn!("[ble] priority channel full, dropping event");
+    }
 }
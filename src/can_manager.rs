@@ -1,31 +1,118 @@
-use defmt::{debug, error, info, Format};
+use core::cell::RefCell;
+use defmt::{debug, error, info, warn, Format};
+use embassy_futures::select::{select, Either};
 use embassy_rp::interrupt;
+
 use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
 use embassy_sync::channel::Channel;
+use embassy_sync::pubsub::{PubSubChannel, Subscriber, WaitResult};
 use embassy_sync::signal::Signal;
-use embassy_time::{Duration, Timer};
-use portable_atomic::{AtomicPtr, Ordering};
+use embassy_time::{with_timeout, Duration, Instant, Timer};
+use portable_atomic::{AtomicBool, AtomicPtr, AtomicU32, AtomicU8, Ordering};
+
+use crate::ble_protocol::{
+    BleEvent, CanErrorKind, CanStatistics, FilterStatistic, QueueStatistics, SnifferBatch,
+};
+use crate::channels::{CAN_CHANNEL, CAN_PRIORITY_CHANNEL};
+use crate::event_bus::{self, BusEvent};
+use crate::supervisor::{self, MonitoredTask};
+use crate::{ble_server, config, isotp_ble_bridge, isotp_handler, transceiver};
+
+// can2040 packs the frame flags into the top bits of the id word
+const CAN2040_ID_RTR: u32 = 1 << 30;
+const CAN2040_ID_EFF: u32 = 1 << 31;
+const STANDARD_ID_MASK: u32 = 0x7FF;
+const EXTENDED_ID_MASK: u32 = 0x1FFF_FFFF;
+
+/// Largest data field the CAN backend carries. can2040 is classic CAN only, a CAN FD
+/// controller would raise this to 64 and the ISO-TP layer switches to FD framing.
+pub const MAX_FRAME_LEN: usize = 8;
 
-use crate::{channels::CAN_CHANNEL, isotp_ble_bridge};
+/// Ids that don't fit in 11 bits have to go out as extended frames
+pub fn is_extended_id(id: u32) -> bool {
+    id > STANDARD_ID_MASK
+}
 
-#[derive(Debug, Format)]
+#[derive(Debug, Clone, Format)]
 pub struct CanMessage {
+    // Arbitration id without any flag bits
     pub id: u32,
-    pub data: heapless::Vec<u8, 8>,
+    // 29-bit identifier frame
+    pub extended: bool,
+    pub data: heapless::Vec<u8, MAX_FRAME_LEN>,
+    // Don't let the controller retransmit this frame on error or lost arbitration
+    pub one_shot: bool,
+    // Drop the frame instead of sending it once this has passed
+    pub deadline: Option<Instant>,
+    // When a received frame reached the interrupt, None for frames we send
+    pub received_at: Option<Instant>,
+}
+
+/// Bus participation mode
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum CanMode {
+    Normal = 0x00,
+    // Receive only, frames queued for transmission are dropped
+    ListenOnly = 0x01,
+    // Controller stopped and transceiver in standby
+    Standby = 0x02,
+    // Frames queued for transmission come back through loopback_receive instead of going
+    // on the bus
+    Loopback = 0x03,
+    // Receive only like ListenOnly, frames queued for transmission are reported to the
+    // client instead, so an app can be tried against a live car without sending anything
+    DryRun = 0x04,
+}
+
+impl TryFrom<u8> for CanMode {
+    type Error = ();
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0x00 => Ok(CanMode::Normal),
+            0x01 => Ok(CanMode::ListenOnly),
+            0x02 => Ok(CanMode::Standby),
+            0x03 => Ok(CanMode::Loopback),
+            0x04 => Ok(CanMode::DryRun),
+            _ => Err(()),
+        }
+    }
 }
 
-#[derive(Debug, Format)]
+#[derive(Debug, Clone, Format)]
 struct RawCanMessage {
     id: u32,
+    extended: bool,
+    remote: bool,
     dlc: u32,
     data: [u8; 8],
+    timestamp_us: u64,
+    // Echo of a frame we transmitted ourselves
+    self_sent: bool,
 }
 
 static CAN_INSTANCE: AtomicPtr<can2040_rs::Can2040> = AtomicPtr::new(core::ptr::null_mut());
 
+// PIO block used by can2040, PIO2 unless the can-pio1 feature moves it to PIO1
+#[cfg(not(feature = "can-pio1"))]
+const PIO_NUM: u32 = 2;
+#[cfg(not(feature = "can-pio1"))]
+type CanPioInterrupt = interrupt::typelevel::PIO2_IRQ_0;
+#[cfg(not(feature = "can-pio1"))]
+const CAN_PIO_IRQ: interrupt::Interrupt = interrupt::PIO2_IRQ_0;
+
+#[cfg(feature = "can-pio1")]
+const PIO_NUM: u32 = 1;
+#[cfg(feature = "can-pio1")]
+type CanPioInterrupt = interrupt::typelevel::PIO1_IRQ_0;
+#[cfg(feature = "can-pio1")]
+const CAN_PIO_IRQ: interrupt::Interrupt = interrupt::PIO1_IRQ_0;
+
 pub struct CanInterruptHandler;
 
-impl interrupt::typelevel::Handler<interrupt::typelevel::PIO2_IRQ_0> for CanInterruptHandler {
+impl interrupt::typelevel::Handler<CanPioInterrupt> for CanInterruptHandler {
     unsafe fn on_interrupt() {
         let can_ptr = CAN_INSTANCE.load(Ordering::Acquire);
         if !can_ptr.is_null() {
@@ -39,13 +126,108 @@ const RING_BUFFER_SIZE: usize = 32;
 static RAW_CAN_RX_QUEUE: Channel<CriticalSectionRawMutex, RawCanMessage, RING_BUFFER_SIZE> =
     Channel::new();
 
-const MAX_FILTERS: usize = 8;
+// Deepest the rx queue has been, frames lost to a full queue, and the worst
+// time a frame waited between the interrupt and the rx processor
+static RX_QUEUE_HIGH_WATER: AtomicU32 = AtomicU32::new(0);
+static RX_QUEUE_DROPPED: AtomicU32 = AtomicU32::new(0);
+static RX_LATENCY_MAX_US: AtomicU32 = AtomicU32::new(0);
+
+// Accepted frames go to every consumer, each reads at its own pace and one that falls
+// behind only loses its own frames. The ISO-TP bridge and the sniffer stream subscribe,
+// the third subscription is left for a logger.
+const RX_FRAME_BUFFER: usize = 16;
+const RX_FRAME_SUBSCRIBERS: usize = 3;
+static CAN_RX_FRAMES: PubSubChannel<
+    CriticalSectionRawMutex,
+    RawCanMessage,
+    RX_FRAME_BUFFER,
+    RX_FRAME_SUBSCRIBERS,
+    1,
+> = PubSubChannel::new();
+type RxFrameSubscriber = Subscriber<
+    'static,
+    CriticalSectionRawMutex,
+    RawCanMessage,
+    RX_FRAME_BUFFER,
+    RX_FRAME_SUBSCRIBERS,
+    1,
+>;
+// Frames consumers missed because they fell behind
+static RX_SUBSCRIBER_LAGGED: AtomicU32 = AtomicU32::new(0);
+
+// A reply id range per ISO-TP handler
+pub const MAX_FILTERS: usize = isotp_ble_bridge::MAX_HANDLERS;
 static mut FILTER_IDS: [u32; MAX_FILTERS] = [0; MAX_FILTERS];
+// Each filter matches FILTER_IDS[i]..=FILTER_LAST_IDS[i]
+static mut FILTER_LAST_IDS: [u32; MAX_FILTERS] = [0; MAX_FILTERS];
+// Id format of each filter, the handler's setting rather than what the ids would fit in
+static mut FILTER_EXTENDED: [bool; MAX_FILTERS] = [false; MAX_FILTERS];
 static mut FILTER_COUNT: u8 = 0;
+// Frames matched by each registered filter, indexed like FILTER_IDS
+static FILTER_MATCH_COUNTS: [AtomicU32; MAX_FILTERS] = [const { AtomicU32::new(0) }; MAX_FILTERS];
+
+// Per-id decimation of chatty broadcast frames
+const MAX_RATE_LIMITS: usize = 8;
+
+struct RateLimit {
+    id: u32,
+    // Forward one frame out of every `divisor`
+    divisor: u16,
+    counter: u16,
+}
+
+static RATE_LIMITS: Mutex<
+    CriticalSectionRawMutex,
+    RefCell<heapless::Vec<RateLimit, MAX_RATE_LIMITS>>,
+> = Mutex::new(RefCell::new(heapless::Vec::new()));
 
 // Add this near the other static declarations
 static RESET_REQUESTED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
 
+// Total error notifications since boot
+static ERROR_COUNT: AtomicU32 = AtomicU32::new(0);
+
+// Frames dropped because they sat in the tx queue past their deadline
+static EXPIRED_FRAME_COUNT: AtomicU32 = AtomicU32::new(0);
+
+// Frames the controller handed us with an out-of-range dlc
+static MALFORMED_FRAME_COUNT: AtomicU32 = AtomicU32::new(0);
+
+static CAN_MODE: AtomicU8 = AtomicU8::new(CanMode::Normal as u8);
+
+// Frames "transmitted" in loopback mode
+static LOOPBACK_CHANNEL: Channel<CriticalSectionRawMutex, CanMessage, 8> = Channel::new();
+
+// Forward every received frame to BLE, unfiltered
+static SNIFFER_ENABLED: AtomicBool = AtomicBool::new(false);
+// Loop transmitted frames back into the sniffer stream
+static TX_ECHO_ENABLED: AtomicBool = AtomicBool::new(false);
+
+// Frames handed to can2040 that haven't been confirmed as transmitted yet
+static TX_PENDING: AtomicU32 = AtomicU32::new(0);
+static TX_CONFIRMED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+// Last pending frame confirmed, kept apart from TX_CONFIRMED so one-shot handling
+// never misses its confirmation
+static TX_DRAINED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+// Any pending frame confirmed, for bursts waiting for room
+static TX_SLOT_FREED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+// Frames dropped by the tx task don't signal TX_DRAINED, so drain waits re-check this often
+const TX_DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+// can2040 always retransmits, so one-shot frames are emulated by restarting the
+// controller when the frame isn't confirmed in time
+const ONE_SHOT_IDLE_TIMEOUT: Duration = Duration::from_millis(50);
+// Longest frame on the wire: 29 bit id, eight data bytes, worst-case bit stuffing and the
+// interframe space
+const MAX_FRAME_BITS: u64 = 160;
+// Callback and executor latency on top of the time on the bus
+const ONE_SHOT_CONFIRM_MARGIN_US: u64 = 500;
+// A frame already on the bus has to finish before ours can go out, so two frame times
+const ONE_SHOT_CONFIRM_TIMEOUT: Duration = Duration::from_micros(
+    2 * MAX_FRAME_BITS * 1_000_000 / BITRATE as u64 + ONE_SHOT_CONFIRM_MARGIN_US,
+);
+
 // Simplified callback that only queues messages
 extern "C" fn can_callback(
     _cd: *mut can2040_rs::can2040,
@@ -62,18 +244,68 @@ extern "C" fn can_callback(
         let frame_data = unsafe { msg.__bindgen_anon_1.data };
 
         // Queue raw message without any processing
+        let (id, extended, remote) = split_id(msg.id);
         let raw_msg = RawCanMessage {
-            id: msg.id,
+            id,
+            extended,
+            remote,
             dlc: msg.dlc,
             data: frame_data,
+            timestamp_us: Instant::now().as_micros(),
+            self_sent: false,
         };
 
-        let _ = RAW_CAN_RX_QUEUE.try_send(raw_msg);
+        queue_raw_message(raw_msg);
     } else if notify & can2040_rs::notify::ERROR != 0 {
         RESET_REQUESTED.signal(());
     } else if notify & can2040_rs::notify::TX != 0 {
-        // TODO: do something?
+        let previous = TX_PENDING.fetch_update(Ordering::AcqRel, Ordering::Acquire, |pending| {
+            Some(pending.saturating_sub(1))
+        });
+        TX_CONFIRMED.signal(());
+        TX_SLOT_FREED.signal(());
+        if previous.is_ok_and(|pending| pending <= 1) {
+            TX_DRAINED.signal(());
+        }
+
+        // Safety: msg points at the transmitted frame when notification is TX
+        if TX_ECHO_ENABLED.load(Ordering::Relaxed) && !msg.is_null() {
+            let msg = unsafe { &*msg };
+            let (id, extended, remote) = split_id(msg.id);
+            let raw_msg = RawCanMessage {
+                id,
+                extended,
+                remote,
+                dlc: msg.dlc,
+                data: unsafe { msg.__bindgen_anon_1.data },
+                timestamp_us: Instant::now().as_micros(),
+                self_sent: true,
+            };
+
+            queue_raw_message(raw_msg);
+        }
+    }
+}
+
+// Called from the interrupt, keeps the queue watermarks up to date
+fn queue_raw_message(raw_msg: RawCanMessage) {
+    if RAW_CAN_RX_QUEUE.try_send(raw_msg).is_err() {
+        RX_QUEUE_DROPPED.fetch_add(1, Ordering::Relaxed);
+        return;
     }
+    RX_QUEUE_HIGH_WATER.fetch_max(RAW_CAN_RX_QUEUE.len() as u32, Ordering::Relaxed);
+}
+
+// Separate a can2040 id word into (id, extended, remote)
+fn split_id(raw_id: u32) -> (u32, bool, bool) {
+    let extended = raw_id & CAN2040_ID_EFF != 0;
+    let remote = raw_id & CAN2040_ID_RTR != 0;
+    let mask = if extended {
+        EXTENDED_ID_MASK
+    } else {
+        STANDARD_ID_MASK
+    };
+    (raw_id & mask, extended, remote)
 }
 
 #[embassy_executor::task]
@@ -81,16 +313,57 @@ pub async fn can_tx_channel_task() {
     info!("[can] CAN task started");
 
     loop {
-        // Wait for the next message
-        let can_message = CAN_CHANNEL.receive().await;
+        // Wait for the next message, flow control first
+        let can_message = match select(CAN_PRIORITY_CHANNEL.receive(), CAN_CHANNEL.receive()).await
+        {
+            Either::First(can_message) | Either::Second(can_message) => can_message,
+        };
 
-        info!(
-            "[can] sending CAN message to {:x} {:02x}",
-            can_message.id, can_message.data
-        );
+        if !isotp_handler::flashing_mode() {
+            info!(
+                "[can] sending CAN message to {:x} {:02x}",
+                can_message.id, can_message.data
+            );
+        }
 
-        if can_message.data.len() != 8 {
-            error!("[can] CAN message data is not 8 bytes");
+        if can_message
+            .deadline
+            .is_some_and(|deadline| Instant::now() > deadline)
+        {
+            let expired_count = EXPIRED_FRAME_COUNT.fetch_add(1, Ordering::Relaxed) + 1;
+            warn!(
+                "[can] dropping expired CAN message to {:x}, total {}",
+                can_message.id, expired_count
+            );
+            event_bus::publish(BusEvent::FrameExpired {
+                id: can_message.id,
+                expired_count,
+            });
+            continue;
+        }
+
+        if CAN_MODE.load(Ordering::Acquire) == CanMode::Loopback as u8 {
+            if LOOPBACK_CHANNEL.try_send(can_message).is_err() {
+                warn!("[can] loopback channel full, dropping CAN message");
+            }
+            continue;
+        }
+
+        if CAN_MODE.load(Ordering::Acquire) == CanMode::DryRun as u8 {
+            info!(
+                "[can] dry run, not sending CAN message to {:x}",
+                can_message.id
+            );
+            ble_server::send_event(BleEvent::DryRunFrame {
+                id: can_message.id,
+                extended: can_message.extended,
+                data: can_message.data,
+            });
+            continue;
+        }
+
+        if CAN_MODE.load(Ordering::Acquire) != CanMode::Normal as u8 {
+            warn!("[can] not in normal mode, dropping CAN message");
             continue;
         }
 
@@ -104,7 +377,11 @@ pub async fn can_tx_channel_task() {
 
         // build message
         let mut msg = can2040_rs::can2040_msg::default();
-        msg.id = can_message.id;
+        msg.id = if can_message.extended {
+            (can_message.id & EXTENDED_ID_MASK) | CAN2040_ID_EFF
+        } else {
+            can_message.id & STANDARD_ID_MASK
+        };
         msg.dlc = can_message.data.len() as u32;
         for (i, &byte) in can_message.data.iter().enumerate() {
             unsafe {
@@ -112,6 +389,12 @@ pub async fn can_tx_channel_task() {
             }
         }
 
+        // a one-shot frame must be alone in the tx queue so its confirmation is unambiguous
+        if can_message.one_shot && !wait_tx_idle().await {
+            error!("[can] CAN tx queue did not drain for one-shot frame");
+            continue;
+        }
+
         // check if we can transmit
         let tx_avail = unsafe { (*can_ptr).check_transmit() };
         if tx_avail <= 0 {
@@ -120,34 +403,175 @@ pub async fn can_tx_channel_task() {
         }
 
         // send
+        TX_CONFIRMED.reset();
         match unsafe { (*can_ptr).transmit(&mut msg) } {
-            Ok(_) => debug!("[can] CAN message sent successfully"),
-            Err(e) => error!("[can] Failed to send CAN message: {}", e),
+            Ok(_) => {
+                TX_PENDING.fetch_add(1, Ordering::AcqRel);
+                debug!("[can] CAN message sent successfully");
+            }
+            Err(e) => {
+                error!("[can] Failed to send CAN message: {}", e);
+                continue;
+            }
+        }
+
+        if can_message.one_shot
+            && with_timeout(ONE_SHOT_CONFIRM_TIMEOUT, TX_CONFIRMED.wait())
+                .await
+                .is_err()
+        {
+            warn!("[can] one-shot frame not confirmed, dropping retransmission");
+            restart_can();
         }
     }
 }
 
-// Replace the old send_message with an async version
-pub async fn send_message(id: u32, data: &[u8]) -> bool {
-    let mut vec = heapless::Vec::new();
-    match vec.extend_from_slice(data) {
-        Ok(_) => {
-            // Send message to CAN task
-            CAN_CHANNEL.send(CanMessage { id, data: vec }).await;
-            true
+async fn wait_tx_idle() -> bool {
+    with_timeout(ONE_SHOT_IDLE_TIMEOUT, async {
+        while TX_PENDING.load(Ordering::Acquire) != 0 {
+            TX_CONFIRMED.wait().await;
+        }
+    })
+    .await
+    .is_ok()
+}
+
+/// Wait until every queued frame has been confirmed on the bus, false on timeout.
+/// Bursts like ISO-TP consecutive frames use this so they never outrun the PIO
+/// transmit queue, whatever STmin the receiver asked for.
+pub async fn wait_tx_drained(timeout: Duration) -> bool {
+    with_timeout(timeout, async {
+        while !CAN_CHANNEL.is_empty()
+            || !CAN_PRIORITY_CHANNEL.is_empty()
+            || TX_PENDING.load(Ordering::Acquire) != 0
+        {
+            let _ = with_timeout(TX_DRAIN_POLL_INTERVAL, TX_DRAINED.wait()).await;
+        }
+    })
+    .await
+    .is_ok()
+}
+
+/// Wait until fewer than `frames` frames are queued or waiting for their confirmation,
+/// false on timeout. Lets a burst keep the bus busy without overrunning the PIO
+/// transmit queue.
+pub async fn wait_tx_below(frames: usize, timeout: Duration) -> bool {
+    with_timeout(timeout, async {
+        while CAN_CHANNEL.len()
+            + CAN_PRIORITY_CHANNEL.len()
+            + TX_PENDING.load(Ordering::Acquire) as usize
+            >= frames
+        {
+            let _ = with_timeout(TX_DRAIN_POLL_INTERVAL, TX_SLOT_FREED.wait()).await;
         }
-        Err(_) => {
-            error!("[can] Data too large for CAN message");
-            false
+    })
+    .await
+    .is_ok()
+}
+
+// Waking through the timer queue costs tens of microseconds, so the tail of a
+// pacing gap is spun out instead
+const PACING_SPIN_THRESHOLD: Duration = Duration::from_micros(50);
+
+/// Paces frames on an absolute schedule with microsecond resolution
+pub struct TxPacer {
+    last_sent: Option<Instant>,
+}
+
+impl TxPacer {
+    pub const fn new() -> Self {
+        Self { last_sent: None }
+    }
+
+    /// Wait until `gap` has passed since the previous frame, then mark a frame as sent
+    pub async fn wait(&mut self, gap: Duration) {
+        if let Some(last_sent) = self.last_sent {
+            pace_until(last_sent + gap).await;
         }
+        self.last_sent = Some(Instant::now());
+    }
+}
+
+pub async fn pace_until(deadline: Instant) {
+    let now = Instant::now();
+    if deadline > now + PACING_SPIN_THRESHOLD {
+        Timer::at(deadline - PACING_SPIN_THRESHOLD).await;
     }
+    while Instant::now() < deadline {}
+}
+
+// Replace the old send_message with an async version
+pub async fn send_message(id: u32, data: &[u8]) -> bool {
+    queue_message(id, is_extended_id(id), data, false, None).await
+}
+
+// Send a frame that is never retransmitted by the controller
+pub async fn send_one_shot_message(id: u32, data: &[u8]) -> bool {
+    queue_message(id, is_extended_id(id), data, true, None).await
+}
+
+// Send a frame with an explicit id format, low ids can still be 29-bit identifiers.
+// With a `ttl` the frame is dropped if it can't be transmitted in time.
+pub async fn send_message_with_format(
+    id: u32,
+    extended: bool,
+    data: &[u8],
+    ttl: Option<Duration>,
+) -> bool {
+    let deadline = ttl.map(|ttl| Instant::now() + ttl);
+    queue_message(id, extended, data, false, deadline).await
+}
+
+/// Send a flow control frame, transmitted ahead of any frames already queued
+pub async fn send_flow_control(id: u32, extended: bool, data: &[u8], ttl: Duration) -> bool {
+    let Ok(vec) = heapless::Vec::from_slice(data) else {
+        error!("[can] Data too large for CAN message");
+        return false;
+    };
+
+    CAN_PRIORITY_CHANNEL
+        .send(CanMessage {
+            id,
+            extended,
+            data: vec,
+            one_shot: false,
+            deadline: Some(Instant::now() + ttl),
+            received_at: None,
+        })
+        .await;
+    true
+}
+
+async fn queue_message(
+    id: u32,
+    extended: bool,
+    data: &[u8],
+    one_shot: bool,
+    deadline: Option<Instant>,
+) -> bool {
+    let Ok(vec) = heapless::Vec::from_slice(data) else {
+        error!("[can] Data too large for CAN message");
+        return false;
+    };
+
+    // Send message to CAN task
+    CAN_CHANNEL
+        .send(CanMessage {
+            id,
+            extended,
+            data: vec,
+            one_shot,
+            deadline,
+            received_at: None,
+        })
+        .await;
+    true
 }
 
 pub fn init_instance(can: *mut can2040_rs::Can2040) {
     CAN_INSTANCE.store(can, Ordering::Release);
 }
 
-#[allow(dead_code)]
 pub fn get_statistics() -> Option<can2040_rs::can2040_stats> {
     let can_ptr = CAN_INSTANCE.load(Ordering::Acquire);
     if !can_ptr.is_null() {
@@ -157,17 +581,54 @@ pub fn get_statistics() -> Option<can2040_rs::can2040_stats> {
     }
 }
 
-const PIO_NUM: u32 = 2;
+/// Snapshot of the controller and bridge-side CAN counters
+pub fn statistics() -> CanStatistics {
+    let stats = get_statistics().unwrap_or_default();
+    CanStatistics {
+        rx_total: stats.rx_total,
+        tx_total: stats.tx_total,
+        tx_attempt: stats.tx_attempt,
+        parse_error: stats.parse_error,
+        malformed: malformed_frame_count(),
+        errors: ERROR_COUNT.load(Ordering::Relaxed),
+        resets: reset_count(),
+        expired: EXPIRED_FRAME_COUNT.load(Ordering::Relaxed),
+    }
+}
+
+/// Rx queue watermarks and worst-case interrupt to processing latency
+pub fn queue_statistics() -> QueueStatistics {
+    QueueStatistics {
+        rx_queue_capacity: RING_BUFFER_SIZE as u32,
+        rx_queue_high_water: RX_QUEUE_HIGH_WATER.load(Ordering::Relaxed),
+        rx_queue_dropped: RX_QUEUE_DROPPED.load(Ordering::Relaxed),
+        rx_latency_max_us: RX_LATENCY_MAX_US.load(Ordering::Relaxed),
+        rx_subscriber_lagged: RX_SUBSCRIBER_LAGGED.load(Ordering::Relaxed),
+    }
+}
+
+/// Frames matched so far by each registered filter
+pub fn filter_statistics() -> heapless::Vec<FilterStatistic, MAX_FILTERS> {
+    critical_section::with(|_| {
+        // Safety: We're in a critical section
+        let filter_count = unsafe { FILTER_COUNT } as usize;
+        (0..filter_count)
+            .map(|i| FilterStatistic {
+                id: unsafe { FILTER_IDS[i] },
+                matched: FILTER_MATCH_COUNTS[i].load(Ordering::Relaxed),
+            })
+            .collect()
+    })
+}
+
 const BITRATE: u32 = 500_000;
-const GPIO_RX: u32 = 10;
-const GPIO_TX: u32 = 11;
 
 pub fn init_can() {
     use embassy_rp::interrupt::InterruptExt;
     use embassy_rp::interrupt::Priority;
 
-    unsafe { cortex_m::peripheral::NVIC::unmask(embassy_rp::interrupt::PIO2_IRQ_0) };
-    embassy_rp::interrupt::PIO2_IRQ_0.set_priority(Priority::P2);
+    unsafe { cortex_m::peripheral::NVIC::unmask(CAN_PIO_IRQ) };
+    CAN_PIO_IRQ.set_priority(Priority::P2);
 
     // Create CAN instance in static storage to ensure it lives for the program duration
     static mut CAN: Option<can2040_rs::Can2040> = None;
@@ -183,8 +644,13 @@ pub fn init_can() {
     let can_ptr = can as *mut _;
     init_instance(can_ptr);
 
-    let sys_clock = embassy_rp::clocks::clk_sys_freq(); // 150_000_000
-    can.start(sys_clock, BITRATE, GPIO_RX, GPIO_TX);
+    let config = config::get();
+    info!(
+        "[can] starting on rx gpio {} tx gpio {}",
+        config.can_gpio_rx, config.can_gpio_tx
+    );
+
+    start_can(can);
 }
 
 #[embassy_executor::task]
@@ -192,8 +658,13 @@ pub async fn can_stats_task() {
     loop {
         let stats = get_statistics().unwrap();
         info!(
-            "[can] stats: tx {:?}, tx_attempt {:?}, parse_error {:?}, rx {:?}",
-            stats.tx_total, stats.tx_attempt, stats.parse_error, stats.rx_total
+            "[can] stats: tx {:?}, tx_attempt {:?}, parse_error {:?}, rx {:?}, malformed {:?}, resets {:?}",
+            stats.tx_total,
+            stats.tx_attempt,
+            stats.parse_error,
+            stats.rx_total,
+            malformed_frame_count(),
+            reset_count()
         );
         Timer::after(Duration::from_millis(1000)).await;
     }
@@ -202,28 +673,102 @@ pub async fn can_stats_task() {
 // Add new task to process raw CAN messages
 #[embassy_executor::task]
 pub async fn can_rx_processor_task() {
+    let frames = CAN_RX_FRAMES.immediate_publisher();
+
     loop {
-        let raw_msg = RAW_CAN_RX_QUEUE.receive().await;
-
-        // Filter check
-        let filter_count = unsafe { FILTER_COUNT };
-        let mut found = false;
-        for i in 0..filter_count as usize {
-            if raw_msg.id == unsafe { FILTER_IDS[i] } {
-                found = true;
-                break;
+        supervisor::heartbeat(MonitoredTask::CanRx);
+        let Ok(mut raw_msg) =
+            with_timeout(supervisor::HEARTBEAT_INTERVAL, RAW_CAN_RX_QUEUE.receive()).await
+        else {
+            continue;
+        };
+        let latency_us = Instant::now().as_micros() - raw_msg.timestamp_us;
+        RX_LATENCY_MAX_US.fetch_max(latency_us as u32, Ordering::Relaxed);
+        sanitize_frame(&mut raw_msg);
+
+        frames.publish_immediate(raw_msg);
+    }
+}
+
+// Next published frame, counting the ones this subscriber missed
+async fn next_rx_frame(frames: &mut RxFrameSubscriber) -> RawCanMessage {
+    loop {
+        match frames.next_message().await {
+            WaitResult::Message(raw_msg) => return raw_msg,
+            WaitResult::Lagged(missed) => {
+                RX_SUBSCRIBER_LAGGED.fetch_add(missed as u32, Ordering::Relaxed);
+                warn!("[can] rx consumer fell behind, {} frames missed", missed);
+            }
+        }
+    }
+}
+
+/// Batches every received frame into sniffer notifications while the sniffer is enabled
+#[embassy_executor::task]
+pub async fn can_sniffer_task() {
+    let Ok(mut frames) = CAN_RX_FRAMES.subscriber() else {
+        error!("[can] no rx subscription left for the sniffer");
+        return;
+    };
+    let mut sniffer_batch = SnifferBatch::default();
+
+    loop {
+        let raw_msg = next_rx_frame(&mut frames).await;
+
+        // Rate limits only thin out the sniffer, ISO-TP sees every frame
+        let limited = !raw_msg.self_sent && !rate_limit_allows(raw_msg.id);
+        if SNIFFER_ENABLED.load(Ordering::Relaxed) && !limited {
+            forward_to_sniffer(&raw_msg, &mut sniffer_batch);
+
+            // Keep batching while frames are queued, flush once we've caught up
+            if frames.available() == 0 {
+                flush_sniffer_batch(&mut sniffer_batch);
             }
         }
+    }
+}
+
+/// Hands frames matching a registered filter to the ISO-TP bridge
+#[embassy_executor::task]
+pub async fn can_isotp_rx_task() {
+    let Ok(mut frames) = CAN_RX_FRAMES.subscriber() else {
+        error!("[can] no rx subscription left for ISO-TP");
+        return;
+    };
+
+    loop {
+        let raw_msg = next_rx_frame(&mut frames).await;
+
+        // Our own frames are only of interest to the sniffer, and remote frames carry no ISO-TP data
+        if raw_msg.self_sent || raw_msg.remote {
+            continue;
+        }
+
+        // Filter check, in a critical section since core0 shifts the table on unregister
+        let found = critical_section::with(|_| {
+            // Safety: We're in a critical section
+            let filter_count = unsafe { FILTER_COUNT } as usize;
+            let Some(i) = (0..filter_count).find(|&i| unsafe {
+                (FILTER_IDS[i]..=FILTER_LAST_IDS[i]).contains(&raw_msg.id)
+                    && raw_msg.extended == FILTER_EXTENDED[i]
+            }) else {
+                return false;
+            };
+            FILTER_MATCH_COUNTS[i].fetch_add(1, Ordering::Relaxed);
+            true
+        });
 
         if !found {
             continue;
         }
 
         // Logging
-        info!(
-            "[can] CAN message received id = {:x} dlc = {:x} data = {:02x}",
-            raw_msg.id, raw_msg.dlc, raw_msg.data
-        );
+        if !isotp_handler::flashing_mode() {
+            info!(
+                "[can] CAN message received id = {:x} dlc = {:x} data = {:02x}",
+                raw_msg.id, raw_msg.dlc, raw_msg.data
+            );
+        }
 
         // Process message
         let mut data = heapless::Vec::new();
@@ -233,45 +778,309 @@ pub async fn can_rx_processor_task() {
         {
             isotp_ble_bridge::handle_can_message(CanMessage {
                 id: raw_msg.id,
+                extended: raw_msg.extended,
                 data,
+                one_shot: false,
+                deadline: None,
+                received_at: Some(Instant::from_micros(raw_msg.timestamp_us)),
+            });
+        }
+    }
+}
+
+// Clamp a glitched dlc so it can always be used to slice the data
+fn sanitize_frame(raw_msg: &mut RawCanMessage) {
+    if raw_msg.dlc as usize > raw_msg.data.len() {
+        let count = MALFORMED_FRAME_COUNT.fetch_add(1, Ordering::Relaxed) + 1;
+        warn!(
+            "[can] malformed frame id = {:x} dlc = {}, total {}",
+            raw_msg.id, raw_msg.dlc, count
+        );
+        raw_msg.dlc = raw_msg.data.len() as u32;
+    }
+}
+
+pub fn malformed_frame_count() -> u32 {
+    MALFORMED_FRAME_COUNT.load(Ordering::Relaxed)
+}
+
+fn forward_to_sniffer(raw_msg: &RawCanMessage, batch: &mut SnifferBatch) {
+    let data = &raw_msg.data[..raw_msg.dlc as usize];
+    let timestamp_us = raw_msg.timestamp_us as u32;
+
+    let mut flags = 0;
+    if raw_msg.self_sent {
+        flags |= SnifferBatch::FLAG_SELF_SENT;
+    }
+    if raw_msg.extended {
+        flags |= SnifferBatch::FLAG_EXTENDED;
+    }
+    if raw_msg.remote {
+        flags |= SnifferBatch::FLAG_REMOTE;
+    }
+
+    if !batch.push(timestamp_us, raw_msg.id, flags, data) {
+        flush_sniffer_batch(batch);
+        batch.push(timestamp_us, raw_msg.id, flags, data);
+    }
+}
+
+fn flush_sniffer_batch(batch: &mut SnifferBatch) {
+    if !batch.is_empty() {
+        ble_server::send_sniffer_event(BleEvent::CanFrames(core::mem::take(batch)));
+    }
+}
+
+// Returns false for frames that should be dropped by their id's rate limit
+fn rate_limit_allows(id: u32) -> bool {
+    RATE_LIMITS.lock(|limits| {
+        let mut limits = limits.borrow_mut();
+        match limits.iter_mut().find(|limit| limit.id == id) {
+            Some(limit) => {
+                let allowed = limit.counter == 0;
+                limit.counter = (limit.counter + 1) % limit.divisor;
+                allowed
+            }
+            None => true,
+        }
+    })
+}
+
+// Forward only one in `divisor` frames with this id, a divisor of 0 or 1 removes the limit
+pub fn set_rate_limit(id: u32, divisor: u16) -> bool {
+    RATE_LIMITS.lock(|limits| {
+        let mut limits = limits.borrow_mut();
+        limits.retain(|limit| limit.id != id);
+        if divisor <= 1 {
+            return true;
+        }
+
+        limits
+            .push(RateLimit {
+                id,
+                divisor,
+                counter: 0,
             })
-            .await;
+            .is_ok()
+    })
+}
+
+pub fn set_mode(mode: CanMode) {
+    let previous = CAN_MODE.swap(mode as u8, Ordering::AcqRel);
+    if previous == mode as u8 {
+        return;
+    }
+    info!("[can] switching to {:?} mode", mode);
+
+    if mode == CanMode::Loopback {
+        LOOPBACK_CHANNEL.clear();
+    }
+
+    // Standby (e.g. TJA1042 STB) switches the receiver off too, so it's only for Standby.
+    // A transceiver with a silent pin (e.g. TJA1051 S) stops acking while listening.
+    transceiver::set_standby(mode == CanMode::Standby);
+    transceiver::set_silent(matches!(mode, CanMode::ListenOnly | CanMode::DryRun));
+
+    if mode == CanMode::Standby {
+        let can_ptr = CAN_INSTANCE.load(Ordering::Acquire);
+        if !can_ptr.is_null() {
+            unsafe { (*can_ptr).stop() };
         }
+    } else if previous == CanMode::Standby as u8 {
+        restart_can();
     }
 }
 
-// Simplified filter registration
-pub fn register_isotp_filter(response_id: u32) -> bool {
+pub fn mode() -> CanMode {
+    CanMode::try_from(CAN_MODE.load(Ordering::Acquire)).unwrap_or(CanMode::Normal)
+}
+
+/// Next frame queued for transmission while in loopback mode, None on timeout
+pub async fn loopback_receive(timeout: Duration) -> Option<CanMessage> {
+    with_timeout(timeout, LOOPBACK_CHANNEL.receive()).await.ok()
+}
+
+pub fn configure_sniffer(enabled: bool, tx_echo: bool) {
+    info!("[can] sniffer enabled {} tx echo {}", enabled, tx_echo);
+    SNIFFER_ENABLED.store(enabled, Ordering::Relaxed);
+    TX_ECHO_ENABLED.store(tx_echo, Ordering::Relaxed);
+}
+
+// Accept every id from first_id to last_id in frames of the given format, e.g. all OBD-II
+// responders 0x7E8..=0x7EF
+pub fn register_isotp_filter_range(first_id: u32, last_id: u32, extended: bool) -> bool {
     critical_section::with(|_| {
         // Safety: We're in a critical section
         unsafe {
-            if FILTER_COUNT as usize >= MAX_FILTERS - 1 {
+            if FILTER_COUNT as usize >= MAX_FILTERS {
                 return false;
             }
 
-            FILTER_IDS[FILTER_COUNT as usize] = response_id;
+            FILTER_IDS[FILTER_COUNT as usize] = first_id;
+            FILTER_LAST_IDS[FILTER_COUNT as usize] = last_id;
+            FILTER_EXTENDED[FILTER_COUNT as usize] = extended;
+            FILTER_MATCH_COUNTS[FILTER_COUNT as usize].store(0, Ordering::Relaxed);
             FILTER_COUNT += 1;
         }
         true
     })
 }
 
+// Drop a range registered with register_isotp_filter_range, false if it wasn't registered
+pub fn unregister_isotp_filter_range(first_id: u32, last_id: u32, extended: bool) -> bool {
+    critical_section::with(|_| {
+        // Safety: We're in a critical section
+        unsafe {
+            let filter_count = FILTER_COUNT as usize;
+            let Some(index) = (0..filter_count).find(|&i| {
+                FILTER_IDS[i] == first_id
+                    && FILTER_LAST_IDS[i] == last_id
+                    && FILTER_EXTENDED[i] == extended
+            }) else {
+                return false;
+            };
+
+            // Keep the registered filters contiguous
+            for i in index..filter_count - 1 {
+                FILTER_IDS[i] = FILTER_IDS[i + 1];
+                FILTER_LAST_IDS[i] = FILTER_LAST_IDS[i + 1];
+                FILTER_EXTENDED[i] = FILTER_EXTENDED[i + 1];
+                FILTER_MATCH_COUNTS[i].store(
+                    FILTER_MATCH_COUNTS[i + 1].load(Ordering::Relaxed),
+                    Ordering::Relaxed,
+                );
+            }
+            FILTER_COUNT -= 1;
+        }
+        true
+    })
+}
+
+// can2040 only signals a generic error (an rx fifo overflow), so infer more detail
+// from the statistics, which are cleared on every restart
+fn classify_error(stats: &can2040_rs::can2040_stats) -> CanErrorKind {
+    if stats.parse_error != 0 {
+        CanErrorKind::Bitstream
+    } else if stats.tx_attempt != stats.tx_total {
+        CanErrorKind::Transmit
+    } else {
+        CanErrorKind::RxOverflow
+    }
+}
+
+// Errors arriving this soon after a restart count towards a reset storm
+const RESET_STORM_INTERVAL: Duration = Duration::from_millis(100);
+// Rapid resets tolerated before backing off, and before tearing the instance down
+const RESET_BACKOFF_THRESHOLD: u32 = 3;
+const RESET_TEARDOWN_THRESHOLD: u32 = 8;
+const RESET_BACKOFF_MIN: Duration = Duration::from_millis(10);
+const RESET_BACKOFF_MAX: Duration = Duration::from_millis(5000);
+
+// Controller restarts since boot
+static RESET_COUNT: AtomicU32 = AtomicU32::new(0);
+
+pub fn reset_count() -> u32 {
+    RESET_COUNT.load(Ordering::Relaxed)
+}
+
 // Add new task to handle CAN reset requests
 #[embassy_executor::task]
 pub async fn can_reset_task() {
+    let mut last_restart: Option<Instant> = None;
+    let mut rapid_resets: u32 = 0;
+    let mut backoff = RESET_BACKOFF_MIN;
+
     loop {
         // Wait for reset signal
         RESET_REQUESTED.wait().await;
         error!("[can] Reset requested due to CAN error");
 
-        let can_ptr = CAN_INSTANCE.load(Ordering::Acquire);
-        if !can_ptr.is_null() {
-            unsafe { (*can_ptr).stop() };
+        if let Some(stats) = get_statistics() {
+            let kind = classify_error(&stats);
+            let error_count = ERROR_COUNT.fetch_add(1, Ordering::Relaxed) + 1;
+            event_bus::publish(BusEvent::CanError { kind, error_count });
+        }
+
+        let rapid = last_restart.is_some_and(|t| t.elapsed() < RESET_STORM_INTERVAL);
+        if rapid {
+            rapid_resets += 1;
+        } else {
+            rapid_resets = 0;
+            backoff = RESET_BACKOFF_MIN;
+        }
+
+        if rapid_resets >= RESET_BACKOFF_THRESHOLD {
+            event_bus::publish(BusEvent::CanBusOff {
+                rapid_resets,
+                backoff_ms: backoff.as_millis() as u32,
+            });
+            Timer::after(backoff).await;
+            backoff = (backoff * 2).min(RESET_BACKOFF_MAX);
+        }
 
-            unsafe { (*can_ptr).setup() };
-            unsafe { (*can_ptr).set_callback(Some(can_callback)) };
-            let sys_clock = embassy_rp::clocks::clk_sys_freq(); // 150_000_000
-            unsafe { (*can_ptr).start(sys_clock, BITRATE, GPIO_RX, GPIO_TX) };
+        if rapid_resets >= RESET_TEARDOWN_THRESHOLD {
+            error!("[can] restarts keep failing, tearing down can2040");
+            teardown_can();
+            rapid_resets = 0;
+        } else {
+            restart_can();
         }
+
+        // Anything signalled while backing off belongs to the previous session
+        RESET_REQUESTED.reset();
+        last_restart = Some(Instant::now());
+        let reset_count = RESET_COUNT.fetch_add(1, Ordering::Relaxed) + 1;
+        event_bus::publish(BusEvent::CanRestart { reset_count });
     }
 }
+
+// Stop and restart the controller, discarding anything still queued for transmission
+fn restart_can() {
+    let can_ptr = CAN_INSTANCE.load(Ordering::Acquire);
+    if can_ptr.is_null() {
+        return;
+    }
+
+    // The PIO interrupt mustn't run against a half set up instance
+    cortex_m::peripheral::NVIC::mask(CAN_PIO_IRQ);
+    unsafe {
+        (*can_ptr).stop();
+        (*can_ptr).setup();
+        (*can_ptr).set_callback(Some(can_callback));
+    }
+    TX_PENDING.store(0, Ordering::Release);
+    unsafe { cortex_m::peripheral::NVIC::unmask(CAN_PIO_IRQ) };
+
+    start_can(unsafe { &mut *can_ptr });
+}
+
+// Replace the instance with a freshly constructed one, reloading the PIO program from scratch
+fn teardown_can() {
+    let can_ptr = CAN_INSTANCE.load(Ordering::Acquire);
+    if can_ptr.is_null() {
+        return;
+    }
+
+    cortex_m::peripheral::NVIC::mask(CAN_PIO_IRQ);
+    unsafe {
+        (*can_ptr).stop();
+        *can_ptr = can2040_rs::Can2040::new(PIO_NUM);
+        (*can_ptr).setup();
+        (*can_ptr).set_callback(Some(can_callback));
+    }
+    TX_PENDING.store(0, Ordering::Release);
+    unsafe { cortex_m::peripheral::NVIC::unmask(CAN_PIO_IRQ) };
+
+    start_can(unsafe { &mut *can_ptr });
+}
+
+fn start_can(can: &mut can2040_rs::Can2040) {
+    let config = config::get();
+    let sys_clock = embassy_rp::clocks::clk_sys_freq(); // 150_000_000
+    can.start(
+        sys_clock,
+        BITRATE,
+        config.can_gpio_rx as u32,
+        config.can_gpio_tx as u32,
+    );
+}
@@ -1,19 +1,166 @@
 //! Inter-module communication channels
 //! This module centralizes all communication channels between different components
 
-use crate::ble_protocol::{IsoTpMessage, ParsedBleMessage};
+use crate::ble_protocol::{BleEvent, BleResponse, ChannelStatistic, ParsedBleMessage};
 use crate::can_manager::CanMessage;
-use embassy_sync::blocking_mutex::raw::{CriticalSectionRawMutex, ThreadModeRawMutex};
-use embassy_sync::channel::Channel;
+use embassy_sync::blocking_mutex::raw::{CriticalSectionRawMutex, RawMutex};
+use embassy_sync::channel::{Channel, TrySendError};
+use portable_atomic::{AtomicU32, Ordering};
+
+// Queue depths, the deep-queues feature doubles them for busy buses at the cost of RAM
+#[cfg(not(feature = "deep-queues"))]
+const DEPTH_SCALE: usize = 1;
+#[cfg(feature = "deep-queues")]
+const DEPTH_SCALE: usize = 2;
+
+// The deterministic feature keeps the queues between a received PDU and its notification
+// shallow, a PDU never waits behind more than a few others
+#[cfg(all(
+    feature = "deterministic",
+    any(feature = "deep-queues", feature = "flashing")
+))]
+compile_error!("the deterministic feature can't be combined with deep-queues or flashing");
+#[cfg(not(feature = "deterministic"))]
+const NOTIFY_DEPTH_DIVISOR: usize = 1;
+#[cfg(feature = "deterministic")]
+const NOTIFY_DEPTH_DIVISOR: usize = 4;
+
+// The flashing feature makes room for the upload chunks a reflash streams in while the
+// previous block is still going out
+#[cfg(not(feature = "flashing"))]
+const COMMAND_DEPTH_SCALE: usize = 1;
+#[cfg(feature = "flashing")]
+const COMMAND_DEPTH_SCALE: usize = 4;
+
+pub const BLE_RESPONSE_DEPTH: usize = 16 * DEPTH_SCALE / NOTIFY_DEPTH_DIVISOR;
+pub const SNIFFER_DEPTH: usize = 8 * DEPTH_SCALE / NOTIFY_DEPTH_DIVISOR;
+pub const CAN_DEPTH: usize = 16 * DEPTH_SCALE;
+pub const ISOTP_BLE_DEPTH: usize = 16 * DEPTH_SCALE * COMMAND_DEPTH_SCALE;
+pub const BLE_PRIORITY_DEPTH: usize = 8 * DEPTH_SCALE;
+pub const CAN_PRIORITY_DEPTH: usize = 4 * DEPTH_SCALE;
+
+pub const CHANNEL_COUNT: usize = 6;
+
+/// A channel that keeps track of how full it got and how many messages it turned away
+pub struct MonitoredChannel<M: RawMutex, T, const N: usize> {
+    channel: Channel<M, T, N>,
+    high_water: AtomicU32,
+    dropped: AtomicU32,
+}
+
+impl<M: RawMutex, T, const N: usize> MonitoredChannel<M, T, N> {
+    pub const fn new() -> Self {
+        Self {
+            channel: Channel::new(),
+            high_water: AtomicU32::new(0),
+            dropped: AtomicU32::new(0),
+        }
+    }
+
+    /// Send, waiting for room
+    pub async fn send(&self, message: T) {
+        self.channel.send(message).await;
+        self.record_depth();
+    }
+
+    /// Send if there's room, a full channel counts the message as dropped
+    pub fn try_send(&self, message: T) -> Result<(), TrySendError<T>> {
+        let result = self.channel.try_send(message);
+        match result {
+            Ok(()) => self.record_depth(),
+            Err(_) => {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        result
+    }
+
+    pub async fn receive(&self) -> T {
+        self.channel.receive().await
+    }
+
+    pub fn try_receive(&self) -> Option<T> {
+        self.channel.try_receive().ok()
+    }
+
+    pub fn len(&self) -> usize {
+        self.channel.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.channel.is_empty()
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.channel.is_full()
+    }
+
+    pub fn clear(&self) {
+        self.channel.clear();
+    }
+
+    pub fn statistic(&self) -> ChannelStatistic {
+        ChannelStatistic {
+            capacity: N as u32,
+            high_water: self.high_water.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+        }
+    }
+
+    fn record_depth(&self) {
+        self.high_water
+            .fetch_max(self.channel.len() as u32, Ordering::Relaxed);
+    }
+}
 
 /// Channel for BLE responses (ISOTP -> BLE)
-pub static BLE_RESPONSE_CHANNEL: Channel<ThreadModeRawMutex, IsoTpMessage, 16> = Channel::new();
+pub static BLE_RESPONSE_CHANNEL: MonitoredChannel<
+    CriticalSectionRawMutex,
+    BleResponse,
+    BLE_RESPONSE_DEPTH,
+> = MonitoredChannel::new();
+
+/// Channel for sniffer captures (CAN -> BLE), kept apart so a busy bus can't crowd out
+/// diagnostic responses
+pub static SNIFFER_CHANNEL: MonitoredChannel<CriticalSectionRawMutex, BleEvent, SNIFFER_DEPTH> =
+    MonitoredChannel::new();
 
 /// Channel for CAN messages (CAN Hardware -> ISOTP)
-pub static CAN_CHANNEL: Channel<CriticalSectionRawMutex, CanMessage, 16> = Channel::new();
+pub static CAN_CHANNEL: MonitoredChannel<CriticalSectionRawMutex, CanMessage, CAN_DEPTH> =
+    MonitoredChannel::new();
+
+/// Priority lane for events (ISOTP -> BLE), always notified before anything waiting in
+/// BLE_RESPONSE_CHANNEL so results and errors never queue behind a long transfer
+pub static BLE_PRIORITY_CHANNEL: MonitoredChannel<
+    CriticalSectionRawMutex,
+    BleEvent,
+    BLE_PRIORITY_DEPTH,
+> = MonitoredChannel::new();
+
+/// Priority lane for flow control frames (ISOTP -> CAN), always transmitted before
+/// anything waiting in CAN_CHANNEL so the peer's N_Bs timer isn't spent behind our own
+/// consecutive frames
+pub static CAN_PRIORITY_CHANNEL: MonitoredChannel<
+    CriticalSectionRawMutex,
+    CanMessage,
+    CAN_PRIORITY_DEPTH,
+> = MonitoredChannel::new();
 
 /// Channel for BLE commands (BLE -> ISOTP)
-pub static ISOTP_BLE_CHANNEL: Channel<ThreadModeRawMutex, ParsedBleMessage, 16> = Channel::new();
+pub static ISOTP_BLE_CHANNEL: MonitoredChannel<
+    CriticalSectionRawMutex,
+    ParsedBleMessage,
+    ISOTP_BLE_DEPTH,
+> = MonitoredChannel::new();
 
-/// Channel for CAN messages to be processed by ISOTP (CAN -> ISOTP)
-pub static ISOTP_CAN_CHANNEL: Channel<ThreadModeRawMutex, CanMessage, 16> = Channel::new();
+/// Depth statistics of every channel, in the order they're declared above
+pub fn statistics() -> [ChannelStatistic; CHANNEL_COUNT] {
+    [
+        BLE_RESPONSE_CHANNEL.statistic(),
+        SNIFFER_CHANNEL.statistic(),
+        CAN_CHANNEL.statistic(),
+        BLE_PRIORITY_CHANNEL.statistic(),
+        CAN_PRIORITY_CHANNEL.statistic(),
+        ISOTP_BLE_CHANNEL.statistic(),
+    ]
+}
@@ -0,0 +1,569 @@
+//! Persistent device configuration
+//! Board-specific settings live in the last flash sector so the same binary can run on
+//! different carrier boards without being rebuilt
+
+use core::cell::RefCell;
+
+use defmt::{error, info, warn, Format};
+use embassy_rp::flash::{Blocking, Flash, ERASE_SIZE};
+use embassy_rp::peripherals::FLASH;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::channel::Channel;
+use embassy_sync::mutex::Mutex as AsyncMutex;
+use embassy_sync::signal::Signal;
+use embassy_time::Duration;
+use heapless::Vec;
+
+/// Must match the FLASH length in memory.x plus the reserved config, session and
+/// watchdog sectors
+pub const FLASH_SIZE: usize = 2 * 1024 * 1024;
+
+/// The config lives in the last sector, which memory.x keeps out of the program image
+const CONFIG_OFFSET: u32 = (FLASH_SIZE - ERASE_SIZE) as u32;
+const CONFIG_MAGIC: u32 = 0x4953_5450; // "ISTP"
+const CONFIG_VERSION: u8 = 1;
+// Unused bytes stay erased (0xFF), so fields added later read back as their defaults
+const CONFIG_SIZE: usize = 64;
+
+// Default CAN transceiver wiring
+const DEFAULT_CAN_GPIO_RX: u8 = 10;
+const DEFAULT_CAN_GPIO_TX: u8 = 11;
+// A shallow response queue has to shed stale responses rather than stall the handlers
+#[cfg(not(feature = "deterministic"))]
+const DEFAULT_RESPONSE_BACKPRESSURE: BackpressurePolicy = BackpressurePolicy::Block;
+#[cfg(feature = "deterministic")]
+const DEFAULT_RESPONSE_BACKPRESSURE: BackpressurePolicy = BackpressurePolicy::DropOldest;
+
+// Log output goes to USB when built for developers without the UART harness
+#[cfg(not(feature = "usb-log"))]
+const DEFAULT_LOG_TARGET: LogTarget = LogTarget::Uart;
+#[cfg(feature = "usb-log")]
+const DEFAULT_LOG_TARGET: LogTarget = LogTarget::Usb;
+
+// Short enough to tell frames apart, long enough to see
+const DEFAULT_LED_ACTIVITY_PULSE_MS: u8 = 20;
+
+// RP2350A exposes GPIO0..=GPIO29
+const MAX_GPIO: u32 = 29;
+// UART1 log output on 4 and 5, then cyw43 power, data, chip select and clock. Taking any
+// of them over would keep BLE from coming up, and with it the only way to fix the config.
+const RESERVED_GPIOS: [u8; 6] = [4, 5, 23, 24, 25, 29];
+// Marks an optional pin as not connected
+const NO_GPIO: u8 = 0xFF;
+
+/// Error type for configuration updates
+#[derive(Debug, Format)]
+pub enum ConfigError {
+    InvalidKey,
+    InvalidValue,
+    NotInitialized,
+    FlashError,
+}
+
+/// Keys accepted by the SetDeviceConfig command
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum ConfigKey {
+    CanGpioRx = 0x01,
+    CanGpioTx = 0x02,
+    CanStandbyGpio = 0x03,
+    CanStandbyActiveLow = 0x04,
+    CanTerminationGpio = 0x05,
+    CanTerminationEnabled = 0x06,
+    DisconnectPolicy = 0x07,
+    FilterIdleTimeout = 0x08,
+    ResponseBackpressure = 0x09,
+    CoalesceResponses = 0x0A,
+    LedMode = 0x0B,
+    LedActivityPulse = 0x0C,
+    LedActivityGpio = 0x0D,
+    LedStatusGpio = 0x0E,
+    LogTarget = 0x0F,
+    CanSilentGpio = 0x10,
+}
+
+impl TryFrom<u8> for ConfigKey {
+    type Error = ConfigError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0x01 => Ok(ConfigKey::CanGpioRx),
+            0x02 => Ok(ConfigKey::CanGpioTx),
+            0x03 => Ok(ConfigKey::CanStandbyGpio),
+            0x04 => Ok(ConfigKey::CanStandbyActiveLow),
+            0x05 => Ok(ConfigKey::CanTerminationGpio),
+            0x06 => Ok(ConfigKey::CanTerminationEnabled),
+            0x07 => Ok(ConfigKey::DisconnectPolicy),
+            0x08 => Ok(ConfigKey::FilterIdleTimeout),
+            0x09 => Ok(ConfigKey::ResponseBackpressure),
+            0x0A => Ok(ConfigKey::CoalesceResponses),
+            0x0B => Ok(ConfigKey::LedMode),
+            0x0C => Ok(ConfigKey::LedActivityPulse),
+            0x0D => Ok(ConfigKey::LedActivityGpio),
+            0x0E => Ok(ConfigKey::LedStatusGpio),
+            0x0F => Ok(ConfigKey::LogTarget),
+            0x10 => Ok(ConfigKey::CanSilentGpio),
+            _ => Err(ConfigError::InvalidKey),
+        }
+    }
+}
+
+/// What happens to the ISO-TP session when the BLE client goes away
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum DisconnectPolicy {
+    // Reboot the bridge, nothing survives
+    Reset = 0x00,
+    // Drop handlers, their keepalives and staged uploads, then wait for the next client
+    EndSession = 0x01,
+    // Leave everything running for a client that reconnects
+    KeepSession = 0x02,
+}
+
+impl TryFrom<u32> for DisconnectPolicy {
+    type Error = ConfigError;
+
+    fn try_from(value: u32) -> Result<Self, Self::Error> {
+        match value {
+            0x00 => Ok(DisconnectPolicy::Reset),
+            0x01 => Ok(DisconnectPolicy::EndSession),
+            0x02 => Ok(DisconnectPolicy::KeepSession),
+            _ => Err(ConfigError::InvalidValue),
+        }
+    }
+}
+
+/// What happens to a PDU or data event when the BLE response queue is full
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum BackpressurePolicy {
+    // Wait for room, the handler stops receiving until the client catches up
+    Block = 0x00,
+    // Drop the message that doesn't fit
+    DropNewest = 0x01,
+    // Drop the oldest queued message to make room
+    DropOldest = 0x02,
+}
+
+impl TryFrom<u32> for BackpressurePolicy {
+    type Error = ConfigError;
+
+    fn try_from(value: u32) -> Result<Self, Self::Error> {
+        match value {
+            0x00 => Ok(BackpressurePolicy::Block),
+            0x01 => Ok(BackpressurePolicy::DropNewest),
+            0x02 => Ok(BackpressurePolicy::DropOldest),
+            _ => Err(ConfigError::InvalidValue),
+        }
+    }
+}
+
+/// What the LEDs show
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum LedMode {
+    // Dark, for installs that shouldn't draw attention
+    Off = 0x00,
+    // Status patterns with activity pulses on top
+    Full = 0x01,
+    // Status patterns only
+    StatusOnly = 0x02,
+    // Dark except for activity pulses
+    ActivityOnly = 0x03,
+}
+
+impl LedMode {
+    pub fn shows_status(self) -> bool {
+        matches!(self, LedMode::Full | LedMode::StatusOnly)
+    }
+
+    pub fn shows_activity(self) -> bool {
+        matches!(self, LedMode::Full | LedMode::ActivityOnly)
+    }
+}
+
+impl TryFrom<u32> for LedMode {
+    type Error = ConfigError;
+
+    fn try_from(value: u32) -> Result<Self, Self::Error> {
+        match value {
+            0x00 => Ok(LedMode::Off),
+            0x01 => Ok(LedMode::Full),
+            0x02 => Ok(LedMode::StatusOnly),
+            0x03 => Ok(LedMode::ActivityOnly),
+            _ => Err(ConfigError::InvalidValue),
+        }
+    }
+}
+
+/// Where defmt log output goes
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum LogTarget {
+    // UART1 on GPIO4 (tx) and GPIO5 (rx)
+    Uart = 0x00,
+    // USB CDC-ACM serial port
+    Usb = 0x01,
+}
+
+impl TryFrom<u32> for LogTarget {
+    type Error = ConfigError;
+
+    fn try_from(value: u32) -> Result<Self, Self::Error> {
+        match value {
+            0x00 => Ok(LogTarget::Uart),
+            0x01 => Ok(LogTarget::Usb),
+            _ => Err(ConfigError::InvalidValue),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Format)]
+pub struct DeviceConfig {
+    pub can_gpio_rx: u8,
+    pub can_gpio_tx: u8,
+    // Transceiver standby pin (e.g. TJA1042 STB), the receiver is off while it's asserted
+    pub can_standby_gpio: Option<u8>,
+    // Pin is an enable rather than a standby input
+    pub can_standby_active_low: bool,
+    // Relay/FET switching a 120 ohm bus termination
+    pub can_termination_gpio: Option<u8>,
+    // Termination state applied at boot
+    pub can_termination_enabled: bool,
+    pub disconnect_policy: DisconnectPolicy,
+    // Seconds without traffic or sends before a filter is removed, 0 keeps filters forever
+    pub filter_idle_timeout_s: u16,
+    pub response_backpressure: BackpressurePolicy,
+    // Pack small responses queued behind each other into one notification
+    pub coalesce_responses: bool,
+    pub led_mode: LedMode,
+    // Milliseconds an activity pulse lasts, traffic in between is shown by the next one
+    pub led_activity_pulse_ms: u8,
+    // External LED taking over the activity pulses from the onboard one, claimed at boot
+    pub led_activity_gpio: Option<u8>,
+    // External LED showing the same as the onboard one, for enclosures that hide the
+    // Pico, claimed at boot
+    pub led_status_gpio: Option<u8>,
+    pub log_target: LogTarget,
+    // Transceiver silent pin (e.g. TJA1051 S), high keeps it off the bus while listening
+    pub can_silent_gpio: Option<u8>,
+}
+
+impl DeviceConfig {
+    pub const fn new() -> Self {
+        Self {
+            can_gpio_rx: DEFAULT_CAN_GPIO_RX,
+            can_gpio_tx: DEFAULT_CAN_GPIO_TX,
+            can_standby_gpio: None,
+            can_standby_active_low: false,
+            can_termination_gpio: None,
+            can_termination_enabled: false,
+            disconnect_policy: DisconnectPolicy::Reset,
+            filter_idle_timeout_s: 0,
+            response_backpressure: DEFAULT_RESPONSE_BACKPRESSURE,
+            coalesce_responses: false,
+            led_mode: LedMode::Full,
+            led_activity_pulse_ms: DEFAULT_LED_ACTIVITY_PULSE_MS,
+            led_activity_gpio: None,
+            led_status_gpio: None,
+            log_target: DEFAULT_LOG_TARGET,
+            can_silent_gpio: None,
+        }
+    }
+
+    /// How long a filter may go unused before its slot is reclaimed, None if never
+    pub fn filter_idle_timeout(&self) -> Option<Duration> {
+        match self.filter_idle_timeout_s {
+            0 => None,
+            seconds => Some(Duration::from_secs(seconds as u64)),
+        }
+    }
+
+    /// How long an activity pulse lasts
+    pub fn led_activity_pulse(&self) -> Duration {
+        Duration::from_millis(self.led_activity_pulse_ms as u64)
+    }
+
+    fn serialize(&self) -> [u8; CONFIG_SIZE] {
+        let mut buffer = [0xFF; CONFIG_SIZE];
+        buffer[0..4].copy_from_slice(&CONFIG_MAGIC.to_be_bytes());
+        buffer[4] = CONFIG_VERSION;
+        buffer[5] = self.can_gpio_rx;
+        buffer[6] = self.can_gpio_tx;
+        buffer[7] = self.can_standby_gpio.unwrap_or(NO_GPIO);
+        buffer[8] = self.can_standby_active_low as u8;
+        buffer[9] = self.can_termination_gpio.unwrap_or(NO_GPIO);
+        buffer[10] = self.can_termination_enabled as u8;
+        buffer[11] = self.disconnect_policy as u8;
+        buffer[12..14].copy_from_slice(&self.filter_idle_timeout_s.to_be_bytes());
+        buffer[14] = self.response_backpressure as u8;
+        buffer[15] = self.coalesce_responses as u8;
+        buffer[16] = self.led_mode as u8;
+        buffer[17] = self.led_activity_pulse_ms;
+        buffer[18] = self.led_activity_gpio.unwrap_or(NO_GPIO);
+        buffer[19] = self.led_status_gpio.unwrap_or(NO_GPIO);
+        buffer[20] = self.log_target as u8;
+        buffer[21] = self.can_silent_gpio.unwrap_or(NO_GPIO);
+        buffer
+    }
+
+    fn deserialize(buffer: &[u8; CONFIG_SIZE]) -> Option<Self> {
+        let magic = u32::from_be_bytes([buffer[0], buffer[1], buffer[2], buffer[3]]);
+        if magic != CONFIG_MAGIC || buffer[4] != CONFIG_VERSION {
+            return None;
+        }
+
+        let mut config = Self::new();
+        config.can_gpio_rx = buffer[5];
+        config.can_gpio_tx = buffer[6];
+        config.can_standby_gpio = Self::stored_gpio(buffer[7]);
+        config.can_standby_active_low = buffer[8] == 1;
+        config.can_termination_gpio = Self::stored_gpio(buffer[9]);
+        config.can_termination_enabled = buffer[10] == 1;
+        if let Ok(policy) = DisconnectPolicy::try_from(buffer[11] as u32) {
+            config.disconnect_policy = policy;
+        }
+        // Erased bytes predate the setting
+        let filter_idle_timeout_s = u16::from_be_bytes([buffer[12], buffer[13]]);
+        if filter_idle_timeout_s != u16::MAX {
+            config.filter_idle_timeout_s = filter_idle_timeout_s;
+        }
+        if let Ok(policy) = BackpressurePolicy::try_from(buffer[14] as u32) {
+            config.response_backpressure = policy;
+        }
+        config.coalesce_responses = buffer[15] == 1;
+        if let Ok(mode) = LedMode::try_from(buffer[16] as u32) {
+            config.led_mode = mode;
+        }
+        if buffer[17] != 0 && buffer[17] != u8::MAX {
+            config.led_activity_pulse_ms = buffer[17];
+        }
+        config.led_activity_gpio = Self::stored_gpio(buffer[18]);
+        config.led_status_gpio = Self::stored_gpio(buffer[19]);
+        if let Ok(target) = LogTarget::try_from(buffer[20] as u32) {
+            config.log_target = target;
+        }
+        config.can_silent_gpio = Self::stored_gpio(buffer[21]);
+        if Self::stored_gpio(config.can_gpio_rx).is_none()
+            || Self::stored_gpio(config.can_gpio_tx).is_none()
+            || !config.pins_are_unique()
+        {
+            warn!("[config] stored pins are invalid, using the default ones");
+            config.reset_pins();
+        }
+        Some(config)
+    }
+
+    // Every pin the bridge claims at boot
+    fn pins(&self) -> [Option<u8>; 7] {
+        [
+            Some(self.can_gpio_rx),
+            Some(self.can_gpio_tx),
+            self.can_standby_gpio,
+            self.can_silent_gpio,
+            self.can_termination_gpio,
+            self.led_activity_gpio,
+            self.led_status_gpio,
+        ]
+    }
+
+    fn pins_are_unique(&self) -> bool {
+        let pins = self.pins();
+        pins.iter()
+            .enumerate()
+            .all(|(i, pin)| pin.is_none() || !pins[i + 1..].contains(pin))
+    }
+
+    fn reset_pins(&mut self) {
+        let defaults = Self::new();
+        self.can_gpio_rx = defaults.can_gpio_rx;
+        self.can_gpio_tx = defaults.can_gpio_tx;
+        self.can_standby_gpio = defaults.can_standby_gpio;
+        self.can_silent_gpio = defaults.can_silent_gpio;
+        self.can_termination_gpio = defaults.can_termination_gpio;
+        self.led_activity_gpio = defaults.led_activity_gpio;
+        self.led_status_gpio = defaults.led_status_gpio;
+    }
+
+    fn apply(&mut self, key: ConfigKey, value: u32) -> Result<(), ConfigError> {
+        match key {
+            ConfigKey::CanGpioRx => self.can_gpio_rx = Self::gpio(value)?,
+            ConfigKey::CanGpioTx => self.can_gpio_tx = Self::gpio(value)?,
+            ConfigKey::CanStandbyGpio => self.can_standby_gpio = Self::optional_gpio(value)?,
+            ConfigKey::CanStandbyActiveLow => self.can_standby_active_low = value != 0,
+            ConfigKey::CanTerminationGpio => {
+                self.can_termination_gpio = Self::optional_gpio(value)?
+            }
+            ConfigKey::CanTerminationEnabled => self.can_termination_enabled = value != 0,
+            ConfigKey::DisconnectPolicy => {
+                self.disconnect_policy = DisconnectPolicy::try_from(value)?
+            }
+            ConfigKey::FilterIdleTimeout => {
+                if value >= u16::MAX as u32 {
+                    return Err(ConfigError::InvalidValue);
+                }
+                self.filter_idle_timeout_s = value as u16
+            }
+            ConfigKey::ResponseBackpressure => {
+                self.response_backpressure = BackpressurePolicy::try_from(value)?
+            }
+            ConfigKey::CoalesceResponses => self.coalesce_responses = value != 0,
+            ConfigKey::LedMode => self.led_mode = LedMode::try_from(value)?,
+            ConfigKey::LedActivityPulse => {
+                if value == 0 || value >= u8::MAX as u32 {
+                    return Err(ConfigError::InvalidValue);
+                }
+                self.led_activity_pulse_ms = value as u8
+            }
+            ConfigKey::LedActivityGpio => self.led_activity_gpio = Self::optional_gpio(value)?,
+            ConfigKey::LedStatusGpio => self.led_status_gpio = Self::optional_gpio(value)?,
+            ConfigKey::LogTarget => self.log_target = LogTarget::try_from(value)?,
+            ConfigKey::CanSilentGpio => self.can_silent_gpio = Self::optional_gpio(value)?,
+        }
+        // A pin can only do one thing, the caller's copy with the clash is dropped
+        if !self.pins_are_unique() {
+            return Err(ConfigError::InvalidValue);
+        }
+        Ok(())
+    }
+
+    fn stored_gpio(value: u8) -> Option<u8> {
+        Self::gpio(value as u32).ok()
+    }
+
+    // NO_GPIO disconnects an optional pin
+    fn optional_gpio(value: u32) -> Result<Option<u8>, ConfigError> {
+        if value == NO_GPIO as u32 {
+            return Ok(None);
+        }
+        Self::gpio(value).map(Some)
+    }
+
+    fn gpio(value: u32) -> Result<u8, ConfigError> {
+        if value > MAX_GPIO || RESERVED_GPIOS.contains(&(value as u8)) {
+            return Err(ConfigError::InvalidValue);
+        }
+        Ok(value as u8)
+    }
+}
+
+pub type ConfigFlash = Flash<'static, FLASH, Blocking, FLASH_SIZE>;
+
+static CONFIG_FLASH: Mutex<CriticalSectionRawMutex, RefCell<Option<ConfigFlash>>> =
+    Mutex::new(RefCell::new(None));
+static CONFIG: Mutex<CriticalSectionRawMutex, RefCell<DeviceConfig>> =
+    Mutex::new(RefCell::new(DeviceConfig::new()));
+
+/// Load the configuration from flash, falling back to defaults when the sector is blank
+pub fn init(flash: FLASH) {
+    let mut flash = ConfigFlash::new_blocking(flash);
+
+    let mut buffer = [0u8; CONFIG_SIZE];
+    let loaded = match flash.blocking_read(CONFIG_OFFSET, &mut buffer) {
+        Ok(_) => DeviceConfig::deserialize(&buffer),
+        Err(e) => {
+            error!("[config] failed to read config: {:?}", e);
+            None
+        }
+    };
+
+    let config = match loaded {
+        Some(config) => {
+            info!("[config] loaded config: {:?}", config);
+            config
+        }
+        None => {
+            info!("[config] no stored config, using defaults");
+            DeviceConfig::new()
+        }
+    };
+
+    CONFIG.lock(|c| *c.borrow_mut() = config);
+    CONFIG_FLASH.lock(|f| *f.borrow_mut() = Some(flash));
+}
+
+/// Get a copy of the current configuration
+pub fn get() -> DeviceConfig {
+    CONFIG.lock(|c| *c.borrow())
+}
+
+/// Update a single configuration value and persist the result to flash
+pub async fn set_value(key: ConfigKey, value: u32) -> Result<(), ConfigError> {
+    let mut config = get();
+    config.apply(key, value)?;
+    write_sector(CONFIG_OFFSET, &config.serialize()).await?;
+    CONFIG.lock(|c| *c.borrow_mut() = config);
+    info!("[config] {:?} set to {}", key, value);
+    Ok(())
+}
+
+/// Run `f` on the flash driver, for other data kept in reserved sectors. Erasing and
+/// writing only works on core0, from core1 use write_sector.
+pub fn with_flash<R>(
+    f: impl FnOnce(&mut ConfigFlash) -> Result<R, ConfigError>,
+) -> Result<R, ConfigError> {
+    CONFIG_FLASH.lock(|flash| {
+        let mut flash = flash.borrow_mut();
+        f(flash.as_mut().ok_or(ConfigError::NotInitialized)?)
+    })
+}
+
+// Sector to erase and rewrite, for the flash writer task
+struct SectorWrite {
+    offset: u32,
+    // Written to the start of the sector once it's erased, nothing leaves it erased
+    data: Vec<u8, ERASE_SIZE>,
+}
+
+static SECTOR_WRITES: Channel<CriticalSectionRawMutex, SectorWrite, 1> = Channel::new();
+static SECTOR_WRITTEN: Signal<CriticalSectionRawMutex, Result<(), ConfigError>> = Signal::new();
+// One write at a time, so every caller gets its own result
+static SECTOR_WRITER: AsyncMutex<CriticalSectionRawMutex, ()> = AsyncMutex::new(());
+
+/// Erase the sector at `offset` and write `data` to its start. embassy-rp only erases and
+/// writes flash from core0 while the bridge runs on core1, so flash_writer_task does it.
+pub async fn write_sector(offset: u32, data: &[u8]) -> Result<(), ConfigError> {
+    let mut sector = Vec::new();
+    // More than a sector
+    sector
+        .extend_from_slice(data)
+        .map_err(|_| ConfigError::InvalidValue)?;
+
+    let _writer = SECTOR_WRITER.lock().await;
+    SECTOR_WRITES
+        .send(SectorWrite {
+            offset,
+            data: sector,
+        })
+        .await;
+    SECTOR_WRITTEN.wait().await
+}
+
+/// Does the sector writes asked for with write_sector, has to run on core0
+#[embassy_executor::task]
+pub async fn flash_writer_task() {
+    loop {
+        let write = SECTOR_WRITES.receive().await;
+        SECTOR_WRITTEN.signal(rewrite_sector(&write));
+    }
+}
+
+// The driver is taken out of its mutex for the write. embassy-rp pauses core1 first, which
+// never happens while core1 waits for the critical section the mutex would hold.
+fn rewrite_sector(write: &SectorWrite) -> Result<(), ConfigError> {
+    let mut flash = CONFIG_FLASH
+        .lock(|flash| flash.borrow_mut().take())
+        .ok_or(ConfigError::NotInitialized)?;
+
+    let result = flash
+        .blocking_erase(write.offset, write.offset + ERASE_SIZE as u32)
+        .and_then(|_| match write.data.is_empty() {
+            true => Ok(()),
+            false => flash.blocking_write(write.offset, &write.data),
+        })
+        .map_err(|_| ConfigError::FlashError);
+
+    CONFIG_FLASH.lock(|f| *f.borrow_mut() = Some(flash));
+    result
+}
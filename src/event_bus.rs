@@ -0,0 +1,282 @@
+//! Bridge event bus
+//! Errors, resets, connections and transfers are published once, where they happen. The
+//! BLE error events, the event log, the log output and the LED all follow the bus, so
+//! none of the places publishing has to know who is interested.
+
+use defmt::{error, info, unwrap, warn, Format};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::pubsub::{PubSubChannel, Subscriber, WaitResult};
+use isotp_core::frame::ProtocolError;
+
+use crate::ble_protocol::{BleEvent, CanErrorKind};
+use crate::ble_server;
+use crate::event_log::{self, LogKind};
+use crate::isotp_handler::IsotpError;
+
+// Events kept for the slowest subscriber before it starts missing them
+const BUS_DEPTH: usize = 16;
+// The reporter and the LED
+const BUS_SUBSCRIBERS: usize = 2;
+// Everything is published immediately, nobody holds a publisher
+const BUS_PUBLISHERS: usize = 1;
+
+#[derive(Debug, Clone, Copy, Format)]
+pub enum BusEvent {
+    // A BLE client connected
+    Connected,
+    // The BLE client went away
+    Disconnected,
+    CanError {
+        kind: CanErrorKind,
+        // Total error notifications since boot
+        error_count: u32,
+    },
+    // Restarts keep failing, the controller is held off the bus for a while
+    CanBusOff {
+        rapid_resets: u32,
+        backoff_ms: u32,
+    },
+    CanRestart {
+        // Restarts since boot
+        reset_count: u32,
+    },
+    IsotpError {
+        request_arbitration_id: u32,
+        reply_arbitration_id: u32,
+        error: IsotpError,
+        correlation_tag: Option<u16>,
+    },
+    // The peer sent a frame that breaks the protocol, pci: its first byte
+    IsotpProtocolError {
+        request_arbitration_id: u32,
+        reply_arbitration_id: u32,
+        error: ProtocolError,
+        pci: u8,
+    },
+    // A Consecutive Frame arrived out of order, the transfer was dropped
+    IsotpSequenceError {
+        request_arbitration_id: u32,
+        reply_arbitration_id: u32,
+        expected: u8,
+        received: u8,
+    },
+    // The client aborted a multi-frame transmission
+    IsotpTransmissionAborted {
+        request_arbitration_id: u32,
+        reply_arbitration_id: u32,
+        bytes_sent: u32,
+    },
+    // A frame missed its deadline in the transmit queue and was dropped
+    FrameExpired {
+        id: u32,
+        // Total expired frames since boot
+        expired_count: u32,
+    },
+    // The first handler started reflashing an ECU, or the last one stopped
+    Flashing {
+        active: bool,
+    },
+    // A whole PDU went out
+    PduSent {
+        request_arbitration_id: u32,
+        length: u32,
+    },
+    // A whole PDU came in and was handed on
+    PduReceived {
+        reply_arbitration_id: u32,
+        length: u32,
+    },
+    // code: ManagerError code
+    CommandRejected {
+        code: u8,
+    },
+    FilterAdded {
+        filter_id: u32,
+    },
+    FilterExpired {
+        filter_id: u32,
+        request_arbitration_id: u32,
+        reply_arbitration_id: u32,
+    },
+    // The client's handlers, uploads and filters were dropped
+    SessionEnded,
+}
+
+pub type BusSubscriber = Subscriber<
+    'static,
+    CriticalSectionRawMutex,
+    BusEvent,
+    BUS_DEPTH,
+    BUS_SUBSCRIBERS,
+    BUS_PUBLISHERS,
+>;
+
+static EVENT_BUS: PubSubChannel<
+    CriticalSectionRawMutex,
+    BusEvent,
+    BUS_DEPTH,
+    BUS_SUBSCRIBERS,
+    BUS_PUBLISHERS,
+> = PubSubChannel::new();
+
+/// Publish an event, never waits. A subscriber that fell behind misses the oldest ones.
+pub fn publish(event: BusEvent) {
+    EVENT_BUS.immediate_publisher().publish_immediate(event);
+}
+
+/// Subscribe to the bus, panics if there are more subscribers than BUS_SUBSCRIBERS
+pub fn subscribe() -> BusSubscriber {
+    unwrap!(EVENT_BUS.subscriber().ok())
+}
+
+/// Next event for this subscriber, skipping over the ones it missed
+pub async fn next_event(subscriber: &mut BusSubscriber) -> BusEvent {
+    loop {
+        match subscriber.next_message().await {
+            WaitResult::Message(event) => return event,
+            WaitResult::Lagged(missed) => {
+                warn!("[bus] subscriber fell behind, {} events missed", missed);
+            }
+        }
+    }
+}
+
+/// Turn bus events into log output, event log entries and BLE events
+#[embassy_executor::task]
+pub async fn reporter_task() {
+    let mut subscriber = subscribe();
+    loop {
+        report(next_event(&mut subscriber).await);
+    }
+}
+
+fn report(event: BusEvent) {
+    match event {
+        BusEvent::Connected => {
+            info!("[bus] client connected");
+        }
+        BusEvent::Disconnected => {
+            info!("[bus] client disconnected");
+        }
+        BusEvent::CanError { kind, error_count } => {
+            error!("[can] error kind {:?} count {}", kind, error_count);
+            event_log::record(LogKind::CanError, kind as u8, error_count);
+            ble_server::send_event(BleEvent::CanError { kind, error_count });
+        }
+        BusEvent::CanBusOff {
+            rapid_resets,
+            backoff_ms,
+        } => {
+            warn!(
+                "[can] reset storm ({} rapid resets), backing off {} ms",
+                rapid_resets, backoff_ms
+            );
+        }
+        BusEvent::CanRestart { reset_count } => {
+            info!("[can] controller restarted, total {}", reset_count);
+            event_log::record(LogKind::CanRestart, 0, reset_count);
+        }
+        BusEvent::IsotpError {
+            request_arbitration_id,
+            reply_arbitration_id,
+            error,
+            correlation_tag,
+        } => {
+            error!(
+                "ISO-TP error on {:x}:{:x}: {:?}",
+                request_arbitration_id, reply_arbitration_id, error
+            );
+            event_log::record(LogKind::IsotpError, error as u8, request_arbitration_id);
+            ble_server::send_event(BleEvent::IsotpError {
+                request_arbitration_id,
+                reply_arbitration_id,
+                error,
+                correlation_tag,
+            });
+        }
+        BusEvent::IsotpProtocolError {
+            request_arbitration_id,
+            reply_arbitration_id,
+            error,
+            pci,
+        } => {
+            error!(
+                "ISO-TP protocol error on {:x}:{:x}: {:?} pci {:02x}",
+                request_arbitration_id, reply_arbitration_id, error, pci
+            );
+            event_log::record(
+                LogKind::IsotpProtocolError,
+                error as u8,
+                request_arbitration_id,
+            );
+            ble_server::send_event(BleEvent::IsotpProtocolError {
+                request_arbitration_id,
+                reply_arbitration_id,
+                error,
+                pci,
+            });
+        }
+        BusEvent::IsotpSequenceError {
+            request_arbitration_id,
+            reply_arbitration_id,
+            expected,
+            received,
+        } => {
+            event_log::record(
+                LogKind::IsotpSequenceError,
+                received,
+                request_arbitration_id,
+            );
+            ble_server::send_event(BleEvent::IsotpSequenceError {
+                request_arbitration_id,
+                reply_arbitration_id,
+                expected,
+                received,
+            });
+        }
+        BusEvent::IsotpTransmissionAborted {
+            request_arbitration_id,
+            reply_arbitration_id,
+            bytes_sent,
+        } => {
+            event_log::record(LogKind::IsotpTransmissionAborted, 0, bytes_sent);
+            ble_server::send_event(BleEvent::IsotpTransmissionAborted {
+                request_arbitration_id,
+                reply_arbitration_id,
+                bytes_sent,
+            });
+        }
+        BusEvent::FrameExpired { id, expired_count } => {
+            event_log::record(LogKind::FrameExpired, 0, id);
+            ble_server::send_event(BleEvent::FrameExpired { id, expired_count });
+        }
+        BusEvent::Flashing { active } => {
+            info!("[bus] flashing mode {}", active);
+        }
+        // Too frequent for anything but the LED
+        BusEvent::PduSent { .. } | BusEvent::PduReceived { .. } => (),
+        BusEvent::CommandRejected { code } => {
+            error!("Command rejected, error code {:x}", code);
+            event_log::record(LogKind::CommandRejected, code, 0);
+        }
+        BusEvent::FilterAdded { filter_id } => {
+            event_log::record(LogKind::FilterAdded, 0, filter_id);
+        }
+        BusEvent::FilterExpired {
+            filter_id,
+            request_arbitration_id,
+            reply_arbitration_id,
+        } => {
+            event_log::record(LogKind::FilterExpired, 0, filter_id);
+            ble_server::send_event(BleEvent::FilterExpired {
+                filter_id,
+                request_arbitration_id,
+                reply_arbitration_id,
+            });
+        }
+        BusEvent::SessionEnded => {
+            info!("Session ended");
+            event_log::record(LogKind::SessionEnded, 0, 0);
+        }
+    }
+}
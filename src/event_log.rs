@@ -0,0 +1,92 @@
+//! Bridge event log
+//! The last few noteworthy events are kept in RAM with a timestamp, so a client connecting
+//! after an intermittent failure in the field can still find out what happened.
+
+use core::cell::RefCell;
+
+use defmt::Format;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_time::Instant;
+use heapless::{Deque, Vec};
+
+/// Entries kept, the oldest ones make room for new ones. Sized so the whole log fits in
+/// one notification.
+pub const EVENT_LOG_SIZE: usize = 32;
+
+/// What a log entry is about, the meaning of its code and argument depends on it
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum LogKind {
+    // The bridge started, nothing before this survives a reset
+    Boot = 0x00,
+    // code: IsotpError, argument: request arbitration id
+    IsotpError = 0x01,
+    // code: ManagerError code, argument: command id
+    CommandRejected = 0x02,
+    // code: CanErrorKind, argument: errors so far
+    CanError = 0x03,
+    // argument: restarts so far
+    CanRestart = 0x04,
+    // argument: filter id
+    FilterAdded = 0x05,
+    // argument: filter id
+    FilterExpired = 0x06,
+    // The client's handlers, uploads and filters were dropped
+    SessionEnded = 0x07,
+    // code: MonitoredTask that stopped checking in before the previous reset
+    WatchdogReset = 0x08,
+    // The bridge panicked before the previous reset
+    Panic = 0x09,
+    // The bridge hit a hard fault before the previous reset
+    HardFault = 0x0A,
+    // code: ProtocolError, argument: request arbitration id
+    IsotpProtocolError = 0x0B,
+    // code: received sequence number, argument: request arbitration id
+    IsotpSequenceError = 0x0C,
+    // argument: bytes sent before the abort
+    IsotpTransmissionAborted = 0x0D,
+    // argument: arbitration id of the dropped frame
+    FrameExpired = 0x0E,
+}
+
+#[derive(Debug, Clone, Copy, Format)]
+pub struct LogEntry {
+    // Milliseconds since boot
+    pub timestamp_ms: u32,
+    pub kind: LogKind,
+    pub code: u8,
+    pub argument: u32,
+}
+
+static EVENT_LOG: Mutex<CriticalSectionRawMutex, RefCell<Deque<LogEntry, EVENT_LOG_SIZE>>> =
+    Mutex::new(RefCell::new(Deque::new()));
+
+/// Add an entry, dropping the oldest one if the log is full
+pub fn record(kind: LogKind, code: u8, argument: u32) {
+    let entry = LogEntry {
+        timestamp_ms: Instant::now().as_millis() as u32,
+        kind,
+        code,
+        argument,
+    };
+    EVENT_LOG.lock(|log| {
+        let mut log = log.borrow_mut();
+        if log.is_full() {
+            log.pop_front();
+        }
+        let _ = log.push_back(entry);
+    });
+}
+
+/// The log from oldest to newest, emptied if `clear`
+pub fn entries(clear: bool) -> Vec<LogEntry, EVENT_LOG_SIZE> {
+    EVENT_LOG.lock(|log| {
+        let mut log = log.borrow_mut();
+        let entries = log.iter().copied().collect();
+        if clear {
+            log.clear();
+        }
+        entries
+    })
+}
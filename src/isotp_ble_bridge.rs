@@ -1,46 +1,449 @@
+use core::cell::Cell;
+
 use crate::can_manager::CanMessage;
-use crate::channels::{ISOTP_BLE_CHANNEL, ISOTP_CAN_CHANNEL};
-use crate::isotp_handler::IsotpHandler;
-use crate::{ble_protocol::*, can_manager, led};
-use defmt::{debug, error, info, Format};
-use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
-use embassy_sync::mutex::Mutex;
+use crate::channels::{self, ISOTP_BLE_CHANNEL};
+use crate::event_bus::{self, BusEvent};
+use crate::event_log;
+use crate::isotp_handler::{self, IsotpError, IsotpHandler, IsotpParameter, Relay, RelayMode};
+use crate::isotp_selftest;
+use crate::macro_engine::{self, MacroError};
+use crate::pdu_buffer::{self, BufferClass, PduBuffer, SMALL_BUFFER_SIZE};
+use crate::session_store::{self, SessionError};
+use crate::stack_monitor;
+use crate::supervisor::{self, MonitoredTask};
+use crate::{ble_protocol::*, ble_server, can_manager, config, transceiver};
+use defmt::{debug, info, warn, Format};
+use embassy_futures::select::{select3, Either3};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex as BlockingMutex;
+use embassy_sync::channel::Channel;
+use embassy_sync::mutex::{Mutex, MutexGuard};
+use embassy_sync::signal::Signal;
+use embassy_time::{with_timeout, Duration, Instant, Timer};
+use portable_atomic::{AtomicBool, AtomicU32, Ordering};
 
 // Create a static shared manager
-static ISOTP_BLE_BRIDGE: Mutex<ThreadModeRawMutex, IsotpBleBridge> =
+static ISOTP_BLE_BRIDGE: Mutex<CriticalSectionRawMutex, IsotpBleBridge> =
     Mutex::new(IsotpBleBridge::new());
 
+// Frames waiting for a handler that's busy, e.g. until the BLE side has room for its last PDU
+const HANDLER_FRAME_QUEUE: usize = 8;
+// Messages and macros waiting for a handler that's still busy with the previous one
+const HANDLER_JOB_QUEUE: usize = 2;
+
+/// Ids a handler takes frames on, kept outside its lock so frames can be routed to it
+/// while it's busy
+#[derive(Clone, Copy)]
+struct Route {
+    request_arbitration_id: u32,
+    reply_arbitration_id: u32,
+    last_reply_arbitration_id: u32,
+    // Frames in the other id format are for someone else, even on the same ids
+    extended: bool,
+    functional: bool,
+    // Created for a single answer to a functional request, freed once it's received
+    ephemeral: bool,
+}
+
+impl Route {
+    fn of(handler: &IsotpHandler) -> Self {
+        Self {
+            request_arbitration_id: handler.request_arbitration_id,
+            reply_arbitration_id: handler.reply_arbitration_id,
+            last_reply_arbitration_id: handler.last_reply_arbitration_id(),
+            extended: handler.extended_ids(),
+            functional: handler.is_functional(),
+            ephemeral: false,
+        }
+    }
+
+    fn accepts(&self, message: &CanMessage) -> bool {
+        let id = message.id;
+        message.extended == self.extended
+            && (id == self.request_arbitration_id
+                || (self.reply_arbitration_id..=self.last_reply_arbitration_id).contains(&id))
+    }
+}
+
+// Uploaded message waiting for its handler
+struct SendRequest {
+    // Reported back with the outcome of the send
+    staging_id: u32,
+    correlation_tag: Option<u16>,
+    response_timeout: Option<Duration>,
+    pdu: PduBuffer,
+}
+
+// Work for a slot's task that needs its handler for a while
+enum SlotJob {
+    Send(SendRequest),
+    RunMacro {
+        macro_id: u8,
+    },
+    // PDU another handler received, retransmitted as is
+    Relay(PduBuffer),
+    // Flow control the client sends itself, in pass-through mode
+    FlowControl {
+        flow_status: u8,
+        block_size: u8,
+        st_min: u8,
+    },
+    SecurityAccess {
+        sub_function: u8,
+        key: heapless::Vec<u8, MAX_SECURITY_ACCESS_DATA>,
+    },
+}
+
+/// A handler and the frames routed to it. Every slot is driven by its own task, so a slow
+/// transfer on one filter doesn't hold up frame handling on the others.
+struct HandlerSlot {
+    // None while the slot is free
+    route: BlockingMutex<CriticalSectionRawMutex, Cell<Option<Route>>>,
+    frames: Channel<CriticalSectionRawMutex, CanMessage, HANDLER_FRAME_QUEUE>,
+    // Uploaded messages and macros, run from the slot's task so the BLE side doesn't wait
+    // for them
+    jobs: Channel<CriticalSectionRawMutex, SlotJob, HANDLER_JOB_QUEUE>,
+    handler: Mutex<CriticalSectionRawMutex, Option<IsotpHandler>>,
+    // Last frame, send or macro, for reclaiming filters nobody uses any more
+    last_activity: BlockingMutex<CriticalSectionRawMutex, Cell<Instant>>,
+    // The handler took a frame since the slot was claimed, an ephemeral handler isn't done
+    // before its First Frame was handled
+    frame_handled: BlockingMutex<CriticalSectionRawMutex, Cell<bool>>,
+}
+
+impl HandlerSlot {
+    const fn new() -> Self {
+        Self {
+            route: BlockingMutex::new(Cell::new(None)),
+            frames: Channel::new(),
+            jobs: Channel::new(),
+            handler: Mutex::new(None),
+            last_activity: BlockingMutex::new(Cell::new(Instant::from_ticks(0))),
+            frame_handled: BlockingMutex::new(Cell::new(false)),
+        }
+    }
+
+    fn route(&self) -> Option<Route> {
+        self.route.lock(|route| route.get())
+    }
+
+    fn free(&self) {
+        self.route.lock(|route| route.set(None));
+    }
+
+    fn touch(&self) {
+        self.last_activity
+            .lock(|last_activity| last_activity.set(Instant::now()));
+    }
+
+    fn idle_time(&self) -> Duration {
+        Instant::now() - self.last_activity.lock(|last_activity| last_activity.get())
+    }
+
+    // The handler for a quick change under the bridge lock. Waiting for it there would
+    // hold up every other command and the bridge heartbeat behind a send or macro.
+    fn try_handler(
+        &self,
+    ) -> Result<MutexGuard<'_, CriticalSectionRawMutex, Option<IsotpHandler>>, ManagerError> {
+        self.handler
+            .try_lock()
+            .map_err(|_| ManagerError::HandlerBusy)
+    }
+
+    fn set_frame_handled(&self, handled: bool) {
+        self.frame_handled
+            .lock(|frame_handled| frame_handled.set(handled));
+    }
+
+    fn frame_handled(&self) -> bool {
+        self.frame_handled.lock(|frame_handled| frame_handled.get())
+    }
+}
+
+static HANDLER_SLOTS: [HandlerSlot; MAX_HANDLERS] = [const { HandlerSlot::new() }; MAX_HANDLERS];
+
+// Take a free slot for a handler with this route, the handler has to be installed before
+// the next await so no frame finds the slot empty
+fn claim_slot(route: Route) -> Option<usize> {
+    HANDLER_SLOTS.iter().position(|slot| {
+        slot.route.lock(|slot_route| {
+            let free = slot_route.get().is_none();
+            if free {
+                slot_route.set(Some(route));
+                slot.set_frame_handled(false);
+            }
+            free
+        })
+    })
+}
+
+// Multi-frame answer to a functional request from an ECU without a filter of its own: give
+// it a handler until the answer is in, so it isn't lost
+async fn spawn_ephemeral_handler(functional: &IsotpHandler, first_frame: &CanMessage) {
+    let limit = functional.ephemeral_handler_limit(first_frame.id, &first_frame.data);
+    if limit == 0 {
+        return;
+    }
+
+    let routes = || HANDLER_SLOTS.iter().filter_map(HandlerSlot::route);
+    if routes().any(|route| !route.functional && route.accepts(first_frame)) {
+        return;
+    }
+    if routes().filter(|route| route.ephemeral).count() >= limit as usize {
+        warn!(
+            "Ephemeral handler limit reached, ignoring {:x}",
+            first_frame.id
+        );
+        return;
+    }
+
+    let Some(handler) = functional.new_ephemeral(first_frame.id) else {
+        warn!(
+            "No buffer for an ephemeral handler for {:x}",
+            first_frame.id
+        );
+        return;
+    };
+    let route = Route {
+        ephemeral: true,
+        ..Route::of(&handler)
+    };
+    let Some(slot_index) = claim_slot(route) else {
+        warn!(
+            "No free slot for an ephemeral handler for {:x}",
+            first_frame.id
+        );
+        return;
+    };
+    let slot = &HANDLER_SLOTS[slot_index];
+    // Held until the First Frame is queued, so it's the first frame the handler sees
+    let mut slot_handler = slot.handler.lock().await;
+    *slot_handler = Some(handler);
+
+    info!("Ephemeral handler for {:x}", first_frame.id);
+    // Frames left behind by the slot's last handler would be taken for the start of the
+    // answer, the First Frame was only routed to the functional handler
+    slot.frames.clear();
+    let _ = slot.frames.try_send(first_frame.clone());
+}
+
+// Slot of the handler talking on exactly these ids
+fn find_slot(
+    request_arbitration_id: u32,
+    reply_arbitration_id: u32,
+) -> Option<&'static HandlerSlot> {
+    HANDLER_SLOTS.iter().find(|slot| {
+        slot.route().is_some_and(|route| {
+            route.request_arbitration_id == request_arbitration_id
+                && route.reply_arbitration_id == reply_arbitration_id
+        })
+    })
+}
+
 /// Error type for message parsing
 #[derive(Debug, Format)]
 pub enum ManagerError {
     FailedToInsertFilter,
-    FilterAlreadyExists,
     InvalidOffset,
     InvalidPayloadLength,
     FilterNotFound,
     FailedToSendMessage,
+    InvalidConfig(config::ConfigError),
+    TooManyRateLimits,
+    InvalidCanMode,
+    TerminationNotConfigured,
+    Isotp(IsotpError),
+    InvalidIsotpParameter,
+    InvalidBufferClass,
+    NoBufferAvailable,
+    InvalidReplyRange,
+    NoStagingBufferAvailable,
+    StagingBufferNotFound,
+    HandlerBusy,
+    // Every slot is taken, by configured filters or by ephemeral handlers
+    NoHandlerSlots { configured: u8, ephemeral: u8 },
+    SessionNotSaved(SessionError),
+    InvalidMacro(MacroError),
+    MacroNotFound,
+    // The upload has a hole starting at this offset
+    MissingChunk { offset: u16 },
+    OverlappingChunks,
+    // Unknown relay mode, or a handler relaying to itself
+    InvalidRelay,
+    // No such periodic slot, not running, or a message count the data doesn't have
+    InvalidPeriodicMessage,
+    // The self-test needs the bus to itself: it's refused while filters are configured,
+    // and filters, raw frames and mode changes are refused while it runs
+    SelfTestConflict,
+}
+
+impl ManagerError {
+    /// Number reported to the client in send results
+    pub fn code(&self) -> u8 {
+        match self {
+            ManagerError::FailedToInsertFilter => 0x01,
+            ManagerError::InvalidOffset => 0x02,
+            ManagerError::InvalidPayloadLength => 0x03,
+            ManagerError::FilterNotFound => 0x04,
+            ManagerError::FailedToSendMessage => 0x05,
+            ManagerError::InvalidConfig(_) => 0x06,
+            ManagerError::TooManyRateLimits => 0x07,
+            ManagerError::InvalidCanMode => 0x08,
+            ManagerError::TerminationNotConfigured => 0x09,
+            ManagerError::Isotp(_) => 0x0A,
+            ManagerError::InvalidIsotpParameter => 0x0B,
+            ManagerError::InvalidBufferClass => 0x0C,
+            ManagerError::NoBufferAvailable => 0x0D,
+            ManagerError::InvalidReplyRange => 0x0E,
+            ManagerError::NoStagingBufferAvailable => 0x0F,
+            ManagerError::StagingBufferNotFound => 0x10,
+            ManagerError::HandlerBusy => 0x11,
+            ManagerError::NoHandlerSlots { .. } => 0x12,
+            ManagerError::SessionNotSaved(_) => 0x13,
+            ManagerError::InvalidMacro(_) => 0x14,
+            ManagerError::MacroNotFound => 0x15,
+            ManagerError::MissingChunk { .. } => 0x16,
+            ManagerError::OverlappingChunks => 0x17,
+            ManagerError::InvalidRelay => 0x18,
+            ManagerError::InvalidPeriodicMessage => 0x19,
+            ManagerError::SelfTestConflict => 0x1A,
+        }
+    }
 }
 
-const MAX_HANDLERS: usize = 4;
-const MAX_TX_BUFFER_SIZE: usize = 4096;
+// Enough for a gateway-wide scan, every handler has its own task and receive buffer
+pub const MAX_HANDLERS: usize = 16;
+// Request and reply arbitration ids followed by the message
+const MAX_TX_BUFFER_SIZE: usize = 8 + isotp_handler::MAX_PDU_SIZE;
+
+// Uploads that can be assembled at the same time, e.g. one per target
+const STAGING_BUFFER_COUNT: usize = 4;
+// An upload without a chunk for this long was abandoned, its buffer goes to a new upload
+// when none is free
+const STAGING_BUFFER_TIMEOUT: Duration = Duration::from_secs(10);
+
+// One bit per byte of a staging buffer
+const COVERAGE_WORDS: usize = MAX_TX_BUFFER_SIZE.div_ceil(32);
+
+/// Chunked upload being assembled, keyed by the staging id its chunks carry
+struct StagingBuffer {
+    // None while the buffer is free
+    staging_id: Option<u32>,
+    data: heapless::Vec<u8, MAX_TX_BUFFER_SIZE>,
+    // Bytes some chunk has written, anything else in data is zero fill
+    written: [u32; COVERAGE_WORDS],
+    // Some byte was written by more than one chunk
+    overlapping: bool,
+    last_write: Instant,
+}
+
+impl StagingBuffer {
+    const fn new() -> Self {
+        Self {
+            staging_id: None,
+            data: heapless::Vec::new(),
+            written: [0; COVERAGE_WORDS],
+            overlapping: false,
+            last_write: Instant::from_ticks(0),
+        }
+    }
+
+    fn release(&mut self) {
+        self.staging_id = None;
+        self.data.clear();
+        self.written = [0; COVERAGE_WORDS];
+        self.overlapping = false;
+    }
+
+    fn idle_time(&self) -> Duration {
+        Instant::now() - self.last_write
+    }
+
+    fn is_written(&self, index: usize) -> bool {
+        self.written[index / 32] & (1 << (index % 32)) != 0
+    }
+
+    // Copy a chunk in, chunks may arrive in any order
+    fn write(&mut self, offset: usize, chunk: &[u8]) -> Result<(), ManagerError> {
+        let end = offset + chunk.len();
+        if end > self.data.len() {
+            self.data
+                .resize(end, 0)
+                .map_err(|_| ManagerError::InvalidOffset)?;
+        }
+        self.data[offset..end].copy_from_slice(chunk);
+
+        for index in offset..end {
+            self.overlapping |= self.is_written(index);
+            self.written[index / 32] |= 1 << (index % 32);
+        }
+        self.last_write = Instant::now();
+        Ok(())
+    }
+
+    // Every byte up to the end was written by exactly one chunk
+    fn check_coverage(&self) -> Result<(), ManagerError> {
+        if self.overlapping {
+            return Err(ManagerError::OverlappingChunks);
+        }
+        match (0..self.data.len()).find(|&index| !self.is_written(index)) {
+            Some(index) => Err(ManagerError::MissingChunk {
+                offset: index as u16,
+            }),
+            None => Ok(()),
+        }
+    }
+}
 
 pub struct IsotpBleBridge {
-    isotp_handlers: heapless::FnvIndexMap<u32, IsotpHandler, MAX_HANDLERS>,
-    isotp_tx_buffer: heapless::Vec<u8, MAX_TX_BUFFER_SIZE>,
+    // Filter id to the slot its handler lives in
+    filters: heapless::FnvIndexMap<u32, usize, MAX_HANDLERS>,
+    staging_buffers: [StagingBuffer; STAGING_BUFFER_COUNT],
 }
 
 impl IsotpBleBridge {
     pub const fn new() -> Self {
         Self {
-            isotp_handlers: heapless::FnvIndexMap::<u32, IsotpHandler, MAX_HANDLERS>::new(),
-            isotp_tx_buffer: heapless::Vec::new(),
+            filters: heapless::FnvIndexMap::<u32, usize, MAX_HANDLERS>::new(),
+            staging_buffers: [const { StagingBuffer::new() }; STAGING_BUFFER_COUNT],
+        }
+    }
+
+    fn find_staging_buffer(&mut self, staging_id: u32) -> Option<&mut StagingBuffer> {
+        self.staging_buffers
+            .iter_mut()
+            .find(|buffer| buffer.staging_id == Some(staging_id))
+    }
+
+    // The upload's buffer, the first chunk of a new upload takes a free one or the one
+    // of an abandoned upload
+    fn staging_buffer(&mut self, staging_id: u32) -> Result<&mut StagingBuffer, ManagerError> {
+        if let Some(index) = self
+            .staging_buffers
+            .iter()
+            .position(|buffer| buffer.staging_id == Some(staging_id))
+        {
+            return Ok(&mut self.staging_buffers[index]);
         }
+
+        let index = self
+            .staging_buffers
+            .iter()
+            .position(|buffer| buffer.staging_id.is_none())
+            .or_else(|| {
+                self.staging_buffers
+                    .iter()
+                    .position(|buffer| buffer.idle_time() >= STAGING_BUFFER_TIMEOUT)
+            })
+            .ok_or(ManagerError::NoStagingBufferAvailable)?;
+
+        let buffer = &mut self.staging_buffers[index];
+        buffer.release();
+        buffer.staging_id = Some(staging_id);
+        Ok(buffer)
     }
 
-    pub async fn handle_ble_message(
-        &mut self,
-        parsed: &ParsedBleMessage,
-    ) -> Result<(), ManagerError> {
+    pub fn handle_ble_message(&mut self, parsed: &ParsedBleMessage) -> Result<(), ManagerError> {
         match parsed {
             ParsedBleMessage::UploadIsotpChunk(upload_chunk_command) => {
                 debug!("UploadIsotpChunk: {:?}", upload_chunk_command);
@@ -50,149 +453,810 @@ impl IsotpBleBridge {
                 let chunk = upload_chunk_command.chunk.as_slice();
 
                 // check if offset + length would exceed max buffer size
-                if offset + chunk_length > MAX_TX_BUFFER_SIZE as u16 {
+                if offset as usize + chunk_length as usize > MAX_TX_BUFFER_SIZE {
                     return Err(ManagerError::InvalidOffset);
                 }
 
-                // Ensure buffer is large enough
-                let required_len = (offset as usize) + (chunk_length as usize);
-                match self.isotp_tx_buffer.resize(required_len, 0) {
-                    Ok(_) => (),
-                    Err(_) => return Err(ManagerError::InvalidOffset),
+                self.staging_buffer(upload_chunk_command.staging_id)?
+                    .write(offset as usize, chunk)
+            }
+            ParsedBleMessage::SendIsotpBuffer(send_isotp_buffer_command) => {
+                debug!("SendIsotpBuffer: {:?}", send_isotp_buffer_command);
+
+                // Once queued, the handler's task reports how the send went
+                let result = self.queue_isotp_buffer(send_isotp_buffer_command);
+                if let Err(error) = &result {
+                    // The client's retry uploads it again, chunks left here would overlap
+                    if let Some(buffer) =
+                        self.find_staging_buffer(send_isotp_buffer_command.staging_id)
+                    {
+                        buffer.release();
+                    }
+                    ble_server::send_event(BleEvent::IsotpSendResult {
+                        staging_id: send_isotp_buffer_command.staging_id,
+                        outcome: SendOutcome::Rejected,
+                        code: error.code(),
+                    });
+                }
+                result
+            }
+            ParsedBleMessage::StartPeriodicIsotpMessage(start_periodic_command) => {
+                debug!("StartPeriodicIsotpMessage: {:?}", start_periodic_command);
+
+                let slot = find_slot(
+                    start_periodic_command.request_arbitration_id,
+                    start_periodic_command.reply_arbitration_id,
+                )
+                .ok_or(ManagerError::FilterNotFound)?;
+                let mut handler = slot.try_handler()?;
+                let handler = handler.as_mut().ok_or(ManagerError::FilterNotFound)?;
+
+                match handler.start_periodic_message(
+                    start_periodic_command.periodic_message_index,
+                    Duration::from_millis(start_periodic_command.interval_ms as u64),
+                    &start_periodic_command.message_data,
+                    start_periodic_command.message_count,
+                ) {
+                    true => Ok(()),
+                    false => Err(ManagerError::InvalidPeriodicMessage),
                 }
+            }
+            ParsedBleMessage::StopPeriodicIsotpMessage(stop_periodic_command) => {
+                debug!("StopPeriodicIsotpMessage: {:?}", stop_periodic_command);
 
-                // Copy chunk into buffer
-                let start = offset as usize;
-                let end = start + chunk_length as usize;
-                self.isotp_tx_buffer[start..end].copy_from_slice(chunk);
+                let slot = find_slot(
+                    stop_periodic_command.request_arbitration_id,
+                    stop_periodic_command.reply_arbitration_id,
+                )
+                .ok_or(ManagerError::FilterNotFound)?;
+                let mut handler = slot.try_handler()?;
+                let handler = handler.as_mut().ok_or(ManagerError::FilterNotFound)?;
 
-                Ok(())
+                match handler.stop_periodic_message(stop_periodic_command.periodic_message_index) {
+                    true => Ok(()),
+                    false => Err(ManagerError::InvalidPeriodicMessage),
+                }
             }
-            ParsedBleMessage::SendIsotpBuffer(send_isotp_buffer_command) => {
-                debug!("SendIsotpBuffer: {:?}", send_isotp_buffer_command);
+            ParsedBleMessage::ConfigureIsotpFilter(configure_filter_command) => {
+                debug!("ConfigureIsotpFilter: {:?}", configure_filter_command);
 
-                let payload_length = send_isotp_buffer_command.total_length;
-                let request_arbitration_id = u32::from_be_bytes([
-                    self.isotp_tx_buffer[0],
-                    self.isotp_tx_buffer[1],
-                    self.isotp_tx_buffer[2],
-                    self.isotp_tx_buffer[3],
-                ]);
-                let reply_arbitration_id = u32::from_be_bytes([
-                    self.isotp_tx_buffer[4],
-                    self.isotp_tx_buffer[5],
-                    self.isotp_tx_buffer[6],
-                    self.isotp_tx_buffer[7],
-                ]);
-                let msg = &self.isotp_tx_buffer[8..];
-
-                // subtract 8 bytes for the arbitration ids
-                if msg.len() != (payload_length - 8) as usize {
-                    debug!(
-                        "Invalid payload length: {:?}, {:?}, {:02x}",
-                        payload_length,
-                        msg.len(),
-                        msg
-                    );
-                    return Err(ManagerError::InvalidPayloadLength);
+                if configure_filter_command.last_reply_arbitration_id
+                    < configure_filter_command.reply_arbitration_id
+                {
+                    return Err(ManagerError::InvalidReplyRange);
                 }
 
-                info!(
-                    "Sending message to {:x}:{:x} {:02x}",
-                    request_arbitration_id, reply_arbitration_id, msg
+                let rx_buffer = Self::claim_buffer(configure_filter_command.buffer_class)?;
+                let mut handler = IsotpHandler::new_with_reply_range(
+                    configure_filter_command.request_arbitration_id,
+                    configure_filter_command.reply_arbitration_id,
+                    configure_filter_command.last_reply_arbitration_id,
+                    rx_buffer,
                 );
+                // The filter isn't added at all if any of its parameters is rejected
+                for parameter_value in &configure_filter_command.parameters {
+                    let parameter = IsotpParameter::try_from(parameter_value.parameter)
+                        .map_err(|_| ManagerError::InvalidIsotpParameter)?;
+                    if !handler.set_parameter(parameter, parameter_value.value) {
+                        return Err(ManagerError::InvalidIsotpParameter);
+                    }
+                }
 
-                // Find the handler that matches both IDs
-                let matching_handler = self.isotp_handlers.iter_mut().find(|(_key, handler)| {
-                    handler.request_arbitration_id == request_arbitration_id
-                        && handler.reply_arbitration_id == reply_arbitration_id
-                });
+                self.add_handler(configure_filter_command.filter_id, handler)
+            }
+            ParsedBleMessage::ConfigureSniffer(configure_sniffer_command) => {
+                debug!("ConfigureSniffer: {:?}", configure_sniffer_command);
 
-                let handler = match matching_handler {
-                    Some((_key, handler)) => handler,
-                    None => return Err(ManagerError::FilterNotFound),
-                };
+                can_manager::configure_sniffer(
+                    configure_sniffer_command.enabled,
+                    configure_sniffer_command.tx_echo,
+                );
 
-                // send message
-                match handler
-                    .send_isotp_message(request_arbitration_id, msg)
-                    .await
-                {
-                    true => (),
-                    false => return Err(ManagerError::FailedToSendMessage),
+                Ok(())
+            }
+            ParsedBleMessage::ConfigureRateLimit(configure_rate_limit_command) => {
+                debug!("ConfigureRateLimit: {:?}", configure_rate_limit_command);
+
+                match can_manager::set_rate_limit(
+                    configure_rate_limit_command.arbitration_id,
+                    configure_rate_limit_command.divisor,
+                ) {
+                    true => Ok(()),
+                    false => Err(ManagerError::TooManyRateLimits),
                 }
+            }
+            ParsedBleMessage::SetCanMode(set_can_mode_command) => {
+                debug!("SetCanMode: {:?}", set_can_mode_command);
 
-                // flush tx buffer
-                self.isotp_tx_buffer.clear();
+                let mode = can_manager::CanMode::try_from(set_can_mode_command.mode)
+                    .map_err(|_| ManagerError::InvalidCanMode)?;
+                refuse_during_self_test()?;
+                can_manager::set_mode(mode);
 
                 Ok(())
             }
-            ParsedBleMessage::StartPeriodicIsotpMessage(_start_periodic_message_command) => {
-                todo!()
+            ParsedBleMessage::SetTermination(set_termination_command) => {
+                debug!("SetTermination: {:?}", set_termination_command);
+
+                match transceiver::set_termination(set_termination_command.enabled) {
+                    true => Ok(()),
+                    false => Err(ManagerError::TerminationNotConfigured),
+                }
             }
-            ParsedBleMessage::StopPeriodicIsotpMessage(_stop_periodic_message_command) => {
-                todo!()
+            ParsedBleMessage::GetStatistics(_) => {
+                ble_server::send_event(BleEvent::CanStatistics(can_manager::statistics()));
+                ble_server::send_event(
+                    BleEvent::FilterStatistics(can_manager::filter_statistics()),
+                );
+                ble_server::send_event(BleEvent::QueueStatistics(can_manager::queue_statistics()));
+                ble_server::send_event(BleEvent::ChannelStatistics(channels::statistics()));
+                ble_server::send_event(BleEvent::StackStatistics(stack_monitor::statistics()));
+                ble_server::send_event(BleEvent::NotifyLatency(ble_server::notify_latency()));
+                ble_server::send_event(BleEvent::BridgeStatistics(statistics()));
+
+                Ok(())
             }
-            ParsedBleMessage::ConfigureIsotpFilter(configure_filter_command) => {
-                debug!("ConfigureIsotpFilter: {:?}", configure_filter_command);
+            ParsedBleMessage::ConfigureIsotpTiming(configure_isotp_timing_command) => {
+                debug!("ConfigureIsotpTiming: {:?}", configure_isotp_timing_command);
 
-                // check if already exists
-                if self
-                    .isotp_handlers
-                    .contains_key(&configure_filter_command.filter_id)
-                {
-                    return Err(ManagerError::FilterAlreadyExists);
+                let parameter = IsotpParameter::try_from(configure_isotp_timing_command.parameter)
+                    .map_err(|_| ManagerError::InvalidIsotpParameter)?;
+                let slot_index = *self
+                    .filters
+                    .get(&configure_isotp_timing_command.filter_id)
+                    .ok_or(ManagerError::FilterNotFound)?;
+                let slot = &HANDLER_SLOTS[slot_index];
+                let mut handler = slot.try_handler()?;
+                let handler = handler.as_mut().ok_or(ManagerError::FilterNotFound)?;
+
+                let was_extended = handler.extended_ids();
+                if !handler.set_parameter(parameter, configure_isotp_timing_command.value) {
+                    return Err(ManagerError::InvalidIsotpParameter);
+                }
+                // The filter and the route only take frames in the handler's id format
+                if handler.extended_ids() != was_extended {
+                    can_manager::unregister_isotp_filter_range(
+                        handler.reply_arbitration_id,
+                        handler.last_reply_arbitration_id(),
+                        was_extended,
+                    );
+                    // Just freed the spot
+                    can_manager::register_isotp_filter_range(
+                        handler.reply_arbitration_id,
+                        handler.last_reply_arbitration_id(),
+                        handler.extended_ids(),
+                    );
+                    slot.route.lock(|route| route.set(Some(Route::of(handler))));
                 }
+                Ok(())
+            }
+            ParsedBleMessage::ConfigureFunctionalFilter(configure_filter_command) => {
+                debug!("ConfigureFunctionalFilter: {:?}", configure_filter_command);
 
-                // register filter with can_manager
-                if !can_manager::register_isotp_filter(
-                    configure_filter_command.reply_arbitration_id,
-                ) {
-                    return Err(ManagerError::FailedToInsertFilter);
+                if configure_filter_command.last_reply_arbitration_id
+                    < configure_filter_command.first_reply_arbitration_id
+                {
+                    return Err(ManagerError::InvalidReplyRange);
                 }
 
-                // insert handler
-                match self.isotp_handlers.insert(
+                let rx_buffer = Self::claim_buffer(configure_filter_command.buffer_class)?;
+                self.add_handler(
                     configure_filter_command.filter_id,
-                    IsotpHandler::new(
+                    IsotpHandler::new_functional(
                         configure_filter_command.request_arbitration_id,
-                        configure_filter_command.reply_arbitration_id,
+                        configure_filter_command.first_reply_arbitration_id,
+                        configure_filter_command.last_reply_arbitration_id,
+                        Duration::from_millis(configure_filter_command.window_ms as u64),
+                        configure_filter_command.max_ephemeral_handlers,
+                        rx_buffer,
                     ),
-                ) {
-                    Ok(_) => (),
-                    Err(_) => return Err(ManagerError::FailedToInsertFilter),
+                )
+            }
+            ParsedBleMessage::ConfigureNormalFixedFilter(configure_filter_command) => {
+                debug!("ConfigureNormalFixedFilter: {:?}", configure_filter_command);
+
+                let rx_buffer = Self::claim_buffer(configure_filter_command.buffer_class)?;
+                self.add_handler(
+                    configure_filter_command.filter_id,
+                    IsotpHandler::new_normal_fixed(
+                        configure_filter_command.source_address,
+                        configure_filter_command.target_address,
+                        rx_buffer,
+                    ),
+                )
+            }
+            ParsedBleMessage::CancelIsotpTransmission(cancel_command) => {
+                // Normally handled before reaching the bridge
+                debug!("CancelIsotpTransmission: {:?}", cancel_command);
+                Ok(())
+            }
+            ParsedBleMessage::SendIsotpFlowControl(flow_control_command) => {
+                debug!("SendIsotpFlowControl: {:?}", flow_control_command);
+
+                let slot = find_slot(
+                    flow_control_command.request_arbitration_id,
+                    flow_control_command.reply_arbitration_id,
+                )
+                .ok_or(ManagerError::FilterNotFound)?;
+
+                // The handler's task sends it and reports a failure
+                slot.jobs
+                    .try_send(SlotJob::FlowControl {
+                        flow_status: flow_control_command.flow_status,
+                        block_size: flow_control_command.block_size,
+                        st_min: flow_control_command.st_min,
+                    })
+                    .map_err(|_| ManagerError::HandlerBusy)
+            }
+            ParsedBleMessage::SecurityAccess(security_access_command) => {
+                debug!("SecurityAccess: {:?}", security_access_command);
+
+                // Queued for the handler's task instead of going through the upload path,
+                // ECUs often give little time between seed and key
+                let slot = find_slot(
+                    security_access_command.request_arbitration_id,
+                    security_access_command.reply_arbitration_id,
+                )
+                .ok_or(ManagerError::FilterNotFound)?;
+
+                slot.jobs
+                    .try_send(SlotJob::SecurityAccess {
+                        sub_function: security_access_command.sub_function,
+                        key: security_access_command.key.clone(),
+                    })
+                    .map_err(|_| ManagerError::HandlerBusy)
+            }
+            ParsedBleMessage::ConfigureObdPoll(configure_poll_command) => {
+                debug!("ConfigureObdPoll: {:?}", configure_poll_command);
+
+                let slot = find_slot(
+                    configure_poll_command.request_arbitration_id,
+                    configure_poll_command.reply_arbitration_id,
+                )
+                .ok_or(ManagerError::FilterNotFound)?;
+                let mut handler = slot.try_handler()?;
+                let handler = handler.as_mut().ok_or(ManagerError::FilterNotFound)?;
+
+                handler.configure_obd_poll(&configure_poll_command.entries);
+                Ok(())
+            }
+            ParsedBleMessage::UploadMacro(upload_macro_command) => {
+                debug!("UploadMacro: {:?}", upload_macro_command);
+
+                macro_engine::upload(upload_macro_command.macro_id, &upload_macro_command.script)
+                    .map_err(ManagerError::InvalidMacro)
+            }
+            ParsedBleMessage::RunMacro(run_macro_command) => {
+                debug!("RunMacro: {:?}", run_macro_command);
+
+                if !macro_engine::is_stored(run_macro_command.macro_id) {
+                    return Err(ManagerError::MacroNotFound);
+                }
+                let slot = find_slot(
+                    run_macro_command.request_arbitration_id,
+                    run_macro_command.reply_arbitration_id,
+                )
+                .ok_or(ManagerError::FilterNotFound)?;
+
+                // The handler's task runs it and reports the result
+                slot.jobs
+                    .try_send(SlotJob::RunMacro {
+                        macro_id: run_macro_command.macro_id,
+                    })
+                    .map_err(|_| ManagerError::HandlerBusy)
+            }
+            ParsedBleMessage::ConfigureIsotpRelay(relay_command) => {
+                debug!("ConfigureIsotpRelay: {:?}", relay_command);
+
+                let mode = RelayMode::try_from(relay_command.mode)
+                    .map_err(|_| ManagerError::InvalidRelay)?;
+                let source = (
+                    relay_command.request_arbitration_id,
+                    relay_command.reply_arbitration_id,
+                );
+                let target = (
+                    relay_command.target_request_arbitration_id,
+                    relay_command.target_reply_arbitration_id,
+                );
+                let relay = match mode {
+                    RelayMode::Off => None,
+                    _ if source == target => return Err(ManagerError::InvalidRelay),
+                    _ => {
+                        // PDUs are dropped and reported if the target goes away later
+                        find_slot(target.0, target.1).ok_or(ManagerError::FilterNotFound)?;
+                        Some(Relay {
+                            request_arbitration_id: target.0,
+                            reply_arbitration_id: target.1,
+                            mirror: mode == RelayMode::Mirror,
+                        })
+                    }
+                };
+
+                let slot = find_slot(source.0, source.1).ok_or(ManagerError::FilterNotFound)?;
+                let mut handler = slot.try_handler()?;
+                let handler = handler.as_mut().ok_or(ManagerError::FilterNotFound)?;
+
+                handler.set_relay(relay);
+                Ok(())
+            }
+            ParsedBleMessage::ConfigureIsotpThrottle(throttle_command) => {
+                debug!("ConfigureIsotpThrottle: {:?}", throttle_command);
+
+                let slot = find_slot(
+                    throttle_command.request_arbitration_id,
+                    throttle_command.reply_arbitration_id,
+                )
+                .ok_or(ManagerError::FilterNotFound)?;
+                let mut handler = slot.try_handler()?;
+                let handler = handler.as_mut().ok_or(ManagerError::FilterNotFound)?;
+
+                handler.set_throttle(
+                    Duration::from_millis(throttle_command.min_interval_ms as u64),
+                    Duration::from_millis(throttle_command.dedup_window_ms as u64),
+                );
+                Ok(())
+            }
+            ParsedBleMessage::RunSelfTest(_) => {
+                info!("RunSelfTest");
+
+                // Loopback mode would swallow the frames of every other handler
+                if HANDLER_SLOTS.iter().any(|slot| slot.route().is_some()) {
+                    return Err(ManagerError::SelfTestConflict);
                 }
+                refuse_during_self_test()?;
 
+                // A failing run waits out every frame timeout, so it runs from a slot's task
+                SELF_TEST_RUNNING.store(true, Ordering::Release);
+                SELF_TEST_REQUESTED.signal(());
                 Ok(())
             }
+            // Handled by handle_unlocked_message, see is_unlocked
+            ParsedBleMessage::SetDeviceConfig(_)
+            | ParsedBleMessage::SendCanFrame(_)
+            | ParsedBleMessage::SaveSession(_)
+            | ParsedBleMessage::GetEventLog(_) => Ok(()),
+        }
+    }
+
+    // Hand an uploaded message to its handler's task
+    fn queue_isotp_buffer(&mut self, command: &SendIsotpBufferCommand) -> Result<(), ManagerError> {
+        let payload_length = command.total_length;
+        let staging_buffer = self
+            .find_staging_buffer(command.staging_id)
+            .ok_or(ManagerError::StagingBufferNotFound)?;
+        // Holes would go out as zeros, overlaps as whichever chunk came last
+        staging_buffer.check_coverage()?;
+        let tx_buffer = &staging_buffer.data;
+        if tx_buffer.len() < 8 {
+            return Err(ManagerError::InvalidPayloadLength);
+        }
+
+        let request_arbitration_id =
+            u32::from_be_bytes([tx_buffer[0], tx_buffer[1], tx_buffer[2], tx_buffer[3]]);
+        let reply_arbitration_id =
+            u32::from_be_bytes([tx_buffer[4], tx_buffer[5], tx_buffer[6], tx_buffer[7]]);
+        let msg = &tx_buffer[8..];
+
+        // the total length includes the 8 bytes of arbitration ids
+        if msg.len() + 8 != payload_length as usize {
+            debug!(
+                "Invalid payload length: {:?}, {:?}, {:02x}",
+                payload_length,
+                msg.len(),
+                msg
+            );
+            return Err(ManagerError::InvalidPayloadLength);
+        }
+
+        if !isotp_handler::flashing_mode() {
+            info!(
+                "Sending message to {:x}:{:x} {:02x}",
+                request_arbitration_id, reply_arbitration_id, msg
+            );
+        }
+
+        // Find the handler that matches both IDs
+        let slot = find_slot(request_arbitration_id, reply_arbitration_id)
+            .ok_or(ManagerError::FilterNotFound)?;
+
+        // The handler's task sends it and reports how it went
+        let buffer_class = if msg.len() <= SMALL_BUFFER_SIZE {
+            BufferClass::Small
+        } else {
+            BufferClass::Large
+        };
+        let mut pdu = PduBuffer::claim(buffer_class).ok_or(ManagerError::NoBufferAvailable)?;
+        pdu.extend_from_slice(msg)
+            .map_err(|_| ManagerError::InvalidPayloadLength)?;
+        slot.jobs
+            .try_send(SlotJob::Send(SendRequest {
+                staging_id: command.staging_id,
+                correlation_tag: command.correlation_tag,
+                response_timeout: command
+                    .response_timeout_ms
+                    .map(|ms| Duration::from_millis(ms as u64)),
+                pdu,
+            }))
+            .map_err(|_| ManagerError::HandlerBusy)?;
+
+        // free the staging buffer for the next upload
+        staging_buffer.release();
+
+        Ok(())
+    }
+
+    // Back to the state at boot: no filters and no half-finished uploads. The handlers
+    // are dropped after the bridge lock is released, see end_session below.
+    fn end_session(&mut self) {
+        for (_, &slot_index) in self.filters.iter() {
+            if let Some(route) = HANDLER_SLOTS[slot_index].route() {
+                can_manager::unregister_isotp_filter_range(
+                    route.reply_arbitration_id,
+                    route.last_reply_arbitration_id,
+                    route.extended,
+                );
+            }
+        }
+        self.filters.clear();
+
+        for buffer in self.staging_buffers.iter_mut() {
+            buffer.release();
+        }
+    }
+
+    // Remove the configured filter in this slot if it's still unused. Checked again under the
+    // bridge lock, a command may have used it in the meantime.
+    fn expire_idle_filter(&mut self, slot_index: usize, idle_timeout: Duration) {
+        let slot = &HANDLER_SLOTS[slot_index];
+        if slot.idle_time() < idle_timeout {
+            return;
+        }
+        // A handler that's busy isn't idle
+        let Ok(mut handler) = slot.try_handler() else {
+            return;
+        };
+        let Some(filter_id) = self
+            .filters
+            .iter()
+            .find(|(_, &index)| index == slot_index)
+            .map(|(&filter_id, _)| filter_id)
+        else {
+            return;
+        };
+
+        self.filters.remove(&filter_id);
+        slot.free();
+        if let Some(expired) = handler.take() {
+            can_manager::unregister_isotp_filter_range(
+                expired.reply_arbitration_id,
+                expired.last_reply_arbitration_id(),
+                expired.extended_ids(),
+            );
+            info!(
+                "Filter {} expired after {} s idle",
+                filter_id,
+                idle_timeout.as_secs()
+            );
+            event_bus::publish(BusEvent::FilterExpired {
+                filter_id,
+                request_arbitration_id: expired.request_arbitration_id,
+                reply_arbitration_id: expired.reply_arbitration_id,
+            });
+        }
+        slot.frames.clear();
+        slot.jobs.clear();
+    }
+
+    fn claim_buffer(buffer_class: u8) -> Result<PduBuffer, ManagerError> {
+        let buffer_class =
+            BufferClass::try_from(buffer_class).map_err(|_| ManagerError::InvalidBufferClass)?;
+        PduBuffer::claim(buffer_class).ok_or(ManagerError::NoBufferAvailable)
+    }
+
+    fn add_handler(&mut self, filter_id: u32, handler: IsotpHandler) -> Result<(), ManagerError> {
+        refuse_during_self_test()?;
+
+        // An existing filter is retargeted in place
+        if let Some(&slot_index) = self.filters.get(&filter_id) {
+            Self::replace_handler(slot_index, handler)?;
+            event_bus::publish(BusEvent::FilterAdded { filter_id });
+            return Ok(());
+        }
+
+        // Ephemeral handlers are freed once their answer is in, so a retry may succeed
+        let slot_index = claim_slot(Route::of(&handler)).ok_or_else(|| {
+            let ephemeral = HANDLER_SLOTS
+                .iter()
+                .filter(|slot| slot.route().is_some_and(|route| route.ephemeral))
+                .count();
+            ManagerError::NoHandlerSlots {
+                configured: (MAX_HANDLERS - ephemeral) as u8,
+                ephemeral: ephemeral as u8,
+            }
+        })?;
+        let slot = &HANDLER_SLOTS[slot_index];
+        // Free slots are only ever locked for a moment
+        let mut slot_handler = match slot.try_handler() {
+            Ok(slot_handler) => slot_handler,
+            Err(error) => {
+                slot.free();
+                return Err(error);
+            }
+        };
+
+        // register filter with can_manager
+        if !can_manager::register_isotp_filter_range(
+            handler.reply_arbitration_id,
+            handler.last_reply_arbitration_id(),
+            handler.extended_ids(),
+        ) {
+            slot.free();
+            return Err(ManagerError::FailedToInsertFilter);
+        }
+
+        // insert handler
+        match self.filters.insert(filter_id, slot_index) {
+            Ok(_) => (),
+            Err(_) => {
+                slot.free();
+                return Err(ManagerError::FailedToInsertFilter);
+            }
         }
+
+        *slot_handler = Some(handler);
+        slot.touch();
+        event_bus::publish(BusEvent::FilterAdded { filter_id });
+
+        Ok(())
     }
 
-    async fn handle_can_frame(&mut self, id: u32, data: &[u8]) {
-        for (_filter_id, handler) in self.isotp_handlers.iter_mut() {
-            if handler.request_arbitration_id == id || handler.reply_arbitration_id == id {
-                handler.handle_received_can_frame(id, data).await;
+    // Swap the handler in a slot, its CAN filter registration is moved to the new ids.
+    // Refused while the old handler is busy with a send or macro.
+    fn replace_handler(slot_index: usize, handler: IsotpHandler) -> Result<(), ManagerError> {
+        let slot = &HANDLER_SLOTS[slot_index];
+        let mut current = slot.try_handler()?;
+
+        if let Some(old) = current.as_ref() {
+            can_manager::unregister_isotp_filter_range(
+                old.reply_arbitration_id,
+                old.last_reply_arbitration_id(),
+                old.extended_ids(),
+            );
+        }
+        if !can_manager::register_isotp_filter_range(
+            handler.reply_arbitration_id,
+            handler.last_reply_arbitration_id(),
+            handler.extended_ids(),
+        ) {
+            // Just freed a spot, so the old range goes back in
+            if let Some(old) = current.as_ref() {
+                can_manager::register_isotp_filter_range(
+                    old.reply_arbitration_id,
+                    old.last_reply_arbitration_id(),
+                    old.extended_ids(),
+                );
             }
+            return Err(ManagerError::FailedToInsertFilter);
         }
+
+        // Frames and uploads queued for the old ids don't concern the new handler
+        slot.frames.clear();
+        slot.jobs.clear();
+        slot.route
+            .lock(|route| route.set(Some(Route::of(&handler))));
+        *current = Some(handler);
+        slot.touch();
+
+        Ok(())
     }
 }
 
-#[embassy_executor::task]
-pub async fn isotp_ble_bridge_can_rx_task() {
-    info!("BLE IsoTP bridge CAN task started");
+/// Runs the handler in one slot: frames routed to it, uploaded messages, macros, response
+/// deadlines, keepalives and receptions held off with WAIT. The first slot's task also runs
+/// the self-test.
+#[embassy_executor::task(pool_size = MAX_HANDLERS)]
+pub async fn isotp_handler_task(slot_index: usize) {
+    info!("ISO-TP handler task {} started", slot_index);
+
+    let slot = &HANDLER_SLOTS[slot_index];
+    let mut next_check = Instant::now() + TIMEOUT_CHECK_INTERVAL;
 
     loop {
-        let can_message = ISOTP_CAN_CHANNEL.receive().await;
+        match select3(
+            slot.frames.receive(),
+            slot.jobs.receive(),
+            Timer::at(next_check),
+        )
+        .await
+        {
+            Either3::First(can_message) => {
+                slot.touch();
+                if let Some(handler) = slot.handler.lock().await.as_mut() {
+                    spawn_ephemeral_handler(handler, &can_message).await;
+                    handler
+                        .handle_received_can_frame(
+                            can_message.id,
+                            &can_message.data,
+                            can_message.received_at,
+                        )
+                        .await;
+                    slot.set_frame_handled(true);
+                }
+            }
+            Either3::Second(SlotJob::Send(request)) => {
+                slot.touch();
+                // Frames for this filter queue up in the slot until the send is over
+                if let Some(handler) = slot.handler.lock().await.as_mut() {
+                    let request_arbitration_id = handler.request_arbitration_id;
+                    let (outcome, code) = match handler
+                        .send_correlated_message(
+                            request_arbitration_id,
+                            request.pdu.as_slice(),
+                            request.correlation_tag,
+                            request.response_timeout,
+                        )
+                        .await
+                    {
+                        Ok(_) => (SendOutcome::Sent, 0),
+                        Err(error) => (SendOutcome::Failed, error as u8),
+                    };
+                    ble_server::send_event(BleEvent::IsotpSendResult {
+                        staging_id: request.staging_id,
+                        outcome,
+                        code,
+                    });
+                }
+            }
+            Either3::Second(SlotJob::RunMacro { macro_id }) => {
+                slot.touch();
+                // The handler stays locked for the whole macro, nothing else goes out on
+                // its ids in between
+                if let Some(handler) = slot.handler.lock().await.as_mut() {
+                    macro_engine::run(macro_id, handler, &slot.frames).await;
+                }
+            }
+            Either3::Second(SlotJob::Relay(pdu)) => {
+                slot.touch();
+                if let Some(handler) = slot.handler.lock().await.as_mut() {
+                    let request_arbitration_id = handler.request_arbitration_id;
+                    // Failures are reported by the handler
+                    let _ = handler
+                        .send_isotp_message(request_arbitration_id, pdu.as_slice())
+                        .await;
+                }
+            }
+            Either3::Second(SlotJob::FlowControl {
+                flow_status,
+                block_size,
+                st_min,
+            }) => {
+                slot.touch();
+                if let Some(handler) = slot.handler.lock().await.as_ref() {
+                    if let Err(error) = handler
+                        .send_client_flow_control(flow_status, block_size, st_min)
+                        .await
+                    {
+                        reject(ManagerError::Isotp(error));
+                    }
+                }
+            }
+            Either3::Second(SlotJob::SecurityAccess { sub_function, key }) => {
+                slot.touch();
+                if let Some(handler) = slot.handler.lock().await.as_mut() {
+                    if let Err(error) = handler.send_security_access(sub_function, &key).await {
+                        reject(ManagerError::Isotp(error));
+                    }
+                }
+            }
+            Either3::Third(_) => (),
+        }
+
+        // Also checked between frames, a busy bus mustn't hold off the timeouts
+        if Instant::now() >= next_check {
+            next_check = Instant::now() + TIMEOUT_CHECK_INTERVAL;
+            if let Some(handler) = slot.handler.lock().await.as_mut() {
+                handler.check_timeouts();
+                handler.resume_throttled_reception().await;
+                handler.send_tester_present_if_due().await;
+                handler.send_obd_poll_if_due().await;
+                handler.send_periodic_message_if_due().await;
+            }
 
-        // Brief critical section
-        ISOTP_BLE_BRIDGE
-            .lock()
-            .await
-            .handle_can_frame(can_message.id, &can_message.data)
-            .await;
+            if slot_index == 0 && SELF_TEST_REQUESTED.try_take().is_some() {
+                match isotp_selftest::run().await {
+                    Some(results) => ble_server::send_event(BleEvent::SelfTestReport(results)),
+                    None => reject(ManagerError::NoBufferAvailable),
+                }
+                SELF_TEST_RUNNING.store(false, Ordering::Release);
+            }
 
-        // blink led
-        led::blink().await;
+            // Opt-in, long-running installations get slots back from filters left behind
+            if let Some(idle_timeout) = config::get().filter_idle_timeout() {
+                if slot.route().is_some_and(|route| !route.ephemeral)
+                    && slot.idle_time() >= idle_timeout
+                {
+                    ISOTP_BLE_BRIDGE
+                        .lock()
+                        .await
+                        .expire_idle_filter(slot_index, idle_timeout);
+                }
+            }
+        }
+
+        // An ephemeral handler is done once its answer is delivered or given up on. Until
+        // the First Frame is handled it isn't receiving yet, but it isn't done either.
+        if slot.route().is_some_and(|route| route.ephemeral) && slot.frame_handled() {
+            let mut handler = slot.handler.lock().await;
+            if !handler.as_ref().is_some_and(IsotpHandler::is_receiving) {
+                debug!("Freeing ephemeral handler in slot {}", slot_index);
+                *handler = None;
+                slot.free();
+            }
+        }
+    }
+}
+
+// Commands that wait on flash or the bus but leave the bridge alone
+fn is_unlocked(parsed: &ParsedBleMessage) -> bool {
+    matches!(
+        parsed,
+        ParsedBleMessage::SetDeviceConfig(_)
+            | ParsedBleMessage::SendCanFrame(_)
+            | ParsedBleMessage::SaveSession(_)
+            | ParsedBleMessage::GetEventLog(_)
+    )
+}
+
+// Handle a command is_unlocked picked, without the bridge lock
+async fn handle_unlocked_message(parsed: &ParsedBleMessage) -> Result<(), ManagerError> {
+    match parsed {
+        ParsedBleMessage::SetDeviceConfig(set_device_config_command) => {
+            debug!("SetDeviceConfig: {:?}", set_device_config_command);
+
+            let key = config::ConfigKey::try_from(set_device_config_command.key)
+                .map_err(ManagerError::InvalidConfig)?;
+            config::set_value(key, set_device_config_command.value)
+                .await
+                .map_err(ManagerError::InvalidConfig)
+        }
+        ParsedBleMessage::SendCanFrame(send_can_frame_command) => {
+            debug!("SendCanFrame: {:?}", send_can_frame_command);
+
+            refuse_during_self_test()?;
+            let id = send_can_frame_command.arbitration_id;
+            let data = send_can_frame_command.data.as_slice();
+            let sent = if send_can_frame_command.one_shot {
+                can_manager::send_one_shot_message(id, data).await
+            } else {
+                can_manager::send_message(id, data).await
+            };
+
+            match sent {
+                true => Ok(()),
+                false => Err(ManagerError::FailedToSendMessage),
+            }
+        }
+        ParsedBleMessage::SaveSession(save_session_command) => {
+            debug!("SaveSession: {:?}", save_session_command);
+
+            match save_session_command.save {
+                true => session_store::save().await,
+                false => session_store::erase().await,
+            }
+            .map_err(ManagerError::SessionNotSaved)
+        }
+        ParsedBleMessage::GetEventLog(get_event_log_command) => {
+            debug!("GetEventLog: {:?}", get_event_log_command);
+
+            ble_server::send_event(BleEvent::EventLog(event_log::entries(
+                get_event_log_command.clear,
+            )));
+            // The client knows about the fault now
+            supervisor::clear_fault().await;
+            Ok(())
+        }
+        // Handled by IsotpBleBridge::handle_ble_message
+        _ => Ok(()),
     }
 }
 
@@ -201,29 +1265,137 @@ pub async fn isotp_ble_bridge_ble_rx_task() {
     info!("BLE IsoTP bridge BLE task started");
 
     loop {
-        let parsed_message = ISOTP_BLE_CHANNEL.receive().await;
-
-        // Brief critical section
-        match ISOTP_BLE_BRIDGE
-            .lock()
-            .await
-            .handle_ble_message(&parsed_message)
-            .await
-        {
-            Ok(_) => (),
-            Err(e) => error!("Error handling BLE message: {:?}", e),
+        supervisor::heartbeat(MonitoredTask::Bridge);
+        let Ok(parsed_message) =
+            with_timeout(supervisor::HEARTBEAT_INTERVAL, ISOTP_BLE_CHANNEL.receive()).await
+        else {
+            continue;
+        };
+
+        // The bridge is only locked for commands that never wait, handler tasks lock it
+        // to expire filters
+        let result = match is_unlocked(&parsed_message) {
+            true => handle_unlocked_message(&parsed_message).await,
+            false => ISOTP_BLE_BRIDGE
+                .lock()
+                .await
+                .handle_ble_message(&parsed_message),
+        };
+        if let Err(e) = result {
+            reject(e);
         }
+    }
+}
+
+// Count and report a command that failed, in the bridge or later in a handler's task
+fn reject(error: ManagerError) {
+    COMMANDS_REJECTED.fetch_add(1, Ordering::Relaxed);
+    event_bus::publish(BusEvent::CommandRejected { code: error.code() });
+}
 
-        // blink led
-        led::blink().await;
+// How often handlers are checked for missed response deadlines, due keepalives and
+// receptions held off with WAIT
+const TIMEOUT_CHECK_INTERVAL: Duration = Duration::from_millis(10);
+
+static COMMANDS_REJECTED: AtomicU32 = AtomicU32::new(0);
+
+// Set by the bridge when it accepts RunSelfTest, cleared once the report is out
+static SELF_TEST_RUNNING: AtomicBool = AtomicBool::new(false);
+// Picked up by the first slot's task, which is free while the self-test may run
+static SELF_TEST_REQUESTED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+fn refuse_during_self_test() -> Result<(), ManagerError> {
+    match SELF_TEST_RUNNING.load(Ordering::Acquire) {
+        true => Err(ManagerError::SelfTestConflict),
+        false => Ok(()),
     }
 }
 
+/// Traffic counters, handler slots and buffer pools at a glance
+pub fn statistics() -> BridgeStatistics {
+    let (pdus_sent, pdus_received) = isotp_handler::pdu_counts();
+    let (responses_dropped, responses_evicted) = ble_server::responses_dropped();
+    let routes = || HANDLER_SLOTS.iter().filter_map(HandlerSlot::route);
+    BridgeStatistics {
+        pdus_sent,
+        pdus_received,
+        commands_rejected: COMMANDS_REJECTED.load(Ordering::Relaxed),
+        sniffer_events_dropped: ble_server::sniffer_events_dropped(),
+        pdus_suppressed: isotp_handler::pdus_suppressed(),
+        responses_dropped,
+        responses_evicted,
+        active_handlers: routes().count() as u8,
+        ephemeral_handlers: routes().filter(|route| route.ephemeral).count() as u8,
+        small_buffers_in_use: pdu_buffer::in_use(BufferClass::Small) as u8,
+        small_buffer_count: pdu_buffer::pool_size(BufferClass::Small) as u8,
+        large_buffers_in_use: pdu_buffer::in_use(BufferClass::Large) as u8,
+        large_buffer_count: pdu_buffer::pool_size(BufferClass::Large) as u8,
+        errors: isotp_handler::error_counts(),
+    }
+}
+
+/// Queue a relayed PDU on the handler talking on these ids, false if it's gone or busy
+pub fn relay_pdu(request_arbitration_id: u32, reply_arbitration_id: u32, pdu: PduBuffer) -> bool {
+    find_slot(request_arbitration_id, reply_arbitration_id)
+        .is_some_and(|slot| slot.jobs.try_send(SlotJob::Relay(pdu)).is_ok())
+}
+
+/// Report a command that failed to parse, counted with the ones the bridge rejected
+pub fn reject_unparsed(buffer: &[u8], reason: ParseError) {
+    COMMANDS_REJECTED.fetch_add(1, Ordering::Relaxed);
+    ble_server::send_event(BleEvent::CommandRejected {
+        command: buffer.first().copied().unwrap_or(0),
+        reason,
+    });
+}
+
 // Helper functions to send messages to the IsoTP task
 pub async fn handle_ble_message(message: ParsedBleMessage) {
+    // Straight to the transmitting handler, a cancellation mustn't wait behind other
+    // commands
+    if let ParsedBleMessage::CancelIsotpTransmission(command) = &message {
+        isotp_handler::request_abort(command.request_arbitration_id, command.reply_arbitration_id);
+        return;
+    }
+
     ISOTP_BLE_CHANNEL.send(message).await;
 }
 
-pub async fn handle_can_message(message: CanMessage) {
-    ISOTP_CAN_CHANNEL.send(message).await;
+/// Tear down everything the disconnected client set up, commands it left queued included
+pub async fn end_session() {
+    ISOTP_BLE_CHANNEL.clear();
+    ISOTP_BLE_BRIDGE.lock().await.end_session();
+
+    // No handlers, so no keepalives or flow control going out. Outside the bridge lock,
+    // a send in progress may take a while.
+    for slot in HANDLER_SLOTS.iter() {
+        // Stop routing frames first, then wait out a send in progress
+        slot.free();
+        let mut handler = slot.handler.lock().await;
+        *handler = None;
+        slot.frames.clear();
+        slot.jobs.clear();
+    }
+    event_bus::publish(BusEvent::SessionEnded);
+    session_store::clear();
+    info!("ISO-TP session ended");
+}
+
+pub fn handle_can_message(message: CanMessage) {
+    // The transmitting handler waits for flow control while its slot is locked,
+    // so it can't go through the slot's frame queue
+    if isotp_handler::deliver_flow_control(&message) {
+        return;
+    }
+
+    // Several handlers can share an id, e.g. a functional filter and a physical one
+    for slot in HANDLER_SLOTS.iter() {
+        if !slot.route().is_some_and(|route| route.accepts(&message)) {
+            continue;
+        }
+        // Waiting here would let one stuck handler stall all the others
+        if slot.frames.try_send(message.clone()).is_err() {
+            warn!("Handler for {:x} is behind, dropping frame", message.id);
+        }
+    }
 }
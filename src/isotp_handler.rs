@@ -1,286 +1,1702 @@
-use core::sync::atomic::{AtomicU8, Ordering};
-use defmt::{debug, error, info};
+use core::cell::Cell;
+use defmt::{debug, error, info, warn, Format};
+use embassy_futures::select::{select, Either};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::channel::Channel;
+use embassy_sync::signal::Signal;
+use embassy_time::{with_deadline, with_timeout, Duration, Instant, Timer};
 use heapless::Vec;
-use portable_atomic::AtomicU16;
+use isotp_core::frame::{
+    self, decode_first_frame, decode_flow_control, decode_single_frame, is_valid_st_min, Frame,
+    FrameFormat, CONTINUE_TO_SEND, FLOW_CONTROL, OVERFLOW, WAIT,
+};
+use isotp_core::transfer::{
+    self, Clock, FrameSink, Receiver, RxError, RxState, Transmitter, TxState,
+};
+use portable_atomic::{AtomicU32, Ordering};
 
-use crate::ble_protocol::IsoTpMessage;
+use crate::ble_protocol::{
+    BleEvent, Direction, IsoTpMessage, IsotpErrorCount, ObdPollEntry, MAX_SECURITY_ACCESS_DATA,
+    STREAM_SEGMENT_SIZE,
+};
 use crate::ble_server::{self};
-use crate::can_manager;
+use crate::can_manager::{self, CanMessage, TxPacer, MAX_FRAME_LEN};
+use crate::event_bus::{self, BusEvent};
+use crate::isotp_ble_bridge;
+use crate::obd_poller::ObdPoller;
+use crate::pdu_buffer::{BufferClass, PduBuffer, SMALL_BUFFER_SIZE};
+use crate::periodic_messages::PeriodicMessages;
 
-// ISO-15765 constants
-const SF_DL_MAX: usize = 7; // Single Frame max data length
-const FF_DL_MAX: usize = 4095; // First Frame max data length
-const CF_DL_MAX: usize = 7; // Consecutive Frame max data length
+// Frames are as large as the backend allows, 8 bytes on classic CAN
+const TX_DL: usize = MAX_FRAME_LEN;
 
-// Frame types
-const SINGLE_FRAME: u8 = 0x00;
-const FIRST_FRAME: u8 = 0x10;
-const CONSECUTIVE_FRAME: u8 = 0x20;
-const FLOW_CONTROL: u8 = 0x30;
-
-// Flow Status
-const CONTINUE_TO_SEND: u8 = 0x00;
-const WAIT: u8 = 0x01;
-const OVERFLOW: u8 = 0x02;
+/// Largest message we buffer in either direction. Anything above FF_DL_MAX goes out
+/// with the ISO 15765-2:2016 escape sequence (32 bit FF_DL).
+pub const MAX_PDU_SIZE: usize = 8192;
 
 // Default timing parameters (in milliseconds)
 const DEFAULT_ST_MIN: u8 = 0x0A; // 10ms
 const DEFAULT_BLOCK_SIZE: u8 = 0x00; // Send all frames
 
-const DEFAULT_TX_PAD_BYTE: u8 = 0x55;
+pub const DEFAULT_TX_PAD_BYTE: u8 = 0x55;
+
+// Consecutive FC WAIT frames we accept before giving up on a transmission
+const DEFAULT_WFT_MAX: u8 = 10;
+
+// N_As: how long one of our frames may take to get onto the bus
+const N_AS_TIMEOUT: Duration = Duration::from_micros(transfer::N_AS_TIMEOUT_US);
+
+// Application layer response supervision. P2 is generous to absorb gateway and BLE
+// scheduling delays, P2* is the UDS default.
+const DEFAULT_P2_TIMEOUT: Duration = Duration::from_millis(1000);
+const DEFAULT_P2_STAR_TIMEOUT: Duration = Duration::from_millis(5000);
+
+// UDS negative response with NRC requestCorrectlyReceived-ResponsePending
+const UDS_NEGATIVE_RESPONSE: u8 = 0x7F;
+const UDS_RESPONSE_PENDING: u8 = 0x78;
+
+// UDS SecurityAccess and its positive response
+const UDS_SECURITY_ACCESS: u8 = 0x27;
+const UDS_SECURITY_ACCESS_RESPONSE: u8 = 0x67;
+// Reported when the ECU grants access, no negative response code is zero
+const SECURITY_ACCESS_GRANTED: u8 = 0x00;
+
+// UDS TesterPresent with the suppress positive response bit, keeps a session alive
+const UDS_TESTER_PRESENT: [u8; 2] = [0x3E, 0x80];
+
+// Transmissions at least this long report their progress to the client
+const PROGRESS_MIN_LENGTH: usize = 512;
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(250);
+
+// A flow control frame is useless once the sender's N_Bs has run out
+const FC_TX_TTL: Duration = Duration::from_micros(transfer::N_BS_TIMEOUT_US);
+
+// Consecutive frames a flashing handler keeps queued or on the bus, one on the wire and
+// the next ready behind it keeps the bus busy without filling can2040's transmit queue
+const TX_BURST_FRAMES: usize = 2;
+
+pub use isotp_core::transfer::IsotpError;
+
+/// Highest IsotpError code
+pub const MAX_ISOTP_ERROR_CODES: usize = IsotpError::PduTooLongToNotify as usize;
+
+/// What a handler does with the PDUs it receives besides handing them to the client
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum RelayMode {
+    Off = 0x00,
+    // Retransmitted on the target, the client doesn't see them
+    Forward = 0x01,
+    // Retransmitted on the target and a copy goes to the client
+    Mirror = 0x02,
+}
+
+impl TryFrom<u8> for RelayMode {
+    type Error = ();
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0x00 => Ok(RelayMode::Off),
+            0x01 => Ok(RelayMode::Forward),
+            0x02 => Ok(RelayMode::Mirror),
+            _ => Err(()),
+        }
+    }
+}
+
+// Keeps a chattering ECU from saturating the BLE side. Only PDUs nobody asked for are held
+// back, answers to requests always go through.
+struct Throttle {
+    // Least time between two PDUs handed to the client
+    min_interval: Duration,
+    // A PDU identical to the last one is dropped within this window
+    dedup_window: Duration,
+    // When the last PDU went to the client, and a digest of it
+    last_forwarded: Option<(Instant, u32)>,
+}
+
+// FNV-1a, enough to tell repeated PDUs apart
+fn pdu_digest(pdu: &[u8]) -> u32 {
+    pdu.iter().fold(0x811C_9DC5, |hash, &byte| {
+        (hash ^ byte as u32).wrapping_mul(0x0100_0193)
+    })
+}
+
+/// Handler whose ids received PDUs are retransmitted on
+#[derive(Debug, Clone, Copy, Format)]
+pub struct Relay {
+    pub request_arbitration_id: u32,
+    pub reply_arbitration_id: u32,
+    pub mirror: bool,
+}
+
+static PDUS_SENT: AtomicU32 = AtomicU32::new(0);
+static PDUS_RECEIVED: AtomicU32 = AtomicU32::new(0);
+static PDUS_SUPPRESSED: AtomicU32 = AtomicU32::new(0);
+// Indexed by error code - 1
+static ERROR_COUNTS: [AtomicU32; MAX_ISOTP_ERROR_CODES] =
+    [const { AtomicU32::new(0) }; MAX_ISOTP_ERROR_CODES];
+
+// Handlers with FlashingMode on
+static FLASHING_HANDLERS: AtomicU32 = AtomicU32::new(0);
+
+/// Whether a handler is reflashing an ECU. Its consecutive frames go out in bursts, the
+/// other handlers hold back their polls and periodic messages and per-frame logging is
+/// off, it costs more time than a frame takes on the bus.
+pub fn flashing_mode() -> bool {
+    FLASHING_HANDLERS.load(Ordering::Relaxed) > 0
+}
+
+fn count_error(error: IsotpError) {
+    ERROR_COUNTS[error as usize - 1].fetch_add(1, Ordering::Relaxed);
+}
+
+/// PDUs sent and received by all handlers since boot
+pub fn pdu_counts() -> (u32, u32) {
+    (
+        PDUS_SENT.load(Ordering::Relaxed),
+        PDUS_RECEIVED.load(Ordering::Relaxed),
+    )
+}
+
+/// Unsolicited PDUs held back from the client by a filter's throttle since boot
+pub fn pdus_suppressed() -> u32 {
+    PDUS_SUPPRESSED.load(Ordering::Relaxed)
+}
+
+/// How often each error has been reported since boot, errors that never happened are left out
+pub fn error_counts() -> Vec<IsotpErrorCount, MAX_ISOTP_ERROR_CODES> {
+    ERROR_COUNTS
+        .iter()
+        .enumerate()
+        .filter_map(|(index, count)| {
+            let count = count.load(Ordering::Relaxed);
+            (count > 0).then_some(IsotpErrorCount {
+                error: index as u8 + 1,
+                count,
+            })
+        })
+        .collect()
+}
+
+pub use isotp_core::frame::ProtocolError;
+
+/// Per-handler parameters, given when a filter is configured or changed later with the
+/// ConfigureIsotpTiming command
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum IsotpParameter {
+    WftMax = 0x01,
+    // Extended addressing (N_AE) byte put in front of every frame we send, above 0xFF disables
+    TxAddressExtension = 0x02,
+    // Extended addressing byte expected in front of every frame we receive, above 0xFF disables
+    RxAddressExtension = 0x03,
+    // Block size advertised in our flow control frames
+    RxBlockSize = 0x04,
+    // STmin advertised in our flow control frames
+    RxStMin = 0x05,
+    // Milliseconds to wait for a response after a request, 0 disables supervision
+    P2Timeout = 0x06,
+    // Milliseconds to wait after a UDS response pending (0x78)
+    P2StarTimeout = 0x07,
+    // Milliseconds between TesterPresent keepalives, 0 disables
+    TesterPresentPeriod = 0x08,
+    // 1 forwards received flow control to the client and leaves sending ours to it
+    FlowControlPassThrough = 0x09,
+    // 1 sends 29-bit frames, 0 sends 11-bit frames
+    ExtendedIds = 0x0A,
+    // Data bytes per consecutive frame we send, 0 uses the whole frame
+    TxConsecutiveFramePayload = 0x0B,
+    // Byte used to pad the frames we send to full length
+    TxPadByte = 0x0C,
+    // 1 forwards PDUs longer than a segment to the client in segments as they arrive
+    StreamReceptions = 0x0D,
+    // 1 makes this the flashing handler, see flashing_mode()
+    FlashingMode = 0x0E,
+}
+
+impl TryFrom<u8> for IsotpParameter {
+    type Error = ();
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0x01 => Ok(IsotpParameter::WftMax),
+            0x02 => Ok(IsotpParameter::TxAddressExtension),
+            0x03 => Ok(IsotpParameter::RxAddressExtension),
+            0x04 => Ok(IsotpParameter::RxBlockSize),
+            0x05 => Ok(IsotpParameter::RxStMin),
+            0x06 => Ok(IsotpParameter::P2Timeout),
+            0x07 => Ok(IsotpParameter::P2StarTimeout),
+            0x08 => Ok(IsotpParameter::TesterPresentPeriod),
+            0x09 => Ok(IsotpParameter::FlowControlPassThrough),
+            0x0A => Ok(IsotpParameter::ExtendedIds),
+            0x0B => Ok(IsotpParameter::TxConsecutiveFramePayload),
+            0x0C => Ok(IsotpParameter::TxPadByte),
+            0x0D => Ok(IsotpParameter::StreamReceptions),
+            0x0E => Ok(IsotpParameter::FlashingMode),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Decode an STmin byte, reserved values are the longest valid gap (127ms)
+pub fn st_min_to_duration(st_min: u8) -> Duration {
+    Duration::from_micros(frame::st_min_to_micros(st_min) as u64)
+}
+
+// Deadlines of the transfer state machines are on the embassy clock
+struct EmbassyClock;
+
+impl Clock for EmbassyClock {
+    fn now_micros(&self) -> u64 {
+        Instant::now().as_micros()
+    }
+}
+
+const CLOCK: EmbassyClock = EmbassyClock;
+
+// The transfer state machines hand over at most one frame per step, sent once the step
+// returns since sending waits for the CAN task
+#[derive(Default)]
+struct NextFrame(Option<Frame>);
+
+impl FrameSink for NextFrame {
+    fn send(&mut self, frame: &[u8]) -> Result<(), IsotpError> {
+        self.0 = Some(Frame::from_slice(frame).map_err(|_| IsotpError::FailedToSend)?);
+        Ok(())
+    }
+}
+
+// Addressing of a handler waiting for flow control
+#[derive(Clone, Copy)]
+struct FlowControlSource {
+    request_arbitration_id: u32,
+    first_reply_arbitration_id: u32,
+    last_reply_arbitration_id: u32,
+    address_extension: Option<u8>,
+    // Replies are only taken in the handler's id format
+    extended: bool,
+}
+
+impl FlowControlSource {
+    fn overlaps(&self, other: &FlowControlSource) -> bool {
+        self.extended == other.extended
+            && self.first_reply_arbitration_id <= other.last_reply_arbitration_id
+            && other.first_reply_arbitration_id <= self.last_reply_arbitration_id
+    }
+
+    fn is_reply(&self, message: &CanMessage) -> bool {
+        message.extended == self.extended
+            && (self.first_reply_arbitration_id..=self.last_reply_arbitration_id)
+                .contains(&message.id)
+    }
+}
+
+// Multi-frame transmissions to different ECUs that can wait for flow control at once
+const TX_LANE_COUNT: usize = 4;
+// How often a transmission checks for a lane while none can be taken
+const TX_LANE_POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+// Flow control and cancellation for one multi-frame transmission
+struct TxLane {
+    flow_control: Channel<CriticalSectionRawMutex, CanMessage, 4>,
+    abort: Signal<CriticalSectionRawMutex, ()>,
+}
+
+impl TxLane {
+    const fn new() -> Self {
+        Self {
+            flow_control: Channel::new(),
+            abort: Signal::new(),
+        }
+    }
+}
+
+static TX_LANES: [TxLane; TX_LANE_COUNT] = [const { TxLane::new() }; TX_LANE_COUNT];
+// Who each lane belongs to, None while it's free
+static TX_LANE_SOURCES: Mutex<
+    CriticalSectionRawMutex,
+    Cell<[Option<FlowControlSource>; TX_LANE_COUNT]>,
+> = Mutex::new(Cell::new([None; TX_LANE_COUNT]));
+
+// FCs are told apart by their id alone, so a peer that is already being sent to has to
+// wait for that transmission to finish
+fn try_claim_tx_lane(source: FlowControlSource) -> Option<usize> {
+    TX_LANE_SOURCES.lock(|sources| {
+        let mut current = sources.get();
+        if current
+            .iter()
+            .flatten()
+            .any(|other| other.overlaps(&source))
+        {
+            return None;
+        }
+        let index = current.iter().position(Option::is_none)?;
+        current[index] = Some(source);
+        sources.set(current);
+        Some(index)
+    })
+}
+
+async fn claim_tx_lane(source: FlowControlSource) -> usize {
+    let index = loop {
+        if let Some(index) = try_claim_tx_lane(source) {
+            break index;
+        }
+        Timer::after(TX_LANE_POLL_INTERVAL).await;
+    };
+
+    // Drop flow control and cancellations left over from an earlier transfer
+    TX_LANES[index].flow_control.clear();
+    TX_LANES[index].abort.reset();
+    index
+}
+
+fn release_tx_lane(index: usize) {
+    TX_LANE_SOURCES.lock(|sources| {
+        let mut current = sources.get();
+        current[index] = None;
+        sources.set(current);
+    });
+}
+
+/// Ask the handler transmitting between these ids to stop. Handled between frames,
+/// a cancellation for a handler that isn't transmitting has no effect.
+pub fn request_abort(request_arbitration_id: u32, reply_arbitration_id: u32) {
+    let sources = TX_LANE_SOURCES.lock(|sources| sources.get());
+    let lane = sources.iter().position(|source| {
+        source.is_some_and(|source| {
+            source.request_arbitration_id == request_arbitration_id
+                && source.first_reply_arbitration_id == reply_arbitration_id
+        })
+    });
+    if let Some(index) = lane {
+        TX_LANES[index].abort.signal(());
+    }
+}
+
+// Normal fixed addressing, physical requests: priority 6, PF 0xDA, then N_TA and N_SA
+const NORMAL_FIXED_PHYSICAL_BASE: u32 = 0x18DA_0000;
+
+/// 29-bit identifier for a physically addressed frame from `source` to `target`
+pub fn normal_fixed_id(target_address: u8, source_address: u8) -> u32 {
+    NORMAL_FIXED_PHYSICAL_BASE | ((target_address as u32) << 8) | source_address as u32
+}
+
+// Physical request id of the ECU answering on `reply_arbitration_id`: normal fixed ids
+// swap their addresses, 11-bit ids follow the OBD layout with the reply 8 above the request
+fn physical_request_id(reply_arbitration_id: u32) -> u32 {
+    if reply_arbitration_id & 0xFFFF_0000 == NORMAL_FIXED_PHYSICAL_BASE {
+        normal_fixed_id(
+            reply_arbitration_id as u8,
+            (reply_arbitration_id >> 8) as u8,
+        )
+    } else {
+        reply_arbitration_id.wrapping_sub(8)
+    }
+}
+
+/// Flow control frames bypass the bridge and go straight to the transmitting handler.
+/// Only frames from the peer it is talking to count, FCs from other testers on the bus
+/// must not change its pacing. Returns false for frames that aren't such a flow control.
+pub fn deliver_flow_control(message: &CanMessage) -> bool {
+    let sources = TX_LANE_SOURCES.lock(|sources| sources.get());
+    let lane = sources.iter().position(|source| {
+        source.is_some_and(|source| {
+            if !source.is_reply(message) {
+                return false;
+            }
+            let pci_index = match source.address_extension {
+                Some(extension) if message.data.first() == Some(&extension) => 1,
+                Some(_) => return false,
+                None => 0,
+            };
+            message
+                .data
+                .get(pci_index)
+                .is_some_and(|pci| pci & 0xF0 == FLOW_CONTROL)
+        })
+    });
+    let Some(index) = lane else {
+        return false;
+    };
+
+    if TX_LANES[index]
+        .flow_control
+        .try_send(message.clone())
+        .is_err()
+    {
+        warn!(
+            "Flow control queue full, dropping frame from {:x}",
+            message.id
+        );
+    }
+    true
+}
+
+/// Functional addressing state: single frame requests go out on a functional id and the
+/// single frame answers of every ECU in the reply id range are collected for a window
+struct FunctionalCollection {
+    window: Duration,
+    // Set while a collection window is open
+    deadline: Option<Instant>,
+    responses: u8,
+    // Multi-frame answers from ECUs without a filter of their own get a temporary
+    // handler, up to this many at a time
+    max_ephemeral_handlers: u8,
+}
 
 pub struct IsotpHandler {
     pub request_arbitration_id: u32,
     pub reply_arbitration_id: u32,
-    rx_buffer: Vec<u8, 4096>,
-    tx_buffer: Vec<u8, 4096>,
-    tx_index: AtomicU8,
-    st_min: AtomicU8,
-    block_size: AtomicU8,
-    expected_sequence_number: AtomicU8,
-    remaining_block_size: AtomicU8,
-    expected_length: AtomicU16,
+    // Replies are accepted from reply_arbitration_id..=last_reply_arbitration_id
+    last_reply_arbitration_id: u32,
+    // Claimed from the pool in the size class the filter asked for
+    rx_buffer: PduBuffer,
+    receiver: Receiver,
+    wft_max: u8,
+    tx_address_extension: Option<u8>,
+    rx_address_extension: Option<u8>,
+    // Flow control parameters we ask senders to use
+    rx_block_size: u8,
+    rx_st_min: u8,
+    // Set while we're waiting for the ECU to answer a request
+    response_deadline: Option<Instant>,
+    // Client's tag for that request, echoed with the answer
+    correlation_tag: Option<u16>,
+    // Sub-function of a SecurityAccess step waiting for the ECU's answer
+    security_access: Option<u8>,
+    obd_poller: ObdPoller,
+    periodic_messages: PeriodicMessages,
+    // Whether the last answer was positive, until a macro waiting for it takes it
+    last_response: Option<bool>,
+    // Transport-level man in the middle: PDUs received from the peer go out again on
+    // another handler
+    relay: Option<Relay>,
+    throttle: Option<Throttle>,
+    p2_timeout: Duration,
+    p2_star_timeout: Duration,
+    functional: Option<FunctionalCollection>,
+    // Opt-in keepalive, due is None while it's disabled
+    tester_present_period: Duration,
+    tester_present_due: Option<Instant>,
+    // Raw mode for ECUs with nonstandard transport behaviour: received FCs are forwarded
+    // to the client and ours are only sent when it asks
+    flow_control_pass_through: bool,
+    // Id format of every frame we send, standard and extended handlers can share a bus
+    extended_ids: bool,
+    // Shorter consecutive frames for ECUs and gateways that choke on full-length ones
+    tx_cf_payload: Option<u8>,
+    tx_pad_byte: u8,
+    // Large reads reach the client while the rest is still on the bus
+    stream_receptions: bool,
+    // Reflashing an ECU, see flashing_mode()
+    flashing: bool,
+    // When the last frame handled was received, a PDU is complete as of its last frame
+    last_frame_received_at: Option<Instant>,
+    // Receive buffer claimed ahead of time, see claim_spare()
+    spare: Option<PduBuffer>,
 }
 
 impl IsotpHandler {
-    pub fn new(request_arbitration_id: u32, reply_arbitration_id: u32) -> Self {
+    pub fn new(
+        request_arbitration_id: u32,
+        reply_arbitration_id: u32,
+        rx_buffer: PduBuffer,
+    ) -> Self {
+        let spare = Self::claim_spare(rx_buffer.class());
         Self {
             request_arbitration_id,
             reply_arbitration_id,
-            rx_buffer: Vec::new(),
-            tx_buffer: Vec::new(),
-            tx_index: AtomicU8::new(0),
-            st_min: AtomicU8::new(DEFAULT_ST_MIN),
-            block_size: AtomicU8::new(DEFAULT_BLOCK_SIZE),
-            expected_sequence_number: AtomicU8::new(0),
-            remaining_block_size: AtomicU8::new(0),
-            expected_length: AtomicU16::new(0),
+            last_reply_arbitration_id: reply_arbitration_id,
+            rx_buffer,
+            receiver: Receiver::new(),
+            wft_max: DEFAULT_WFT_MAX,
+            tx_address_extension: None,
+            rx_address_extension: None,
+            rx_block_size: DEFAULT_BLOCK_SIZE,
+            rx_st_min: DEFAULT_ST_MIN,
+            response_deadline: None,
+            correlation_tag: None,
+            security_access: None,
+            obd_poller: ObdPoller::new(),
+            periodic_messages: PeriodicMessages::new(),
+            last_response: None,
+            relay: None,
+            throttle: None,
+            p2_timeout: DEFAULT_P2_TIMEOUT,
+            p2_star_timeout: DEFAULT_P2_STAR_TIMEOUT,
+            functional: None,
+            tester_present_period: Duration::from_ticks(0),
+            tester_present_due: None,
+            flow_control_pass_through: false,
+            extended_ids: can_manager::is_extended_id(request_arbitration_id),
+            tx_cf_payload: None,
+            tx_pad_byte: DEFAULT_TX_PAD_BYTE,
+            stream_receptions: false,
+            flashing: false,
+            last_frame_received_at: None,
+            spare,
+        }
+    }
+
+    // Deterministic builds keep the buffer the next PDU is received into claimed ahead of
+    // time, so handing a PDU over never depends on what is left in the pool
+    fn claim_spare(class: BufferClass) -> Option<PduBuffer> {
+        if cfg!(feature = "deterministic") {
+            PduBuffer::claim(class)
+        } else {
+            None
+        }
+    }
+
+    /// Handler that transmits on one request id and accepts replies from a range of ids,
+    /// e.g. 0x7E8..=0x7EF. Each PDU is delivered tagged with the id it actually came from
+    pub fn new_with_reply_range(
+        request_arbitration_id: u32,
+        first_reply_arbitration_id: u32,
+        last_reply_arbitration_id: u32,
+        rx_buffer: PduBuffer,
+    ) -> Self {
+        let mut handler = Self::new(
+            request_arbitration_id,
+            first_reply_arbitration_id,
+            rx_buffer,
+        );
+        handler.last_reply_arbitration_id = last_reply_arbitration_id;
+        handler
+    }
+
+    /// Handler for functional requests, answers from first..=last reply id are collected
+    /// for `window` after each request
+    pub fn new_functional(
+        request_arbitration_id: u32,
+        first_reply_arbitration_id: u32,
+        last_reply_arbitration_id: u32,
+        window: Duration,
+        max_ephemeral_handlers: u8,
+        rx_buffer: PduBuffer,
+    ) -> Self {
+        let mut handler = Self::new_with_reply_range(
+            request_arbitration_id,
+            first_reply_arbitration_id,
+            last_reply_arbitration_id,
+            rx_buffer,
+        );
+        handler.functional = Some(FunctionalCollection {
+            window,
+            deadline: None,
+            responses: 0,
+            max_ephemeral_handlers,
+        });
+        handler
+    }
+
+    /// Temporary physical handler for a multi-frame answer to one of our functional
+    /// requests, receiving the way we do. None if no buffer is free.
+    pub fn new_ephemeral(&self, reply_arbitration_id: u32) -> Option<Self> {
+        let rx_buffer = PduBuffer::claim(self.rx_buffer.class())?;
+        let mut handler = Self::new(
+            physical_request_id(reply_arbitration_id),
+            reply_arbitration_id,
+            rx_buffer,
+        );
+        handler.tx_address_extension = self.tx_address_extension;
+        handler.rx_address_extension = self.rx_address_extension;
+        handler.rx_block_size = self.rx_block_size;
+        handler.rx_st_min = self.rx_st_min;
+        handler.tx_pad_byte = self.tx_pad_byte;
+        handler.stream_receptions = self.stream_receptions;
+        handler.extended_ids = self.extended_ids;
+        Some(handler)
+    }
+
+    pub fn is_functional(&self) -> bool {
+        self.functional.is_some()
+    }
+
+    /// How many ephemeral handlers a First Frame from `id` may still count on, 0 if it
+    /// isn't an answer to a functional request of ours
+    pub fn ephemeral_handler_limit(&self, id: u32, data: &[u8]) -> u8 {
+        let Some(functional) = &self.functional else {
+            return 0;
+        };
+        let pci_index = self.rx_address_extension.map_or(0, |_| 1);
+        let is_first_frame = data.get(pci_index).is_some_and(|pci| pci >> 4 == 1);
+        if functional.deadline.is_none() || !is_first_frame || !self.is_reply_id(id) {
+            return 0;
+        }
+        functional.max_ephemeral_handlers
+    }
+
+    /// Last id of the reply range, the same as the reply id for a single reply id
+    pub fn last_reply_arbitration_id(&self) -> u32 {
+        self.last_reply_arbitration_id
+    }
+
+    /// Whether the handler talks in extended frames, set by ExtendedIds
+    pub fn extended_ids(&self) -> bool {
+        self.extended_ids
+    }
+
+    fn is_reply_id(&self, id: u32) -> bool {
+        (self.reply_arbitration_id..=self.last_reply_arbitration_id).contains(&id)
+    }
+
+    /// Handler for normal fixed addressing, requests go to the ECU and replies come back swapped
+    pub fn new_normal_fixed(source_address: u8, target_address: u8, rx_buffer: PduBuffer) -> Self {
+        Self::new(
+            normal_fixed_id(target_address, source_address),
+            normal_fixed_id(source_address, target_address),
+            rx_buffer,
+        )
+    }
+
+    /// Update a configurable parameter, returns false if the value is out of range
+    pub fn set_parameter(&mut self, parameter: IsotpParameter, value: u32) -> bool {
+        match parameter {
+            IsotpParameter::WftMax => match u8::try_from(value) {
+                Ok(wft_max) => self.wft_max = wft_max,
+                Err(_) => return false,
+            },
+            IsotpParameter::TxAddressExtension => {
+                self.tx_address_extension = u8::try_from(value).ok()
+            }
+            IsotpParameter::RxAddressExtension => {
+                self.rx_address_extension = u8::try_from(value).ok()
+            }
+            IsotpParameter::RxBlockSize => match u8::try_from(value) {
+                Ok(block_size) => self.rx_block_size = block_size,
+                Err(_) => return false,
+            },
+            IsotpParameter::RxStMin => match u8::try_from(value) {
+                Ok(st_min) if is_valid_st_min(st_min) => self.rx_st_min = st_min,
+                _ => return false,
+            },
+            IsotpParameter::P2Timeout => self.p2_timeout = Duration::from_millis(value as u64),
+            IsotpParameter::P2StarTimeout => {
+                self.p2_star_timeout = Duration::from_millis(value as u64)
+            }
+            IsotpParameter::TesterPresentPeriod => {
+                self.tester_present_period = Duration::from_millis(value as u64);
+                self.postpone_tester_present();
+            }
+            IsotpParameter::FlowControlPassThrough => match value {
+                0 | 1 => self.flow_control_pass_through = value == 1,
+                _ => return false,
+            },
+            IsotpParameter::ExtendedIds => match value {
+                0 if can_manager::is_extended_id(self.request_arbitration_id) => return false,
+                0 | 1 => self.extended_ids = value == 1,
+                _ => return false,
+            },
+            IsotpParameter::TxConsecutiveFramePayload => match value {
+                0 => self.tx_cf_payload = None,
+                1..=0xFF => self.tx_cf_payload = Some(value as u8),
+                _ => return false,
+            },
+            IsotpParameter::TxPadByte => match u8::try_from(value) {
+                Ok(pad_byte) => self.tx_pad_byte = pad_byte,
+                Err(_) => return false,
+            },
+            IsotpParameter::StreamReceptions => match value {
+                0 | 1 => self.stream_receptions = value == 1,
+                _ => return false,
+            },
+            IsotpParameter::FlashingMode => match value {
+                0 | 1 => self.set_flashing(value == 1),
+                _ => return false,
+            },
+        }
+        true
+    }
+
+    fn set_flashing(&mut self, flashing: bool) {
+        if flashing == self.flashing {
+            return;
+        }
+        // Only the first handler to start and the last one to stop are worth an event
+        let transition = if flashing {
+            FLASHING_HANDLERS.fetch_add(1, Ordering::Relaxed) == 0
+        } else {
+            FLASHING_HANDLERS.fetch_sub(1, Ordering::Relaxed) == 1
+        };
+        if transition {
+            event_bus::publish(BusEvent::Flashing { active: flashing });
         }
+        self.flashing = flashing;
+    }
+
+    // Another handler is flashing and has the bus to itself
+    fn standing_back(&self) -> bool {
+        !self.flashing && flashing_mode()
     }
 
-    pub async fn handle_received_can_frame(&mut self, id: u32, data: &[u8]) {
+    pub async fn handle_received_can_frame(
+        &mut self,
+        id: u32,
+        data: &[u8],
+        received_at: Option<Instant>,
+    ) {
+        self.last_frame_received_at = received_at;
+
+        // With extended addressing the first byte has to be ours and isn't part of the PCI
+        let data = match self.rx_address_extension {
+            Some(extension) if data.first() == Some(&extension) => &data[1..],
+            Some(_) => {
+                debug!("Ignoring frame for another address extension");
+                return;
+            }
+            None => data,
+        };
+
         if data.is_empty() {
             return;
         }
 
+        if let Some(functional) = &self.functional {
+            // Only answers within the window count, and only single frames can be collected
+            if functional.deadline.is_none() || data[0] >> 4 != 0 {
+                debug!(
+                    "Ignoring frame from {:x} outside a functional collection",
+                    id
+                );
+                return;
+            }
+        }
+
         let frame_type = data[0] >> 4;
         match frame_type {
             0 => self.handle_single_frame(id, data).await,
             1 => self.handle_first_frame(id, data).await,
             2 => self.handle_consecutive_frame(id, data).await,
-            3 => self.handle_flow_control(id, data).await,
-            _ => error!("Unknown frame type: {}", frame_type),
+            3 => self.forward_flow_control(id, data),
+            _ => {
+                error!("Unknown frame type: {}", frame_type);
+                self.report_protocol_error(id, ProtocolError::UnknownFrameType, data);
+            }
         }
     }
 
-    pub async fn send_isotp_message(&mut self, id: u32, data: &[u8]) -> bool {
-        if data.len() <= SF_DL_MAX {
+    /// Send a message. Transmissions are serialized by `&mut self`, but a multi-frame
+    /// reception may still be in flight and its FC/CF frames would interleave with ours.
+    pub async fn send_isotp_message(&mut self, id: u32, data: &[u8]) -> Result<(), IsotpError> {
+        let result = if self.is_receiving() {
+            Err(IsotpError::Busy)
+        } else if self.functional.is_some() {
+            self.send_functional_request(id, data).await
+        } else if data.len() <= self.frame_format().single_frame_max() {
             self.send_single_frame(id, data).await
         } else {
-            self.send_multi_frame(id, data).await
+            let lane = claim_tx_lane(FlowControlSource {
+                request_arbitration_id: self.request_arbitration_id,
+                first_reply_arbitration_id: self.reply_arbitration_id,
+                last_reply_arbitration_id: self.last_reply_arbitration_id,
+                address_extension: self.rx_address_extension,
+                extended: self.extended_ids,
+            })
+            .await;
+            let result = self.send_multi_frame(id, data, &TX_LANES[lane]).await;
+            release_tx_lane(lane);
+            result
+        };
+
+        if result.is_ok() {
+            PDUS_SENT.fetch_add(1, Ordering::Relaxed);
+            event_bus::publish(BusEvent::PduSent {
+                request_arbitration_id: id,
+                length: data.len() as u32,
+            });
+            // The request keeps the session alive just as well
+            self.postpone_tester_present();
+        }
+
+        match result {
+            // Functional requests are supervised by their collection window
+            Ok(_) if self.functional.is_some() => (),
+            Ok(_) if self.p2_timeout.as_ticks() > 0 => {
+                self.response_deadline = Some(Instant::now() + self.p2_timeout);
+            }
+            Ok(_) => (),
+            // Already reported with the number of bytes sent
+            Err(IsotpError::Aborted) => (),
+            Err(e) => self.report_error(e),
         }
+        result
     }
 
-    fn pad_frame(frame: &mut Vec<u8, 8>) {
-        while frame.len() < 8 {
-            frame.extend_from_slice(&[DEFAULT_TX_PAD_BYTE]).unwrap();
+    /// Send a request whose answer, or timeout, is reported with the client's tag.
+    /// `response_timeout` replaces P2 for this request, zero leaves it unsupervised.
+    pub async fn send_correlated_message(
+        &mut self,
+        id: u32,
+        data: &[u8],
+        correlation_tag: Option<u16>,
+        response_timeout: Option<Duration>,
+    ) -> Result<(), IsotpError> {
+        let result = self.send_isotp_message(id, data).await;
+        // A request that didn't go out gets no answer, an earlier one may still be pending
+        if result.is_ok() {
+            self.correlation_tag = correlation_tag;
+            match response_timeout {
+                Some(timeout) if self.functional.is_none() => {
+                    self.response_deadline =
+                        (timeout.as_ticks() > 0).then(|| Instant::now() + timeout);
+                }
+                _ => (),
+            }
         }
+        result
     }
 
-    async fn send_single_frame(&self, id: u32, data: &[u8]) -> bool {
-        let mut frame = Vec::<u8, 8>::new();
-        frame
-            .extend_from_slice(&[SINGLE_FRAME | (data.len() as u8)])
+    /// Send a UDS SecurityAccess step. The ECU's answer goes to the client as a seed or
+    /// result event rather than a PDU, so it can compute the key without parsing responses.
+    pub async fn send_security_access(
+        &mut self,
+        sub_function: u8,
+        key: &[u8],
+    ) -> Result<(), IsotpError> {
+        let mut request: Vec<u8, { 2 + MAX_SECURITY_ACCESS_DATA }> = Vec::new();
+        request
+            .extend_from_slice(&[UDS_SECURITY_ACCESS, sub_function])
             .unwrap();
-        frame.extend_from_slice(data).unwrap();
-        Self::pad_frame(&mut frame);
-        can_manager::send_message(id, &frame).await
+        request.extend_from_slice(key).unwrap();
+
+        self.send_isotp_message(self.request_arbitration_id, &request)
+            .await?;
+        self.security_access = Some(sub_function);
+        Ok(())
     }
 
-    async fn send_multi_frame(&mut self, id: u32, data: &[u8]) -> bool {
-        // Send First Frame
-        let mut frame = Vec::<u8, 8>::new();
-        let length = data.len();
-        frame
-            .extend_from_slice(&[FIRST_FRAME | ((length >> 8) as u8), length as u8])
-            .unwrap();
-        frame.extend_from_slice(&data[0..6]).unwrap();
-        // First frame is already 8 bytes, no padding needed
+    // Event for the answer to a SecurityAccess step, None if rx_buffer holds something else
+    fn security_access_event(&mut self, reply_arbitration_id: u32) -> Option<BleEvent> {
+        let sub_function = self.security_access?;
 
-        if !can_manager::send_message(id, &frame).await {
-            return false;
+        let result = |response_code| BleEvent::SecurityAccessResult {
+            request_arbitration_id: self.request_arbitration_id,
+            reply_arbitration_id,
+            sub_function,
+            response_code,
+        };
+        let event = match self.rx_buffer.as_slice() {
+            // The real answer follows within P2*
+            [UDS_NEGATIVE_RESPONSE, UDS_SECURITY_ACCESS, UDS_RESPONSE_PENDING, ..] => return None,
+            [UDS_NEGATIVE_RESPONSE, UDS_SECURITY_ACCESS, response_code, ..] => {
+                result(*response_code)
+            }
+            [UDS_SECURITY_ACCESS_RESPONSE, level, seed @ ..] if *level == sub_function => {
+                // A zero seed means the level is already unlocked
+                if sub_function % 2 == 0 || seed.iter().all(|&byte| byte == 0) {
+                    result(SECURITY_ACCESS_GRANTED)
+                } else {
+                    let seed = Vec::from_slice(seed).ok()?;
+                    BleEvent::SecuritySeed {
+                        request_arbitration_id: self.request_arbitration_id,
+                        reply_arbitration_id,
+                        sub_function,
+                        seed,
+                    }
+                }
+            }
+            _ => return None,
+        };
+
+        self.security_access = None;
+        Some(event)
+    }
+
+    /// Hold back unsolicited PDUs that come faster than `min_interval`, or repeat the last
+    /// one within `dedup_window`. Zero turns either off.
+    pub fn set_throttle(&mut self, min_interval: Duration, dedup_window: Duration) {
+        self.throttle =
+            (min_interval.as_ticks() > 0 || dedup_window.as_ticks() > 0).then_some(Throttle {
+                min_interval,
+                dedup_window,
+                last_forwarded: None,
+            });
+    }
+
+    /// Retransmit PDUs received from the peer on another handler, None stops relaying
+    pub fn set_relay(&mut self, relay: Option<Relay>) {
+        self.relay = relay;
+    }
+
+    /// Poll PIDs on this handler's ECU, replacing any earlier list
+    pub fn configure_obd_poll(&mut self, entries: &[ObdPollEntry]) {
+        self.obd_poller.configure(entries);
+    }
+
+    /// Send the most overdue PID poll. Held back while a transfer or response is in flight
+    /// or another handler is flashing, called periodically by the bridge.
+    pub async fn send_obd_poll_if_due(&mut self) {
+        if self.transfer_in_flight() || self.standing_back() {
+            return;
         }
+        let Some(request) = self.obd_poller.next_request() else {
+            return;
+        };
 
-        // Store remaining data in tx buffer
-        self.tx_buffer.clear();
-        self.tx_buffer.extend_from_slice(&data[6..]).unwrap();
-        self.tx_index.store(1, Ordering::Release);
+        // Failures are reported like those of the client's own requests
+        if self
+            .send_isotp_message(self.request_arbitration_id, &request)
+            .await
+            .is_err()
+        {
+            self.obd_poller.clear_pending();
+        }
+    }
 
-        let mut sequence_number: u8 = 1;
-        let mut data_index = 6;
+    /// Cycle through the first `count` messages in `data`, one every `interval`. False if
+    /// the slot doesn't exist or the messages don't add up.
+    pub fn start_periodic_message(
+        &mut self,
+        index: u8,
+        interval: Duration,
+        data: &[u8],
+        count: u16,
+    ) -> bool {
+        self.periodic_messages.start(index, interval, data, count)
+    }
 
-        while data_index < data.len() {
-            // Wait for ST_MIN
-            let st_min = self.st_min.load(Ordering::Acquire);
-            if st_min > 0 {
-                embassy_time::Timer::after(embassy_time::Duration::from_millis(st_min as u64))
-                    .await;
-            }
+    pub fn stop_periodic_message(&mut self, index: u8) -> bool {
+        self.periodic_messages.stop(index)
+    }
+
+    /// Send the next periodic message that's due. Held back while a transfer or response is
+    /// in flight or another handler is flashing, called periodically by the bridge.
+    pub async fn send_periodic_message_if_due(&mut self) {
+        if self.transfer_in_flight() || self.standing_back() {
+            return;
+        }
+        let Some(message) = self.periodic_messages.next_message() else {
+            return;
+        };
 
-            let mut frame = Vec::<u8, 8>::new();
-            frame
-                .push(CONSECUTIVE_FRAME | (sequence_number & 0x0F))
-                .unwrap();
+        // Failures are reported like those of the client's own requests
+        let _ = self
+            .send_isotp_message(self.request_arbitration_id, &message)
+            .await;
+    }
 
-            let remaining = data.len() - data_index;
-            let chunk_size = remaining.min(CF_DL_MAX);
-            frame
-                .extend_from_slice(&data[data_index..data_index + chunk_size])
-                .unwrap();
-            Self::pad_frame(&mut frame);
+    // Functional addressing can't do flow control, so requests must fit a single frame
+    async fn send_functional_request(&mut self, id: u32, data: &[u8]) -> Result<(), IsotpError> {
+        if data.len() > self.frame_format().single_frame_max() {
+            return Err(IsotpError::FunctionalTooLong);
+        }
 
-            if !can_manager::send_message(id, &frame).await {
-                return false;
-            }
+        self.send_single_frame(id, data).await?;
 
-            data_index += chunk_size;
-            sequence_number = if sequence_number == 0x0F {
-                0
-            } else {
-                sequence_number + 1
-            };
+        if let Some(functional) = &mut self.functional {
+            functional.responses = 0;
+            functional.deadline = Some(Instant::now() + functional.window);
+        }
+        Ok(())
+    }
+
+    fn report_error(&self, error: IsotpError) {
+        count_error(error);
+        event_bus::publish(BusEvent::IsotpError {
+            request_arbitration_id: self.request_arbitration_id,
+            reply_arbitration_id: self.reply_arbitration_id,
+            error,
+            correlation_tag: self.correlation_tag,
+        });
+    }
+
+    // The offending PCI byte goes along so the client can tell what the peer sent
+    fn report_protocol_error(&self, id: u32, error: ProtocolError, data: &[u8]) {
+        event_bus::publish(BusEvent::IsotpProtocolError {
+            request_arbitration_id: self.request_arbitration_id,
+            reply_arbitration_id: id,
+            error,
+            pci: data.first().copied().unwrap_or_default(),
+        });
+    }
+
+    // Queue a frame, giving up if it can't be handed to the CAN task within N_As
+    async fn send_frame(&self, id: u32, frame: &[u8]) -> Result<(), IsotpError> {
+        let send = can_manager::send_message_with_format(id, self.extended_ids, frame, None);
+        match with_timeout(N_AS_TIMEOUT, send).await {
+            Ok(true) => Ok(()),
+            Ok(false) => Err(IsotpError::FailedToSend),
+            Err(_) => Err(IsotpError::TimeoutAs),
+        }
+    }
+
+    fn frame_format(&self) -> FrameFormat {
+        FrameFormat {
+            frame_len: TX_DL,
+            address_extension: self.tx_address_extension,
+            pad_byte: self.tx_pad_byte,
+            consecutive_frame_payload: self.tx_cf_payload,
+        }
+    }
+
+    // Send the frame a transfer state machine step produced, if any
+    async fn send_next_frame(&self, id: u32, next_frame: NextFrame) -> Result<(), IsotpError> {
+        match next_frame.0 {
+            Some(frame) => self.send_frame(id, &frame).await,
+            None => Ok(()),
+        }
+    }
+
+    async fn send_single_frame(&self, id: u32, data: &[u8]) -> Result<(), IsotpError> {
+        self.send_frame(id, &self.frame_format().single_frame(data))
+            .await
+    }
 
-            let block_size = self.block_size.load(Ordering::Acquire);
-            if block_size > 0 {
-                let mut remaining = self.remaining_block_size.load(Ordering::Acquire);
-                remaining -= 1;
-                if remaining == 0 {
-                    // Wait for next Flow Control frame
-                    // Note: In a complete implementation, you would want to add timeout handling here
-                    self.remaining_block_size
-                        .store(block_size, Ordering::Release);
+    async fn send_multi_frame(
+        &self,
+        id: u32,
+        data: &[u8],
+        lane: &TxLane,
+    ) -> Result<(), IsotpError> {
+        let mut transmitter = Transmitter::new(self.frame_format(), self.wft_max);
+        let mut first_frame = NextFrame::default();
+        transmitter.start(data, &mut first_frame, &CLOCK)?;
+        self.send_next_frame(id, first_frame).await?;
+
+        // The receiver tells us how to pace the first block
+        let mut pacer = TxPacer::new();
+        let mut next_progress = Instant::now() + PROGRESS_INTERVAL;
+
+        while !transmitter.is_idle() {
+            // The handler's slot stays locked for the whole transfer, so cancellation
+            // can't go through it and is signalled on the lane instead
+            let step = self.advance_transmission(&mut transmitter, id, data, lane, &mut pacer);
+            let outcome = select(step, lane.abort.wait()).await;
+            match outcome {
+                Either::First(result) => {
+                    result?;
+                    let sent = transmitter.sent();
+                    if data.len() >= PROGRESS_MIN_LENGTH
+                        && (sent == data.len() || Instant::now() >= next_progress)
+                    {
+                        next_progress = Instant::now() + PROGRESS_INTERVAL;
+                        ble_server::send_event(BleEvent::IsotpTransmitProgress {
+                            request_arbitration_id: self.request_arbitration_id,
+                            reply_arbitration_id: self.reply_arbitration_id,
+                            bytes_sent: sent as u32,
+                            total_length: data.len() as u32,
+                        });
+                    }
+                }
+                Either::Second(()) => {
+                    let bytes_sent = transmitter.abort();
+                    info!(
+                        "Transmission to {:x} aborted after {} bytes",
+                        id, bytes_sent
+                    );
+                    event_bus::publish(BusEvent::IsotpTransmissionAborted {
+                        request_arbitration_id: self.request_arbitration_id,
+                        reply_arbitration_id: self.reply_arbitration_id,
+                        bytes_sent: bytes_sent as u32,
+                    });
+                    count_error(IsotpError::Aborted);
+                    return Err(IsotpError::Aborted);
                 }
             }
         }
 
-        true
+        Ok(())
     }
 
-    async fn handle_single_frame(&mut self, _id: u32, data: &[u8]) {
-        let length = data[0] & 0x0F;
-        if length as usize > data.len() - 1 {
-            error!("Invalid SF length");
-            return;
+    // One step of a multi-frame transmission: wait for flow control or send the next CF
+    async fn advance_transmission(
+        &self,
+        transmitter: &mut Transmitter,
+        id: u32,
+        data: &[u8],
+        lane: &TxLane,
+        pacer: &mut TxPacer,
+    ) -> Result<(), IsotpError> {
+        match transmitter.state() {
+            TxState::Idle => Ok(()),
+            TxState::WaitingForFlowControl { deadline, .. } => {
+                let deadline = Instant::from_micros(deadline);
+                let Ok(frame) = with_deadline(deadline, lane.flow_control.receive()).await else {
+                    return transmitter.check_timeout(&CLOCK);
+                };
+
+                // deliver_flow_control already checked the address extension
+                let pci_index = self.rx_address_extension.map_or(0, |_| 1);
+                let data = frame.data.get(pci_index..).unwrap_or_default();
+                self.forward_flow_control(frame.id, data);
+                match decode_flow_control(data) {
+                    Ok(flow_control) => {
+                        if flow_control.flow_status == OVERFLOW {
+                            error!("Received OVERFLOW flow status");
+                        }
+                        transmitter.handle_flow_control(flow_control, &CLOCK)?;
+                        *pacer = TxPacer::new();
+                        Ok(())
+                    }
+                    // The frame's BS and STmin can't be trusted either, so stop here
+                    Err(error @ ProtocolError::ReservedFlowStatus) => {
+                        error!("Invalid flow status: {}", data[0] & 0x0F);
+                        self.report_protocol_error(frame.id, error, data);
+                        Err(IsotpError::InvalidFlowStatus)
+                    }
+                    Err(error) => {
+                        error!("Invalid FC frame length");
+                        self.report_protocol_error(frame.id, error, data);
+                        Ok(())
+                    }
+                }
+            }
+            TxState::SendingConsecutive { st_min, .. } => {
+                pacer.wait(st_min_to_duration(st_min)).await;
+
+                let mut next_frame = NextFrame::default();
+                transmitter.send_consecutive_frame(data, &mut next_frame, &CLOCK)?;
+                self.send_next_frame(id, next_frame).await?;
+
+                // STmin is only a lower bound, the next CF also waits for this one to be
+                // on the bus so a fast receiver can't overflow the controller's queue.
+                // A flashing handler keeps the next frames coming in the middle of a
+                // block instead, paced by the confirmations. N_As covers the time until
+                // the transmit confirmation.
+                let burst = self.flashing
+                    && matches!(transmitter.state(), TxState::SendingConsecutive { .. });
+                let confirmed = if burst {
+                    can_manager::wait_tx_below(TX_BURST_FRAMES, N_AS_TIMEOUT).await
+                } else {
+                    can_manager::wait_tx_drained(N_AS_TIMEOUT).await
+                };
+                match confirmed {
+                    true => Ok(()),
+                    false => Err(IsotpError::TimeoutAs),
+                }
+            }
         }
+    }
+
+    async fn handle_single_frame(&mut self, id: u32, data: &[u8]) {
+        let payload = match decode_single_frame(data) {
+            Ok(payload) => payload,
+            Err(e) => {
+                error!("Invalid SF length for {} byte frame", data.len());
+                self.report_protocol_error(id, e, data);
+                return;
+            }
+        };
 
         self.rx_buffer.clear();
-        self.rx_buffer
-            .extend_from_slice(&data[1..=length as usize])
-            .unwrap();
+        if self.rx_buffer.extend_from_slice(payload).is_err() {
+            self.report_error(IsotpError::RxOverflow);
+            return;
+        }
 
-        info!("Received complete message: {:02x}", self.rx_buffer);
+        if !flashing_mode() {
+            info!(
+                "Received complete message: {:02x}",
+                self.rx_buffer.as_slice()
+            );
+        }
 
-        // Send structured response to BLE client
-        let message = IsoTpMessage {
-            request_arbitration_id: self.request_arbitration_id,
-            reply_arbitration_id: self.reply_arbitration_id,
-            pdu: self.rx_buffer.clone(),
-        };
-        ble_server::send_isotp_response(message).await;
+        if let Some(functional) = &mut self.functional {
+            functional.responses = functional.responses.saturating_add(1);
+        }
+        // Tagged with the ECU it came from, the reply range may cover several
+        self.deliver_rx_buffer(id).await;
     }
 
     async fn handle_first_frame(&mut self, id: u32, data: &[u8]) {
-        if data.len() < 2 {
-            error!("Invalid FF length");
-            return;
+        let (length, first_chunk) = match decode_first_frame(data) {
+            Ok(decoded) => decoded,
+            Err(e) => {
+                error!("Invalid FF length for {} byte frame", data.len());
+                self.report_protocol_error(id, e, data);
+                return;
+            }
+        };
+
+        // The sender gave up on the previous transfer, start over with this one
+        if self.is_receiving() {
+            self.reset_rx();
+            self.report_error(IsotpError::ReceptionRestarted);
         }
 
-        let length = (((data[0] & 0x0F) as u16) << 8) | (data[1] as u16);
-        if length > FF_DL_MAX as u16 {
+        if length > self.rx_buffer.capacity() {
             error!("FF length too large: {}", length);
+            self.reset_rx();
+            self.send_flow_control(OVERFLOW).await;
+            self.report_error(IsotpError::RxOverflow);
             return;
         }
 
         self.rx_buffer.clear();
-        self.rx_buffer.extend_from_slice(&data[2..]).unwrap();
-        self.expected_length.store(length, Ordering::Release);
-        self.expected_sequence_number.store(1, Ordering::Release);
-
-        // Send Flow Control frame
-        let mut fc_frame = heapless::Vec::<u8, 8>::new();
-        fc_frame
-            .extend_from_slice(&[
-                FLOW_CONTROL | CONTINUE_TO_SEND,
-                DEFAULT_BLOCK_SIZE,
-                DEFAULT_ST_MIN,
-            ])
-            .unwrap();
-        Self::pad_frame(&mut fc_frame);
+        self.rx_buffer.extend_from_slice(first_chunk).unwrap();
+        // The response has started arriving, N_Cr supervises the rest of it
+        if self.is_reply_id(id) {
+            self.response_deadline = None;
+        }
+
+        // Data accepted now would only be dropped on the way out
+        if ble_server::response_queue_full() {
+            debug!("Response queue full, holding off {:x}", id);
+            self.receiver
+                .hold_off(id, length, first_chunk.len(), &CLOCK);
+            self.send_flow_control(WAIT).await;
+            return;
+        }
+
+        self.receiver.start(id, length, first_chunk.len(), &CLOCK);
+        self.send_flow_control(CONTINUE_TO_SEND).await;
+    }
 
-        // Send flow control frame asynchronously
-        can_manager::send_message(id, &fc_frame).await;
+    /// Let a throttled sender continue once the BLE side has room, or keep it waiting.
+    /// Called periodically by the bridge.
+    pub async fn resume_throttled_reception(&mut self) {
+        let room = !ble_server::response_queue_full();
+        match self.receiver.resume(room, &CLOCK) {
+            None => (),
+            Some(Ok(flow_status)) => self.send_flow_control(flow_status).await,
+            Some(Err(e)) => {
+                self.rx_buffer.clear();
+                self.send_flow_control(OVERFLOW).await;
+                self.report_error(e);
+            }
+        }
     }
 
-    async fn handle_consecutive_frame(&mut self, _id: u32, data: &[u8]) {
-        if data.len() < 2 {
-            error!("Invalid CF length");
+    async fn send_flow_control(&self, flow_status: u8) {
+        if self.flow_control_pass_through {
+            debug!("Flow control left to the client");
             return;
         }
+        self.send_flow_control_frame(flow_status, self.rx_block_size, self.rx_st_min)
+            .await;
+    }
+
+    /// Send a flow control frame the client asked for, only allowed in pass-through mode.
+    /// Nothing is checked, nonstandard values are the point.
+    pub async fn send_client_flow_control(
+        &self,
+        flow_status: u8,
+        block_size: u8,
+        st_min: u8,
+    ) -> Result<(), IsotpError> {
+        if !self.flow_control_pass_through {
+            return Err(IsotpError::PassThroughDisabled);
+        }
+        match self
+            .send_flow_control_frame(flow_status & 0x0F, block_size, st_min)
+            .await
+        {
+            true => Ok(()),
+            false => Err(IsotpError::FailedToSend),
+        }
+    }
 
-        let sequence_number = data[0] & 0x0F;
-        let expected = self.expected_sequence_number.load(Ordering::Acquire);
+    // Flow control goes back to the sender on the request id, never on the id it came in on
+    async fn send_flow_control_frame(&self, flow_status: u8, block_size: u8, st_min: u8) -> bool {
+        let fc_frame = self
+            .frame_format()
+            .flow_control(flow_status, block_size, st_min);
 
-        if sequence_number != expected {
-            error!(
-                "Unexpected sequence number. Expected: {}, got: {}",
-                expected, sequence_number
-            );
+        // Send flow control frame asynchronously, ahead of any queued frames
+        can_manager::send_flow_control(
+            self.request_arbitration_id,
+            self.extended_ids,
+            &fc_frame,
+            FC_TX_TTL,
+        )
+        .await
+    }
+
+    // In pass-through mode the client sees every flow control from the peer
+    fn forward_flow_control(&self, id: u32, data: &[u8]) {
+        if !self.flow_control_pass_through {
+            debug!("Flow control is consumed by the transmit path");
             return;
         }
+        let [pci, block_size, st_min, ..] = *data else {
+            error!("Invalid FC frame length");
+            return;
+        };
+        ble_server::send_event(BleEvent::IsotpFlowControl {
+            request_arbitration_id: self.request_arbitration_id,
+            reply_arbitration_id: id,
+            flow_status: pci & 0x0F,
+            block_size,
+            st_min,
+        });
+    }
 
-        self.rx_buffer.extend_from_slice(&data[1..]).unwrap();
+    async fn handle_consecutive_frame(&mut self, id: u32, data: &[u8]) {
+        let expected_length = match self.receiver.state() {
+            RxState::Receiving {
+                expected_length, ..
+            } => expected_length,
+            _ => 0,
+        };
 
-        let next_sequence = if expected == 0x0F { 0 } else { expected + 1 };
-        self.expected_sequence_number
-            .store(next_sequence, Ordering::Release);
+        let consecutive =
+            match self
+                .receiver
+                .handle_consecutive_frame(id, data, self.rx_block_size, &CLOCK)
+            {
+                Ok(consecutive) => consecutive,
+                Err(RxError::Protocol(e)) => {
+                    error!("Invalid CF length");
+                    self.report_protocol_error(id, e, data);
+                    return;
+                }
+                Err(RxError::Unexpected) => {
+                    debug!(
+                        "Ignoring CF from {:x}, no reception from it in progress",
+                        id
+                    );
+                    return;
+                }
+                Err(RxError::Transfer(e)) => {
+                    self.rx_buffer.clear();
+                    self.report_error(e);
+                    return;
+                }
+                Err(RxError::Sequence { expected, received }) => {
+                    error!(
+                        "Unexpected sequence number. Expected: {}, got: {}",
+                        expected, received
+                    );
+                    // The rest of the transfer can't be reassembled, drop it and let the
+                    // client retry
+                    self.rx_buffer.clear();
+                    event_bus::publish(BusEvent::IsotpSequenceError {
+                        request_arbitration_id: self.request_arbitration_id,
+                        reply_arbitration_id: id,
+                        expected,
+                        received,
+                    });
+                    return;
+                }
+            };
 
-        let expected_length = self.expected_length.load(Ordering::Acquire) as usize;
-        if self.rx_buffer.len() >= expected_length {
-            info!(
-                "Received complete multi-frame message: {:02x}",
-                self.rx_buffer
-            );
-            self.rx_buffer.truncate(expected_length);
+        let previous_len = self.rx_buffer.len();
+        if self.rx_buffer.extend_from_slice(consecutive.data).is_err() {
+            self.reset_rx();
+            self.report_error(IsotpError::RxOverflow);
+            return;
+        }
 
-            // Send structured response to BLE client
-            let message = IsoTpMessage {
+        let streaming = self.stream_receptions && expected_length > STREAM_SEGMENT_SIZE;
+        if streaming {
+            self.stream_segments(id, previous_len, expected_length)
+                .await;
+        }
+
+        if consecutive.complete {
+            if !flashing_mode() {
+                info!(
+                    "Received complete multi-frame message: {:02x}",
+                    self.rx_buffer.as_slice()
+                );
+            }
+
+            if streaming {
+                // The last segment completed the delivery
+                self.note_delivered_pdu(id);
+                self.rx_buffer.clear();
+            } else {
+                self.deliver_rx_buffer(id).await;
+            }
+            return;
+        }
+
+        // Block complete, let the sender continue with the next one
+        if consecutive.clear_to_send {
+            self.send_flow_control(CONTINUE_TO_SEND).await;
+        }
+    }
+
+    // Another tester's request seen on our request id doesn't answer ours
+    fn direction_of(&self, id: u32) -> Direction {
+        if self.is_reply_id(id) {
+            Direction::Response
+        } else {
+            Direction::Request
+        }
+    }
+
+    // Forward every segment completed by the bytes after `previous_len`, and the shorter
+    // last one once the whole PDU is in
+    async fn stream_segments(&self, source_id: u32, previous_len: usize, expected_length: usize) {
+        let len = self.rx_buffer.len();
+        let mut offset = previous_len / STREAM_SEGMENT_SIZE * STREAM_SEGMENT_SIZE;
+        while offset < len {
+            let end = (offset + STREAM_SEGMENT_SIZE).min(len);
+            if end - offset < STREAM_SEGMENT_SIZE && len < expected_length {
+                break;
+            }
+
+            ble_server::send_data_event(BleEvent::IsotpSegment {
                 request_arbitration_id: self.request_arbitration_id,
-                reply_arbitration_id: self.reply_arbitration_id,
-                pdu: self.rx_buffer.clone(),
+                reply_arbitration_id: source_id,
+                direction: self.direction_of(source_id),
+                offset: offset as u32,
+                total_length: expected_length as u32,
+                data: Vec::from_slice(&self.rx_buffer.as_slice()[offset..end]).unwrap(),
+            })
+            .await;
+            offset = end;
+        }
+    }
+
+    // Response supervision for a PDU in rx_buffer that has been handed to the client
+    fn note_delivered_pdu(&mut self, reply_arbitration_id: u32) -> Direction {
+        PDUS_RECEIVED.fetch_add(1, Ordering::Relaxed);
+        event_bus::publish(BusEvent::PduReceived {
+            reply_arbitration_id,
+            length: self.rx_buffer.len() as u32,
+        });
+        let direction = self.direction_of(reply_arbitration_id);
+
+        // 0x7F <sid> 0x78: the ECU needs longer, the real answer comes within P2*
+        if direction == Direction::Response {
+            self.response_deadline = match self.rx_buffer.as_slice() {
+                [UDS_NEGATIVE_RESPONSE, _, UDS_RESPONSE_PENDING, ..]
+                    if self.functional.is_none() =>
+                {
+                    debug!("Response pending, waiting up to P2*");
+                    Some(Instant::now() + self.p2_star_timeout)
+                }
+                _ => None,
             };
-            ble_server::send_isotp_response(message).await;
+            if self.response_deadline.is_none() {
+                self.last_response =
+                    Some(self.rx_buffer.as_slice().first() != Some(&UDS_NEGATIVE_RESPONSE));
+            }
         }
+        direction
     }
 
-    async fn handle_flow_control(&mut self, _id: u32, data: &[u8]) {
-        if data.len() < 3 {
-            error!("Invalid FC frame length");
+    /// Whether the answer that arrived since the last call was positive, None if there
+    /// was none
+    pub fn take_response(&mut self) -> Option<bool> {
+        self.last_response.take()
+    }
+
+    async fn deliver_rx_buffer(&mut self, reply_arbitration_id: u32) {
+        let awaited = self.transfer_in_flight();
+        let direction = self.note_delivered_pdu(reply_arbitration_id);
+        // Answers to requests the bridge made for the client go out as events
+        if direction == Direction::Response {
+            let request_arbitration_id = self.request_arbitration_id;
+            let event = self
+                .security_access_event(reply_arbitration_id)
+                .or_else(|| {
+                    self.obd_poller.take_sample(
+                        request_arbitration_id,
+                        reply_arbitration_id,
+                        self.rx_buffer.as_slice(),
+                    )
+                });
+            if let Some(event) = event {
+                ble_server::send_event(event);
+                self.rx_buffer.clear();
+                return;
+            }
+
+            if let Some(relay) = self.relay {
+                self.relay_rx_buffer(&relay);
+                if !relay.mirror {
+                    self.rx_buffer.clear();
+                    return;
+                }
+            }
+
+            if !awaited && self.throttled() {
+                PDUS_SUPPRESSED.fetch_add(1, Ordering::Relaxed);
+                self.rx_buffer.clear();
+                return;
+            }
+        }
+        let correlation_tag = match direction {
+            Direction::Request => None,
+            // Functional answers keep the tag until the collection window closes, a
+            // response pending keeps it for the real answer
+            Direction::Response
+                if self.functional.is_some() || self.response_deadline.is_some() =>
+            {
+                self.correlation_tag
+            }
+            Direction::Response => self.correlation_tag.take(),
+        };
+
+        // Longer ones have to be streamed in segments
+        if self.rx_buffer.len() > IsoTpMessage::MAX_NOTIFIED_PDU {
+            self.rx_buffer.clear();
+            self.report_error(IsotpError::PduTooLongToNotify);
             return;
         }
 
-        let flow_status = data[0] & 0x0F;
-        match flow_status {
-            CONTINUE_TO_SEND => {
-                self.block_size.store(data[1], Ordering::Release);
-                self.st_min.store(data[2], Ordering::Release);
+        let Some(pdu) = self.take_rx_buffer() else {
+            self.report_error(IsotpError::NoBufferAvailable);
+            return;
+        };
+
+        // Send structured response to BLE client
+        let message = IsoTpMessage {
+            request_arbitration_id: self.request_arbitration_id,
+            reply_arbitration_id,
+            direction,
+            correlation_tag,
+            received_at: self.last_frame_received_at,
+            pdu,
+        };
+        ble_server::send_isotp_response(message).await;
+    }
+
+    // Whether the throttle holds back the PDU in rx_buffer, otherwise it counts as forwarded
+    fn throttled(&mut self) -> bool {
+        let Some(throttle) = &mut self.throttle else {
+            return false;
+        };
+        let now = Instant::now();
+        let digest = pdu_digest(self.rx_buffer.as_slice());
+
+        if let Some((forwarded_at, last_digest)) = throttle.last_forwarded {
+            let elapsed = now - forwarded_at;
+            if elapsed < throttle.min_interval
+                || (digest == last_digest && elapsed < throttle.dedup_window)
+            {
+                return true;
             }
-            WAIT => {
-                debug!("Received WAIT flow status");
+        }
+        throttle.last_forwarded = Some((now, digest));
+        false
+    }
+
+    // Copy the received PDU to the relay target's queue, its task sends it
+    fn relay_rx_buffer(&mut self, relay: &Relay) {
+        let buffer_class = if self.rx_buffer.len() <= SMALL_BUFFER_SIZE {
+            BufferClass::Small
+        } else {
+            BufferClass::Large
+        };
+        let Some(mut pdu) = PduBuffer::claim(buffer_class) else {
+            self.report_error(IsotpError::NoBufferAvailable);
+            return;
+        };
+        pdu.extend_from_slice(self.rx_buffer.as_slice()).unwrap();
+
+        if !isotp_ble_bridge::relay_pdu(
+            relay.request_arbitration_id,
+            relay.reply_arbitration_id,
+            pdu,
+        ) {
+            self.report_error(IsotpError::RelayFailed);
+        }
+    }
+
+    // The filled buffer goes to the BLE side as is and the handler carries on with a fresh
+    // one. Short PDUs are copied into a small buffer instead, so they don't hold on to a
+    // large one while they wait to be sent.
+    fn take_rx_buffer(&mut self) -> Option<PduBuffer> {
+        if self.rx_buffer.class() != BufferClass::Small && self.rx_buffer.len() <= SMALL_BUFFER_SIZE
+        {
+            if let Some(mut pdu) = PduBuffer::claim(BufferClass::Small) {
+                pdu.extend_from_slice(self.rx_buffer.as_slice()).unwrap();
+                self.rx_buffer.clear();
+                return Some(pdu);
             }
-            OVERFLOW => {
-                error!("Received OVERFLOW flow status");
+        }
+
+        let replacement = match self.spare.take() {
+            Some(spare) => spare,
+            None => PduBuffer::claim(self.rx_buffer.class())?,
+        };
+        Some(core::mem::replace(&mut self.rx_buffer, replacement))
+    }
+
+    /// Report a response or consecutive frame that didn't arrive in time, called
+    /// periodically by the bridge
+    pub fn check_timeouts(&mut self) {
+        // Off the receive path, the spare handed over with the last PDU is replaced here
+        if self.spare.is_none() {
+            self.spare = Self::claim_spare(self.rx_buffer.class());
+        }
+
+        if let Some(functional) = &mut self.functional {
+            if functional
+                .deadline
+                .is_some_and(|deadline| Instant::now() > deadline)
+            {
+                functional.deadline = None;
+                ble_server::send_event(BleEvent::FunctionalWindowClosed {
+                    request_arbitration_id: self.request_arbitration_id,
+                    responses: functional.responses,
+                });
+                self.correlation_tag = None;
             }
-            _ => error!("Invalid flow status: {}", flow_status),
         }
+
+        if self
+            .response_deadline
+            .is_some_and(|deadline| Instant::now() > deadline)
+        {
+            self.response_deadline = None;
+            self.report_error(IsotpError::ResponseTimeout);
+            self.correlation_tag = None;
+            self.security_access = None;
+            self.obd_poller.clear_pending();
+        }
+
+        // The sender stalled mid-transfer, free the handler instead of waiting for a new FF
+        if let Err(e) = self.receiver.check_timeout(&CLOCK) {
+            self.rx_buffer.clear();
+            self.report_error(e);
+        }
+    }
+
+    /// Send a TesterPresent once the keepalive period has passed without another request.
+    /// Held back while a transfer or response is in flight, called periodically by the bridge.
+    pub async fn send_tester_present_if_due(&mut self) {
+        let Some(due) = self.tester_present_due else {
+            return;
+        };
+        if Instant::now() < due || self.transfer_in_flight() {
+            return;
+        }
+
+        self.postpone_tester_present();
+        debug!("Sending TesterPresent to {:x}", self.request_arbitration_id);
+        if let Err(e) = self
+            .send_single_frame(self.request_arbitration_id, &UDS_TESTER_PRESENT)
+            .await
+        {
+            self.report_error(e);
+        }
+    }
+
+    fn postpone_tester_present(&mut self) {
+        self.tester_present_due = (self.tester_present_period.as_ticks() > 0)
+            .then(|| Instant::now() + self.tester_present_period);
+    }
+
+    fn transfer_in_flight(&self) -> bool {
+        self.is_receiving()
+            || self.response_deadline.is_some()
+            || self
+                .functional
+                .as_ref()
+                .is_some_and(|functional| functional.deadline.is_some())
+    }
+
+    pub fn is_receiving(&self) -> bool {
+        self.receiver.is_receiving(&CLOCK)
+    }
+
+    fn reset_rx(&mut self) {
+        self.rx_buffer.clear();
+        self.receiver.reset();
+    }
+}
+
+impl Drop for IsotpHandler {
+    fn drop(&mut self) {
+        self.set_flashing(false);
     }
 }
@@ -0,0 +1,345 @@
+//! ISO-TP conformance self-test
+//! A handler is run against a scripted peer with the CAN controller in loopback mode, so
+//! framing, sequence numbering, padding and flow control can be checked without an ECU.
+//! The frames are written out by hand here rather than with the handler's own encoding.
+
+use defmt::{info, Format};
+use embassy_futures::join::join;
+use embassy_time::Duration;
+use heapless::Vec;
+
+use crate::ble_protocol::SelfTestResult;
+use crate::can_manager::{self, CanMessage, CanMode};
+use crate::isotp_handler::{self, IsotpError, IsotpHandler, IsotpParameter, DEFAULT_TX_PAD_BYTE};
+use crate::pdu_buffer::{BufferClass, PduBuffer};
+
+// Loopback keeps the test frames off the bus, so these never reach a real ECU
+const REQUEST_ID: u32 = 0x7F0;
+const REPLY_ID: u32 = 0x7F8;
+
+// Frames only pass through the CAN task, anything slower than this is a failure
+const FRAME_TIMEOUT: Duration = Duration::from_millis(100);
+// How long a sender has to stay quiet while it waits for flow control
+const QUIET_PERIOD: Duration = Duration::from_millis(50);
+
+// Flow control frames from the scripted peer
+const FC_CONTINUE_TO_SEND: u8 = 0x30;
+const FC_WAIT: u8 = 0x31;
+const FC_OVERFLOW: u8 = 0x32;
+
+// Block size the handler advertises while receiving in the tests
+const TEST_RX_BLOCK_SIZE: u8 = 2;
+
+pub const MAX_SELF_TEST_CASES: usize = 16;
+
+/// Checks reported in the self-test matrix
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum SelfTestCase {
+    // SF PCI, data and padding
+    SingleFrame = 0x01,
+    // FF PCI with the 12 bit length and the first six bytes
+    FirstFrame = 0x02,
+    // CF PCI and data after a CTS
+    ConsecutiveFrames = 0x03,
+    // Last CF padded to eight bytes
+    Padding = 0x04,
+    // Sequence numbers wrap from 0xF back to 0x0
+    SequenceWrap = 0x05,
+    // Sender stops after BS frames until the next CTS
+    BlockSize = 0x06,
+    // Sender keeps waiting through FC WAIT
+    FlowControlWait = 0x07,
+    // Sender gives up on FC OVERFLOW
+    FlowControlOverflow = 0x08,
+    // Receiver answers a FF with CTS and its BS/STmin
+    ReceiveFlowControl = 0x09,
+    // Receiver sends another CTS after each block
+    ReceiveBlockFlowControl = 0x0A,
+}
+
+/// Run every check and restore the previous CAN mode. None if no buffer could be claimed
+/// for the test handler. The PDU reassembled by the receive checks is delivered to the
+/// client like any other, on the self-test ids.
+pub async fn run() -> Option<Vec<SelfTestResult, MAX_SELF_TEST_CASES>> {
+    let rx_buffer = PduBuffer::claim(BufferClass::Small)?;
+    let mut handler = IsotpHandler::new(REQUEST_ID, REPLY_ID, rx_buffer);
+    // Nothing answers the requests, don't report missing responses
+    handler.set_parameter(IsotpParameter::P2Timeout, 0);
+    handler.set_parameter(IsotpParameter::RxBlockSize, TEST_RX_BLOCK_SIZE as u32);
+    handler.set_parameter(IsotpParameter::RxStMin, 0);
+
+    let previous_mode = can_manager::mode();
+    can_manager::set_mode(CanMode::Loopback);
+
+    let mut results = Vec::new();
+    let mut record = |case: SelfTestCase, passed: bool| {
+        info!(
+            "[selftest] {:?}: {}",
+            case,
+            if passed { "pass" } else { "FAIL" }
+        );
+        let _ = results.push(SelfTestResult {
+            case: case as u8,
+            passed,
+        });
+    };
+
+    record(SelfTestCase::SingleFrame, single_frame(&mut handler).await);
+
+    let (first_frame, consecutive_frames, padding) = multi_frame(&mut handler).await;
+    record(SelfTestCase::FirstFrame, first_frame);
+    record(SelfTestCase::ConsecutiveFrames, consecutive_frames);
+    record(SelfTestCase::Padding, padding);
+
+    record(
+        SelfTestCase::SequenceWrap,
+        sequence_wrap(&mut handler).await,
+    );
+    record(SelfTestCase::BlockSize, block_size(&mut handler).await);
+    record(
+        SelfTestCase::FlowControlWait,
+        flow_control_wait(&mut handler).await,
+    );
+    record(
+        SelfTestCase::FlowControlOverflow,
+        flow_control_overflow(&mut handler).await,
+    );
+
+    let (receive_flow_control, receive_block_flow_control) = receive(&mut handler).await;
+    record(SelfTestCase::ReceiveFlowControl, receive_flow_control);
+    record(
+        SelfTestCase::ReceiveBlockFlowControl,
+        receive_block_flow_control,
+    );
+
+    can_manager::set_mode(previous_mode);
+    Some(results)
+}
+
+fn pattern(len: usize) -> Vec<u8, 128> {
+    (0..len).map(|i| i as u8).collect()
+}
+
+async fn next_frame() -> Option<CanMessage> {
+    can_manager::loopback_receive(FRAME_TIMEOUT).await
+}
+
+// Nothing may be sent while the handler waits for flow control
+async fn stays_quiet() -> bool {
+    can_manager::loopback_receive(QUIET_PERIOD).await.is_none()
+}
+
+fn is_padded(frame: &CanMessage, data_end: usize) -> bool {
+    frame.data.len() == 8
+        && frame.data[data_end..]
+            .iter()
+            .all(|&byte| byte == DEFAULT_TX_PAD_BYTE)
+}
+
+// Flow control from the peer goes straight to the transmitting handler
+fn peer_flow_control(flow_status: u8, block_size: u8) {
+    let message = CanMessage {
+        id: REPLY_ID,
+        extended: false,
+        data: Vec::from_slice(&[flow_status, block_size, 0, 0, 0, 0, 0, 0]).unwrap(),
+        one_shot: false,
+        deadline: None,
+        received_at: None,
+    };
+    isotp_handler::deliver_flow_control(&message);
+}
+
+// Check CFs until `data` is complete or `limit` frames have arrived
+async fn expect_consecutive_frames(
+    data: &[u8],
+    offset: &mut usize,
+    sequence_number: &mut u8,
+    limit: usize,
+) -> bool {
+    for _ in 0..limit {
+        if *offset >= data.len() {
+            break;
+        }
+        let Some(frame) = next_frame().await else {
+            return false;
+        };
+
+        let chunk = (data.len() - *offset).min(7);
+        if frame.data.len() != 8
+            || frame.data[0] != 0x20 | *sequence_number
+            || frame.data[1..1 + chunk] != data[*offset..*offset + chunk]
+        {
+            return false;
+        }
+
+        *offset += chunk;
+        *sequence_number = (*sequence_number + 1) & 0x0F;
+    }
+    true
+}
+
+async fn single_frame(handler: &mut IsotpHandler) -> bool {
+    let data = [0x22, 0xF1, 0x90, 0x01, 0x02];
+    let (result, frame) = join(handler.send_isotp_message(REQUEST_ID, &data), next_frame()).await;
+    let Some(frame) = frame else {
+        return false;
+    };
+
+    result.is_ok()
+        && frame.id == REQUEST_ID
+        && is_padded(&frame, 6)
+        && frame.data[0] == 0x05
+        && frame.data[1..6] == data
+}
+
+// 18 bytes: FF with six, a full CF and a CF with five bytes and two padding
+async fn multi_frame(handler: &mut IsotpHandler) -> (bool, bool, bool) {
+    let data = pattern(18);
+    let (result, checks) = join(
+        handler.send_isotp_message(REQUEST_ID, &data),
+        multi_frame_peer(&data),
+    )
+    .await;
+    match checks {
+        Some(checks) if result.is_ok() => checks,
+        _ => (false, false, false),
+    }
+}
+
+async fn multi_frame_peer(data: &[u8]) -> Option<(bool, bool, bool)> {
+    let first = next_frame().await?;
+    let first_frame = first.data.len() == 8
+        && first.data[..2] == [0x10, data.len() as u8]
+        && first.data[2..] == data[..6];
+
+    peer_flow_control(FC_CONTINUE_TO_SEND, 0);
+    let second = next_frame().await?;
+    let last = next_frame().await?;
+    let consecutive_frames = second.data.len() == 8
+        && second.data[0] == 0x21
+        && second.data[1..] == data[6..13]
+        && last.data.len() == 8
+        && last.data[0] == 0x22
+        && last.data[1..6] == data[13..];
+
+    Some((first_frame, consecutive_frames, is_padded(&last, 6)))
+}
+
+// 120 bytes take 17 CFs, so the sequence number goes 1..=F, 0, 1
+async fn sequence_wrap(handler: &mut IsotpHandler) -> bool {
+    let data = pattern(120);
+    let peer = async {
+        if next_frame().await.is_none() {
+            return false;
+        }
+        peer_flow_control(FC_CONTINUE_TO_SEND, 0);
+        let (mut offset, mut sequence_number) = (6, 1);
+        expect_consecutive_frames(&data, &mut offset, &mut sequence_number, 17).await
+            && offset == data.len()
+    };
+    let (result, passed) = join(handler.send_isotp_message(REQUEST_ID, &data), peer).await;
+    result.is_ok() && passed
+}
+
+// 40 bytes in blocks of two CFs, with a quiet gap before every CTS
+async fn block_size(handler: &mut IsotpHandler) -> bool {
+    let data = pattern(40);
+    let peer = async {
+        if next_frame().await.is_none() {
+            return false;
+        }
+        let (mut offset, mut sequence_number) = (6, 1);
+        while offset < data.len() {
+            peer_flow_control(FC_CONTINUE_TO_SEND, 2);
+            if !expect_consecutive_frames(&data, &mut offset, &mut sequence_number, 2).await {
+                return false;
+            }
+            if offset < data.len() && !stays_quiet().await {
+                return false;
+            }
+        }
+        true
+    };
+    let (result, passed) = join(handler.send_isotp_message(REQUEST_ID, &data), peer).await;
+    result.is_ok() && passed
+}
+
+async fn flow_control_wait(handler: &mut IsotpHandler) -> bool {
+    let data = pattern(20);
+    let peer = async {
+        if next_frame().await.is_none() {
+            return false;
+        }
+        peer_flow_control(FC_WAIT, 0);
+        if !stays_quiet().await {
+            return false;
+        }
+        peer_flow_control(FC_CONTINUE_TO_SEND, 0);
+        let (mut offset, mut sequence_number) = (6, 1);
+        expect_consecutive_frames(&data, &mut offset, &mut sequence_number, 2).await
+    };
+    let (result, passed) = join(handler.send_isotp_message(REQUEST_ID, &data), peer).await;
+    result.is_ok() && passed
+}
+
+async fn flow_control_overflow(handler: &mut IsotpHandler) -> bool {
+    let data = pattern(20);
+    let peer = async {
+        if next_frame().await.is_none() {
+            return false;
+        }
+        peer_flow_control(FC_OVERFLOW, 0);
+        stays_quiet().await
+    };
+    let (result, passed) = join(handler.send_isotp_message(REQUEST_ID, &data), peer).await;
+    result == Err(IsotpError::ReceiverOverflow) && passed
+}
+
+fn consecutive_frame(sequence_number: u8, chunk: &[u8]) -> [u8; 8] {
+    let mut frame = [DEFAULT_TX_PAD_BYTE; 8];
+    frame[0] = 0x20 | sequence_number;
+    frame[1..1 + chunk.len()].copy_from_slice(chunk);
+    frame
+}
+
+fn is_clear_to_send(frame: &CanMessage) -> bool {
+    frame.id == REQUEST_ID
+        && is_padded(frame, 3)
+        && frame.data[..3] == [FC_CONTINUE_TO_SEND, TEST_RX_BLOCK_SIZE, 0]
+}
+
+// 40 bytes from the peer: FF, then five CFs with a CTS expected after every second one
+async fn receive(handler: &mut IsotpHandler) -> (bool, bool) {
+    let data = pattern(40);
+
+    let mut first_frame = [0u8; 8];
+    first_frame[..2].copy_from_slice(&[0x10, data.len() as u8]);
+    first_frame[2..].copy_from_slice(&data[..6]);
+    handler
+        .handle_received_can_frame(REPLY_ID, &first_frame, None)
+        .await;
+    let flow_control = next_frame()
+        .await
+        .is_some_and(|frame| is_clear_to_send(&frame));
+
+    let mut block_flow_control = true;
+    for (index, chunk) in data[6..].chunks(7).enumerate() {
+        let sequence_number = (index as u8 + 1) & 0x0F;
+        handler
+            .handle_received_can_frame(REPLY_ID, &consecutive_frame(sequence_number, chunk), None)
+            .await;
+
+        let end_of_block = (index + 1) % TEST_RX_BLOCK_SIZE as usize == 0;
+        let complete = 6 + (index + 1) * 7 >= data.len();
+        block_flow_control &= if end_of_block && !complete {
+            next_frame()
+                .await
+                .is_some_and(|frame| is_clear_to_send(&frame))
+        } else {
+            stays_quiet().await
+        };
+    }
+
+    (flow_control, flow_control && block_flow_control)
+}
@@ -1,31 +1,279 @@
+//! Status LED
+//! The onboard LED repeats a pattern for what the bridge is doing, so its state can be
+//! told at a glance without a client. From most to least important:
+//! - a panic, hard fault or watchdog reset the client hasn't seen yet: SOS
+//! - CAN restarts keep failing: two short flashes, then a pause
+//! - an ECU is being reflashed: fast, even blinking
+//! - a client is connected: steady on
+//! - advertising: a short flash every second
+//!
+//! PDUs sent or received briefly invert the pattern. Pulses are rate limited, a burst of
+//! traffic shows as steady flicker rather than queueing up blinks that play out long
+//! after the traffic is over.
+//!
+//! The device config can turn either of them off, or both, change the pulse length and
+//! move the pulses to an external LED. Another external LED can follow the onboard one,
+//! for enclosures the onboard one can't be seen through.
+
+use core::cell::RefCell;
+
 use cyw43::Control;
-use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
-use embassy_sync::channel::Channel;
-use embassy_time::{Duration, Timer};
+use defmt::info;
+use embassy_futures::select::{select, Either};
+use embassy_rp::gpio::{AnyPin, Level, Output};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_time::{Duration, Instant, Timer};
+
+use crate::config;
+use crate::event_bus::{self, BusEvent};
+use crate::supervisor;
+
+// Lit or not, and for how long, repeated for as long as the state lasts
+type Pattern = &'static [(bool, Duration)];
+
+const ADVERTISING_PATTERN: Pattern = &[
+    (true, Duration::from_millis(100)),
+    (false, Duration::from_millis(900)),
+];
+const CONNECTED_PATTERN: Pattern = &[(true, Duration::from_millis(1000))];
+const FLASHING_PATTERN: Pattern = &[
+    (true, Duration::from_millis(125)),
+    (false, Duration::from_millis(125)),
+];
+const SOS_PATTERN: Pattern = &[
+    (true, Duration::from_millis(150)),
+    (false, Duration::from_millis(150)),
+    (true, Duration::from_millis(150)),
+    (false, Duration::from_millis(150)),
+    (true, Duration::from_millis(150)),
+    (false, Duration::from_millis(450)),
+    (true, Duration::from_millis(450)),
+    (false, Duration::from_millis(150)),
+    (true, Duration::from_millis(450)),
+    (false, Duration::from_millis(150)),
+    (true, Duration::from_millis(450)),
+    (false, Duration::from_millis(450)),
+    (true, Duration::from_millis(150)),
+    (false, Duration::from_millis(150)),
+    (true, Duration::from_millis(150)),
+    (false, Duration::from_millis(150)),
+    (true, Duration::from_millis(150)),
+    (false, Duration::from_millis(1050)),
+];
+const BUS_OFF_PATTERN: Pattern = &[
+    (true, Duration::from_millis(100)),
+    (false, Duration::from_millis(150)),
+    (true, Duration::from_millis(100)),
+    (false, Duration::from_millis(900)),
+];
+
+// How long the LED shows bus-off after the last failing restart, unless frames come in
+const BUS_OFF_HOLD: Duration = Duration::from_secs(5);
+// Activity pulses start at least this many pulse lengths apart, traffic in between is
+// coalesced
+const ACTIVITY_SPACING: u32 = 3;
+
+type LedPin = Mutex<CriticalSectionRawMutex, RefCell<Option<Output<'static>>>>;
 
-pub static LED_CHANNEL: Channel<ThreadModeRawMutex, LedCommand, 4> = Channel::new();
+static ACTIVITY_PIN: LedPin = Mutex::new(RefCell::new(None));
+static STATUS_PIN: LedPin = Mutex::new(RefCell::new(None));
 
-#[derive(Debug, Clone, Copy)]
-pub enum LedCommand {
-    Blink,
+/// Claim the external LEDs named in the device config
+pub fn init() {
+    let config = config::get();
+
+    if let Some(gpio) = config.led_activity_gpio {
+        info!("[led] activity on gpio {}", gpio);
+        claim(&ACTIVITY_PIN, gpio);
+    }
+
+    if let Some(gpio) = config.led_status_gpio {
+        info!("[led] status on gpio {}", gpio);
+        claim(&STATUS_PIN, gpio);
+    }
+}
+
+fn claim(led: &LedPin, gpio: u8) {
+    // Safety: the config only takes pins that aren't in RESERVED_GPIOS and that no other
+    // config key has (DeviceConfig::pins_are_unique), and each LED is claimed once at boot
+    let pin = unsafe { AnyPin::steal(gpio) };
+    led.lock(|p| *p.borrow_mut() = Some(Output::new(pin, Level::Low)));
+}
+
+// Light an external LED, false if there is none
+fn set_external(led: &LedPin, lit: bool) -> bool {
+    led.lock(|p| match p.borrow_mut().as_mut() {
+        Some(output) => {
+            output.set_level(Level::from(lit));
+            true
+        }
+        None => false,
+    })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum LedState {
+    Advertising,
+    Connected,
+    Flashing,
+    CanBusOff,
+    Fault,
+}
+
+impl LedState {
+    fn pattern(self) -> Pattern {
+        match self {
+            LedState::Advertising => ADVERTISING_PATTERN,
+            LedState::Connected => CONNECTED_PATTERN,
+            LedState::Flashing => FLASHING_PATTERN,
+            LedState::CanBusOff => BUS_OFF_PATTERN,
+            LedState::Fault => SOS_PATTERN,
+        }
+    }
+}
+
+// What the bus said so far, the most important state wins
+#[derive(Default)]
+struct Status {
+    connected: bool,
+    flashing: bool,
+    bus_off_until: Option<Instant>,
+}
+
+impl Status {
+    fn update(&mut self, event: &BusEvent) {
+        match *event {
+            BusEvent::Connected => self.connected = true,
+            BusEvent::Disconnected => self.connected = false,
+            BusEvent::Flashing { active } => self.flashing = active,
+            BusEvent::CanBusOff { .. } => self.bus_off_until = Some(Instant::now() + BUS_OFF_HOLD),
+            // Errors while it's showing bus-off keep it there
+            BusEvent::CanError { .. } if self.bus_off_until.is_some() => {
+                self.bus_off_until = Some(Instant::now() + BUS_OFF_HOLD)
+            }
+            // The bus is back
+            BusEvent::PduReceived { .. } => self.bus_off_until = None,
+            _ => (),
+        }
+    }
+
+    fn state(&self) -> LedState {
+        if supervisor::fault_pending() {
+            LedState::Fault
+        } else if self
+            .bus_off_until
+            .is_some_and(|until| Instant::now() < until)
+        {
+            LedState::CanBusOff
+        } else if self.flashing {
+            LedState::Flashing
+        } else if self.connected {
+            LedState::Connected
+        } else {
+            LedState::Advertising
+        }
+    }
+}
+
+// Traffic indication on top of the pattern
+struct ActivityPulse {
+    // Showing a pulse until then
+    pulse_until: Option<Instant>,
+    // Traffic that hasn't been shown yet
+    pending: bool,
+    // Earliest start of the next pulse
+    next_pulse: Instant,
+}
+
+impl ActivityPulse {
+    const fn new() -> Self {
+        Self {
+            pulse_until: None,
+            pending: false,
+            next_pulse: Instant::MIN,
+        }
+    }
+
+    fn trigger(&mut self) {
+        self.pending = true;
+    }
+
+    // End a pulse that's over and start a pending one if it's allowed, whether a pulse
+    // is showing now
+    fn update(&mut self, now: Instant, pulse: Duration) -> bool {
+        if self.pulse_until.is_some_and(|until| now >= until) {
+            self.pulse_until = None;
+        }
+        if self.pending && self.pulse_until.is_none() && now >= self.next_pulse {
+            self.pending = false;
+            self.pulse_until = Some(now + pulse);
+            self.next_pulse = now + pulse * ACTIVITY_SPACING;
+        }
+        self.pulse_until.is_some()
+    }
+
+    // When update has something to do next, if anything
+    fn next_change(&self) -> Option<Instant> {
+        self.pulse_until.or(self.pending.then_some(self.next_pulse))
+    }
 }
 
 #[embassy_executor::task]
 pub async fn led_task(control: &'static mut Control<'static>) {
-    let receiver = LED_CHANNEL.receiver();
+    let mut events = event_bus::subscribe();
+    let mut status = Status::default();
+    let mut activity = ActivityPulse::new();
+    let mut state = status.state();
+    let mut step = 0;
+    let mut step_end = Instant::now();
+    let mut lit = false;
+    // What the LED was last set to
+    let mut shown = None;
 
     loop {
-        match receiver.receive().await {
-            LedCommand::Blink => {
-                control.gpio_set(0, true).await;
-                Timer::after(Duration::from_millis(10)).await;
-                control.gpio_set(0, false).await;
+        let now = Instant::now();
+        let config = config::get();
+        if status.state() != state {
+            state = status.state();
+            step = 0;
+            step_end = now;
+        }
+        if now >= step_end {
+            let pattern = state.pattern();
+            let duration;
+            (lit, duration) = pattern[step % pattern.len()];
+            step += 1;
+            step_end = now + duration;
+        }
+
+        let pulse =
+            activity.update(now, config.led_activity_pulse()) && config.led_mode.shows_activity();
+        // The external LED takes the pulses if there is one
+        let external = set_external(&ACTIVITY_PIN, pulse);
+        let level = (lit && config.led_mode.shows_status()) != (pulse && !external);
+        // Cheap enough to set every time, and the pin may only just have been claimed
+        set_external(&STATUS_PIN, level);
+        if shown != Some(level) {
+            control.gpio_set(0, level).await;
+            shown = Some(level);
+        }
+
+        let wake = activity
+            .next_change()
+            .map_or(step_end, |change| change.min(step_end));
+        if let Either::First(event) =
+            select(event_bus::next_event(&mut events), Timer::at(wake)).await
+        {
+            status.update(&event);
+            if config.led_mode.shows_activity()
+                && matches!(
+                    event,
+                    BusEvent::PduSent { .. } | BusEvent::PduReceived { .. }
+                )
+            {
+                activity.trigger();
             }
         }
     }
 }
-
-// Helper function to send blink commands
-pub async fn blink() {
-    LED_CHANNEL.send(LedCommand::Blink).await;
-}
@@ -0,0 +1,132 @@
+//! Log output
+//! defmt frames go out on the UART1 pins or, for developers without the UART harness, on
+//! a USB CDC-ACM serial port over the cable that powers the board. The `usb-log` feature
+//! makes USB the default and the device config can switch between them at runtime. USB
+//! output is buffered until a host opens the port, a full buffer drops log output rather
+//! than stalling the bridge.
+
+use core::ptr::{addr_of_mut, null_mut};
+
+use critical_section::RestoreState;
+use embassy_futures::join::join;
+use embassy_rp::peripherals::{UART1, USB};
+use embassy_rp::uart::{Blocking, Uart};
+use embassy_rp::usb::Driver;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::pipe::Pipe;
+use embassy_usb::class::cdc_acm::{CdcAcmClass, State};
+use embassy_usb::Builder;
+use portable_atomic::{AtomicBool, AtomicPtr, Ordering};
+
+use crate::config::{self, LogTarget};
+
+// Output held for the USB host, enough to cover boot until the port is opened
+const USB_LOG_BUFFER_SIZE: usize = 4096;
+// Full speed bulk endpoints
+const USB_PACKET_SIZE: usize = 64;
+// Placeholder ids, the same ones the embassy examples use
+const USB_VID: u16 = 0xc0de;
+const USB_PID: u16 = 0xcafe;
+
+static UART: AtomicPtr<Uart<'static, UART1, Blocking>> = AtomicPtr::new(null_mut());
+static USB_LOG: Pipe<CriticalSectionRawMutex, USB_LOG_BUFFER_SIZE> = Pipe::new();
+
+static TAKEN: AtomicBool = AtomicBool::new(false);
+// Only touched between acquire and release, inside the logger's critical section
+static mut RESTORE_STATE: RestoreState = RestoreState::invalid();
+static mut ENCODER: defmt::Encoder = defmt::Encoder::new();
+// Picked once per frame, a frame never straddles both outputs
+static mut FRAME_TARGET: LogTarget = LogTarget::Uart;
+
+#[defmt::global_logger]
+struct Logger;
+
+unsafe impl defmt::Logger for Logger {
+    fn acquire() {
+        // Safety: released again in release(), the frame is written with interrupts off
+        let restore = unsafe { critical_section::acquire() };
+        if TAKEN.swap(true, Ordering::Relaxed) {
+            panic!("defmt logger taken reentrantly");
+        }
+        // Safety: inside the critical section and the logger isn't taken by anyone else
+        unsafe {
+            RESTORE_STATE = restore;
+            FRAME_TARGET = config::get().log_target;
+            (*addr_of_mut!(ENCODER)).start_frame(write);
+        }
+    }
+
+    unsafe fn flush() {}
+
+    unsafe fn release() {
+        (*addr_of_mut!(ENCODER)).end_frame(write);
+        TAKEN.store(false, Ordering::Relaxed);
+        critical_section::release(RESTORE_STATE);
+    }
+
+    unsafe fn write(bytes: &[u8]) {
+        (*addr_of_mut!(ENCODER)).write(bytes, write);
+    }
+}
+
+fn write(bytes: &[u8]) {
+    // Safety: only called by the logger, between acquire and release
+    match unsafe { FRAME_TARGET } {
+        LogTarget::Uart => {
+            let uart = UART.load(Ordering::Acquire);
+            if !uart.is_null() {
+                // Safety: handed over once in init_uart, only used from inside the logger
+                let _ = unsafe { (*uart).blocking_write(bytes) };
+            }
+        }
+        // Whatever doesn't fit is lost
+        LogTarget::Usb => {
+            let _ = USB_LOG.try_write(bytes);
+        }
+    }
+}
+
+/// Hand the UART to the logger, output sent to it before this is lost
+pub fn init_uart(uart: &'static mut Uart<'static, UART1, Blocking>) {
+    UART.store(uart, Ordering::Release);
+}
+
+/// Serve the USB serial port, buffered log output goes out while a host has it open
+#[embassy_executor::task]
+pub async fn usb_log_task(driver: Driver<'static, USB>) {
+    let mut config = embassy_usb::Config::new(USB_VID, USB_PID);
+    config.manufacturer = Some("rp2350-isotp-ble-bridge");
+    config.product = Some("BLE_TO_ISOTP log");
+    config.max_power = 100;
+    config.max_packet_size_0 = USB_PACKET_SIZE as u8;
+
+    let mut config_descriptor = [0; 256];
+    let mut bos_descriptor = [0; 256];
+    let mut control_buf = [0; 64];
+    let mut state = State::new();
+    let mut builder = Builder::new(
+        driver,
+        config,
+        &mut config_descriptor,
+        &mut bos_descriptor,
+        &mut [],
+        &mut control_buf,
+    );
+    let mut class = CdcAcmClass::new(&mut builder, &mut state, USB_PACKET_SIZE as u16);
+    let mut usb = builder.build();
+
+    join(usb.run(), async {
+        let mut packet = [0u8; USB_PACKET_SIZE];
+        loop {
+            class.wait_connection().await;
+            loop {
+                let len = USB_LOG.read(&mut packet).await;
+                // Unplugged, keep buffering for the next host
+                if class.write_packet(&packet[..len]).await.is_err() {
+                    break;
+                }
+            }
+        }
+    })
+    .await;
+}
@@ -0,0 +1,257 @@
+//! Scripted UDS sequences
+//! A macro is a short program of sends, delays, waits for the response and branches on
+//! whether it was positive, uploaded ahead of time and run by a handler's task on command.
+//! The handler stays locked while it runs, so nothing else goes out on its ids and no BLE
+//! round-trip sits between the steps of a time-critical sequence.
+//!
+//! Instructions, multi-byte operands big endian:
+//! - 0x01 SEND length(2) data: send a request
+//! - 0x02 DELAY ms(2): pause, frames are still handled
+//! - 0x03 WAIT_RESPONSE ms(2): wait for the answer to the last request, the macro
+//!   stops with a timeout if none arrives. Response pending doesn't count as an answer.
+//! - 0x04 JUMP offset(2): continue at the instruction at this byte offset
+//! - 0x05 JUMP_IF_NEGATIVE offset(2): jump if the last answer was a negative response
+//! - 0x06 JUMP_IF_POSITIVE offset(2): jump if it was positive
+//! - 0x07 END: stop, completed
+//! - 0x08 FAIL code(1): stop, failed with this code
+
+use core::cell::RefCell;
+
+use defmt::{debug, info, Format};
+use embassy_futures::select::{select, Either};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::channel::Channel;
+use embassy_time::{Duration, Instant, Timer};
+use heapless::Vec;
+
+use crate::ble_protocol::{BleEvent, MacroOutcome, MAX_MACRO_SIZE};
+use crate::ble_server;
+use crate::can_manager::CanMessage;
+use crate::isotp_handler::IsotpHandler;
+
+pub const MAX_MACROS: usize = 4;
+
+// Instructions run before a macro is stopped, a loop without an exit mustn't hold the
+// handler forever
+const MAX_MACRO_STEPS: usize = 256;
+
+const OP_SEND: u8 = 0x01;
+const OP_DELAY: u8 = 0x02;
+const OP_WAIT_RESPONSE: u8 = 0x03;
+const OP_JUMP: u8 = 0x04;
+const OP_JUMP_IF_NEGATIVE: u8 = 0x05;
+const OP_JUMP_IF_POSITIVE: u8 = 0x06;
+const OP_END: u8 = 0x07;
+const OP_FAIL: u8 = 0x08;
+
+/// Why an uploaded script was refused
+#[derive(Debug, Format)]
+pub enum MacroError {
+    InvalidId,
+    TooLarge,
+    // Unknown opcode or an operand running past the end, at this offset
+    Malformed(u16),
+    // A jump that doesn't land on an instruction, at this offset
+    InvalidJump(u16),
+}
+
+enum Step<'a> {
+    Send(&'a [u8]),
+    Delay(Duration),
+    WaitResponse(Duration),
+    Jump(usize),
+    JumpIfNegative(usize),
+    JumpIfPositive(usize),
+    End,
+    Fail(u8),
+}
+
+// The instruction at `pc` and the offset of the next one
+fn decode(script: &[u8], pc: usize) -> Option<(Step<'_>, usize)> {
+    let operand_u16 = |at: usize| {
+        script
+            .get(at..at + 2)
+            .map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+    };
+    let millis = |at: usize| operand_u16(at).map(|ms| Duration::from_millis(ms as u64));
+
+    match *script.get(pc)? {
+        OP_SEND => {
+            let length = operand_u16(pc + 1)? as usize;
+            let data = script.get(pc + 3..pc + 3 + length)?;
+            Some((Step::Send(data), pc + 3 + length))
+        }
+        OP_DELAY => Some((Step::Delay(millis(pc + 1)?), pc + 3)),
+        OP_WAIT_RESPONSE => Some((Step::WaitResponse(millis(pc + 1)?), pc + 3)),
+        OP_JUMP => Some((Step::Jump(operand_u16(pc + 1)? as usize), pc + 3)),
+        OP_JUMP_IF_NEGATIVE => Some((Step::JumpIfNegative(operand_u16(pc + 1)? as usize), pc + 3)),
+        OP_JUMP_IF_POSITIVE => Some((Step::JumpIfPositive(operand_u16(pc + 1)? as usize), pc + 3)),
+        OP_END => Some((Step::End, pc + 1)),
+        OP_FAIL => Some((Step::Fail(*script.get(pc + 1)?), pc + 2)),
+        _ => None,
+    }
+}
+
+// Every instruction decodes and every jump lands on one
+fn validate(script: &[u8]) -> Result<(), MacroError> {
+    let mut starts: Vec<usize, MAX_MACRO_SIZE> = Vec::new();
+    let mut pc = 0;
+    while pc < script.len() {
+        starts.push(pc).map_err(|_| MacroError::TooLarge)?;
+        let (_, next) = decode(script, pc).ok_or(MacroError::Malformed(pc as u16))?;
+        pc = next;
+    }
+
+    for &pc in &starts {
+        let target = match decode(script, pc) {
+            Some((Step::Jump(target), _))
+            | Some((Step::JumpIfNegative(target), _))
+            | Some((Step::JumpIfPositive(target), _)) => target,
+            _ => continue,
+        };
+        // Jumping to the very end is a way to finish
+        if target != script.len() && !starts.contains(&target) {
+            return Err(MacroError::InvalidJump(pc as u16));
+        }
+    }
+    Ok(())
+}
+
+static MACROS: Mutex<
+    CriticalSectionRawMutex,
+    RefCell<[Option<Vec<u8, MAX_MACRO_SIZE>>; MAX_MACROS]>,
+> = Mutex::new(RefCell::new([const { None }; MAX_MACROS]));
+
+/// Whether a macro is stored under `macro_id`
+pub fn is_stored(macro_id: u8) -> bool {
+    MACROS.lock(|macros| {
+        macros
+            .borrow()
+            .get(macro_id as usize)
+            .is_some_and(Option::is_some)
+    })
+}
+
+/// Store a macro under `macro_id`, replacing the one there. An empty script deletes it.
+pub fn upload(macro_id: u8, script: &[u8]) -> Result<(), MacroError> {
+    let index = macro_id as usize;
+    if index >= MAX_MACROS {
+        return Err(MacroError::InvalidId);
+    }
+    let stored = match script.is_empty() {
+        true => None,
+        false => {
+            let script = Vec::from_slice(script).map_err(|_| MacroError::TooLarge)?;
+            validate(&script)?;
+            Some(script)
+        }
+    };
+
+    MACROS.lock(|macros| macros.borrow_mut()[index] = stored);
+    info!("[macro] {} uploaded, {} bytes", macro_id, script.len());
+    Ok(())
+}
+
+// Handle the handler's frames until the deadline, or until its request has been answered
+// if `until_answered`. Returns whether the answer was positive.
+async fn serve_frames<const N: usize>(
+    handler: &mut IsotpHandler,
+    frames: &Channel<CriticalSectionRawMutex, CanMessage, N>,
+    deadline: Instant,
+    until_answered: bool,
+) -> Option<bool> {
+    loop {
+        if until_answered {
+            if let Some(positive) = handler.take_response() {
+                return Some(positive);
+            }
+        }
+        match select(frames.receive(), Timer::at(deadline)).await {
+            Either::First(frame) => {
+                handler
+                    .handle_received_can_frame(frame.id, &frame.data, frame.received_at)
+                    .await
+            }
+            Either::Second(_) => return None,
+        }
+    }
+}
+
+// Run a script on the handler, returns the outcome, its code and where it stopped
+async fn execute<const N: usize>(
+    script: &[u8],
+    handler: &mut IsotpHandler,
+    frames: &Channel<CriticalSectionRawMutex, CanMessage, N>,
+) -> (MacroOutcome, u8, usize) {
+    let mut pc = 0;
+    // Whether the last answer was positive, nothing counts as positive
+    let mut positive = false;
+
+    for _ in 0..MAX_MACRO_STEPS {
+        let Some((step, next)) = decode(script, pc) else {
+            return (MacroOutcome::Completed, 0, pc);
+        };
+        pc = match step {
+            Step::Send(data) => {
+                // An answer to an earlier request mustn't satisfy the next wait
+                handler.take_response();
+                let request_arbitration_id = handler.request_arbitration_id;
+                if let Err(error) = handler
+                    .send_isotp_message(request_arbitration_id, data)
+                    .await
+                {
+                    return (MacroOutcome::IsotpError, error as u8, pc);
+                }
+                next
+            }
+            Step::Delay(delay) => {
+                serve_frames(handler, frames, Instant::now() + delay, false).await;
+                next
+            }
+            Step::WaitResponse(timeout) => {
+                match serve_frames(handler, frames, Instant::now() + timeout, true).await {
+                    Some(answer) => positive = answer,
+                    None => return (MacroOutcome::ResponseTimeout, 0, pc),
+                }
+                next
+            }
+            Step::Jump(target) => target,
+            Step::JumpIfNegative(target) if !positive => target,
+            Step::JumpIfPositive(target) if positive => target,
+            Step::JumpIfNegative(_) | Step::JumpIfPositive(_) => next,
+            Step::End => return (MacroOutcome::Completed, 0, pc),
+            Step::Fail(code) => return (MacroOutcome::Failed, code, pc),
+        };
+    }
+    (MacroOutcome::StepLimit, 0, pc)
+}
+
+/// Run a stored macro on the handler and report how it ended
+pub async fn run<const N: usize>(
+    macro_id: u8,
+    handler: &mut IsotpHandler,
+    frames: &Channel<CriticalSectionRawMutex, CanMessage, N>,
+) {
+    let script = MACROS.lock(|macros| {
+        macros
+            .borrow()
+            .get(macro_id as usize)
+            .and_then(|script| script.clone())
+    });
+
+    let (outcome, code, position) = match script {
+        Some(script) => execute(&script, handler, frames).await,
+        None => (MacroOutcome::NotFound, 0, 0),
+    };
+    debug!("[macro] {} ended: {:?} {}", macro_id, outcome, code);
+
+    ble_server::send_event(BleEvent::MacroResult {
+        macro_id,
+        request_arbitration_id: handler.request_arbitration_id,
+        reply_arbitration_id: handler.reply_arbitration_id,
+        outcome,
+        code,
+        position: position as u16,
+    });
+}
@@ -5,24 +5,42 @@ mod ble_protocol;
 mod ble_server;
 mod can_manager;
 mod channels;
+mod config;
+mod event_bus;
+mod event_log;
 mod isotp_ble_bridge;
 mod isotp_handler;
+mod isotp_selftest;
 mod led;
+mod log_sink;
+mod macro_engine;
+mod obd_poller;
+mod pdu_buffer;
+mod periodic_messages;
+mod session_store;
+mod stack_monitor;
+mod supervisor;
+mod transceiver;
+
+use core::ptr::addr_of_mut;
 
 use bt_hci::controller::ExternalController;
 use cyw43::bluetooth::BtDriver;
 use cyw43_pio::PioSpi;
 use defmt::unwrap;
-use embassy_executor::Spawner;
+use embassy_executor::{Executor, InterruptExecutor, Spawner};
 use embassy_rp::bind_interrupts;
 use embassy_rp::gpio::{Level, Output};
-use embassy_rp::peripherals::{DMA_CH0, PIO0, UART1};
+use embassy_rp::interrupt::{self, InterruptExt, Priority};
+use embassy_rp::multicore::{spawn_core1, Stack};
+use embassy_rp::peripherals::{DMA_CH0, PIO0, UART1, USB};
 use embassy_rp::pio::{self, Pio};
 use embassy_rp::uart::{self};
+use embassy_rp::usb;
+use embassy_rp::watchdog::Watchdog;
 use embassy_time::{Duration, Timer};
 use fixed::FixedU32;
 use static_cell::StaticCell;
-use {defmt_serial as _, panic_probe as _};
 
 // Program metadata for `picotool info`.
 #[link_section = ".bi_entries"]
@@ -35,9 +53,19 @@ pub static PICOTOOL_ENTRIES: [embassy_rp::binary_info::EntryAddr; 4] = [
 ];
 
 // interrupt handlers
+#[cfg(not(feature = "can-pio1"))]
 bind_interrupts!(struct Irqs {
     PIO0_IRQ_0 => pio::InterruptHandler<PIO0>;
     PIO2_IRQ_0 => can_manager::CanInterruptHandler;
+    USBCTRL_IRQ => usb::InterruptHandler<USB>;
+});
+
+// can2040 on PIO1 leaves PIO2 free for other consumers
+#[cfg(feature = "can-pio1")]
+bind_interrupts!(struct Irqs {
+    PIO0_IRQ_0 => pio::InterruptHandler<PIO0>;
+    PIO1_IRQ_0 => can_manager::CanInterruptHandler;
+    USBCTRL_IRQ => usb::InterruptHandler<USB>;
 });
 
 // cyw43 task
@@ -55,8 +83,67 @@ async fn ble_task(bt_device: BtDriver<'static>) {
     ble_server::run::<_, 128>(controller).await;
 }
 
+// The CAN/ISO-TP pipeline runs on core1 so PIO-CAN interrupts and frame handling don't
+// contend with the BLE stack and cyw43 radio on core0. Everything shared between the two
+// cores goes through critical section mutexes and channels.
+const CORE1_STACK_SIZE: usize = 16384;
+static mut CORE1_STACK: Stack<CORE1_STACK_SIZE> = Stack::new();
+static EXECUTOR1: StaticCell<Executor> = StaticCell::new();
+
+// CAN TX and the ISO-TP handlers, which pace consecutive frames and answer with flow
+// control, preempt everything else on core1. Below the CAN interrupt so receiving never
+// waits on a pacing gap.
+static EXECUTOR_TIMING: InterruptExecutor = InterruptExecutor::new();
+
+#[interrupt]
+unsafe fn SWI_IRQ_0() {
+    EXECUTOR_TIMING.on_interrupt()
+}
+
+// The supervisor preempts the BLE stack on core0, so a hung GATT handler can't keep it
+// from recording what starved before the watchdog resets
+static EXECUTOR_SUPERVISOR: InterruptExecutor = InterruptExecutor::new();
+
+#[interrupt]
+unsafe fn SWI_IRQ_1() {
+    EXECUTOR_SUPERVISOR.on_interrupt()
+}
+
+// can pipeline task, the first on core1
+#[embassy_executor::task]
+async fn can_pipeline_task(spawner: Spawner) {
+    // init can bus, unmasking its interrupt on this core so it's serviced here
+    can_manager::init_can();
+
+    // sleep to allow can to settle
+    Timer::after(Duration::from_millis(250)).await;
+
+    // started from here so the executor's interrupt is taken on core1
+    interrupt::SWI_IRQ_0.set_priority(Priority::P3);
+    let timing_spawner = EXECUTOR_TIMING.start(interrupt::SWI_IRQ_0);
+
+    unwrap!(timing_spawner.spawn(can_manager::can_tx_channel_task()));
+    unwrap!(spawner.spawn(can_manager::can_rx_processor_task()));
+    unwrap!(spawner.spawn(can_manager::can_isotp_rx_task()));
+    unwrap!(spawner.spawn(can_manager::can_sniffer_task()));
+    unwrap!(spawner.spawn(can_manager::can_stats_task()));
+    unwrap!(spawner.spawn(can_manager::can_reset_task()));
+
+    // init ble isotp bridge
+    unwrap!(spawner.spawn(isotp_ble_bridge::isotp_ble_bridge_ble_rx_task()));
+    for slot_index in 0..isotp_ble_bridge::MAX_HANDLERS {
+        unwrap!(timing_spawner.spawn(isotp_ble_bridge::isotp_handler_task(slot_index)));
+    }
+
+    // bring back the filters of a saved session
+    session_store::restore().await;
+}
+
 #[embassy_executor::main]
 async fn main(spawner: Spawner) {
+    // paint the stack before it's used for anything else
+    stack_monitor::paint_core0();
+
     // init peripherals
     let p = embassy_rp::init(Default::default());
 
@@ -69,8 +156,11 @@ async fn main(spawner: Spawner) {
         uart::Config::default(),
     ));
 
-    // init defmt serial
-    defmt_serial::defmt_serial(uart1);
+    // init log output, the device config picks uart or usb once it's loaded
+    log_sink::init_uart(uart1);
+    unwrap!(spawner.spawn(log_sink::usb_log_task(usb::Driver::new(p.USB, Irqs))));
+
+    event_log::record(event_log::LogKind::Boot, 0, 0);
 
     // init cyw43
     let fw = include_bytes!("../cyw43-firmware/43439A0.bin");
@@ -104,6 +194,9 @@ async fn main(spawner: Spawner) {
     let control = CONTROL.init(control);
     unwrap!(spawner.spawn(led::led_task(control)));
 
+    // errors and resets go out to the client and the event log from here
+    unwrap!(spawner.spawn(event_bus::reporter_task()));
+
     // sleep to allow cyw43 to settle
     Timer::after(Duration::from_millis(250)).await;
 
@@ -113,21 +206,44 @@ async fn main(spawner: Spawner) {
     // sleep to allow cyw43 to settle
     Timer::after(Duration::from_millis(250)).await;
 
-    // init can bus
-
-    can_manager::init_can();
-
-    // sleep to allow can to settle
-    Timer::after(Duration::from_millis(250)).await;
-
-    unwrap!(spawner.spawn(can_manager::can_tx_channel_task()));
-    unwrap!(spawner.spawn(can_manager::can_rx_processor_task()));
-    unwrap!(spawner.spawn(can_manager::can_stats_task()));
-    unwrap!(spawner.spawn(can_manager::can_reset_task()));
+    // load device config (can pins etc.)
+    config::init(p.FLASH);
+
+    // flash erases and writes only work on core0, the bridge on core1 asks this task
+    unwrap!(spawner.spawn(config::flash_writer_task()));
+
+    // tell the client if a fault caused the last reset
+    let mut watchdog = Watchdog::new(p.WATCHDOG);
+    supervisor::report_last_reset(&mut watchdog);
+
+    // init transceiver control pins
+    transceiver::init();
+
+    // init the external leds, if there are any
+    led::init();
+
+    // start the can pipeline on core1
+    // Safety: core1 isn't running yet
+    unsafe {
+        stack_monitor::paint_core1(
+            addr_of_mut!(CORE1_STACK).cast(),
+            core::mem::size_of::<Stack<CORE1_STACK_SIZE>>(),
+        )
+    };
+    spawn_core1(
+        p.CORE1,
+        // Safety: the stack is only ever handed to core1, once
+        unsafe { &mut *addr_of_mut!(CORE1_STACK) },
+        move || {
+            let executor1 = EXECUTOR1.init(Executor::new());
+            executor1.run(|spawner| unwrap!(spawner.spawn(can_pipeline_task(spawner))));
+        },
+    );
 
-    // init ble isotp bridge
-    unwrap!(spawner.spawn(isotp_ble_bridge::isotp_ble_bridge_ble_rx_task()));
-    unwrap!(spawner.spawn(isotp_ble_bridge::isotp_ble_bridge_can_rx_task()));
+    // start feeding the watchdog
+    interrupt::SWI_IRQ_1.set_priority(Priority::P3);
+    let supervisor_spawner = EXECUTOR_SUPERVISOR.start(interrupt::SWI_IRQ_1);
+    unwrap!(supervisor_spawner.spawn(supervisor::supervisor_task(watchdog)));
 
     // tasks will run in background
 }
@@ -0,0 +1,102 @@
+//! OBD-II PID polling
+//! A handler can poll its ECU for a list of PIDs, each at its own rate, turning the bridge
+//! into a data logger front end. Polls go out between the client's own requests, like the
+//! TesterPresent keepalive, and the answers reach the client as timestamped samples.
+
+use embassy_time::{Duration, Instant};
+use heapless::Vec;
+
+use crate::ble_protocol::{BleEvent, ObdPollEntry, MAX_OBD_SAMPLE_DATA, MAX_POLLED_PIDS};
+
+// Positive responses echo the mode with this bit set
+const OBD_RESPONSE_OFFSET: u8 = 0x40;
+const UDS_NEGATIVE_RESPONSE: u8 = 0x7F;
+
+struct ScheduledPid {
+    mode: u8,
+    pid: u8,
+    period: Duration,
+    due: Instant,
+}
+
+/// Poll schedule of one handler
+pub struct ObdPoller {
+    pids: Vec<ScheduledPid, MAX_POLLED_PIDS>,
+    // Mode and PID of the poll waiting for its answer
+    pending: Option<(u8, u8)>,
+}
+
+impl ObdPoller {
+    pub const fn new() -> Self {
+        Self {
+            pids: Vec::new(),
+            pending: None,
+        }
+    }
+
+    /// Replace the schedule, every PID is polled once right away. An empty list stops polling.
+    pub fn configure(&mut self, entries: &[ObdPollEntry]) {
+        let now = Instant::now();
+        self.pids = entries
+            .iter()
+            .map(|entry| ScheduledPid {
+                mode: entry.mode,
+                pid: entry.pid,
+                period: Duration::from_millis(entry.period_ms as u64),
+                due: now,
+            })
+            .collect();
+        self.pending = None;
+    }
+
+    /// The most overdue poll, which is then rescheduled a period from now
+    pub fn next_request(&mut self) -> Option<[u8; 2]> {
+        let now = Instant::now();
+        let next = self
+            .pids
+            .iter_mut()
+            .filter(|pid| pid.due <= now)
+            .min_by_key(|pid| pid.due)?;
+
+        next.due = now + next.period;
+        self.pending = Some((next.mode, next.pid));
+        Some([next.mode, next.pid])
+    }
+
+    /// Forget the poll in flight, its answer is no longer expected
+    pub fn clear_pending(&mut self) {
+        self.pending = None;
+    }
+
+    /// Sample for the answer to the poll in flight, None if the PDU is something else.
+    /// Negative responses end the poll but go to the client as they are.
+    pub fn take_sample(
+        &mut self,
+        request_arbitration_id: u32,
+        reply_arbitration_id: u32,
+        pdu: &[u8],
+    ) -> Option<BleEvent> {
+        let (mode, pid) = self.pending?;
+
+        match pdu {
+            [UDS_NEGATIVE_RESPONSE, service, ..] if *service == mode => {
+                self.pending = None;
+                None
+            }
+            [service, response_pid, data @ ..]
+                if *service == mode | OBD_RESPONSE_OFFSET && *response_pid == pid =>
+            {
+                self.pending = None;
+                Some(BleEvent::ObdSample {
+                    request_arbitration_id,
+                    reply_arbitration_id,
+                    mode,
+                    pid,
+                    timestamp_ms: Instant::now().as_millis() as u32,
+                    data: Vec::<u8, MAX_OBD_SAMPLE_DATA>::from_slice(data).ok()?,
+                })
+            }
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,160 @@
+//! Receive buffers for ISO-TP handlers
+//! Buffers come in two size classes so handlers talking to ECUs that only ever send short
+//! responses don't tie up a full-size buffer each. A completed PDU travels to the BLE side
+//! in the buffer it was received into, which goes back to the pool once it's been sent.
+
+use core::ptr::addr_of_mut;
+
+use defmt::{Format, Formatter};
+use portable_atomic::{AtomicBool, Ordering};
+
+use crate::isotp_ble_bridge::MAX_HANDLERS;
+use crate::isotp_handler::MAX_PDU_SIZE;
+
+pub const SMALL_BUFFER_SIZE: usize = 256;
+pub const LARGE_BUFFER_SIZE: usize = MAX_PDU_SIZE;
+
+// A receive buffer for every handler, and as many again for PDUs waiting to be sent
+const SMALL_BUFFER_COUNT: usize = 2 * MAX_HANDLERS;
+const LARGE_BUFFER_COUNT: usize = 4;
+
+static mut SMALL_BUFFERS: [[u8; SMALL_BUFFER_SIZE]; SMALL_BUFFER_COUNT] =
+    [[0; SMALL_BUFFER_SIZE]; SMALL_BUFFER_COUNT];
+static mut LARGE_BUFFERS: [[u8; LARGE_BUFFER_SIZE]; LARGE_BUFFER_COUNT] =
+    [[0; LARGE_BUFFER_SIZE]; LARGE_BUFFER_COUNT];
+
+static SMALL_IN_USE: [AtomicBool; SMALL_BUFFER_COUNT] =
+    [const { AtomicBool::new(false) }; SMALL_BUFFER_COUNT];
+static LARGE_IN_USE: [AtomicBool; LARGE_BUFFER_COUNT] =
+    [const { AtomicBool::new(false) }; LARGE_BUFFER_COUNT];
+
+/// Size class requested when a filter is configured
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum BufferClass {
+    // Up to MAX_PDU_SIZE, for transfers like calibration reads
+    Large = 0x00,
+    // Up to SMALL_BUFFER_SIZE, plenty for most diagnostic responses
+    Small = 0x01,
+}
+
+impl TryFrom<u8> for BufferClass {
+    type Error = ();
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0x00 => Ok(BufferClass::Large),
+            0x01 => Ok(BufferClass::Small),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Buffers of a class currently claimed
+pub fn in_use(class: BufferClass) -> usize {
+    let in_use = match class {
+        BufferClass::Small => &SMALL_IN_USE[..],
+        BufferClass::Large => &LARGE_IN_USE[..],
+    };
+    in_use
+        .iter()
+        .filter(|flag| flag.load(Ordering::Relaxed))
+        .count()
+}
+
+pub fn pool_size(class: BufferClass) -> usize {
+    match class {
+        BufferClass::Small => SMALL_BUFFER_COUNT,
+        BufferClass::Large => LARGE_BUFFER_COUNT,
+    }
+}
+
+/// A claimed buffer, returned to its pool when dropped
+pub struct PduBuffer {
+    data: &'static mut [u8],
+    len: usize,
+    class: BufferClass,
+    slot: usize,
+}
+
+impl PduBuffer {
+    /// Claim a free buffer of the given class, None if they're all in use
+    pub fn claim(class: BufferClass) -> Option<Self> {
+        let in_use = match class {
+            BufferClass::Small => &SMALL_IN_USE[..],
+            BufferClass::Large => &LARGE_IN_USE[..],
+        };
+        let slot = in_use.iter().position(|flag| {
+            flag.compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire)
+                .is_ok()
+        })?;
+
+        // Safety: the in-use flag hands out each slot to a single owner
+        let data: &'static mut [u8] = unsafe {
+            match class {
+                BufferClass::Small => &mut (*addr_of_mut!(SMALL_BUFFERS))[slot],
+                BufferClass::Large => &mut (*addr_of_mut!(LARGE_BUFFERS))[slot],
+            }
+        };
+
+        Some(Self {
+            data,
+            len: 0,
+            class,
+            slot,
+        })
+    }
+
+    pub fn class(&self) -> BufferClass {
+        self.class
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        &self.data[..self.len]
+    }
+
+    pub fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    /// Append data, fails without copying anything if it doesn't fit
+    pub fn extend_from_slice(&mut self, data: &[u8]) -> Result<(), ()> {
+        let end = self.len + data.len();
+        if end > self.capacity() {
+            return Err(());
+        }
+        self.data[self.len..end].copy_from_slice(data);
+        self.len = end;
+        Ok(())
+    }
+}
+
+impl core::fmt::Debug for PduBuffer {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_list().entries(self.as_slice()).finish()
+    }
+}
+
+impl Format for PduBuffer {
+    fn format(&self, f: Formatter) {
+        defmt::write!(f, "{:02x}", self.as_slice())
+    }
+}
+
+impl Drop for PduBuffer {
+    fn drop(&mut self) {
+        let in_use = match self.class {
+            BufferClass::Small => &SMALL_IN_USE[self.slot],
+            BufferClass::Large => &LARGE_IN_USE[self.slot],
+        };
+        in_use.store(false, Ordering::Release);
+    }
+}
@@ -0,0 +1,91 @@
+//! Periodic ISO-TP messages
+//! A handler can repeat messages on its own, e.g. a keepalive other than TesterPresent or
+//! a rotating set of reads. Each periodic slot cycles through its messages, one per
+//! interval, and goes out between the client's own requests like the OBD polls.
+
+use embassy_time::{Duration, Instant};
+use heapless::Vec;
+
+use crate::ble_protocol::PeriodicMessageIterator;
+
+pub const MAX_PERIODIC_MESSAGES: usize = 4;
+/// Messages of one periodic slot, length(2) + data each, as the client sent them
+pub const MAX_PERIODIC_DATA: usize = 512;
+
+struct PeriodicMessage {
+    interval: Duration,
+    due: Instant,
+    data: Vec<u8, MAX_PERIODIC_DATA>,
+    count: u16,
+    // Index of the message sent next
+    next: u16,
+}
+
+impl PeriodicMessage {
+    fn messages(&self) -> PeriodicMessageIterator<'_> {
+        PeriodicMessageIterator::new(&self.data)
+    }
+}
+
+/// Periodic message slots of one handler
+pub struct PeriodicMessages {
+    slots: [Option<PeriodicMessage>; MAX_PERIODIC_MESSAGES],
+}
+
+impl PeriodicMessages {
+    pub const fn new() -> Self {
+        Self {
+            slots: [const { None }; MAX_PERIODIC_MESSAGES],
+        }
+    }
+
+    /// Start cycling through the first `count` messages in `data`, replacing whatever the
+    /// slot was sending. The first one goes out right away. False if the slot doesn't exist
+    /// or there's nothing to send.
+    pub fn start(&mut self, index: u8, interval: Duration, data: &[u8], count: u16) -> bool {
+        let Some(slot) = self.slots.get_mut(index as usize) else {
+            return false;
+        };
+        let Ok(data) = Vec::from_slice(data) else {
+            return false;
+        };
+
+        let available = PeriodicMessageIterator::new(&data).count();
+        if count == 0 || available < count as usize || interval.as_ticks() == 0 {
+            return false;
+        }
+
+        *slot = Some(PeriodicMessage {
+            interval,
+            due: Instant::now(),
+            data,
+            count,
+            next: 0,
+        });
+        true
+    }
+
+    /// Stop a slot, false if it wasn't running
+    pub fn stop(&mut self, index: u8) -> bool {
+        self.slots
+            .get_mut(index as usize)
+            .and_then(Option::take)
+            .is_some()
+    }
+
+    /// The next message of the most overdue slot, which moves on to the one after it
+    pub fn next_message(&mut self) -> Option<Vec<u8, MAX_PERIODIC_DATA>> {
+        let now = Instant::now();
+        let slot = self
+            .slots
+            .iter_mut()
+            .flatten()
+            .filter(|slot| slot.due <= now)
+            .min_by_key(|slot| slot.due)?;
+
+        let message = Vec::from_slice(slot.messages().nth(slot.next as usize)?).ok()?;
+        slot.next = (slot.next + 1) % slot.count;
+        slot.due = now + slot.interval;
+        Some(message)
+    }
+}
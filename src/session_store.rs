@@ -0,0 +1,163 @@
+//! Saved ISO-TP session
+//! The filter setup commands of the current session are journaled as they arrive. Once
+//! saved, the journal lives in the sector below the device config and is replayed at boot,
+//! so a permanently installed bridge resumes keepalive and gateway duty after a power cycle
+//! without waiting for a client.
+
+use core::cell::RefCell;
+
+use defmt::{info, warn, Format};
+use embassy_rp::flash::ERASE_SIZE;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use heapless::Vec;
+
+use crate::ble_protocol::BleMessageParser;
+use crate::config::{self, ConfigError, FLASH_SIZE};
+use crate::isotp_ble_bridge;
+
+// Just below the device config, memory.x keeps both out of the program image
+const SESSION_OFFSET: u32 = (FLASH_SIZE - 2 * ERASE_SIZE) as u32;
+const SESSION_MAGIC: u32 = 0x4953_5353; // "ISSS"
+const SESSION_VERSION: u8 = 1;
+// magic(4) + version(1) + journal length(2)
+const HEADER_SIZE: usize = 7;
+const JOURNAL_CAPACITY: usize = ERASE_SIZE - HEADER_SIZE;
+
+/// Error type for saving the session
+#[derive(Debug, Format)]
+pub enum SessionError {
+    // More setup commands than fit in a sector, end the session and configure it again
+    TooLarge,
+    Flash(ConfigError),
+}
+
+// Each command as length(2) + the bytes the client wrote
+struct Journal {
+    records: Vec<u8, JOURNAL_CAPACITY>,
+    // Commands were lost, this session can't be saved
+    overflowed: bool,
+}
+
+static JOURNAL: Mutex<CriticalSectionRawMutex, RefCell<Journal>> =
+    Mutex::new(RefCell::new(Journal {
+        records: Vec::new(),
+        overflowed: false,
+    }));
+
+/// Remember a setup command of the current session
+pub fn record(command: &[u8]) {
+    JOURNAL.lock(|journal| {
+        let mut journal = journal.borrow_mut();
+        let length = (command.len() as u16).to_be_bytes();
+        if journal.records.len() + length.len() + command.len() > JOURNAL_CAPACITY {
+            warn!("[session] journal full, this session can't be saved");
+            journal.overflowed = true;
+            return;
+        }
+        journal.records.extend_from_slice(&length).unwrap();
+        journal.records.extend_from_slice(command).unwrap();
+    });
+}
+
+/// Forget the current session, the saved one stays in flash
+pub fn clear() {
+    JOURNAL.lock(|journal| {
+        let mut journal = journal.borrow_mut();
+        journal.records.clear();
+        journal.overflowed = false;
+    });
+}
+
+/// Save the current session to be restored at boot
+pub async fn save() -> Result<(), SessionError> {
+    let (records, overflowed) = JOURNAL.lock(|journal| {
+        let journal = journal.borrow();
+        (journal.records.clone(), journal.overflowed)
+    });
+    if overflowed {
+        return Err(SessionError::TooLarge);
+    }
+
+    let mut header = [0u8; HEADER_SIZE];
+    header[0..4].copy_from_slice(&SESSION_MAGIC.to_be_bytes());
+    header[4] = SESSION_VERSION;
+    header[5..7].copy_from_slice(&(records.len() as u16).to_be_bytes());
+
+    // The journal leaves room for the header
+    let mut sector: Vec<u8, ERASE_SIZE> = Vec::new();
+    sector.extend_from_slice(&header).unwrap();
+    sector.extend_from_slice(&records).unwrap();
+
+    config::write_sector(SESSION_OFFSET, &sector)
+        .await
+        .map_err(SessionError::Flash)?;
+
+    info!("[session] saved {} bytes of setup", records.len());
+    Ok(())
+}
+
+/// Remove the saved session, the bridge boots without filters again
+pub async fn erase() -> Result<(), SessionError> {
+    config::write_sector(SESSION_OFFSET, &[])
+        .await
+        .map_err(SessionError::Flash)?;
+
+    info!("[session] saved session erased");
+    Ok(())
+}
+
+/// Replay the saved session, if there is one. The bridge tasks have to be running.
+pub async fn restore() {
+    let mut header = [0u8; HEADER_SIZE];
+    let mut records: Vec<u8, JOURNAL_CAPACITY> = Vec::new();
+    let loaded = config::with_flash(|flash| {
+        flash
+            .blocking_read(SESSION_OFFSET, &mut header)
+            .map_err(|_| ConfigError::FlashError)?;
+        let magic = u32::from_be_bytes([header[0], header[1], header[2], header[3]]);
+        let length = u16::from_be_bytes([header[5], header[6]]) as usize;
+        if magic != SESSION_MAGIC || header[4] != SESSION_VERSION || length > JOURNAL_CAPACITY {
+            return Ok(false);
+        }
+
+        records.resize(length, 0).unwrap();
+        flash
+            .blocking_read(SESSION_OFFSET + HEADER_SIZE as u32, &mut records)
+            .map_err(|_| ConfigError::FlashError)?;
+        Ok(true)
+    });
+    match loaded {
+        Ok(true) => (),
+        Ok(false) => {
+            info!("[session] no saved session");
+            return;
+        }
+        Err(e) => {
+            warn!("[session] failed to read saved session: {:?}", e);
+            return;
+        }
+    }
+
+    // Saving again without changes keeps the same setup
+    JOURNAL.lock(|journal| journal.borrow_mut().records = records.clone());
+
+    let mut remaining = records.as_slice();
+    let mut restored = 0;
+    while let [high, low, rest @ ..] = remaining {
+        let length = u16::from_be_bytes([*high, *low]) as usize;
+        let Some(command) = rest.get(..length) else {
+            warn!("[session] saved session is truncated");
+            break;
+        };
+        match BleMessageParser::parse(command) {
+            Ok(parsed) => {
+                isotp_ble_bridge::handle_ble_message(parsed).await;
+                restored += 1;
+            }
+            Err(e) => warn!("[session] skipping saved command: {:?}", e),
+        }
+        remaining = &rest[length..];
+    }
+    info!("[session] restored {} setup commands", restored);
+}
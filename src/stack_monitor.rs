@@ -0,0 +1,90 @@
+//! Stack usage
+//! Tasks don't get stacks of their own, every task and interrupt on a core shares that
+//! core's stack. Both stacks are painted with a pattern at boot and the deepest point
+//! either ever reached is found by looking for where the pattern is still intact, so
+//! users raising buffer sizes or handler counts can see how much headroom is left.
+
+use core::ptr::addr_of;
+
+use portable_atomic::{AtomicPtr, AtomicUsize, Ordering};
+
+use crate::ble_protocol::StackStatistic;
+
+const PAINT: u32 = 0xC0FF_EE55;
+// Left unpainted below the stack pointer while painting the stack in use
+const PAINT_MARGIN: usize = 256;
+
+extern "C" {
+    // Top of the core0 stack, from the cortex-m-rt linker script
+    static _stack_start: u32;
+}
+
+struct PaintedStack {
+    bottom: AtomicPtr<u32>,
+    // In words
+    size: AtomicUsize,
+}
+
+impl PaintedStack {
+    const fn new() -> Self {
+        Self {
+            bottom: AtomicPtr::new(core::ptr::null_mut()),
+            size: AtomicUsize::new(0),
+        }
+    }
+
+    fn statistic(&self) -> StackStatistic {
+        let bottom = self.bottom.load(Ordering::Acquire);
+        let size = self.size.load(Ordering::Acquire);
+        // Safety: set once from a region reserved for this stack, only read afterwards
+        let untouched = (0..size)
+            .take_while(|&i| unsafe { bottom.add(i).read_volatile() } == PAINT)
+            .count();
+        StackStatistic {
+            size: (size * 4) as u32,
+            used: ((size - untouched) * 4) as u32,
+        }
+    }
+}
+
+static CORE0_STACK: PaintedStack = PaintedStack::new();
+static CORE1_STACK: PaintedStack = PaintedStack::new();
+
+// Safety: `bottom..bottom + words` must be unused stack memory
+unsafe fn paint(stack: &PaintedStack, bottom: *mut u32, words: usize) {
+    for i in 0..words {
+        bottom.add(i).write_volatile(PAINT);
+    }
+    stack.bottom.store(bottom, Ordering::Release);
+    stack.size.store(words, Ordering::Release);
+}
+
+/// Paint the unused part of the core0 stack, early in main so little of it is in use yet
+pub fn paint_core0() {
+    let bottom = cortex_m_rt::heap_start();
+    // Safety: only the address of the linker symbol is used
+    let top = unsafe { addr_of!(_stack_start) } as usize;
+    let sp = cortex_m::register::msp::read() as usize;
+    let words = (sp - PAINT_MARGIN - bottom as usize) / 4;
+
+    // Safety: everything between the end of static data and just below the stack
+    // pointer is stack that hasn't been used yet
+    unsafe { paint(&CORE0_STACK, bottom, words) };
+    // The part in use counts as used
+    CORE0_STACK
+        .size
+        .store((top - bottom as usize) / 4, Ordering::Release);
+}
+
+/// Paint the core1 stack, before core1 is started on it
+///
+/// # Safety
+/// `bottom..bottom + size` must be the core1 stack and core1 must not be running yet
+pub unsafe fn paint_core1(bottom: *mut u8, size: usize) {
+    paint(&CORE1_STACK, bottom.cast(), size / 4);
+}
+
+/// Size and deepest use of the core0 and core1 stacks, in bytes
+pub fn statistics() -> [StackStatistic; 2] {
+    [CORE0_STACK.statistic(), CORE1_STACK.statistic()]
+}
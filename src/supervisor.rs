@@ -0,0 +1,202 @@
+//! Watchdog supervisor
+//! The hardware watchdog is only fed while the BLE stack, the CAN rx path and the bridge
+//! all keep checking in. When one of them stops, the supervisor notes which one in a
+//! watchdog scratch register and lets the watchdog reset the chip. Panics and hard faults
+//! reset the chip straight away, leaving the same kind of note.
+//!
+//! Flash can't be erased while the other core may be stuck, so the next boot turns the
+//! note into a record in a reserved flash sector before core1 starts. The record stays
+//! until the client reads the event log, logged again on every boot and shown on the LED,
+//! so a bridge stuck in a crash loop doesn't go unnoticed.
+
+use core::panic::PanicInfo;
+
+use defmt::{error, info, warn, Format};
+use embassy_rp::flash::ERASE_SIZE;
+use embassy_rp::peripherals::WATCHDOG;
+use embassy_rp::watchdog::Watchdog;
+use embassy_time::{Duration, Timer};
+use portable_atomic::{AtomicBool, Ordering};
+
+use crate::config::{self, ConfigError, FLASH_SIZE};
+use crate::event_log::{self, LogKind};
+
+// Below the saved session, memory.x keeps it out of the program image
+const FAULT_RECORD_OFFSET: u32 = (FLASH_SIZE - 3 * ERASE_SIZE) as u32;
+// magic(4) + kind(1) + code(1)
+const FAULT_RECORD_SIZE: usize = 6;
+const FAULT_RECORD_MAGIC: u32 = 0x4953_4654; // "ISFT"
+
+// Survives the reset, the bootrom only uses scratch 4 to 7
+const FAULT_SCRATCH: usize = 0;
+// "IS" followed by the LogKind and its code
+const FAULT_SCRATCH_MAGIC: u32 = 0x4953_0000;
+
+/// Monitored tasks check in at least this often, waking up just for it when idle
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+// Every task has to have checked in once per check
+const CHECK_INTERVAL: Duration = Duration::from_secs(3);
+// Long enough to cover a check, short enough that a hung bridge is back quickly
+const WATCHDOG_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Tasks whose liveness the watchdog depends on
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum MonitoredTask {
+    Ble = 0x00,
+    CanRx = 0x01,
+    Bridge = 0x02,
+}
+
+const MONITORED_TASKS: [MonitoredTask; 3] = [
+    MonitoredTask::Ble,
+    MonitoredTask::CanRx,
+    MonitoredTask::Bridge,
+];
+
+static HEARTBEATS: [AtomicBool; MONITORED_TASKS.len()] =
+    [const { AtomicBool::new(false) }; MONITORED_TASKS.len()];
+
+static FAULT_PENDING: AtomicBool = AtomicBool::new(false);
+
+/// Check in, a task that hasn't done so since the last check is considered starved
+pub fn heartbeat(task: MonitoredTask) {
+    HEARTBEATS[task as usize].store(true, Ordering::Relaxed);
+}
+
+/// Whether a fault record is waiting for the client to read the event log
+pub fn fault_pending() -> bool {
+    FAULT_PENDING.load(Ordering::Relaxed)
+}
+
+// Only faults that end in a reset leave a record
+fn fault_kind(kind: u8) -> Option<LogKind> {
+    [LogKind::WatchdogReset, LogKind::Panic, LogKind::HardFault]
+        .into_iter()
+        .find(|&fault| fault as u8 == kind)
+}
+
+/// Report a reset caused by a starved task, a panic or a hard fault in the event log,
+/// on every boot until the client has read it. Has to run before core1 is started.
+pub fn report_last_reset(watchdog: &mut Watchdog) {
+    // The fault only got as far as the scratch register
+    let scratch = watchdog.get_scratch(FAULT_SCRATCH);
+    if scratch & 0xFFFF_0000 == FAULT_SCRATCH_MAGIC {
+        watchdog.set_scratch(FAULT_SCRATCH, 0);
+        if let Err(e) = record_fault((scratch >> 8) as u8, scratch as u8) {
+            warn!("[supervisor] failed to record fault: {:?}", e);
+        }
+    }
+
+    let mut record = [0u8; FAULT_RECORD_SIZE];
+    let fault = config::with_flash(|flash| {
+        flash
+            .blocking_read(FAULT_RECORD_OFFSET, &mut record)
+            .map_err(|_| ConfigError::FlashError)?;
+        let magic = u32::from_be_bytes([record[0], record[1], record[2], record[3]]);
+        if magic != FAULT_RECORD_MAGIC {
+            return Ok(None);
+        }
+        Ok(fault_kind(record[4]).map(|kind| (kind, record[5])))
+    });
+
+    match fault {
+        Ok(Some((kind, code))) => {
+            warn!(
+                "[supervisor] fault before an earlier reset: {} code {}",
+                kind, code
+            );
+            event_log::record(kind, code, 0);
+            FAULT_PENDING.store(true, Ordering::Relaxed);
+        }
+        Ok(None) => (),
+        Err(e) => warn!("[supervisor] failed to read fault record: {:?}", e),
+    }
+}
+
+/// Forget the fault record once the client has seen it
+pub async fn clear_fault() {
+    if !FAULT_PENDING.swap(false, Ordering::Relaxed) {
+        return;
+    }
+    if let Err(e) = config::write_sector(FAULT_RECORD_OFFSET, &[]).await {
+        warn!("[supervisor] failed to clear fault record: {:?}", e);
+    }
+}
+
+// Only at boot, while core1 isn't running yet
+fn record_fault(kind: u8, code: u8) -> Result<(), ConfigError> {
+    let mut record = [0u8; FAULT_RECORD_SIZE];
+    record[0..4].copy_from_slice(&FAULT_RECORD_MAGIC.to_be_bytes());
+    record[4] = kind;
+    record[5] = code;
+
+    config::with_flash(|flash| {
+        flash
+            .blocking_erase(FAULT_RECORD_OFFSET, FAULT_RECORD_OFFSET + ERASE_SIZE as u32)
+            .map_err(|_| ConfigError::FlashError)?;
+        flash
+            .blocking_write(FAULT_RECORD_OFFSET, &record)
+            .map_err(|_| ConfigError::FlashError)
+    })
+}
+
+// Left for report_last_reset on the next boot
+fn note_fault(watchdog: &mut Watchdog, kind: LogKind, code: u8) {
+    watchdog.set_scratch(
+        FAULT_SCRATCH,
+        FAULT_SCRATCH_MAGIC | ((kind as u32) << 8) | code as u32,
+    );
+}
+
+// Flash can't be trusted from here on, so the fault is noted in a scratch register for
+// the next boot and the chip reset right away
+fn reset_after_fault(kind: LogKind) -> ! {
+    // Safety: nothing else gets to run any more, the supervisor's watchdog is abandoned
+    let mut watchdog = Watchdog::new(unsafe { WATCHDOG::steal() });
+    note_fault(&mut watchdog, kind, 0);
+    watchdog.trigger_reset();
+    loop {
+        cortex_m::asm::nop();
+    }
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    error!("[supervisor] panic: {}", defmt::Display2Format(info));
+    reset_after_fault(LogKind::Panic)
+}
+
+#[cortex_m_rt::exception]
+unsafe fn HardFault(_frame: &cortex_m_rt::ExceptionFrame) -> ! {
+    reset_after_fault(LogKind::HardFault)
+}
+
+/// Feed the watchdog for as long as every monitored task keeps checking in
+#[embassy_executor::task]
+pub async fn supervisor_task(mut watchdog: Watchdog) {
+    watchdog.pause_on_debug(true);
+    watchdog.start(WATCHDOG_TIMEOUT);
+    info!("[supervisor] watchdog started");
+
+    loop {
+        Timer::after(CHECK_INTERVAL).await;
+
+        let starved = MONITORED_TASKS
+            .into_iter()
+            .find(|task| !HEARTBEATS[*task as usize].swap(false, Ordering::Relaxed));
+
+        match starved {
+            None => watchdog.feed(),
+            Some(task) => {
+                error!("[supervisor] {} stopped checking in, resetting", task);
+                // Erasing flash would have to pause core1, which may be the one stuck
+                note_fault(&mut watchdog, LogKind::WatchdogReset, task as u8);
+                // Stop feeding, the watchdog takes it from here
+                loop {
+                    Timer::after(WATCHDOG_TIMEOUT).await;
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,96 @@
+//! CAN transceiver control pins
+//! Drives the optional board GPIOs around the transceiver, as set up in the device config
+
+use core::cell::RefCell;
+
+use defmt::info;
+use embassy_rp::gpio::{AnyPin, Level, Output};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+
+use crate::config;
+
+struct StandbyPin {
+    output: Output<'static>,
+    active_low: bool,
+}
+
+static STANDBY_PIN: Mutex<CriticalSectionRawMutex, RefCell<Option<StandbyPin>>> =
+    Mutex::new(RefCell::new(None));
+static SILENT_PIN: Mutex<CriticalSectionRawMutex, RefCell<Option<Output<'static>>>> =
+    Mutex::new(RefCell::new(None));
+static TERMINATION_PIN: Mutex<CriticalSectionRawMutex, RefCell<Option<Output<'static>>>> =
+    Mutex::new(RefCell::new(None));
+
+/// Claim the pins named in the device config and put the transceiver in normal mode
+pub fn init() {
+    let config = config::get();
+
+    if let Some(gpio) = config.can_standby_gpio {
+        info!(
+            "[transceiver] standby on gpio {} active low {}",
+            gpio, config.can_standby_active_low
+        );
+
+        // Safety: DeviceConfig::apply and deserialize reject RESERVED_GPIOS (radio and UART)
+        // and pins another config key already has, so nothing else claims this one
+        let pin = unsafe { AnyPin::steal(gpio) };
+        let level = Level::from(config.can_standby_active_low);
+        STANDBY_PIN.lock(|p| {
+            *p.borrow_mut() = Some(StandbyPin {
+                output: Output::new(pin, level),
+                active_low: config.can_standby_active_low,
+            })
+        });
+    }
+
+    if let Some(gpio) = config.can_silent_gpio {
+        info!("[transceiver] silent on gpio {}", gpio);
+
+        // Safety: free for the same reason as the standby pin
+        let pin = unsafe { AnyPin::steal(gpio) };
+        SILENT_PIN.lock(|p| *p.borrow_mut() = Some(Output::new(pin, Level::Low)));
+    }
+
+    if let Some(gpio) = config.can_termination_gpio {
+        info!(
+            "[transceiver] termination on gpio {} enabled {}",
+            gpio, config.can_termination_enabled
+        );
+
+        // Safety: free for the same reason as the standby pin
+        let pin = unsafe { AnyPin::steal(gpio) };
+        let level = Level::from(config.can_termination_enabled);
+        TERMINATION_PIN.lock(|p| *p.borrow_mut() = Some(Output::new(pin, level)));
+    }
+}
+
+/// Put the transceiver in (or take it out of) standby, it doesn't receive either
+pub fn set_standby(standby: bool) {
+    STANDBY_PIN.lock(|p| {
+        if let Some(pin) = p.borrow_mut().as_mut() {
+            pin.output.set_level(Level::from(standby != pin.active_low));
+        }
+    });
+}
+
+/// Keep the transceiver from driving the bus while it still receives, if it can
+pub fn set_silent(silent: bool) {
+    SILENT_PIN.lock(|p| {
+        if let Some(output) = p.borrow_mut().as_mut() {
+            output.set_level(Level::from(silent));
+        }
+    });
+}
+
+/// Switch the bus termination resistor, returns false if the board has no termination pin
+pub fn set_termination(enabled: bool) -> bool {
+    TERMINATION_PIN.lock(|p| match p.borrow_mut().as_mut() {
+        Some(output) => {
+            info!("[transceiver] termination enabled {}", enabled);
+            output.set_level(Level::from(enabled));
+            true
+        }
+        None => false,
+    })
+}